@@ -16,6 +16,9 @@ async fn accept_connection(peer: SocketAddr, stream: TcpStream) {
     }
 }
 
+// `tokio::select!` expands to `IntoFuture`, which the versions of tokio that build on the
+// minimum supported Rust version do without
+#[allow(clippy::incompatible_msrv)]
 async fn handle_connection(peer: SocketAddr, stream: TcpStream) -> Result<()> {
     let ws_stream = accept_async(stream).await.expect("Failed to accept");
     info!("New WebSocket connection: {}", peer);
@@ -13,7 +13,7 @@
 use std::env;
 
 use futures_util::{future, pin_mut, StreamExt};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncBufReadExt;
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 #[tokio::main]
 async fn main() {
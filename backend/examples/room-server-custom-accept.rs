@@ -2,26 +2,97 @@
 //!
 //! This is a simple line-based server which accepts WebSocket connections,
 //! reads lines from those connections, and broadcasts the lines to all other
-//! connected clients.
+//! connected clients. Each text frame is relayed as one message, unless its
+//! room is listed in `ServerConfig::line_split_rooms`, in which case every
+//! line of the frame is relayed as a message of its own. Rooms set to
+//! `RoomMode::Encrypted` only relay opaque ciphertext, see its documentation.
 //!
 //! You can test this out by running:
 //!
 //!     cargo run --example room-server-custom-accept 127.0.0.1:12345
 //!
+//! or, with the `rustls-tls-server` feature, pass the PEM files of a certificate
+//! and its key after the address to serve `wss://`.
+//!
 //! And then in another window run:
 //!
-//!     cargo run --example client ws://127.0.0.1:12345/socket?name=test&transcribe_to=jp&translate_to=en
+//!     cargo run --example client ws://127.0.0.1:12345/socket?name=test&transcribe_to=ja&translate_to=en
+//!
+//! The room can be named with `&room=<room>` instead of the path, for clients
+//! that cannot pick it; the path wins if both name a room.
+//!
+//! Several languages to translate to can be given either comma-separated
+//! (`translate_to=en,ja`) or by repeating the parameter.
+//!
+//! Add `&delivery=both` to also receive spoken versions of the messages when a
+//! `Synthesizer` is plugged into the server, translated to your first
+//! `translate_to` language, or `&delivery=audio` to only get them spoken
+//! (`text`, the default, only gets them written; `&tts=true` is short for
+//! `both`). Add `&timezone=%2B09:00` to get
+//! server messages with a timestamp preformatted for that UTC offset. With
+//! `ServerConfig::batch_window` set, `&batch=true` gets messages sent in quick
+//! succession as one batch frame. With `ServerConfig::reconnect_grace` set,
+//! `&session_id=<id>` keeps the name reserved for a while after a dropped
+//! connection, for a reconnect with the same `session_id` to reclaim, whose
+//! `room_snapshot` has `"resumed":true` along with the joiner's `role`.
+//! `&spectator=true` joins as a spectator, taking a place of
+//! `ServerConfig::max_spectators` rather than `ServerConfig::max_members`.
+//!
+//! Binary frames are audio spoken in the `transcribe_to` language. Besides
+//! being relayed, they are transcribed by the server's `Transcriber`, and the
+//! room gets a `transcript` of each of them tagged with the frame's `seq`.
+//! With `&chunked_audio=true`, binary frames are numbered `AudioChunk`s
+//! instead, which the server puts back in order before transcribing them an
+//! utterance at a time: the transcript of an utterance is tagged with the
+//! `seq` of its final chunk. Chunks arriving too far ahead, see
+//! `ServerConfig::audio_reorder_window`, are dropped with an `audio_nack`
+//! naming the chunk the server still waits for.
+//! Transcribers reporting interim results get the room `transcript`s with
+//! `"final":false` first, each replacing the one before, which the speaker is
+//! not sent. Only the final transcript, `"final":true`, is translated.
+//! Whoever creates a room may pick one of the transcriber's profiles for it
+//! with `&transcription_profile=<profile>`. Every participant gets the
+//! transcripts with `translations` into its own `translate_to` languages,
+//! which `{"type":"control","action":"set_translate_to","lang":"de,fr"}`
+//! changes mid-session: the server answers with an `ack` of the new languages,
+//! or an `error` leaving them as they were.
+//! `GET /rooms` lists the rooms anyone is in, each with its participants, their
+//! `count`, the `max` of `ServerConfig::max_participants` and whether a new
+//! participant could join it, `joinable`. `?joinable=true` leaves out the full
+//! rooms.
+//! With `ServerConfig::transcript_dir` set, the final transcripts of each room
+//! are saved to `<room>.jsonl` in that directory, see `FileTranscriptSink`, and
+//! `GET /rooms/<room>/search?q=<text>` finds the entries containing the text,
+//! in any case, each with the entries said right before and after it. Add
+//! `&from=` and `&to=` RFC3339 timestamps to only search part of the room's
+//! history. The search is authorized like the handshakes are.
+//!
+//! With a `Synthesizer` plugged in, `{"type":"follow","name":"X","lang":"en"}`
+//! gets what X says interpreted: each of X's transcripts is translated to `en`
+//! and spoken to the follower only, until `{"type":"unfollow"}`. Only audio
+//! participants send is ever transcribed, never the speech the server
+//! synthesizes, so participants following each other cannot loop.
+//!
+//! Setting `ServerConfig::debug_token` enables `GET /debug/self-test`, which
+//! reports on the server and its pipeline backends to requests authorized with
+//! `Authorization: Bearer <token>`. With `ServerConfig::debug_connections`
+//! also set, `GET /debug/connections` lists the active connections and
+//! `GET /debug/connections/<addr>` describes one of them. `GET /metrics` serves
+//! the room, participant and traffic counters in the Prometheus text format,
+//! behind the same token if one is set, along with how many of the latest
+//! messages each room keeps, up to `ServerConfig::max_buffered_messages`.
 //!
 //! You can run the second command in multiple windows and then chat between the
 //! two, seeing the messages from the other client as they're received. For all
 //! connected clients they'll all join the same room and see everyone else's
 //! messages.
 
+use chrono::{DateTime, FixedOffset, Utc};
 use hyper::{
     body::Incoming,
     header::{
-        HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION,
-        UPGRADE,
+        HeaderValue, AUTHORIZATION, CONNECTION, CONTENT_TYPE, RETRY_AFTER, SEC_WEBSOCKET_ACCEPT,
+        SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE,
     },
     server::conn::http1,
     service::service_fn,
@@ -34,343 +105,1489 @@ use std::{
     collections::HashMap,
     convert::Infallible,
     env,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
 };
-use tokio::net::TcpListener;
 
-use futures_channel::mpsc::{unbounded, UnboundedSender};
-use futures_util::{future, pin_mut, stream::TryStreamExt, StreamExt};
+use futures_channel::mpsc;
+use futures_util::{pin_mut, SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::TcpListener,
+    sync::Semaphore,
+};
 
 use tokio_tungstenite::{
+    batch, binary_envelope, heartbeat, idle_timeout, is_valid_session_id, join_notice, keepalive,
+    leave_notice, max_lifetime, normalize_name, parse_room_id, prioritized, relay,
+    requested_room_id, same_name, sanitize_text, split_lines,
     tungstenite::{
         handshake::derive_accept_key,
-        protocol::{Message, Role},
+        protocol::{frame::coding::CloseCode, CloseFrame, Message, Role},
+        Bytes,
     },
-    WebSocketStream,
+    until_shutdown, Activity, AudioChunk, AuthInfo, AuthPolicy, ClientMessage, Delivery, EventSink,
+    FileTranscriptSink, Heartbeat, InFlight, InFlightGuard, InvalidAudioChunk, JoinError,
+    LanguageCode, LogFilter, Logger, MessageType, Metrics, NoopTranscriber, NoopTranscriptSink,
+    NoopTranslator, OutOfWindow, Participant, QueueSender, RateLimit, Recipient, Rejection,
+    RemoteRoster, ReorderBuffer, RoomEvent, RoomManager, RoomMode, ServerConfig, ServerError,
+    Shutdown, StreamAcceptor, Synthesizer, TlsConfig, TokenBucket, Transcriber, TranscriptEntry,
+    TranscriptEvent, TranscriptQuery, TranscriptSink, Translations, Translator, WebSocketStream,
+    MAX_SESSION_ID_LEN,
 };
 
-type Tx = UnboundedSender<Message>;
+type Tx = QueueSender;
 type Body = http_body_util::Full<hyper::body::Bytes>;
-use url::{form_urlencoded, Url};
+use tracing::{debug, field::Empty, info, info_span, trace, warn, Instrument, Level, Span};
+use url::form_urlencoded;
 
 struct PartialParticipant {
     name: String,
-    transcribe_to: String,
-    translate_to: String,
+    room_name: String,
+    transcribe_to: LanguageCode,
+    translate_to: Vec<LanguageCode>,
+    delivery: Delivery,
+    timezone: Option<FixedOffset>,
+    /// Whether the participant accepts batch frames
+    batch: bool,
+    /// The transcription profile for the room, should the participant create it
+    transcription_profile: Option<String>,
+    /// The session a reconnecting participant reclaims its reserved name with
+    session_id: Option<String>,
+    /// Whether the participant sends its audio as numbered chunks
+    chunked_audio: bool,
+    /// Whether the participant only follows the room, counting against `max_spectators`
+    spectator: bool,
+}
+
+/// A participant having someone else's speech interpreted for it
+struct Follow {
+    /// The name of the participant followed
+    leader: String,
+    /// The language to interpret into
+    lang: LanguageCode,
+    /// Where the follower gets the speech
+    tx: Tx,
 }
 
+/// The pipeline backends, shared by all connections
 #[derive(Clone)]
-struct Participant {
-    name: String,
-    _transcribe_to: String,
-    _translate_to: String,
-    sender: Tx,
+struct Backends {
+    synthesizer: Option<Arc<dyn Synthesizer>>,
+    transcriber: Arc<dyn Transcriber>,
+    translator: Arc<dyn Translator>,
+    /// Keeps the final transcripts of the rooms
+    transcripts: Arc<dyn TranscriptSink>,
+    /// The transcripts kept on disk, if any, searched at `GET /rooms/<room>/search`
+    archive: Option<Arc<FileTranscriptSink>>,
+    /// Receives what happens in the rooms, e.g. to post it to a webhook
+    events: Arc<dyn EventSink>,
+    /// Decides which handshakes are upgraded at all
+    auth: Arc<dyn AuthPolicy>,
+    /// Permits for the transcriptions running at once, across all rooms
+    transcriptions: Arc<Semaphore>,
+    /// The transcription profiles of the rooms using one, by canonical room id
+    profiles: Arc<Mutex<HashMap<String, String>>>,
+    /// The followers of each room by their address, by canonical room id
+    follows: Arc<Mutex<HashMap<String, HashMap<SocketAddr, Follow>>>>,
+    /// The transcriptions and syntheses under way, which a shutdown lets finish
+    pipeline: InFlight,
+    /// The WebSocket connections open, which a shutdown closes and waits for
+    connections: InFlight,
+    /// Closes every connection once triggered
+    shutdown: Shutdown,
+    /// What the server did, served at `GET /metrics`
+    metrics: Metrics,
 }
 
-type RoomName = String;
+impl Backends {
+    fn new(
+        synthesizer: Option<Arc<dyn Synthesizer>>,
+        transcriber: Arc<dyn Transcriber>,
+        translator: Arc<dyn Translator>,
+        transcripts: Arc<dyn TranscriptSink>,
+        events: Arc<dyn EventSink>,
+        auth: Arc<dyn AuthPolicy>,
+        config: &ServerConfig,
+    ) -> Self {
+        Backends {
+            synthesizer,
+            transcriber,
+            translator,
+            transcripts,
+            archive: None,
+            events,
+            auth,
+            transcriptions: Arc::new(Semaphore::new(config.max_concurrent_transcriptions)),
+            profiles: Arc::default(),
+            follows: Arc::default(),
+            pipeline: InFlight::new(),
+            connections: InFlight::new(),
+            shutdown: Shutdown::new(),
+            metrics: Metrics::new(),
+        }
+    }
 
-type RoomParticipants = HashMap<SocketAddr, Participant>;
+    /// The transcription profile of `room_id`, empty for the transcriber's default
+    fn profile(&self, room_id: &str) -> String {
+        self.profiles.lock().unwrap().get(room_id).cloned().unwrap_or_default()
+    }
+}
 
-type RoomMap = Arc<Mutex<HashMap<RoomName, RoomParticipants>>>;
+/// Source of the ids the server assigns to connections, unique for the server's lifetime
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
 
-fn get_room_participants(room_id: &str, room_map: &RoomMap) -> Vec<Participant> {
-    let map = room_map.lock().unwrap();
-    map.get(room_id).map(|peers| peers.values().map(|p| p.clone()).collect()).unwrap_or_default()
+/// Render a message of the server for its recipient and queue it, unless a transform dropped it
+fn send_to(config: &ServerConfig, tx: &Tx, recipient: &Recipient, msg: Message) {
+    if let Some(msg) = config.outbound.apply(msg, recipient) {
+        let _ = tx.push(msg);
+    }
+}
+
+/// Render a message of another participant for its recipient like `send_to`, leaving alone
+/// what only the server's messages get, e.g. local timestamps
+fn relay_to(config: &ServerConfig, tx: &Tx, recipient: &Recipient, msg: Message) {
+    if let Some(msg) = config.outbound.apply_relayed(msg, recipient) {
+        let _ = tx.push(msg);
+    }
 }
 
 fn broadcast_ws_handshake_success(
     curr_addr: SocketAddr,
     curr_participant: &Participant,
     room_id: &str,
-    room_map: &RoomMap,
+    room_map: &RoomManager,
+    config: &ServerConfig,
 ) {
     let timestamp = chrono::Utc::now().to_rfc3339();
+    let requested_room = curr_participant.room_name.as_deref().unwrap_or(room_id);
 
     // Send to owner
-    let _ = curr_participant.sender.unbounded_send(Message::Text(
-        json!({
-            "type": "ws_handshake_status",
-            "status": "connected",
-            "timestamp": timestamp,
-            "message": format!("You joined the room '{}'", room_id)
-        })
-        .to_string()
-        .into(),
-    ));
-
-    // Collect senders for others without holding the lock
-    let other_senders: Vec<Tx> =
-        {
-            let map = room_map.lock().unwrap();
-            map.get(room_id)
-                .map(|peers| {
-                    peers
-                        .iter()
-                        .filter_map(|(peer_addr, p)| {
-                            if *peer_addr != curr_addr {
-                                Some(p.sender.clone())
-                            } else {
-                                None
-                            }
-                        })
-                        .collect()
-                })
-                .unwrap_or_default()
-        };
+    let msg = json!({
+        "type": "ws_handshake_status",
+        "status": "connected",
+        "timestamp": timestamp,
+        "message": sanitize_text(format!("You joined the room '{}'", requested_room))
+    });
+    send_to(
+        config,
+        &curr_participant.control,
+        &curr_participant.recipient(room_id),
+        Message::Text(msg.to_string().into()),
+    );
 
-    // Send to everyone else
-    for tx in other_senders {
-        let _ = tx.unbounded_send(Message::Text(
-            json!({
-                "type": "ws_handshake_status",
-                "status": "connected",
-                "timestamp": timestamp,
-                "message": format!("{} joined the room", curr_participant.name)
-            })
-            .to_string()
-            .into(),
-        ));
-    }
+    // Send to everyone else, like the room server does
+    let msg = join_notice(&curr_participant.name, curr_addr);
+    room_map.broadcast(room_id, &msg, Some(curr_addr));
 }
 
 fn broadcast_ws_handshake_close(
-    curr_addr: SocketAddr,
     curr_participant: &Participant,
     room_id: &str,
-    room_map: &RoomMap,
+    config: &ServerConfig,
 ) {
     let timestamp = chrono::Utc::now().to_rfc3339();
+    let requested_room = curr_participant.room_name.as_deref().unwrap_or(room_id);
 
     // Send to owner
-    let _ = curr_participant.sender.unbounded_send(Message::Text(
-        json!({
-            "type": "ws_handshake_status",
-            "status": "close",
-            "timestamp": timestamp,
-            "message": format!("You left the room '{}'", room_id)
-        })
-        .to_string()
-        .into(),
-    ));
+    let msg = json!({
+        "type": "ws_handshake_status",
+        "status": "close",
+        "timestamp": timestamp,
+        "message": sanitize_text(format!("You left the room '{}'", requested_room))
+    });
+    send_to(
+        config,
+        &curr_participant.control,
+        &curr_participant.recipient(room_id),
+        Message::Text(msg.to_string().into()),
+    );
+}
 
-    // Collect senders for others without holding the lock
-    let other_senders: Vec<Tx> =
-        {
-            let map = room_map.lock().unwrap();
-            map.get(room_id)
-                .map(|peers| {
-                    peers
-                        .iter()
-                        .filter_map(|(peer_addr, p)| {
-                            if *peer_addr != curr_addr {
-                                Some(p.sender.clone())
-                            } else {
-                                None
-                            }
-                        })
-                        .collect()
-                })
-                .unwrap_or_default()
+/// Synthesize `text` in the background and push the audio to a single participant, counting
+/// as `work` until the audio is queued
+fn speak_to(
+    synthesizer: Arc<dyn Synthesizer>,
+    text: String,
+    lang: LanguageCode,
+    speaker: String,
+    tx: Tx,
+    work: InFlightGuard,
+) {
+    tokio::spawn(
+        async move {
+            let _work = work;
+            match synthesizer.synthesize(&text, &lang).await {
+                Ok(audio) => {
+                    let header = json!({
+                        "type": "tts",
+                        "name": sanitize_text(&speaker),
+                        "lang": lang.to_string(),
+                    });
+                    let _ = tx.push(Message::Binary(binary_envelope(&header, &audio).into()));
+                }
+                Err(e) => warn!(speaker, error = %e, "Failed to synthesize a message"),
+            }
+        }
+        .instrument(Span::current()),
+    );
+}
+
+/// Speak `text`, written in `from`, to each of `listeners` in the language it reads, counting
+/// as `work` until every synthesis is under way
+///
+/// Each language is translated to once, however many listeners read it.
+async fn speak_translated(
+    backends: Backends,
+    text: String,
+    from: LanguageCode,
+    speaker: String,
+    listeners: Vec<(LanguageCode, Tx)>,
+    work: InFlightGuard,
+) {
+    let _work = work;
+    let synthesizer = match &backends.synthesizer {
+        Some(synthesizer) => synthesizer,
+        None => return,
+    };
+    let mut translations = Translations::new(&*backends.translator, &text, &from);
+    for (lang, tx) in listeners {
+        if let Some(text) = translations.get(&lang).await {
+            let work = backends.pipeline.begin();
+            speak_to(synthesizer.clone(), text.to_string(), lang, speaker.clone(), tx, work);
+        }
+    }
+    backends.metrics.translations(translations.attempted());
+}
+
+/// Transcribe the audio frames a participant sends, one after the other, and tell the room
+/// what was said
+///
+/// Runs until the participant's connection stops queueing frames. Each transcription takes
+/// one of the server-wide permits, frames arriving while none is free are skipped.
+async fn transcribe_audio(
+    backends: Backends,
+    room_map: RoomManager,
+    room_id: String,
+    addr: SocketAddr,
+    speaker: Participant,
+    lang: LanguageCode,
+    mut frames: mpsc::Receiver<(u64, Bytes)>,
+) {
+    while let Some((seq, audio)) = frames.next().await {
+        let _permit = match backends.transcriptions.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                info!(speaker = %speaker.name, "Skipped transcribing audio, no transcriber free");
+                let _ = speaker.control.push(transcription_skipped_notice(seq));
+                continue;
+            }
         };
+        let _work = backends.pipeline.begin();
+        let profile = backends.profile(&room_id);
+        backends.metrics.transcription();
+        let mut events = backends.transcriber.transcribe_stream(&audio, &lang, &profile);
+        while let Some(event) = events.next().await {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!(speaker = %speaker.name, error = %e, "Failed to transcribe audio");
+                    break;
+                }
+            };
+            let mut msg = event.to_message(&speaker.name, &lang);
+            msg["seq"] = seq.into();
+            let transcript = match event {
+                // Partials are neither translated nor spoken, the speaker knows what it said
+                TranscriptEvent::Partial(_) => {
+                    room_map.broadcast_partial(&room_id, addr, &msg);
+                    continue;
+                }
+                TranscriptEvent::Final(transcript) => transcript,
+            };
+            let mut translations =
+                Translations::new(&*backends.translator, &transcript.text, &lang);
+            room_map.broadcast_translated(&room_id, &msg, &mut translations).await;
+            let entry = TranscriptEntry::new(&speaker.name, &transcript.text, lang.clone());
+            if let Err(e) = backends.transcripts.record(&room_id, entry).await {
+                warn!(speaker = %speaker.name, error = %e, "Failed to record a transcript");
+            }
+            interpret_for_followers(&backends, &room_id, &speaker.name, &mut translations).await;
+            backends.metrics.translations(translations.attempted());
+            break;
+        }
+    }
+}
 
-    // Send to everyone else
-    for tx in other_senders {
-        let _ = tx.unbounded_send(Message::Text(
-            json!({
-                "type": "ws_handshake_status",
-                "status": "close",
-                "timestamp": timestamp,
-                "message": format!("{} left the room", curr_participant.name)
-            })
-            .to_string()
-            .into(),
-        ));
+/// Speak what `speaker` said to whoever follows them, in the language each follows in
+///
+/// The translations made for the room are reused, so interpreting costs no translations of
+/// its own unless a follower picked a language nobody reads.
+async fn interpret_for_followers(
+    backends: &Backends,
+    room_id: &str,
+    speaker: &str,
+    translations: &mut Translations<'_>,
+) {
+    let synthesizer = match &backends.synthesizer {
+        Some(synthesizer) => synthesizer,
+        None => return,
+    };
+    let followers: Vec<(LanguageCode, Tx)> = {
+        let follows = backends.follows.lock().unwrap();
+        let follows = follows.get(room_id).into_iter().flat_map(|follows| follows.values());
+        follows
+            .filter(|follow| follow.leader == speaker)
+            .map(|follow| (follow.lang.clone(), follow.tx.clone()))
+            .collect()
+    };
+    for (lang, tx) in followers {
+        if let Some(text) = translations.get(&lang).await {
+            let work = backends.pipeline.begin();
+            speak_to(synthesizer.clone(), text.to_string(), lang, speaker.to_string(), tx, work);
+        }
+    }
+}
+
+/// Tell a participant why its request was refused
+fn refused(code: &str, message: String) -> Message {
+    let msg = json!({ "type": "error", "code": code, "message": message });
+    Message::Text(msg.to_string().into())
+}
+
+/// Stop interpreting anyone's speech for the participant at `addr`, returning what to tell it
+fn handle_unfollow(backends: &Backends, room_id: &str, addr: SocketAddr) -> Message {
+    let mut follows = backends.follows.lock().unwrap();
+    follows.get_mut(room_id).map(|follows| follows.remove(&addr));
+    let msg = json!({ "type": "following", "name": null });
+    Message::Text(msg.to_string().into())
+}
+
+/// Start interpreting the speech of `leader` in `lang` for the participant at `addr`,
+/// returning what to tell it
+fn handle_follow(
+    backends: &Backends,
+    room_map: &RoomManager,
+    room_id: &str,
+    addr: SocketAddr,
+    follower: &Participant,
+    leader: &str,
+    lang: &str,
+) -> Message {
+    let lang = match lang.parse::<LanguageCode>() {
+        Ok(lang) => lang,
+        Err(e) => return refused("invalid_language", e.to_string()),
+    };
+    if backends.synthesizer.is_none() {
+        return refused("speech_unavailable", "Speech synthesis is not available".into());
+    }
+    if same_name(leader, &follower.name) {
+        return refused("invalid_follow", "Participants cannot follow themselves".into());
+    }
+    // Followed by the name the leader goes by, whichever case the follower asked in
+    let found = room_map.participants(room_id).into_iter().find(|p| same_name(&p.name, leader));
+    let (leader, from) = match found {
+        Some(Participant { name, transcribe_to: Some(from), .. }) => (name, from),
+        _ => {
+            let message = format!("Nobody called '{}' is in the room", sanitize_text(leader));
+            return refused("unknown_participant", message);
+        }
+    };
+    if from != lang && !backends.translator.supports(&from, &lang) {
+        let message = format!("Translation from '{}' to '{}' not available", from, lang);
+        return refused("translation_unavailable", message);
+    }
+
+    let msg = json!({ "type": "following", "name": sanitize_text(&leader), "lang": lang.as_str() });
+    let follow = Follow { leader, lang, tx: follower.sender.clone() };
+    backends.follows.lock().unwrap().entry(room_id.to_string()).or_default().insert(addr, follow);
+    Message::Text(msg.to_string().into())
+}
+
+/// Switch the languages the participant at `addr` gets transcripts translated to, returning
+/// what to tell it. The languages are left alone unless all of `lang` can be translated to.
+fn handle_set_translate_to(
+    backends: &Backends,
+    room_map: &RoomManager,
+    room_id: &str,
+    addr: SocketAddr,
+    participant: &Participant,
+    lang: &str,
+) -> Message {
+    let translate_to = match LanguageCode::parse_list(std::iter::once(lang)) {
+        Ok(translate_to) if !translate_to.is_empty() => translate_to,
+        Ok(_) => return refused("invalid_language", "No language to translate to".into()),
+        Err(e) => return refused("invalid_language", e.to_string()),
+    };
+    if let Some(from) = &participant.transcribe_to {
+        let untranslatable =
+            translate_to.iter().find(|to| *to != from && !backends.translator.supports(from, to));
+        if let Some(to) = untranslatable {
+            let message = format!("Translation from '{}' to '{}' not available", from, to);
+            return refused("translation_unavailable", message);
+        }
+    }
+
+    let value = translate_to.iter().map(LanguageCode::as_str).collect::<Vec<_>>();
+    let msg = json!({ "type": "ack", "field": "translate_to", "value": value });
+    info!(translate_to = ?value, "Changed the languages to translate to");
+    room_map.with_room(room_id, |room| {
+        if let Some(participant) = room.get_mut(&addr) {
+            participant.translate_to = translate_to;
+        }
+    });
+    Message::Text(msg.to_string().into())
+}
+
+/// Tell a participant that its audio frame was not transcribed, as too many were waiting
+fn audio_dropped_notice(seq: u64) -> Message {
+    let msg = json!({
+        "type": "audio_dropped",
+        "seq": seq,
+        "message": "You are sending audio faster than it is transcribed, this frame was dropped"
+    });
+    Message::Text(msg.to_string().into())
+}
+
+/// Tell a participant that its audio frame was not transcribed, as the server is busy
+fn transcription_skipped_notice(seq: u64) -> Message {
+    let msg = json!({
+        "type": "transcription_skipped",
+        "seq": seq,
+        "message": "The server is too busy to transcribe this frame"
+    });
+    Message::Text(msg.to_string().into())
+}
+
+/// Put a numbered audio chunk in order, returning the utterances it completes, each tagged
+/// with the `seq` of its last chunk, or the notice telling the sender why it was dropped
+fn reorder_audio(
+    reorder: &mut ReorderBuffer,
+    utterance: &mut Vec<u8>,
+    payload: &[u8],
+    max_len: usize,
+) -> Result<Vec<(u64, Bytes)>, Message> {
+    let chunk = AudioChunk::decode(payload).map_err(invalid_audio_chunk)?;
+    let chunks = reorder.push(chunk).map_err(audio_nack)?;
+    let mut utterances = Vec::new();
+    for chunk in chunks {
+        utterance.extend_from_slice(&chunk.data);
+        // Utterances that never end are transcribed in pieces as large as a single frame
+        if chunk.is_final || utterance.len() >= max_len {
+            utterances.push((chunk.seq, Bytes::from(std::mem::take(utterance))));
+        }
+    }
+    Ok(utterances)
+}
+
+/// Tell a participant that its audio chunk was dropped for arriving too far ahead, and which
+/// chunk to send again
+fn audio_nack(e: OutOfWindow) -> Message {
+    let msg = json!({
+        "type": "audio_nack",
+        "seq": e.seq,
+        "expected": e.expected,
+        "message": e.to_string()
+    });
+    Message::Text(msg.to_string().into())
+}
+
+/// Tell a participant that its binary frame was dropped for not being a numbered audio chunk
+fn invalid_audio_chunk(e: InvalidAudioChunk) -> Message {
+    let msg = json!({
+        "type": "error",
+        "code": "invalid_audio_chunk",
+        "message": e.to_string()
+    });
+    Message::Text(msg.to_string().into())
+}
+
+/// Relay a message of an end-to-end encrypted room, attributed to its sender
+///
+/// Only key exchange messages are read, to route them to the participant they are meant for.
+fn relay_encrypted(
+    room_map: &RoomManager,
+    metrics: &Metrics,
+    room_id: &str,
+    from_addr: SocketAddr,
+    from: &str,
+    msg: Message,
+) {
+    let from = sanitize_text(from);
+    let (to, msg) = match msg {
+        Message::Text(text) => match serde_json::from_str::<serde_json::Value>(&text) {
+            Ok(mut key_exchange) if key_exchange["type"] == "key_exchange" => {
+                let to = key_exchange["to"].as_str().map(str::to_string);
+                key_exchange["from"] = from.into();
+                (to, Message::text(key_exchange.to_string()))
+            }
+            _ => {
+                let msg = json!({ "type": "encrypted", "from": from, "payload": text.as_str() });
+                (None, Message::text(msg.to_string()))
+            }
+        },
+        Message::Binary(data) => {
+            let header = json!({ "type": "encrypted", "from": from });
+            (None, Message::binary(binary_envelope(&header, &data)))
+        }
+        _ => return,
+    };
+
+    metrics.message_broadcast();
+    let peers = room_map.room(room_id).unwrap_or_default();
+    for participant in peers.others(from_addr) {
+        if to.as_ref().map_or(true, |to| same_name(to, &participant.name)) {
+            metrics.bytes_forwarded(msg.len());
+            let _ = participant.sender.push(msg.clone());
+        }
     }
 }
 
 async fn handle_connection(
     room_id: String,
-    room_map: RoomMap,
+    room_map: RoomManager,
+    config: Arc<ServerConfig>,
+    backends: Backends,
     partial_participant: PartialParticipant,
-    ws_stream: WebSocketStream<TokioIo<Upgraded>>,
+    mut ws_stream: WebSocketStream<TokioIo<Upgraded>>,
     addr: SocketAddr,
 ) {
-    // ---- Create a sender channel for this participant ----
-    let (tx, rx) = unbounded();
-
-    // ---- Insert participant (safe now because name already validated) ----
-    let participant = Participant {
-        name: partial_participant.name,
-        _transcribe_to: partial_participant.transcribe_to,
-        _translate_to: partial_participant.translate_to,
-        sender: tx,
-    };
+    // ---- Create the sender channels for this participant ----
+    let (tx, rx) = config.outbound_queue();
+    let (control_tx, control_rx) = config.outbound_queue();
+    let batched = partial_participant.batch;
+    let chunked_audio = partial_participant.chunked_audio;
 
-    let participant_for_broadcast = participant.clone();
+    // -- Tell the joiner who the server knows it as, before anything else
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let _ = control_tx.push(Message::Text(
+        json!({
+            "type": "connection_info",
+            "your_id": id.to_string(),
+            "your_addr": addr.to_string(),
+            "room": sanitize_text(&room_id),
+            "server_version": env!("CARGO_PKG_VERSION")
+        })
+        .to_string()
+        .into(),
+    ));
 
-    {
-        let mut map = room_map.lock().unwrap();
-        map.entry(room_id.clone()).or_default().insert(addr, participant);
-        println!("WebSocket connection established: {}", addr);
+    // ---- Insert participant, unless someone took its name or place since the handshake ----
+    let mut participant = Participant::new(id, partial_participant.name, tx, control_tx);
+    participant.room_name = Some(partial_participant.room_name).filter(|name| *name != room_id);
+    participant.transcribe_to = Some(partial_participant.transcribe_to);
+    participant.translate_to = partial_participant.translate_to;
+    participant.delivery = partial_participant.delivery;
+    participant.timezone = partial_participant.timezone;
+    participant.session_id = partial_participant.session_id;
+    participant.spectator = partial_participant.spectator;
+
+    let mut participant_for_broadcast = participant.clone();
+    let (created, resumed) = match room_map.join(&room_id, addr, participant) {
+        Ok(joined) => {
+            participant_for_broadcast.name = joined.name;
+            (joined.created, joined.resumed)
+        }
+        Err(e) => {
+            warn!(error = %e, "Could not join the room");
+            let code = match e {
+                JoinError::RoomFull => {
+                    let msg = config.room_full_error();
+                    let _ = ws_stream.send(Message::text(msg.to_string())).await;
+                    CloseCode::Again
+                }
+                JoinError::DuplicateName(_) => CloseCode::Policy,
+            };
+            let close = CloseFrame { code, reason: e.to_string().into() };
+            let _ = ws_stream.close(Some(close)).await;
+            return;
+        }
+    };
+    let span = Span::current();
+    span.record("name", participant_for_broadcast.name.as_str());
+    info!(created, resumed, "Joined the room");
+    if created {
+        backends.events.send(RoomEvent::RoomCreated { room: room_id.clone() });
+    }
+    let name = participant_for_broadcast.name.clone();
+    backends.events.send(RoomEvent::Joined { room: room_id.clone(), name });
+    // A room keeps the profile its creator picked until it closes
+    if let (true, Some(profile)) = (created, partial_participant.transcription_profile) {
+        backends.profiles.lock().unwrap().insert(room_id.clone(), profile);
     }
+
+    // -- Transcribe the participant's audio apart from its connection, which keeps reading
+    let mut audio_tx = participant_for_broadcast.transcribe_to.clone().map(|lang| {
+        let (audio_tx, audio_rx) = mpsc::channel(config.transcription_queue);
+        let transcription = transcribe_audio(
+            backends.clone(),
+            room_map.clone(),
+            room_id.clone(),
+            addr,
+            participant_for_broadcast.clone(),
+            lang,
+            audio_rx,
+        );
+        tokio::spawn(transcription.instrument(Span::current()));
+        audio_tx
+    });
+    let mut audio_seq = 0;
+    // Numbered audio is put back in order, and transcribed an utterance at a time
+    let mut reorder = chunked_audio.then(|| ReorderBuffer::new(config.audio_reorder_window));
+    let mut utterance = Vec::new();
+
+    // -- Tell the joiner about the room it landed in, as it is now even if it reconnected
+    let role = room_map.read_room(&room_id, |room| room.get(&addr).map(|p| p.role)).flatten();
+    let _ = participant_for_broadcast.control.push(Message::Text(
+        json!({
+            "type": "room_snapshot",
+            "room": sanitize_text(participant_for_broadcast.room_name.as_deref().unwrap_or(&room_id)),
+            "name": sanitize_text(&participant_for_broadcast.name),
+            "created": created,
+            "role": role.unwrap_or_default().as_str(),
+            // Nobody can pause the rooms of this server
+            "paused": false,
+            "resumed": resumed
+        })
+        .to_string()
+        .into(),
+    ));
+    // Single instance, so nobody joined elsewhere
+    let max_listed = config.max_listed_participants;
+    room_map.send_participants_to(&RemoteRoster::new(), &room_id, addr, max_listed);
+
     // -- Broadcast WS Handshake
-    broadcast_ws_handshake_success(addr, &participant_for_broadcast, &room_id, &room_map);
+    broadcast_ws_handshake_success(addr, &participant_for_broadcast, &room_id, &room_map, &config);
 
-    // ---- Split into outgoing/incoming streams ----
-    let (outgoing, incoming) = ws_stream.split();
+    // ---- Relay messages until the participant disconnects ----
+    let mut rate_limit = config.message_rate_limit.map(TokenBucket::new);
+    let stats = participant_for_broadcast.stats.clone();
+    *stats.rate_limit_tokens.lock().unwrap() = rate_limit.as_ref().map(TokenBucket::tokens);
+    let pongs = Heartbeat::new();
+    let activity = Activity::new();
+    let on_message = |msg: Message| {
+        stats.bytes_in.fetch_add(msg.len() as u64, Ordering::Relaxed);
+        if msg.is_pong() {
+            pongs.pong();
+            return;
+        }
+        activity.observe(&msg);
+        if let Some(bucket) = &mut rate_limit {
+            let admitted = bucket.admit(&msg);
+            *stats.rate_limit_tokens.lock().unwrap() = Some(bucket.tokens());
+            if !admitted {
+                info!("Dropped a message, rate limited");
+                let notice = RateLimit::exceeded_notice().to_string();
+                let _ = participant_for_broadcast.control.push(Message::Text(notice.into()));
+                return;
+            }
+        }
 
-    let broadcast_incoming = incoming.try_for_each(|msg| {
-        match msg {
-            Message::Text(ref text) => {
-                println!("[Room: {}] Received a message from {}: {}", room_id, addr, text);
+        // Requests to the server are answered whichever types the room allows, anything else
+        // is chat for the room
+        let parsed = match &msg {
+            Message::Text(text) => ClientMessage::parse(text).ok(),
+            _ => None,
+        };
+        let kind = MessageType::of(&msg, parsed.as_ref());
+        if let (false, Some(kind)) = (config.allows_message(&room_id, &msg, parsed.as_ref()), kind)
+        {
+            info!(kind = kind.as_str(), "Rejected a message of a type the room does not allow");
+            let notice = kind.not_allowed_error().to_string();
+            let _ = participant_for_broadcast.control.push(Message::Text(notice.into()));
+            return;
+        }
+
+        if let Some(error) = config.oversized_audio(&msg) {
+            info!(bytes = msg.len(), "Dropped an oversized audio chunk");
+            let _ = participant_for_broadcast.control.push(Message::Text(error.to_string().into()));
+            return;
+        }
+
+        // Encrypted rooms are relayed without looking into, or logging, the payloads
+        if config.room_mode(&room_id) == RoomMode::Encrypted {
+            let from = &participant_for_broadcast.name;
+            relay_encrypted(&room_map, &backends.metrics, &room_id, addr, from, msg);
+            return;
+        }
+
+        if msg.is_text() || msg.is_binary() {
+            let (kind, payload) = config.describe_message(&room_id, &msg);
+            info!(kind, len = msg.len(), "Received a message");
+            if let Some(payload) = payload {
+                trace!(kind, payload = ?payload, "Payload of the message");
             }
-            Message::Binary(ref bin) => {
-                println!("[Room: {}] Received binary from {}: {:?}", room_id, addr, bin);
+        }
+
+        let reply = match parsed {
+            Some(ClientMessage::Follow { name, lang }) => Some(handle_follow(
+                &backends,
+                &room_map,
+                &room_id,
+                addr,
+                &participant_for_broadcast,
+                &name,
+                &lang,
+            )),
+            Some(ClientMessage::Unfollow) => Some(handle_unfollow(&backends, &room_id, addr)),
+            Some(ClientMessage::SetTranslateTo { lang }) => Some(handle_set_translate_to(
+                &backends,
+                &room_map,
+                &room_id,
+                addr,
+                &participant_for_broadcast,
+                &lang,
+            )),
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            let _ = participant_for_broadcast.control.push(reply);
+            return;
+        }
+        if let Message::Text(text) = &msg {
+            let (room, name) = (room_id.clone(), participant_for_broadcast.name.clone());
+            backends.events.send(RoomEvent::Chat { room, name, text: text.to_string() });
+        }
+
+        // A full queue means the transcriber lags behind, the frame is still relayed
+        if let (Message::Binary(audio), Some(audio_tx)) = (&msg, &mut audio_tx) {
+            let frames = match &mut reorder {
+                Some(reorder) => {
+                    let max_len = config.max_audio_chunk_bytes;
+                    match reorder_audio(reorder, &mut utterance, audio, max_len) {
+                        Ok(frames) => frames,
+                        Err(notice) => {
+                            info!(expected = reorder.expected(), "Dropped an audio chunk");
+                            let _ = participant_for_broadcast.control.push(notice);
+                            return;
+                        }
+                    }
+                }
+                None => {
+                    audio_seq += 1;
+                    vec![(audio_seq, audio.clone())]
+                }
+            };
+            for (seq, frame) in frames {
+                if audio_tx.try_send((seq, frame)).is_err() {
+                    info!(seq, "Dropped audio, transcription queue full");
+                    let _ = participant_for_broadcast.control.push(audio_dropped_notice(seq));
+                }
             }
-            _ => {}
         }
 
-        let room_map = room_map.lock().unwrap();
-        if let Some(peers) = room_map.get(&room_id) {
-            for (peer_addr, participant) in peers.iter() {
-                if *peer_addr != addr {
-                    let _ = participant.sender.unbounded_send(msg.clone());
+        // Rooms in line mode relay each line of a batched frame as a message of its own
+        let msgs = match msg {
+            Message::Text(ref text) if config.splits_lines(&room_id) => {
+                split_lines(text).map(Message::text).collect()
+            }
+            msg => vec![msg],
+        };
+
+        let peers = room_map.participants(&room_id);
+        for msg in &msgs {
+            backends.metrics.message_broadcast();
+            room_map.buffer_message(&room_id, msg);
+            let mut listeners = Vec::new();
+            for participant in peers.iter().filter(|p| p.id != id) {
+                // Participants who opted in get the message spoken to them in the language they
+                // read, those who only want audio get nothing else unless there is no one to
+                // speak it
+                let synthesizer = &backends.synthesizer;
+                let spoken = match (msg, synthesizer, &participant_for_broadcast.transcribe_to) {
+                    (Message::Text(_), Some(_), Some(lang))
+                        if participant.delivery.wants_audio() =>
+                    {
+                        let to = participant.translate_to.first().unwrap_or(lang);
+                        listeners.push((to.clone(), participant.sender.clone()));
+                        true
+                    }
+                    _ => false,
+                };
+                if !spoken || participant.delivery.wants_text() {
+                    let recipient = participant.recipient(&room_id);
+                    backends.metrics.bytes_forwarded(msg.len());
+                    relay_to(&config, &participant.sender, &recipient, msg.clone());
+                }
+            }
+            if let (Message::Text(text), Some(lang)) =
+                (msg, &participant_for_broadcast.transcribe_to)
+            {
+                if !listeners.is_empty() {
+                    let speech = speak_translated(
+                        backends.clone(),
+                        text.to_string(),
+                        lang.clone(),
+                        participant_for_broadcast.name.clone(),
+                        listeners,
+                        backends.pipeline.begin(),
+                    );
+                    tokio::spawn(speech.instrument(Span::current()));
                 }
             }
         }
+    };
 
-        future::ok(())
+    let on_disconnect = || {
+        info!("Disconnected");
+
+        // -- Broadcast WS Handshake - Close
+        broadcast_ws_handshake_close(&participant_for_broadcast, &room_id, &config);
+
+        // ---- Remove participant, tell everyone else, then flush what is still queued for it ----
+        if let Some(left) = room_map.leave(&room_id, addr) {
+            room_map.broadcast(&room_id, &leave_notice(&left.participant.name), None);
+            let (room, name) = (room_id.clone(), left.participant.name.clone());
+            backends.events.send(RoomEvent::Left { room, name });
+            if left.closed {
+                backends.events.send(RoomEvent::RoomClosed { room: room_id.clone() });
+            }
+            let mut follows = backends.follows.lock().unwrap();
+            if left.closed {
+                backends.profiles.lock().unwrap().remove(&room_id);
+                follows.remove(&room_id);
+            } else if let Some(follows) = follows.get_mut(&room_id) {
+                // Whoever rejoins under the name is not followed by the followers of the leaver
+                follows.retain(|follower, follow| {
+                    *follower != addr && follow.leader != left.participant.name
+                });
+            }
+        }
+    };
+
+    let outbound = prioritized(control_rx, rx);
+    let outbound = match config.batch_window {
+        Some(window) if batched => batch(outbound, window).boxed(),
+        _ => outbound.boxed(),
+    };
+    let outbound = match config.keepalive_interval {
+        Some(interval) => keepalive(outbound, interval).boxed(),
+        None => outbound,
+    };
+    let outbound = match config.ping_interval {
+        Some(interval) => heartbeat(outbound, pongs.clone(), interval, config.pong_timeout).boxed(),
+        None => outbound,
+    };
+    let outbound = match config.max_connection_lifetime {
+        Some(lifetime) => max_lifetime(outbound, lifetime).boxed(),
+        None => outbound,
+    };
+    let outbound = match config.idle_timeout {
+        Some(timeout) => {
+            let notice = Message::text(json!({ "type": "idle_timeout" }).to_string());
+            idle_timeout(outbound, activity.clone(), timeout, notice).boxed()
+        }
+        None => outbound,
+    };
+    let notice = Message::text(json!({ "type": "server_shutdown" }).to_string());
+    let outbound = until_shutdown(outbound, backends.shutdown.clone(), notice);
+    let outbound = outbound.inspect(|msg| {
+        stats.bytes_out.fetch_add(msg.len() as u64, Ordering::Relaxed);
     });
+    relay(
+        ws_stream,
+        outbound,
+        on_message,
+        on_disconnect,
+        config.drain_timeout,
+        config.write_timeout,
+    )
+    .await;
+}
 
-    let receive_from_others = rx.map(Ok).forward(outgoing);
+/// Describe a connection for the diagnostics
+fn connection_report(
+    addr: SocketAddr,
+    room_id: &str,
+    participant: &Participant,
+) -> serde_json::Value {
+    json!({
+        "addr": addr.to_string(),
+        "id": participant.id.to_string(),
+        "room": room_id,
+        "name": participant.name,
+        "role": participant.role.as_str(),
+        "joined_at": participant.joined_at.to_rfc3339(),
+        "queue_depth": participant.sender.len() + participant.control.len(),
+        "rate_limit_tokens": *participant.stats.rate_limit_tokens.lock().unwrap(),
+        "bytes_in": participant.stats.bytes_in.load(Ordering::Relaxed),
+        "bytes_out": participant.stats.bytes_out.load(Ordering::Relaxed)
+    })
+}
 
-    pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(broadcast_incoming, receive_from_others).await;
+/// List the active connections, or describe the one from `addr` if given, `None` if there
+/// is no such connection
+fn list_connections(room_map: &RoomManager, addr: Option<&str>) -> Option<serde_json::Value> {
+    let rooms: Vec<_> = room_map
+        .room_ids()
+        .into_iter()
+        .filter_map(|room_id| Some((room_map.room(&room_id)?, room_id)))
+        .collect();
+    let mut connections = rooms.iter().flat_map(|(peers, room_id)| {
+        peers.iter().map(move |(addr, participant)| (addr, room_id, participant))
+    });
+    match addr {
+        None => {
+            let all = connections.map(|(addr, room_id, p)| connection_report(*addr, room_id, p));
+            Some(json!({ "connections": all.collect::<Vec<_>>() }))
+        }
+        Some(addr) => {
+            let addr: SocketAddr = addr.parse().ok()?;
+            let (_, room_id, participant) = connections.find(|(peer, _, _)| **peer == addr)?;
+            Some(connection_report(addr, room_id, participant))
+        }
+    }
+}
+
+/// List the rooms anyone is in, with their participants and whether a new member could join
+/// them, only the joinable ones if `query` has `joinable=true`
+fn list_rooms(
+    room_map: &RoomManager,
+    config: &ServerConfig,
+    query: Option<&str>,
+) -> serde_json::Value {
+    let joinable_only = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .any(|(key, value)| key == "joinable" && value == "true");
+    let rooms = room_map.rosters().into_iter().filter_map(|(room_id, names)| {
+        // Any name will do, as only a full room keeps out whoever picks a free one
+        let joinable = !matches!(room_map.can_join(&room_id, "", None), Err(JoinError::RoomFull));
+        if joinable_only && !joinable {
+            return None;
+        }
+        let names: Vec<_> = names.iter().map(sanitize_text).collect();
+        Some(json!({
+            "room": sanitize_text(&room_id),
+            "count": names.len(),
+            "max": config.max_participants,
+            "joinable": joinable,
+            "participants": names,
+        }))
+    });
+    serde_json::Value::Array(rooms.collect())
+}
 
-    println!("{} disconnected", &addr);
+/// The query parameters of a handshake as they may be logged, without the values of the
+/// ones carrying secrets
+fn redact_secrets(params: &[(String, String)]) -> Vec<(&str, &str)> {
+    params
+        .iter()
+        .map(|(key, value)| match key.as_str() {
+            "token" | "password" => (key.as_str(), "<redacted>"),
+            _ => (key.as_str(), value.as_str()),
+        })
+        .collect()
+}
 
-    // -- Broadcast WS Handshake - Close
-    broadcast_ws_handshake_close(addr, &participant_for_broadcast, &room_id, &room_map);
+/// Search the transcripts of `room` for the `q` of `query`, said between its `from` and `to`
+/// timestamps if given, answering with the matches and the entries around them
+async fn search_transcripts(
+    backends: &Backends,
+    config: &ServerConfig,
+    room: &str,
+    query: Option<&str>,
+) -> Response<Body> {
+    let respond = |status, body: String| {
+        let mut res = Response::new(Body::from(body));
+        *res.status_mut() = status;
+        res
+    };
+    let archive = match &backends.archive {
+        Some(archive) => archive,
+        None => return respond(StatusCode::NOT_FOUND, "Transcripts are not kept".into()),
+    };
+    let params: HashMap<String, String> =
+        form_urlencoded::parse(query.unwrap_or_default().as_bytes()).into_owned().collect();
+    let mut search = match params.get("q").filter(|q| !q.is_empty()) {
+        Some(q) => TranscriptQuery::new(q.as_str()),
+        None => return respond(StatusCode::BAD_REQUEST, "Query required".into()),
+    };
+    for (param, bound) in [("from", &mut search.from), ("to", &mut search.to)] {
+        if let Some(value) = params.get(param) {
+            match DateTime::parse_from_rfc3339(value) {
+                Ok(timestamp) => *bound = Some(timestamp.with_timezone(&Utc)),
+                Err(e) => {
+                    let message = format!("Invalid {}: {}", param, e);
+                    return respond(StatusCode::BAD_REQUEST, message);
+                }
+            }
+        }
+    }
 
-    // ---- Remove participant ----
-    {
-        let mut room_map = room_map.lock().unwrap();
-        if let Some(peers) = room_map.get_mut(&room_id) {
-            peers.remove(&addr);
+    let room = match parse_room_id(room, config.max_room_id_length) {
+        Ok(Some(room)) => room,
+        Ok(None) => return respond(StatusCode::BAD_REQUEST, "Room required".into()),
+        Err(e) => return respond(StatusCode::BAD_REQUEST, format!("Invalid room: {}", e)),
+    };
+    // Aliases share the transcripts of their canonical room
+    let room_id = config.resolve_room(&room);
+    match archive.search(room_id, &search).await {
+        Ok(matches) => {
+            let matches: Vec<_> = matches.iter().map(|found| found.to_json()).collect();
+            let mut res = respond(StatusCode::OK, serde_json::Value::from(matches).to_string());
+            res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            res
+        }
+        Err(e) => {
+            warn!(room = %room_id, error = %e, "Failed to search the transcripts");
+            respond(StatusCode::INTERNAL_SERVER_ERROR, "Search failed".into())
+        }
+    }
+}
+
+/// Answer a request with `rejection`, logging it
+fn respond_rejected(rejection: Rejection) -> Response<Body> {
+    rejection.log();
+    let status = rejection.status.unwrap_or(StatusCode::BAD_REQUEST);
+    let retry_after = rejection.retry_after_header();
+    let mut res = Response::new(Body::from(rejection.message));
+    *res.status_mut() = status;
+    if let Some(retry_after) = retry_after.and_then(|secs| HeaderValue::from_str(&secs).ok()) {
+        res.headers_mut().insert(RETRY_AFTER, retry_after);
+    }
+    res
+}
+
+/// Turn a handshake away with `rejection`, counting it in the metrics by its reason code
+fn reject(backends: &Backends, rejection: Rejection) -> Response<Body> {
+    backends.metrics.handshake_rejected(rejection.reason_code);
+    respond_rejected(rejection)
+}
+
+/// Ask the auth policy whether the client may go on, returning why not otherwise
+fn authorize(backends: &Backends, req: &Request<Incoming>, client_ip: IpAddr) -> Option<Rejection> {
+    let query = req.uri().query().unwrap_or_default();
+    let query: HashMap<String, String> =
+        form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+    match backends.auth.authorize(req.headers(), &query) {
+        Ok(AuthInfo { identity: Some(identity) }) => {
+            info!(%client_ip, identity, "Request authorized");
+            None
         }
+        Ok(_) => None,
+        Err(e) => Some(Rejection::new("unauthorized", e.reason, client_ip).status(e.status)),
     }
 }
 
+/// Exercise the pipeline backends with a known sample and report how they did
+async fn self_test(
+    room_map: &RoomManager,
+    config: &ServerConfig,
+    backends: &Backends,
+    started: Instant,
+) -> serde_json::Value {
+    let (active_rooms, participants) = {
+        let rooms = room_map.room_ids().into_iter().filter_map(|room_id| room_map.room(&room_id));
+        let active: Vec<_> = rooms.filter(|peers| !peers.is_empty()).collect();
+        (active.len(), active.iter().map(|peers| peers.len()).sum::<usize>())
+    };
+
+    let synthesizer = match backends.synthesizer.as_deref() {
+        Some(synthesizer) => {
+            let lang = "en".parse().unwrap();
+            let begin = Instant::now();
+            let result = synthesizer.synthesize("This is a self-test.", &lang).await;
+            let latency_ms = begin.elapsed().as_millis() as u64;
+            match result {
+                Ok(audio) => json!({ "ok": true, "latency_ms": latency_ms, "bytes": audio.len() }),
+                Err(e) => json!({
+                    "ok": false,
+                    "latency_ms": latency_ms,
+                    "error": sanitize_text(e.to_string())
+                }),
+            }
+        }
+        None => json!(null),
+    };
+    let transcriptions_in_flight =
+        config.max_concurrent_transcriptions - backends.transcriptions.available_permits();
+
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": started.elapsed().as_secs(),
+        "active_rooms": active_rooms,
+        "participants": participants,
+        "reserved_names": room_map.reservation_count(),
+        "synthesizer": synthesizer,
+        "transcriptions_in_flight": transcriptions_in_flight
+    })
+}
+
 async fn handle_request(
-    room_map: RoomMap,
+    room_map: RoomManager,
+    config: Arc<ServerConfig>,
+    backends: Backends,
+    started: Instant,
     mut req: Request<Incoming>,
     addr: SocketAddr,
 ) -> Result<Response<Body>, Infallible> {
     let headers = req.headers();
 
+    // hyper enforces the limits while parsing, this catches what fits its buffers anyway
+    if !config.headers_within_limits(headers) {
+        let message = "Request Header Fields Too Large";
+        let rejection = Rejection::new("headers_too_large", message, addr.ip())
+            .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        return Ok(reject(&backends, rejection));
+    }
+
+    let client_ip = config.client_ip(addr.ip(), headers);
+    if client_ip != addr.ip() {
+        info!(%client_ip, "Request forwarded by a proxy");
+        if config.is_banned_ip(client_ip) {
+            let rejection = Rejection::new("ip_banned", "Address banned", client_ip)
+                .status(StatusCode::FORBIDDEN);
+            return Ok(reject(&backends, rejection));
+        }
+    }
+
+    // Diagnostics are only served to whoever holds the configured token
+    let path = req.uri().path().to_string();
+    let connections = path
+        .strip_prefix("/debug/connections")
+        .filter(|rest| config.debug_connections && (rest.is_empty() || rest.starts_with('/')));
+    let diagnostics = path == "/debug/self-test" || connections.is_some();
+    if let (true, Some(token)) = (diagnostics, &config.debug_token) {
+        let expected = format!("Bearer {}", token);
+        if headers.get(AUTHORIZATION).map_or(true, |auth| *auth != *expected) {
+            let rejection = Rejection::new("unauthorized", "Unauthorized", client_ip)
+                .status(StatusCode::UNAUTHORIZED);
+            return Ok(respond_rejected(rejection));
+        }
+
+        let report = match connections {
+            Some(rest) => list_connections(&room_map, rest.strip_prefix('/')),
+            None => Some(self_test(&room_map, &config, &backends, started).await),
+        };
+        let mut res = match report {
+            Some(report) => Response::new(Body::from(report.to_string())),
+            None => {
+                let mut res = Response::new(Body::from(r#"{"error":"no such connection"}"#));
+                *res.status_mut() = StatusCode::NOT_FOUND;
+                res
+            }
+        };
+        res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        return Ok(res);
+    }
+
+    // Metrics are served to anyone, unless the diagnostics call for a token
+    if path == "/metrics" && req.method() == Method::GET {
+        if let Some(token) = &config.debug_token {
+            let expected = format!("Bearer {}", token);
+            if headers.get(AUTHORIZATION).map_or(true, |auth| *auth != *expected) {
+                let rejection = Rejection::new("unauthorized", "Unauthorized", client_ip)
+                    .status(StatusCode::UNAUTHORIZED);
+                return Ok(respond_rejected(rejection));
+            }
+        }
+        let mut res = Response::new(Body::from(backends.metrics.render(&room_map)));
+        let content_type = HeaderValue::from_static("text/plain; version=0.0.4");
+        res.headers_mut().insert(CONTENT_TYPE, content_type);
+        return Ok(res);
+    }
+
+    // The rooms are listed to whoever may join them
+    if path == "/rooms" && req.method() == Method::GET {
+        if let Some(rejection) = authorize(&backends, &req, client_ip) {
+            return Ok(respond_rejected(rejection));
+        }
+        let rooms = list_rooms(&room_map, &config, req.uri().query());
+        let mut res = Response::new(Body::from(rooms.to_string()));
+        res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        return Ok(res);
+    }
+
+    // So are the transcripts of the rooms, as whoever may join a room may hear them
+    let search = path.strip_prefix("/rooms/").and_then(|rest| rest.strip_suffix("/search"));
+    if let (Some(room), &Method::GET) = (search, req.method()) {
+        if let Some(rejection) = authorize(&backends, &req, client_ip) {
+            return Ok(respond_rejected(rejection));
+        }
+        return Ok(search_transcripts(&backends, &config, room, req.uri().query()).await);
+    }
+
     // Only accept proper WebSocket handshake requests
     if req.method() != Method::GET
         || headers.get(SEC_WEBSOCKET_VERSION).map(|h| h != "13").unwrap_or(true)
     {
-        return Ok(Response::new(Body::from("Hi, you are in the wrong place.")));
+        let landing_page = &config.landing_page;
+        let mut res = Response::new(Body::from(landing_page.body.clone()));
+        *res.status_mut() = landing_page.status;
+        if let Ok(content_type) = HeaderValue::from_str(&landing_page.content_type) {
+            res.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        return Ok(res);
     }
 
-    println!("Received a new, potentially WS Handshake");
-    println!("Request Path: {}", req.uri().path());
+    debug!(path = req.uri().path(), "Received a possible handshake");
+
+    // Without a key there is no accept key to answer with, so nothing is upgraded
+    let key = headers.get(SEC_WEBSOCKET_KEY);
+    let accept = key.and_then(|key| HeaderValue::from_str(&derive_accept_key(key.as_bytes())).ok());
+    let accept = match accept {
+        Some(accept) => accept,
+        None => {
+            let rejection = Rejection::new("missing_key", "Missing Sec-WebSocket-Key", client_ip)
+                .status(StatusCode::BAD_REQUEST);
+            return Ok(reject(&backends, rejection));
+        }
+    };
+
+    if let Some(rejection) = authorize(&backends, &req, client_ip) {
+        return Ok(reject(&backends, rejection));
+    }
 
-    // Extract room_id from path
-    let mut room_id = String::from("default");
-    let uri = req.uri().to_string();
-    if let Ok(url) = Url::parse(&format!("ws://localhost{}", uri)) {
-        let path_room = url.path().trim_start_matches('/');
-        if !path_room.is_empty() {
-            room_id = path_room.to_string();
+    // Extract room_id from the raw path, so traversal attempts are rejected, not normalized away,
+    // or else from the `room` parameter
+    let (path, query) = (req.uri().path(), req.uri().query());
+    let room_id = match requested_room_id(path, query, config.max_room_id_length) {
+        Ok(Some(room_id)) => room_id,
+        Ok(None) if config.require_explicit_room => {
+            let rejection = Rejection::new("room_required", "Room required", client_ip)
+                .status(StatusCode::BAD_REQUEST);
+            return Ok(reject(&backends, rejection));
+        }
+        Ok(None) => String::from("default"),
+        Err(e) => {
+            let rejection =
+                Rejection::new("invalid_room", format!("Invalid room: {}", e), client_ip)
+                    .status(StatusCode::BAD_REQUEST);
+            return Ok(reject(&backends, rejection));
         }
+    };
+    if config.is_reserved_room(&room_id) {
+        let rejection = Rejection::new("room_reserved", "Reserved room name", client_ip)
+            .status(StatusCode::BAD_REQUEST)
+            .room(room_id);
+        return Ok(reject(&backends, rejection));
     }
 
+    let room_name = room_id.clone();
+    let room_id = config.resolve_room(&room_name).to_string();
+    if room_id != room_name {
+        info!(room = %room_name, canonical = %room_id, "Room is an alias");
+    }
+    Span::current().record("room_id", room_id.as_str());
+    // Past here the rejections name the room, and the name once it is known
+    let rejection = |reason_code, message: String| {
+        Rejection::new(reason_code, message, client_ip)
+            .status(StatusCode::BAD_REQUEST)
+            .room(room_name.clone())
+    };
+
     // Default participant data
     let mut participant_name = String::from("participant-name");
-    let mut translate_to = String::from("en");
-    let mut transcribe_to = String::from("jp");
+    let mut translate_to = vec![String::from("en")];
+    let mut transcribe_to = String::from("ja");
+    let mut delivery = Delivery::Text;
+    let mut batch = false;
+    let mut timezone = None;
+    let mut transcription_profile = None;
+    let mut session_id = None;
+    let mut chunked_audio = false;
+    let mut spectator = false;
 
     // Extract from query string
     if let Some(query_str) = req.uri().query() {
-        let params: HashMap<_, _> =
+        let params: Vec<(String, String)> =
             form_urlencoded::parse(query_str.as_bytes()).into_owned().collect();
 
-        println!("Request Parameters: {:?}", params);
+        debug!(params = ?redact_secrets(&params), "Handshake parameters");
+
+        // Single values keep the last occurrence, lists keep every occurrence
+        let value = |key: &str| params.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v);
+        let values = |key: &str| -> Vec<String> {
+            params.iter().filter(|(k, _)| k == key).map(|(_, v)| v.clone()).collect()
+        };
 
-        if let Some(name) = params.get("name") {
-            participant_name = name.clone();
+        if let Some(name) = value("name") {
+            match normalize_name(name) {
+                Some(name) => participant_name = name,
+                None => {
+                    let rejection = rejection("name_required", "Name required".into());
+                    return Ok(reject(&backends, rejection));
+                }
+            }
         }
-        if let Some(tt) = params.get("translate_to") {
-            translate_to = tt.clone();
+        let tt = values("translate_to");
+        if !tt.is_empty() {
+            translate_to = tt;
         }
-        if let Some(tc) = params.get("transcribe_to") {
+        if let Some(tc) = value("transcribe_to") {
             transcribe_to = tc.clone();
         }
-    }
-
-    // Reject duplicate participant name
-    {
-        let rooms_lock = room_map.lock().unwrap();
-        if let Some(room_participants) = rooms_lock.get(&room_id) {
-            if room_participants.values().any(|p: &Participant| p.name == participant_name) {
-                println!(
-                    "Cannot upgrade or proceed. Participant {} is already in the room {}",
-                    participant_name, room_id
+        if let Some(t) = value("tts") {
+            if t == "true" || t == "1" {
+                delivery = Delivery::Both;
+            }
+        }
+        if let Some(d) = value("delivery") {
+            match Delivery::parse(d) {
+                Some(d) => delivery = d,
+                None => {
+                    let message =
+                        format!("Invalid delivery '{}', expected 'text', 'audio' or 'both'", d);
+                    let rejection = rejection("invalid_delivery", message);
+                    return Ok(reject(&backends, rejection.name(participant_name)));
+                }
+            }
+        }
+        if let Some(b) = value("batch") {
+            batch = b == "true" || b == "1";
+        }
+        if let Some(c) = value("chunked_audio") {
+            chunked_audio = c == "true" || c == "1";
+        }
+        if let Some(s) = value("spectator") {
+            spectator = s == "true" || s == "1";
+        }
+        if let Some(profile) = value("transcription_profile") {
+            if !backends.transcriber.profiles().contains(profile) {
+                let message = format!("Unknown transcription profile '{}'", profile);
+                let rejection = rejection("unknown_transcription_profile", message);
+                return Ok(reject(&backends, rejection.name(participant_name)));
+            }
+            transcription_profile = Some(profile.clone());
+        }
+        if let Some(id) = value("session_id") {
+            if !is_valid_session_id(id) {
+                let message = format!(
+                    "Invalid session id, expected 1 to {} printable characters",
+                    MAX_SESSION_ID_LEN
                 );
-                let mut res = Response::new(Body::from(format!(
-                    "Name '{}' is already in use",
-                    participant_name
-                )));
-                *res.status_mut() = StatusCode::CONFLICT;
-                return Ok(res);
+                let rejection = rejection("invalid_session_id", message);
+                return Ok(reject(&backends, rejection.name(participant_name)));
+            }
+            session_id = Some(id.clone());
+        }
+        if let Some(tz) = value("timezone") {
+            match tz.parse::<FixedOffset>() {
+                Ok(tz) => timezone = Some(tz),
+                Err(e) => {
+                    debug!(timezone = %tz, error = %e, "Invalid timezone");
+                    let message =
+                        format!("Invalid timezone '{}', expected a UTC offset like '+09:00'", tz);
+                    let rejection = rejection("invalid_timezone", message);
+                    return Ok(reject(&backends, rejection.name(participant_name)));
+                }
             }
         }
     }
 
-    println!(
-        "Participant: {}, Translate to: {}, Transcribe to: {}",
-        participant_name, translate_to, transcribe_to
-    );
+    // The name is settled, so every rejection from here on names it
+    let rejection = |reason_code, message| rejection(reason_code, message).name(&participant_name);
+    if config.is_banned_name(&participant_name) {
+        let rejection = rejection("name_banned", "Name banned".into());
+        return Ok(reject(&backends, rejection.status(StatusCode::FORBIDDEN)));
+    }
+
+    // Encrypted payloads cannot be spoken
+    if delivery.wants_audio() && config.room_mode(&room_id) == RoomMode::Encrypted {
+        let message =
+            format!("Room '{}' is end-to-end encrypted, audio delivery is unavailable", room_name);
+        return Ok(reject(&backends, rejection("room_encrypted", message)));
+    }
+
+    // Reject duplicate participant name, unless duplicates get renamed on insert
+    match room_map.can_join_as(&room_id, &participant_name, session_id.as_deref(), spectator) {
+        Ok(()) => {}
+        Err(JoinError::RoomFull) => {
+            let rejection = rejection("room_full", "Room full".into())
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .retry_after(config.capacity_retry_after);
+            return Ok(reject(&backends, rejection));
+        }
+        Err(e) => {
+            let rejection = rejection("name_in_use", e.to_string()).status(StatusCode::CONFLICT);
+            return Ok(reject(&backends, rejection));
+        }
+    }
 
-    println!("Request Headers:");
-    for (ref header, _value) in req.headers() {
-        println!("* {}", header);
+    // Validate the negotiated languages, telling the client which parameter was off
+    let translate_to = LanguageCode::parse_list(translate_to.iter().map(String::as_str))
+        .map_err(|e| ("translate_to", e));
+    let transcribe_to = transcribe_to.parse::<LanguageCode>().map_err(|e| ("transcribe_to", e));
+    let (translate_to, transcribe_to) = match (translate_to, transcribe_to) {
+        (Ok(translate_to), Ok(transcribe_to)) if !translate_to.is_empty() => {
+            (translate_to, transcribe_to)
+        }
+        (Ok(_), Ok(transcribe_to)) => (vec!["en".parse().unwrap()], transcribe_to),
+        (Err((param, e)), _) | (_, Err((param, e))) => {
+            let message = format!("Invalid {}: {}", param, e);
+            return Ok(reject(&backends, rejection("invalid_language", message)));
+        }
+    };
+    let untranslatable = translate_to
+        .iter()
+        .find(|to| **to != transcribe_to && !backends.translator.supports(&transcribe_to, to));
+    if let Some(to) = untranslatable {
+        let message = format!("Translation from '{}' to '{}' not available", transcribe_to, to);
+        return Ok(reject(&backends, rejection("translation_unavailable", message)));
+    }
+    if !backends.transcriber.supports(&transcribe_to) {
+        let message = format!("Transcription not available for '{}'", transcribe_to);
+        return Ok(reject(&backends, rejection("transcription_unavailable", message)));
+    }
+
+    let translate_list = translate_to.iter().map(LanguageCode::as_str).collect::<Vec<_>>();
+    info!(name = %participant_name, translate_to = ?translate_list, %transcribe_to, "Handshake accepted");
+    if tracing::enabled!(Level::DEBUG) {
+        let names = req.headers().keys().map(|header| header.as_str()).collect::<Vec<_>>();
+        debug!(headers = ?names, "Handshake headers");
     }
 
     let upgrade = HeaderValue::from_static("Upgrade");
     let websocket = HeaderValue::from_static("websocket");
-    let key = headers.get(SEC_WEBSOCKET_KEY);
-    let derived = key.map(|k| derive_accept_key(k.as_bytes()));
     let req_ver = req.version();
+    // Room ids come from the percent-encoded path, so they are valid header values
+    let room_header = HeaderValue::from_str(&room_id).ok();
 
     // Upgrade the Connection
-    tokio::task::spawn(async move {
-        match hyper::upgrade::on(&mut req).await {
-            Ok(upgraded) => {
-                let upgraded = TokioIo::new(upgraded);
-
-                let participant_obj = PartialParticipant {
-                    name: participant_name,
-                    transcribe_to: transcribe_to,
-                    translate_to: translate_to,
-                };
+    let connection = backends.connections.begin();
+    tokio::task::spawn(
+        async move {
+            let _connection = connection;
+            match hyper::upgrade::on(&mut req).await.map_err(ServerError::upgrade) {
+                Ok(upgraded) => {
+                    let upgraded = TokioIo::new(upgraded);
+                    let ws_config = config.websocket_config();
 
-                handle_connection(
-                    room_id,
-                    room_map,
-                    participant_obj,
-                    WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await,
-                    addr,
-                )
-                .await;
+                    let participant_obj = PartialParticipant {
+                        name: participant_name,
+                        room_name,
+                        transcribe_to,
+                        translate_to,
+                        delivery,
+                        timezone,
+                        batch,
+                        transcription_profile,
+                        session_id,
+                        chunked_audio,
+                        spectator,
+                    };
+
+                    handle_connection(
+                        room_id,
+                        room_map,
+                        config,
+                        backends,
+                        participant_obj,
+                        WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(ws_config))
+                            .await,
+                        addr,
+                    )
+                    .await;
+                }
+                Err(e) => warn!(error = %e, "Connection failed"),
             }
-            Err(e) => println!("upgrade error: {}", e),
         }
-    });
+        .instrument(Span::current()),
+    );
 
     let mut res = Response::new(Body::default());
 
@@ -378,35 +1595,1009 @@ async fn handle_request(
     *res.version_mut() = req_ver;
     res.headers_mut().append(CONNECTION, upgrade);
     res.headers_mut().append(UPGRADE, websocket);
-    res.headers_mut().append(SEC_WEBSOCKET_ACCEPT, derived.unwrap().parse().unwrap());
+    res.headers_mut().append(SEC_WEBSOCKET_ACCEPT, accept);
     // Let's add an additional header to our response to the client.
-    res.headers_mut().append("MyCustomHeader", ":)".parse().unwrap());
-    res.headers_mut().append("SOME_TUNGSTENITE_HEADER", "header_value".parse().unwrap());
+    res.headers_mut().append("MyCustomHeader", HeaderValue::from_static(":)"));
+    res.headers_mut().append("SOME_TUNGSTENITE_HEADER", HeaderValue::from_static("header_value"));
+    if let Some(room_header) = room_header {
+        res.headers_mut().append("X-Room-Id", room_header);
+    }
 
     Ok(res)
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let curr_room_state = RoomMap::new(Mutex::new(HashMap::new()));
+async fn main() -> Result<(), ServerError> {
+    let started = Instant::now();
+    // Plug a text-to-speech backend in here to serve participants who asked for audio delivery,
+    // and speech-to-text and translation ones to replace the placeholders.
+    let synthesizer: Option<Arc<dyn Synthesizer>> = None;
+    let transcriber: Arc<dyn Transcriber> = Arc::new(NoopTranscriber);
+    let translator: Arc<dyn Translator> = Arc::new(NoopTranslator);
+
+    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let addr = addr.parse::<SocketAddr>().map_err(|e| ServerError::config("address", e))?;
+
+    let mut config = ServerConfig::default();
+    if let (Some(cert), Some(key)) = (env::args().nth(2), env::args().nth(3)) {
+        config.tls = Some(TlsConfig::new(cert, key));
+    }
+    let config = Arc::new(config);
+    Logger::new(config.log_format, LogFilter::from_env()).install().expect("Logger already set");
+    let listener = config.bind(addr)?;
+    let acceptor = config.stream_acceptor()?;
+    // Swap in a policy of your own, e.g. checking API keys or the Origin header
+    let auth = config.auth_policy();
+    let curr_room_state = RoomManager::new()
+        .dedupe_names(config.auto_dedupe_names)
+        .max_participants(config.max_participants)
+        .max_members(config.max_members)
+        .max_spectators(config.max_spectators)
+        .reconnect_grace(config.reconnect_grace)
+        .max_reservations(config.max_reservations_per_ip, config.max_reservations_per_room)
+        .max_buffered_messages(config.max_buffered_messages)
+        .outbound(config.outbound.clone());
+    let archive = match &config.transcript_dir {
+        Some(dir) => Some(Arc::new(
+            FileTranscriptSink::new(dir).map_err(|e| ServerError::config("transcript_dir", e))?,
+        )),
+        None => None,
+    };
+    let transcripts: Arc<dyn TranscriptSink> = match &archive {
+        Some(archive) => archive.clone(),
+        None => Arc::new(NoopTranscriptSink),
+    };
+    let events = config.event_sink()?;
+    let mut backends =
+        Backends::new(synthesizer, transcriber, translator, transcripts, events, auth, &config);
+    backends.archive = archive;
 
-    let addr =
-        env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_string()).parse::<SocketAddr>()?;
+    info!(%addr, scheme = acceptor.scheme(), "Listening, enter `shutdown` to stop");
+    let signal = shutdown_requested();
+    run_until_shutdown(listener, acceptor, curr_room_state, config, backends, started, signal).await
+}
 
-    let listener = TcpListener::bind(addr).await?;
+/// Resolves once `shutdown` is entered on stdin, in place of a signal handler
+async fn shutdown_requested() {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim() == "shutdown" {
+            return;
+        }
+    }
+    std::future::pending().await
+}
 
+/// Accept connections on `listener`, through `acceptor`, and serve their requests until
+/// `signal` resolves, then shut down gracefully
+///
+/// No connection is accepted anymore, the transcriptions and syntheses under way get to
+/// deliver their results, and then every participant is sent a `server_shutdown` notice and
+/// a close frame. Each of the waits lasts at most for the shutdown grace period.
+// `tokio::select!` expands to `IntoFuture`, which the versions of tokio that build on the
+// minimum supported Rust version do without
+#[allow(clippy::incompatible_msrv)]
+async fn run_until_shutdown(
+    listener: TcpListener,
+    acceptor: StreamAcceptor,
+    curr_room_state: RoomManager,
+    config: Arc<ServerConfig>,
+    backends: Backends,
+    started: Instant,
+    signal: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    pin_mut!(signal);
     loop {
-        let (stream, remote_addr) = listener.accept().await?;
+        let (stream, remote_addr) = tokio::select! {
+            _ = &mut signal => break,
+            accepted = config.accept(&listener) => accepted.map_err(ServerError::Accept)?,
+        };
+        if config.is_banned_ip(remote_addr.ip()) {
+            let rejection = Rejection::new("ip_banned", "Address banned", remote_addr.ip());
+            rejection.log();
+            backends.metrics.handshake_rejected(rejection.reason_code);
+            continue;
+        }
+        if let Err(e) = config.configure_stream(&stream) {
+            warn!(addr = %remote_addr, error = %e, "Failed to configure the connection");
+        }
         let curr_room_state = curr_room_state.clone();
+        let config = config.clone();
+        let backends = backends.clone();
+        let acceptor = acceptor.clone();
 
-        tokio::spawn(async move {
-            let io = TokioIo::new(stream);
-            let service =
-                service_fn(move |req| handle_request(curr_room_state.clone(), req, remote_addr));
-            let conn = http1::Builder::new().serve_connection(io, service).with_upgrades();
-            if let Err(err) = conn.await {
-                eprintln!("failed to serve connection: {err:?}");
+        let span = info_span!("connection", addr = %remote_addr, room_id = Empty, name = Empty);
+        tokio::spawn(
+            async move {
+                let mut builder = http1::Builder::new();
+                builder
+                    .max_headers(config.max_header_count)
+                    .max_header_size(config.max_header_size);
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!(error = %ServerError::Tls(e), "Connection failed");
+                        return;
+                    }
+                };
+                let io = TokioIo::new(stream);
+                let service = service_fn(move |req| {
+                    handle_request(
+                        curr_room_state.clone(),
+                        config.clone(),
+                        backends.clone(),
+                        started,
+                        req,
+                        remote_addr,
+                    )
+                });
+                let conn = builder.serve_connection(io, service).with_upgrades();
+                if let Err(err) = conn.await {
+                    warn!(error = ?err, "Failed to serve the connection");
+                }
             }
-        });
+            .instrument(span),
+        );
+    }
+
+    drop(listener);
+    let grace = config.shutdown_grace;
+    info!(transcriptions = backends.pipeline.count(), "Shutting down, finishing transcriptions");
+    if !backends.pipeline.wait(grace).await {
+        warn!(transcriptions = backends.pipeline.count(), ?grace, "Gave up on transcriptions");
+    }
+    info!(connections = backends.connections.count(), "Closing connections");
+    backends.shutdown.trigger();
+    if !backends.connections.wait(grace).await {
+        warn!(connections = backends.connections.count(), ?grace, "Gave up on connections");
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "connect"))]
+mod tests {
+    use super::*;
+    use futures_util::{
+        future::{self, BoxFuture},
+        stream::{self, BoxStream},
+    };
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio_tungstenite::{
+        connect_async, LandingPage, RateLimit, SynthError, TranscribeError, Transcript,
+        TranscriptEvent, TranslateError, WebhookConfig,
+    };
+
+    /// Start a server on an ephemeral port, wired up like `main` with the placeholder backends
+    fn spawn_server(config: ServerConfig) -> SocketAddr {
+        spawn_server_until(config, Arc::new(NoopTranscriber), std::future::pending()).0
+    }
+
+    /// Start a server like `spawn_server`, with `transcriber`, which shuts down once `signal`
+    /// resolves, returning its backends and the task serving it too
+    fn spawn_server_until(
+        config: ServerConfig,
+        transcriber: Arc<dyn Transcriber>,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> (SocketAddr, Backends, tokio::task::JoinHandle<Result<(), ServerError>>) {
+        spawn_server_with(config, None, transcriber, Arc::new(NoopTranslator), signal)
+    }
+
+    /// Start a server like `spawn_server_until`, with all of its pipeline backends given
+    fn spawn_server_with(
+        config: ServerConfig,
+        synthesizer: Option<Arc<dyn Synthesizer>>,
+        transcriber: Arc<dyn Transcriber>,
+        translator: Arc<dyn Translator>,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> (SocketAddr, Backends, tokio::task::JoinHandle<Result<(), ServerError>>) {
+        let listener = config.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let rooms = RoomManager::new()
+            .dedupe_names(config.auto_dedupe_names)
+            .max_participants(config.max_participants)
+            .reconnect_grace(config.reconnect_grace)
+            .max_buffered_messages(config.max_buffered_messages)
+            .outbound(config.outbound.clone());
+        let archive = config
+            .transcript_dir
+            .as_ref()
+            .map(|dir| Arc::new(FileTranscriptSink::new(dir).unwrap()));
+        let transcripts: Arc<dyn TranscriptSink> = match &archive {
+            Some(archive) => archive.clone(),
+            None => Arc::new(NoopTranscriptSink),
+        };
+        let events = config.event_sink().unwrap();
+        let auth = config.auth_policy();
+        let mut backends =
+            Backends::new(synthesizer, transcriber, translator, transcripts, events, auth, &config);
+        backends.archive = archive;
+        let server = tokio::spawn(run_until_shutdown(
+            listener,
+            config.stream_acceptor().unwrap(),
+            rooms,
+            Arc::new(config),
+            backends.clone(),
+            Instant::now(),
+            signal,
+        ));
+        (addr, backends, server)
+    }
+
+    /// Send a bodiless `GET` for `path`, returning the status and body of the response
+    async fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request =
+            format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    /// Join `room` as `name`, keeping the connection open until its room snapshot arrived
+    async fn join(addr: SocketAddr, room: &str, name: &str) {
+        let url = format!("ws://{}/{}?name={}", addr, room, name);
+        let (mut ws_stream, _) = connect_async(url).await.unwrap();
+        loop {
+            let msg = ws_stream.next().await.unwrap().unwrap();
+            if msg.to_text().map_or(false, |text| text.contains(r#""room_snapshot""#)) {
+                break;
+            }
+        }
+        tokio::spawn(async move { while ws_stream.next().await.is_some() {} });
+    }
+
+    /// Answer the next request on the connection of a webhook, returning the event it posted
+    async fn next_event(webhook: &mut BufReader<tokio::net::TcpStream>) -> serde_json::Value {
+        let mut len = 0;
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            webhook.read_line(&mut line).await.unwrap();
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                len = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; len];
+        webhook.read_exact(&mut body).await.unwrap();
+        webhook.get_mut().write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn room_events_are_posted_to_the_webhook() {
+        let webhook = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", webhook.local_addr().unwrap());
+        let webhook_config = WebhookConfig::new(url.parse().unwrap());
+        let addr =
+            spawn_server(ServerConfig { webhook: Some(webhook_config), ..Default::default() });
+
+        let url = format!("ws://{}/main?name=Alice", addr);
+        let (mut alice, _) = connect_async(url).await.unwrap();
+        alice.send(Message::text("hello")).await.unwrap();
+        alice.close(None).await.unwrap();
+        while alice.next().await.is_some() {}
+
+        let (stream, _) = webhook.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut events = Vec::new();
+        for _ in 0..5 {
+            events.push(next_event(&mut stream).await);
+        }
+        let kinds: Vec<_> = events.iter().map(|event| event["event"].as_str().unwrap()).collect();
+        assert_eq!(kinds, ["room_created", "join", "chat", "leave", "room_closed"]);
+        assert!(events.iter().all(|event| event["room"] == "main"));
+        assert_eq!(events[2]["name"], "Alice");
+        assert_eq!(events[2]["text"], "hello");
+    }
+
+    #[tokio::test]
+    async fn rooms_are_listed_with_their_participants() {
+        let addr = spawn_server(ServerConfig::default());
+        assert_eq!(get(addr, "/rooms").await, (200, "[]".to_string()));
+
+        join(addr, "main", "Alice").await;
+        join(addr, "main", "Bob").await;
+        join(addr, "quiet", "Carol").await;
+
+        let (status, body) = get(addr, "/rooms").await;
+        assert_eq!(status, 200);
+        let rooms: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            rooms,
+            json!([
+                {
+                    "room": "main",
+                    "count": 2,
+                    "max": null,
+                    "joinable": true,
+                    "participants": ["Alice", "Bob"],
+                },
+                {
+                    "room": "quiet",
+                    "count": 1,
+                    "max": null,
+                    "joinable": true,
+                    "participants": ["Carol"],
+                },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn full_rooms_are_grayed_out_or_left_out() {
+        let addr = spawn_server(ServerConfig { max_participants: Some(2), ..Default::default() });
+        join(addr, "main", "Alice").await;
+        join(addr, "main", "Bob").await;
+        join(addr, "quiet", "Carol").await;
+
+        let (_, body) = get(addr, "/rooms").await;
+        let rooms: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let listed: Vec<_> = rooms
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|room| (room["room"].clone(), room["max"].clone(), room["joinable"].clone()))
+            .collect();
+        assert_eq!(
+            listed,
+            [(json!("main"), json!(2), json!(false)), (json!("quiet"), json!(2), json!(true))]
+        );
+
+        let (_, body) = get(addr, "/rooms?joinable=true").await;
+        let rooms: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let listed: Vec<_> = rooms.as_array().unwrap().iter().map(|room| &room["room"]).collect();
+        assert_eq!(listed, ["quiet"]);
+    }
+
+    #[test]
+    fn secrets_are_not_logged() {
+        let params: Vec<_> = [("name", "Alice"), ("token", "s3cret"), ("password", "hunter2")]
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(
+            redact_secrets(&params),
+            [("name", "Alice"), ("token", "<redacted>"), ("password", "<redacted>")]
+        );
+    }
+
+    /// Tags each text with the language it was translated to
+    struct TaggingTranslator;
+
+    impl Translator for TaggingTranslator {
+        fn translate<'a>(
+            &'a self,
+            text: &'a str,
+            _from: &'a LanguageCode,
+            to: &'a LanguageCode,
+        ) -> BoxFuture<'a, Result<String, TranslateError>> {
+            Box::pin(future::ready(Ok(format!("[{}] {}", to, text))))
+        }
+    }
+
+    /// Speaks a text as its own bytes
+    struct VerbatimSynthesizer;
+
+    impl Synthesizer for VerbatimSynthesizer {
+        fn synthesize<'a>(
+            &'a self,
+            text: &'a str,
+            _lang: &'a LanguageCode,
+        ) -> BoxFuture<'a, Result<Vec<u8>, SynthError>> {
+            Box::pin(future::ready(Ok(text.as_bytes().to_vec())))
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_is_spoken_in_the_language_of_each_listener() {
+        let (addr, _, _) = spawn_server_with(
+            ServerConfig::default(),
+            Some(Arc::new(VerbatimSynthesizer)),
+            Arc::new(NoopTranscriber),
+            Arc::new(TaggingTranslator),
+            std::future::pending(),
+        );
+        join(addr, "main", "Alice").await;
+        let mut listeners = Vec::new();
+        for (name, lang) in [("Bob", "fr"), ("Carol", "ja")] {
+            let url =
+                format!("ws://{}/main?name={}&delivery=audio&translate_to={}", addr, name, lang);
+            let (mut ws_stream, _) = connect_async(url).await.unwrap();
+            next_of_type(&mut ws_stream, "room_snapshot").await;
+            listeners.push(ws_stream);
+        }
+        let (mut dave, _) =
+            connect_async(format!("ws://{}/main?name=Dave&transcribe_to=ja", addr)).await.unwrap();
+        next_of_type(&mut dave, "room_snapshot").await;
+        dave.send(Message::text("hello")).await.unwrap();
+
+        // Bob reads French, Carol the language Dave speaks, which needs no translation
+        for (ws_stream, (lang, speech)) in
+            listeners.iter_mut().zip([("fr", "[fr] hello"), ("ja", "hello")])
+        {
+            let frame = loop {
+                if let Message::Binary(frame) = ws_stream.next().await.unwrap().unwrap() {
+                    break frame;
+                }
+            };
+            let len = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize;
+            let header: serde_json::Value = serde_json::from_slice(&frame[4..4 + len]).unwrap();
+            assert_eq!((&header["name"], &header["lang"]), (&json!("Dave"), &json!(lang)));
+            assert_eq!(&frame[4 + len..], speech.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_count_what_the_server_did() {
+        let addr = spawn_server(ServerConfig::default());
+        join(addr, "main", "Alice").await;
+        let url = format!("ws://{}/main?name=Bob", addr);
+        let (mut bob, _) = connect_async(url).await.unwrap();
+        while !bob.next().await.unwrap().unwrap().to_text().unwrap().contains("room_snapshot") {}
+        bob.send(Message::text("hello")).await.unwrap();
+
+        // Turned away for taking a name in use, and for a blank one
+        let url = format!("ws://{}/main?name=Alice", addr);
+        assert!(connect_async(url).await.is_err());
+        let url = format!("ws://{}/main?name=%20", addr);
+        assert!(connect_async(url).await.is_err());
+
+        // The message only counts once relayed to Alice, so wait for it
+        let mut body = String::new();
+        for _ in 0..50 {
+            let (status, text) = get(addr, "/metrics").await;
+            assert_eq!(status, 200);
+            body = text;
+            if body.contains("forwarded_bytes_total 5\n") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let samples: Vec<_> = body.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(
+            samples,
+            [
+                "rooms_active 1",
+                "participants 2",
+                "messages_broadcast_total 1",
+                "forwarded_bytes_total 5",
+                "transcriptions_total 0",
+                "translations_total 0",
+                r#"buffered_messages{room="main"} 1"#,
+                r#"handshakes_rejected_total{reason="name_in_use"} 1"#,
+                r#"handshakes_rejected_total{reason="name_required"} 1"#,
+            ]
+        );
+    }
+
+    /// "Transcribes" audio into a list of its bytes, to tell what the transcriber was fed
+    struct EchoTranscriber;
+
+    impl Transcriber for EchoTranscriber {
+        fn transcribe<'a>(
+            &'a self,
+            audio: &'a [u8],
+            _lang: &'a LanguageCode,
+            _profile: &'a str,
+        ) -> BoxFuture<'a, Result<Transcript, TranscribeError>> {
+            Box::pin(future::ready(Ok(Transcript::new(format!("{:?}", audio)))))
+        }
+    }
+
+    #[tokio::test]
+    async fn numbered_audio_is_transcribed_in_order() {
+        let config = ServerConfig { audio_reorder_window: 4, ..ServerConfig::default() };
+        let (addr, _, _) =
+            spawn_server_until(config, Arc::new(EchoTranscriber), std::future::pending());
+        let url = format!("ws://{}/main?name=Alice&chunked_audio=true", addr);
+        let (mut ws_stream, _) = connect_async(url).await.unwrap();
+        while !ws_stream.next().await.unwrap().unwrap().to_text().unwrap().contains("room_snapshot")
+        {
+        }
+
+        let chunks = [
+            AudioChunk::new(1, [1]),
+            AudioChunk::new(0, [0]),
+            AudioChunk::new(3, [3]),
+            AudioChunk::new(2, [2]).final_chunk(),
+            // Too far ahead while 4 is missing
+            AudioChunk::new(8, [8]),
+            AudioChunk::new(5, [5]).final_chunk(),
+            AudioChunk::new(4, [4]),
+        ];
+        for chunk in &chunks {
+            ws_stream.send(Message::binary(chunk.encode())).await.unwrap();
+        }
+        ws_stream.send(Message::binary(vec![0; 3])).await.unwrap();
+
+        // Transcripts come from the pipeline, so they may pass the other notices
+        let (mut transcripts, mut notices) = (Vec::new(), Vec::new());
+        while transcripts.len() + notices.len() < 4 {
+            let msg = ws_stream.next().await.unwrap().unwrap();
+            let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            match msg["type"].as_str().unwrap() {
+                "transcript" => transcripts.push(json!([msg["seq"], msg["text"]])),
+                "audio_nack" => notices.push(json!([msg["seq"], msg["expected"]])),
+                "error" => notices.push(msg["code"].clone()),
+                _ => {}
+            }
+        }
+        assert_eq!(transcripts, [json!([2, "[0, 1, 2]"]), json!([5, "[3, 4, 5]"])]);
+        assert_eq!(notices, [json!([8, 4]), json!("invalid_audio_chunk")]);
+    }
+
+    /// Hears "hello world" in any audio, guessing at it twice before
+    struct HesitantTranscriber;
+
+    impl Transcriber for HesitantTranscriber {
+        fn transcribe<'a>(
+            &'a self,
+            _audio: &'a [u8],
+            _lang: &'a LanguageCode,
+            _profile: &'a str,
+        ) -> BoxFuture<'a, Result<Transcript, TranscribeError>> {
+            Box::pin(future::ready(Ok(Transcript::new("hello world"))))
+        }
+
+        fn transcribe_stream<'a>(
+            &'a self,
+            _audio: &'a [u8],
+            _lang: &'a LanguageCode,
+            _profile: &'a str,
+        ) -> BoxStream<'a, Result<TranscriptEvent, TranscribeError>> {
+            Box::pin(stream::iter([
+                Ok(TranscriptEvent::Partial("hel".into())),
+                Ok(TranscriptEvent::Partial("hello".into())),
+                Ok(TranscriptEvent::Final(Transcript::new("hello world"))),
+            ]))
+        }
+    }
+
+    /// Read the `transcript`s `ws_stream` gets up to the final one, as their `final` and `text`
+    async fn transcripts_until_final(
+        ws_stream: &mut WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+    ) -> Vec<(bool, String)> {
+        let mut transcripts = Vec::new();
+        loop {
+            let msg = ws_stream.next().await.unwrap().unwrap();
+            let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            if msg["type"] == "transcript" {
+                let is_final = msg["final"].as_bool().unwrap();
+                transcripts.push((is_final, msg["text"].as_str().unwrap().to_string()));
+                if is_final {
+                    return transcripts;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_transcripts_reach_the_peers_before_the_final_one() {
+        let (addr, _, _) = spawn_server_until(
+            ServerConfig::default(),
+            Arc::new(HesitantTranscriber),
+            std::future::pending(),
+        );
+        let url = format!("ws://{}/main?name=Bob", addr);
+        let (mut bob, _) = connect_async(url).await.unwrap();
+        while !bob.next().await.unwrap().unwrap().to_text().unwrap().contains("room_snapshot") {}
+        let url = format!("ws://{}/main?name=Alice", addr);
+        let (mut alice, _) = connect_async(url).await.unwrap();
+        while !alice.next().await.unwrap().unwrap().to_text().unwrap().contains("room_snapshot") {}
+
+        alice.send(Message::binary(vec![0; 4])).await.unwrap();
+        // Bob may miss the first guess, as the second one replaces it if still queued
+        let heard = transcripts_until_final(&mut bob).await;
+        let (last, partials) = heard.split_last().unwrap();
+        assert_eq!(*last, (true, "hello world".to_string()));
+        assert!(partials.ends_with(&[(false, "hello".to_string())]));
+        assert!(partials.len() <= 2 && partials.iter().all(|(is_final, _)| !is_final));
+        // The speaker only gets the final transcript
+        assert_eq!(transcripts_until_final(&mut alice).await, [(true, "hello world".to_string())]);
+    }
+
+    /// Ask to have transcripts translated to `lang`, returning the `ack` or `error` answering
+    async fn set_translate_to(
+        ws_stream: &mut WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+        lang: &str,
+    ) -> serde_json::Value {
+        let request = json!({ "type": "control", "action": "set_translate_to", "lang": lang });
+        ws_stream.send(Message::text(request.to_string())).await.unwrap();
+        loop {
+            let msg = ws_stream.next().await.unwrap().unwrap();
+            let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            if msg["type"] == "ack" || msg["type"] == "error" {
+                return msg;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn translate_to_can_be_changed_mid_session() {
+        let (addr, _, _) = spawn_server_until(
+            ServerConfig::default(),
+            Arc::new(EchoTranscriber),
+            std::future::pending(),
+        );
+        let url = format!("ws://{}/main?name=Bob&transcribe_to=en&translate_to=ja", addr);
+        let (mut bob, _) = connect_async(url).await.unwrap();
+        while !bob.next().await.unwrap().unwrap().to_text().unwrap().contains("room_snapshot") {}
+        let url = format!("ws://{}/main?name=Alice&transcribe_to=en", addr);
+        let (mut alice, _) = connect_async(url).await.unwrap();
+        while !alice.next().await.unwrap().unwrap().to_text().unwrap().contains("room_snapshot") {}
+
+        let ack = set_translate_to(&mut bob, "de,fr").await;
+        assert_eq!(ack, json!({ "type": "ack", "field": "translate_to", "value": ["de", "fr"] }));
+        // A bad code leaves the languages as they were
+        assert_eq!(
+            set_translate_to(&mut bob, "de,not a language").await["code"],
+            "invalid_language"
+        );
+        assert_eq!(set_translate_to(&mut bob, "").await["code"], "invalid_language");
+
+        alice.send(Message::binary(vec![7])).await.unwrap();
+        let transcript = loop {
+            let msg = bob.next().await.unwrap().unwrap();
+            let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            if msg["type"] == "transcript" {
+                break msg;
+            }
+        };
+        assert_eq!(transcript["translations"], json!({ "de": "[7]", "fr": "[7]" }));
+    }
+
+    #[tokio::test]
+    async fn rooms_can_be_given_in_the_query() {
+        let addr = spawn_server(ServerConfig::default());
+        join(addr, "main", "Alice").await;
+        for path in ["/?name=Bob&room=main", "/quiet?room=main&name=Carol"] {
+            let (mut ws_stream, _) = connect_async(format!("ws://{}{}", addr, path)).await.unwrap();
+            while !ws_stream.next().await.unwrap().unwrap().to_text().unwrap().contains("snapshot")
+            {
+            }
+            tokio::spawn(async move { while ws_stream.next().await.is_some() {} });
+        }
+
+        let (_, body) = get(addr, "/rooms").await;
+        let rooms: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            rooms,
+            json!([
+                {
+                    "room": "main",
+                    "count": 2,
+                    "max": null,
+                    "joinable": true,
+                    "participants": ["Alice", "Bob"],
+                },
+                {
+                    "room": "quiet",
+                    "count": 1,
+                    "max": null,
+                    "joinable": true,
+                    "participants": ["Carol"],
+                },
+            ])
+        );
+    }
+
+    /// Read messages from `ws_stream` until one of type `kind`
+    async fn next_of_type<S>(ws_stream: &mut S, kind: &str) -> serde_json::Value
+    where
+        S: futures_util::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>>
+            + Unpin,
+    {
+        loop {
+            let msg = ws_stream.next().await.unwrap().unwrap();
+            if let Ok(msg) = serde_json::from_str::<serde_json::Value>(msg.to_text().unwrap()) {
+                if msg["type"] == kind {
+                    return msg;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshots_tell_the_role_and_whether_the_joiner_reconnected() {
+        let config = ServerConfig {
+            reconnect_grace: Some(Duration::from_secs(30)),
+            ..ServerConfig::default()
+        };
+        let addr = spawn_server(config);
+        let url = |name| format!("ws://{}/meeting?name={}&session_id=s-{}", addr, name, name);
+        let (mut host, _) = connect_async(url("Host")).await.unwrap();
+        let snapshot = next_of_type(&mut host, "room_snapshot").await;
+        assert_eq!((&snapshot["role"], &snapshot["paused"]), (&json!("owner"), &json!(false)));
+        assert_eq!(snapshot["resumed"], false);
+        let (mut guest, _) = connect_async(url("Guest")).await.unwrap();
+        let snapshot = next_of_type(&mut guest, "room_snapshot").await;
+        assert_eq!(
+            (&snapshot["role"], &snapshot["resumed"]),
+            (&json!("participant"), &json!(false))
+        );
+        guest.close(None).await.unwrap();
+        next_of_type(&mut host, "leave").await;
+
+        let (mut guest, _) = connect_async(url("Guest")).await.unwrap();
+        let snapshot = next_of_type(&mut guest, "room_snapshot").await;
+        assert_eq!(
+            (&snapshot["role"], &snapshot["resumed"]),
+            (&json!("participant"), &json!(true))
+        );
+    }
+
+    #[tokio::test]
+    async fn key_exchanges_reach_their_recipient_however_it_is_written() {
+        let mut config = ServerConfig::default();
+        config.room_modes.insert("secret".into(), RoomMode::Encrypted);
+        let addr = spawn_server(config);
+        let url = |name| format!("ws://{}/secret?name={}", addr, name);
+        let (mut alice, _) = connect_async(url("Alice")).await.unwrap();
+        next_of_type(&mut alice, "room_snapshot").await;
+        let (mut bob, _) = connect_async(url("Bob")).await.unwrap();
+        next_of_type(&mut bob, "room_snapshot").await;
+        let (mut carol, _) = connect_async(url("Carol")).await.unwrap();
+        next_of_type(&mut carol, "room_snapshot").await;
+
+        let key_exchange = r#"{"type":"key_exchange","to":" bob ","key":"k1"}"#;
+        alice.send(Message::text(key_exchange)).await.unwrap();
+        let received = next_of_type(&mut bob, "key_exchange").await;
+        assert_eq!((&received["from"], &received["key"]), (&json!("Alice"), &json!("k1")));
+
+        // Carol gets the next message to everyone, not the key meant for Bob
+        alice.send(Message::text("ciphertext")).await.unwrap();
+        let relayed = loop {
+            let msg = carol.next().await.unwrap().unwrap();
+            let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            if msg["type"] == "key_exchange" || msg["type"] == "encrypted" {
+                break msg;
+            }
+        };
+        assert_eq!(
+            (&relayed["type"], &relayed["payload"]),
+            (&json!("encrypted"), &json!("ciphertext"))
+        );
+    }
+
+    #[tokio::test]
+    async fn local_timestamps_follow_the_timezone_of_the_recipient() {
+        let addr = spawn_server(ServerConfig::default());
+        let url = format!("ws://{}/main?name=Alice&timezone=%2B09:00", addr);
+        let (mut alice, _) = connect_async(url).await.unwrap();
+        let status = next_of_type(&mut alice, "ws_handshake_status").await;
+        let timestamp = DateTime::parse_from_rfc3339(status["timestamp"].as_str().unwrap());
+        let tokyo = FixedOffset::east_opt(9 * 3600).unwrap();
+        let local = timestamp.unwrap().with_timezone(&tokyo).format("%Y-%m-%d %H:%M:%S");
+        assert_eq!(status["local_timestamp"], local.to_string());
+
+        let (mut bob, _) = connect_async(format!("ws://{}/main?name=Bob", addr)).await.unwrap();
+        let status = next_of_type(&mut bob, "ws_handshake_status").await;
+        assert!(status["timestamp"].is_string());
+        assert!(status.get("local_timestamp").is_none());
+        // Alice gets the server's news of Bob in her timezone too
+        assert!(next_of_type(&mut alice, "join").await["local_timestamp"].is_string());
+    }
+
+    #[tokio::test]
+    async fn connections_report_their_rate_limit_and_queues() {
+        let config = ServerConfig {
+            debug_token: Some("s3cret".into()),
+            debug_connections: true,
+            message_rate_limit: Some(RateLimit { capacity: 5, refill_rate: 0.0 }),
+            ..ServerConfig::default()
+        };
+        let addr = spawn_server(config);
+        let url = format!("ws://{}/main?name=Alice", addr);
+        let (mut alice, _) = connect_async(url).await.unwrap();
+        while !alice.next().await.unwrap().unwrap().to_text().unwrap().contains("room_snapshot") {}
+        alice.send(Message::text("hello")).await.unwrap();
+
+        // The message is taken in the background, so ask until its token is gone
+        let report = loop {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "GET /debug/connections HTTP/1.1\r\nHost: {}\r\n\
+                 Authorization: Bearer s3cret\r\nConnection: close\r\n\r\n",
+                addr
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            let (_, body) = response.split_once("\r\n\r\n").unwrap();
+            let report: serde_json::Value = serde_json::from_str(body).unwrap();
+            if report["connections"][0]["rate_limit_tokens"] != 5.0 {
+                break report["connections"][0].clone();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(report["name"], "Alice");
+        assert_eq!(report["role"], "owner");
+        assert_eq!(report["rate_limit_tokens"], 4.0);
+        assert!(report["queue_depth"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn transcripts_can_be_searched() {
+        let dir = std::env::temp_dir().join(format!("search-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = ServerConfig { transcript_dir: Some(dir.clone()), ..ServerConfig::default() };
+        let sink = FileTranscriptSink::new(&dir).unwrap();
+        for (minute, text) in ["hello", "the budget", "next week"].iter().enumerate() {
+            let mut entry = TranscriptEntry::new("Alice", *text, "en".parse().unwrap());
+            entry.timestamp = format!("2024-05-01T12:0{}:00Z", minute).parse().unwrap();
+            sink.record("main", entry).await.unwrap();
+        }
+        let addr = spawn_server(config);
+
+        let (status, body) = get(addr, "/rooms/main/search?q=BUDGET").await;
+        assert_eq!(status, 200);
+        let found: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(found.as_array().unwrap().len(), 1);
+        assert_eq!(found[0]["entry"]["text"], "the budget");
+        assert_eq!(found[0]["before"][0]["text"], "hello");
+        assert_eq!(found[0]["after"][0]["text"], "next week");
+
+        let late = "/rooms/main/search?q=budget&from=2024-05-01T12:02:00Z";
+        assert_eq!(get(addr, late).await, (200, "[]".to_string()));
+        assert_eq!(get(addr, "/rooms/main/search").await, (400, "Query required".to_string()));
+        assert_eq!(get(addr, "/rooms/a.b/search?q=x").await.0, 400);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn handshakes_without_a_key_are_rejected() {
+        let addr = spawn_server(ServerConfig::default());
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /main?name=Alice HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\n\
+             Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![0; 1024];
+        let len = stream.read(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response[..len]);
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nMissing Sec-WebSocket-Key"), "{}", response);
+
+        // The server carries on
+        join(addr, "main", "Alice").await;
+        assert_eq!(get(addr, "/rooms").await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn joiners_get_the_participant_list_right_away() {
+        let addr = spawn_server(ServerConfig::default());
+        join(addr, "main", "Alice").await;
+
+        let (mut bob, _) = connect_async(format!("ws://{}/main?name=Bob", addr)).await.unwrap();
+        next_of_type(&mut bob, "room_snapshot").await;
+        let msg = bob.next().await.unwrap().unwrap();
+        let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+        assert_eq!(msg["type"], "participants");
+        let mut names: Vec<_> =
+            msg["participants"].as_array().unwrap().iter().map(|e| &e["name"]).collect();
+        names.sort_by_key(|name| name.as_str());
+        assert_eq!(names, vec!["Alice", "Bob"]);
+    }
+
+    #[tokio::test]
+    async fn the_landing_page_is_served_to_other_requests() {
+        let addr = spawn_server(ServerConfig::default());
+        let body = "Hi, you are in the wrong place.".to_string();
+        assert_eq!(get(addr, "/").await, (200, body));
+
+        let landing_page = LandingPage {
+            status: StatusCode::NOT_FOUND,
+            content_type: "application/json".into(),
+            body: r#"{"service":"rooms"}"#.into(),
+        };
+        let addr = spawn_server(ServerConfig { landing_page, ..ServerConfig::default() });
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+        let lowercase = response.to_ascii_lowercase();
+        assert!(lowercase.contains("\r\ncontent-type: application/json\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n{\"service\":\"rooms\"}"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn explicit_rooms_can_be_required() {
+        let config = ServerConfig { require_explicit_room: true, ..ServerConfig::default() };
+        let addr = spawn_server(config);
+
+        match connect_async(format!("ws://{}/?name=Alice", addr)).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 400);
+                assert_eq!(response.body().as_deref(), Some(&b"Room required"[..]));
+            }
+            other => panic!("unexpected handshake result: {:?}", other.map(|_| ())),
+        }
+
+        join(addr, "main", "Alice").await;
+        assert_eq!(get(addr, "/rooms").await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn full_rooms_ask_joiners_to_retry_later() {
+        let config = ServerConfig {
+            max_participants: Some(1),
+            capacity_retry_after: Duration::from_millis(2500),
+            ..ServerConfig::default()
+        };
+        let addr = spawn_server(config);
+        join(addr, "main", "Alice").await;
+
+        match connect_async(format!("ws://{}/main?name=Bob", addr)).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 503);
+                assert_eq!(response.headers()["Retry-After"], "3");
+                assert_eq!(response.body().as_deref(), Some(&b"Room full"[..]));
+            }
+            other => panic!("unexpected handshake result: {:?}", other.map(|_| ())),
+        }
+        let (_, metrics) = get(addr, "/metrics").await;
+        assert!(metrics.contains("handshakes_rejected_total{reason=\"room_full\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn room_lists_are_guarded_by_the_auth_policy() {
+        let handshake_tokens = std::iter::once("s3cret".to_string()).collect();
+        let addr = spawn_server(ServerConfig { handshake_tokens, ..ServerConfig::default() });
+
+        assert_eq!(get(addr, "/rooms").await, (401, "Token required".to_string()));
+        assert_eq!(get(addr, "/rooms?token=guess").await, (403, "Invalid token".to_string()));
+        assert_eq!(get(addr, "/rooms?token=s3cret").await, (200, "[]".to_string()));
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_the_pipeline_finish_then_closes_connections() {
+        let (stop, stopped) = futures_channel::oneshot::channel::<()>();
+        let transcriber = Arc::new(NoopTranscriber);
+        let (addr, backends, server) =
+            spawn_server_until(ServerConfig::default(), transcriber, async {
+                let _ = stopped.await;
+            });
+        let url = format!("ws://{}/main?name=Alice", addr);
+        let (mut ws_stream, _) = connect_async(url).await.unwrap();
+        while !ws_stream.next().await.unwrap().unwrap().to_text().unwrap().contains("room_snapshot")
+        {
+        }
+
+        // A transcription is under way as the shutdown starts
+        let work = backends.pipeline.begin();
+        stop.send(()).unwrap();
+        // The connection stays open while the pipeline is busy, past the joining messages
+        while let Ok(msg) = tokio::time::timeout(Duration::from_millis(200), ws_stream.next()).await
+        {
+            let msg = msg.unwrap().unwrap();
+            assert!(msg.is_text() && !msg.to_text().unwrap().contains("server_shutdown"));
+        }
+        assert!(connect_async(format!("ws://{}/main?name=Bob", addr)).await.is_err());
+
+        drop(work);
+        let mut types = Vec::new();
+        let close = loop {
+            match ws_stream.next().await.unwrap().unwrap() {
+                Message::Close(frame) => break frame.unwrap(),
+                msg => {
+                    let msg: serde_json::Value =
+                        serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                    types.push(msg["type"].as_str().unwrap().to_string());
+                }
+            }
+        };
+        assert_eq!(types.last().map(String::as_str), Some("server_shutdown"));
+        assert_eq!(close.code, CloseCode::Away);
+        while ws_stream.next().await.is_some() {}
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
     }
 }
@@ -3,266 +3,2194 @@
 //! Usage:
 //! Server: cargo run --example server_room 127.0.0.1:12345
 //! Client: cargo run --example client ws://127.0.0.1:12345/room?name=John
+//!
+//! Pass the PEM files of a certificate and its key after the address to serve `wss://` instead,
+//! with the `rustls-tls-server` feature enabled.
+//!
+//! The path names the room to join. Clients that cannot pick the path may name it with
+//! `?room=<room>` instead, e.g. `ws://127.0.0.1:12345/?room=room&name=John`; the path wins if
+//! both name a room. A room of `ServerConfig::room_aliases` leads into the room it is an alias
+//! of, which the `connection_info` and `room_snapshot` still call by the alias; the
+//! `X-Room-Id` header of the handshake response names the room joined.
+//!
+//! Add `&encoding=cbor` to get the server's own messages (the room snapshot and roster
+//! updates) as CBOR binary frames instead of JSON text, and `&batch=true` to get messages
+//! sent in quick succession as one batch frame when the server batches them.
+//!
+//! With `ServerConfig::reconnect_grace` set, add `&session_id=<id>` to have the name kept for
+//! you for a while if the connection drops: reconnecting with the same `session_id` gets it
+//! back, along with your place in the roster. The `room_snapshot` of a reconnect has
+//! `"resumed":true`, and tells the room's settings and roster as they are now.
+//!
+//! Add `&spectator=true` to join as a spectator, e.g. a guest, taking a place of
+//! `ServerConfig::max_spectators` rather than `ServerConfig::max_members`.
+//!
+//! Whoever creates a room owns it, and owners and moderators may send `{"type":"pause_room"}`
+//! to silence everyone else until `{"type":"resume_room"}`. They also hand out roles with
+//! `{"type":"set_role","id":"<connection id>","role":"moderator"}`. A room always has exactly
+//! one owner, who hands ownership over by giving someone the `owner` role.
+//!
+//! Every chat message is relayed with a `message_id` the server gives it. Moderators pin one
+//! for the room with `{"type":"pin","message_id":"<id>"}`, which everyone hears about as a
+//! `message_pinned` event, and unpin it with `{"type":"unpin","message_id":"<id>"}`. The
+//! `room_snapshot` lists the ids of the pinned messages as `pinned`, oldest pin first; a room
+//! pins at most `ServerConfig::max_pinned_messages`.
+//!
+//! With `ServerConfig::auth_tokens` set, a participant first sends
+//! `{"type":"auth","token":"<token>"}` and only joins its room once the token checks out.
+//!
+//! Participants describe themselves with `{"type":"update_meta","meta":{"color":"teal"}}`,
+//! which the room hears about as a `participant_updated` event. A `null` field is cleared.
+//!
+//! Text frames are JSON messages, e.g. `{"type":"chat","text":"hi"}`, see `ClientMessage` for
+//! all of them. Anything else only gets `{"type":"error","reason":"invalid_message"}` back.
 
 use std::{
     collections::HashMap,
     env,
-    io::Error as IoError,
+    future::Future,
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use futures_channel::mpsc::{unbounded, UnboundedSender};
-use futures_util::{future, pin_mut, stream::TryStreamExt, SinkExt, StreamExt};
+use futures_util::{pin_mut, SinkExt, StreamExt};
 use serde_json::json;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+#[cfg(any(test, feature = "test-support"))]
+use tokio_tungstenite::QueueReceiver;
 use tokio_tungstenite::{
-    accept_hdr_async,
+    accept_hdr_async_with_config, batch, heartbeat, idle_timeout, is_valid_session_id, join_notice,
+    keepalive, leave_notice, max_lifetime, normalize_name, prioritized, relay, requested_room_id,
+    sanitize_text,
     tungstenite::{
         handshake::server::{Request, Response},
-        protocol::Message,
+        http::{header::RETRY_AFTER, StatusCode},
+        protocol::{frame::coding::CloseCode, CloseFrame, Message},
     },
-    WebSocketStream,
+    until_shutdown, Activity, AuthInfo, AuthPolicy, ClientMessage, Coalescer, Encoding, EventSink,
+    Heartbeat, InFlight, JoinError, LogFilter, Logger, MessageType, NoopPresence, Participant,
+    PresenceBackend, PresenceEvent, RateLimit, Rejection, RemoteRoster, RoomEvent, RoomManager,
+    RoomRole, ServerConfig, ServerError, ServerStream, Shutdown, StreamAcceptor, TlsConfig,
+    TokenBucket, WebSocketStream, MAX_SESSION_ID_LEN,
 };
+use tracing::{debug, field::Empty, info, info_span, trace, warn, Instrument, Level, Span};
 use tungstenite::handshake::server::ErrorResponse;
-use url::Url;
+use url::{form_urlencoded, Url};
+
+type RoomName = String;
+
+/// State of a room besides its participants, reset when the room is created anew
+#[derive(Debug, Default)]
+struct RoomSettings {
+    /// Whether only moderators may send chat and audio
+    paused: bool,
+    /// The id the next chat message gets, counting from 0
+    next_message_id: u64,
+    /// The ids of the pinned messages, oldest pin first
+    pinned: Vec<String>,
+}
 
-type Tx = UnboundedSender<Message>;
+/// Settings of the rooms, always locked after the rooms when both are needed
+type SettingsMap = Arc<Mutex<HashMap<RoomName, RoomSettings>>>;
 
-#[derive(Clone)]
-struct Participant {
-    name: String,
-    sender: Tx,
+/// Source of the ids the server assigns to connections, unique for the server's lifetime
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Fill a room with synthetic participants, backed by channels instead of sockets
+///
+/// Returns the receiving end of each participant's channel, in the order of `names`, so
+/// tests and demos can inspect what the room logic sends them.
+#[cfg(any(test, feature = "test-support"))]
+#[allow(dead_code)] // The server itself never seeds rooms
+fn seed_room(rooms: &RoomManager, room_id: &str, names: &[&str]) -> Vec<QueueReceiver> {
+    rooms.with_room(room_id, |room| {
+        names
+            .iter()
+            .map(|name| {
+                let (sender, rx) = ServerConfig::default().outbound_queue();
+                // Fake addresses from the documentation range never clash with real peers
+                let addr = SocketAddr::from(([192, 0, 2, 1], room.len() as u16 + 1));
+                let control = sender.clone();
+                let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+                room.add_participant(addr, Participant::new(id, *name, sender, control));
+                rx
+            })
+            .collect()
+    })
 }
 
-type RoomName = String;
+/// Broadcast the participant count and list, merged with other changes of the burst
+fn broadcast_roster(
+    rooms: &RoomManager,
+    remote: &RemoteRoster,
+    coalescer: &Coalescer,
+    room_id: &str,
+    max_listed: usize,
+) {
+    let (rooms, remote, room) = (rooms.clone(), remote.clone(), room_id.to_string());
+    coalescer.schedule(room_id, move || {
+        rooms.broadcast_count(&remote, &room);
+        rooms.broadcast_participants(&remote, &room, max_listed);
+    });
+}
 
-type RoomParticipants = HashMap<SocketAddr, Participant>;
+/// Tell a participant that it is disconnected for sending nothing for too long
+fn idle_timeout_notice() -> serde_json::Value {
+    json!({ "type": "idle_timeout" })
+}
 
-type RoomMap = Arc<Mutex<HashMap<RoomName, RoomParticipants>>>;
+/// Tell a participant that the server is going away, right before its connection is closed
+fn shutdown_notice() -> serde_json::Value {
+    json!({ "type": "server_shutdown" })
+}
 
-/// Collect senders for a room without holding the lock while sending
-fn collect_room_senders(rooms: &RoomMap, room_id: &str) -> Vec<Tx> {
-    let map = rooms.lock().unwrap();
-    map.get(room_id)
-        .map(|peers| peers.values().map(|p| p.sender.clone()).collect())
-        .unwrap_or_default()
+/// Tell a new participant who the server knows it as, before anything else is sent to it,
+/// in the room as it named it
+fn connection_info(id: u64, addr: SocketAddr, room_name: &str) -> serde_json::Value {
+    json!({
+        "type": "connection_info",
+        "your_id": id.to_string(),
+        "your_addr": addr.to_string(),
+        "room": sanitize_text(room_name),
+        "server_version": env!("CARGO_PKG_VERSION")
+    })
 }
 
-/// Broadcast participant count (lock-free sending)
-fn broadcast_count(rooms: &RoomMap, room_id: &str) {
-    let senders = collect_room_senders(rooms, room_id);
-    let count = senders.len();
+/// Tell a participant that the room is paused, so its message was dropped
+fn room_paused_notice() -> serde_json::Value {
+    json!({
+        "type": "room_paused",
+        "message": "The room is paused, only moderators may speak"
+    })
+}
 
-    let msg = json!({
-        "type": "count",
-        "count": count
+/// Tell a participant that it may not run a moderator command
+fn not_moderator(command: &str) -> serde_json::Value {
+    json!({
+        "type": "error",
+        "code": "not_moderator",
+        "message": format!("Only moderators may send '{}'", command)
     })
-    .to_string();
+}
+
+/// Tell a participant that its message was dropped, as the server could not make sense of it
+fn invalid_message(message: String) -> serde_json::Value {
+    json!({ "type": "error", "reason": "invalid_message", "message": message })
+}
 
-    for tx in senders {
-        let _ = tx.unbounded_send(Message::Text(msg.clone().into()));
+/// Pause or resume a room on behalf of a moderator, returning what to tell everyone if that
+/// changed anything
+fn handle_moderator_command(
+    settings: &SettingsMap,
+    room_id: &str,
+    sender: &Participant,
+    command: &str,
+) -> Option<serde_json::Value> {
+    if !sender.role.moderates() {
+        sender.notify(&not_moderator(command));
+        return None;
+    }
+
+    let paused = command == "pause_room";
+    let was_paused = {
+        let mut settings = settings.lock().unwrap();
+        std::mem::replace(&mut settings.entry(room_id.to_string()).or_default().paused, paused)
+    };
+    match (was_paused, paused) {
+        (false, true) => Some(room_paused_notice()),
+        (true, false) => Some(json!({ "type": "room_resumed" })),
+        _ => None,
     }
 }
 
-/// Broadcast participant list (lock-free sending)
-fn broadcast_participants(rooms: &RoomMap, room_id: &str) {
-    let (list, senders): (Vec<String>, Vec<Tx>) = {
-        let map = rooms.lock().unwrap();
-        if let Some(peers) = map.get(room_id) {
-            let list: Vec<String> = peers.values().map(|p| p.name.clone()).collect();
-            let senders: Vec<Tx> = peers.values().map(|p| p.sender.clone()).collect();
-            (list, senders)
-        } else {
-            (Vec::new(), Vec::new())
+/// Tell a participant why its pin was rejected
+fn invalid_pin(code: &str, message: String) -> serde_json::Value {
+    json!({ "type": "error", "code": code, "message": message })
+}
+
+/// Pin or unpin the message `message_id` on behalf of a moderator, returning what to tell
+/// everyone if that changed anything
+///
+/// Only ids the room gave out may be pinned, and only as many as the room pins at most.
+fn handle_pin(
+    settings: &SettingsMap,
+    config: &ServerConfig,
+    room_id: &str,
+    sender: &Participant,
+    message_id: &str,
+    pin: bool,
+) -> Option<serde_json::Value> {
+    let command = if pin { "pin" } else { "unpin" };
+    if !sender.role.moderates() {
+        sender.notify(&not_moderator(command));
+        return None;
+    }
+
+    let mut settings = settings.lock().unwrap();
+    let settings = settings.entry(room_id.to_string()).or_default();
+    let pinned = settings.pinned.iter().position(|id| id == message_id);
+    match (pin, pinned) {
+        (true, None) => {
+            let known = message_id
+                .parse::<u64>()
+                .map_or(false, |id| id < settings.next_message_id && id.to_string() == message_id);
+            if !known {
+                let message = format!("No message has the id '{}'", sanitize_text(message_id));
+                sender.notify(&invalid_pin("unknown_message", message));
+                return None;
+            }
+            if settings.pinned.len() >= config.max_pinned_messages {
+                let message =
+                    format!("A room may pin at most {} messages", config.max_pinned_messages);
+                sender.notify(&invalid_pin("too_many_pins", message));
+                return None;
+            }
+            settings.pinned.push(message_id.to_string());
+            Some(json!({ "type": "message_pinned", "message_id": message_id }))
         }
+        (false, Some(index)) => {
+            settings.pinned.remove(index);
+            Some(json!({ "type": "message_unpinned", "message_id": message_id }))
+        }
+        _ => None,
+    }
+}
+
+/// Give a chat message the next id of its room, which it is relayed with as `message_id`
+///
+/// Audio, and text which is no JSON object, is relayed as is.
+fn stamp_message_id(settings: &SettingsMap, room_id: &str, msg: Message) -> Message {
+    let mut value: serde_json::Value = match &msg {
+        Message::Text(text) => match serde_json::from_str(text) {
+            Ok(value @ serde_json::Value::Object(_)) => value,
+            _ => return msg,
+        },
+        _ => return msg,
     };
+    let id = {
+        let mut settings = settings.lock().unwrap();
+        let settings = settings.entry(room_id.to_string()).or_default();
+        settings.next_message_id += 1;
+        settings.next_message_id - 1
+    };
+    value["message_id"] = id.to_string().into();
+    Message::text(value.to_string())
+}
 
-    let msg = json!({
-        "type": "participants",
-        "participants": list
-    })
-    .to_string();
+/// Tell a participant why its metadata update was rejected
+fn invalid_meta(code: &str, message: String) -> serde_json::Value {
+    json!({ "type": "error", "code": code, "message": message })
+}
+
+/// Merge a metadata update into the participant's metadata and tell the room what it is now
+///
+/// Unknown fields and updates making the metadata too large are rejected as a whole.
+fn update_meta(
+    rooms: &RoomManager,
+    config: &ServerConfig,
+    room_id: &str,
+    addr: SocketAddr,
+    update: serde_json::Map<String, serde_json::Value>,
+) {
+    let update = rooms.with_room(room_id, |room| {
+        let participant = room.get_mut(&addr)?;
+        if let Some(field) = update.keys().find(|f| !config.participant_meta_fields.contains(*f)) {
+            let message = format!("Metadata field '{}' is not allowed", sanitize_text(field));
+            participant.notify(&invalid_meta("meta_not_allowed", message));
+            return None;
+        }
+        let mut meta = participant.meta.clone();
+        for (field, value) in update {
+            match value {
+                serde_json::Value::Null => meta.remove(&field),
+                value => meta.insert(field, value),
+            };
+        }
+        let size = serde_json::Value::Object(meta.clone()).to_string().len();
+        if size > config.max_participant_meta {
+            let message = format!("Metadata may be at most {} bytes", config.max_participant_meta);
+            participant.notify(&invalid_meta("meta_too_large", message));
+            return None;
+        }
+        participant.meta = meta;
 
-    for tx in senders {
-        let _ = tx.unbounded_send(Message::Text(msg.clone().into()));
+        Some(json!({
+            "type": "participant_updated",
+            "id": participant.id.to_string(),
+            "meta": participant.meta
+        }))
+    });
+    if let Some(update) = update {
+        rooms.broadcast(room_id, &update, None);
     }
 }
 
+/// Tell a participant why its role change was rejected
+fn invalid_role_change(code: &str, message: &str) -> serde_json::Value {
+    json!({ "type": "error", "code": code, "message": message })
+}
+
+/// Give the participant with connection id `target` the role `role` on behalf of the one at
+/// `addr`, telling the room about every role that changed
+///
+/// The change is checked and applied under the room lock, so concurrent changes apply one
+/// after the other and each is checked against the result of the one before. Whatever the
+/// order, the room keeps exactly one owner: only the owner may make someone else the owner,
+/// which makes it a moderator in the same step, and the owner's own role cannot be changed
+/// otherwise.
+fn change_role(rooms: &RoomManager, room_id: &str, addr: SocketAddr, target: &str, role: &str) {
+    let notices = rooms.with_room(room_id, |room| {
+        let sender = room.get(&addr)?;
+        let reply = |error| {
+            sender.notify(&error);
+            None
+        };
+
+        if !sender.role.moderates() {
+            return reply(not_moderator("set_role"));
+        }
+        let role = match RoomRole::parse(role) {
+            Some(role) => role,
+            None => {
+                let message = "The role must be 'owner', 'moderator' or 'participant'";
+                return reply(invalid_role_change("invalid_role", message));
+            }
+        };
+        let (target, target_role) = match room.iter().find(|(_, p)| p.id.to_string() == target) {
+            Some((target, p)) => (*target, p.role),
+            None => {
+                let message = "Nobody in the room has this id";
+                return reply(invalid_role_change("unknown_participant", message));
+            }
+        };
+
+        let mut changes = vec![(target, role)];
+        match (role, target_role) {
+            (RoomRole::Owner, _) if sender.role != RoomRole::Owner => {
+                let message = "Only the owner may hand over the ownership";
+                return reply(invalid_role_change("owner_required", message));
+            }
+            (RoomRole::Owner, RoomRole::Owner) => changes.clear(),
+            (RoomRole::Owner, _) => changes.push((addr, RoomRole::Moderator)),
+            (_, RoomRole::Owner) => {
+                let message = "The owner must hand over the ownership before changing role";
+                return reply(invalid_role_change("owner_required", message));
+            }
+            _ => {}
+        }
+
+        let mut notices = Vec::new();
+        for (peer, role) in changes {
+            let participant = room.get_mut(&peer).expect("checked above");
+            if participant.role != role {
+                participant.role = role;
+                let id = participant.id.to_string();
+                notices.push(json!({ "type": "role_changed", "id": id, "role": role.as_str() }));
+            }
+        }
+        Some(notices)
+    });
+    for notice in notices.into_iter().flatten() {
+        rooms.broadcast(room_id, &notice, None);
+    }
+}
+
+/// Take a participant out of its room, then close its queues and tell the event sink
+fn remove_participant(
+    rooms: &RoomManager,
+    events: &dyn EventSink,
+    room_id: &str,
+    addr: SocketAddr,
+) {
+    if let Some(left) = rooms.leave(room_id, addr) {
+        // Whatever name the handshake settled on, it is the one the room knows
+        rooms.broadcast(room_id, &leave_notice(&left.participant.name), None);
+        let (room, name) = (room_id.to_string(), left.participant.name);
+        events.send(RoomEvent::Left { room, name });
+        if left.closed {
+            events.send(RoomEvent::RoomClosed { room: room_id.to_string() });
+        }
+    }
+}
+
+/// Stamp an ephemeral message, i.e. one with an `expires_in` of seconds, with the
+/// `expires_at` time clients should remove it at
+///
+/// Returns `None` for other messages, and the reason if `expires_in` is out of bounds.
+fn stamp_expiry(msg: &Message, max: Duration) -> Result<Option<Message>, String> {
+    let mut value: serde_json::Value = match msg {
+        Message::Text(text) => match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(_) => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    let expires_in = match value.get("expires_in") {
+        Some(expires_in) => expires_in,
+        None => return Ok(None),
+    };
+
+    let secs = expires_in
+        .as_u64()
+        .filter(|secs| (1..=max.as_secs()).contains(secs))
+        .ok_or_else(|| format!("expires_in must be between 1 and {} seconds", max.as_secs()))?;
+    let expires_at = chrono::Utc::now() + chrono::Duration::seconds(secs as i64);
+    value["expires_at"] = expires_at.to_rfc3339().into();
+    Ok(Some(Message::text(value.to_string())))
+}
+
+/// What a message of a participant makes the room send
+enum Outgoing {
+    /// Chat, relayed to everyone else
+    Chat(Message),
+    /// One of the room's own messages, for everyone
+    Notice(serde_json::Value),
+}
+
 /// Handle all incoming messages from this client and broadcast them to others
-fn handle_incoming(rooms: &RoomMap, room_id: &str, addr: SocketAddr, msg: Message) {
-    let senders: Vec<Tx> = {
-        let map = rooms.lock().unwrap();
-        if let Some(peers) = map.get(room_id) {
-            peers
-                .iter()
-                .filter(|(peer_addr, _)| *peer_addr != &addr) // exclude self
-                .map(|(_, p)| p.sender.clone())
-                .collect()
-        } else {
-            Vec::new()
+fn handle_incoming(
+    rooms: &RoomManager,
+    settings: &SettingsMap,
+    events: &dyn EventSink,
+    config: &ServerConfig,
+    room_id: &str,
+    addr: SocketAddr,
+    mut msg: Message,
+) {
+    // Binary frames are audio, and the relay answers control frames
+    let parsed = match &msg {
+        Message::Text(text) => Some(ClientMessage::parse(text)),
+        _ => None,
+    };
+    // Metadata updates describe the participant rather than being chat, whatever the room allows
+    let parsed = match parsed {
+        Some(Ok(ClientMessage::UpdateMeta { meta })) => {
+            return update_meta(rooms, config, room_id, addr, meta);
+        }
+        Some(Ok(ClientMessage::SetRole { id, role })) => {
+            return change_role(rooms, room_id, addr, &id, &role);
         }
+        parsed => parsed,
     };
 
-    for tx in senders {
-        let _ = tx.unbounded_send(msg.clone());
+    let outgoing = rooms.read_room(room_id, |room| {
+        let sender = room.get(&addr)?;
+        // Requests to the room are let through whichever types the room allows, text which is
+        // none counts as chat
+        let request = parsed.as_ref().and_then(|parsed| parsed.as_ref().ok());
+        let kind = MessageType::of(&msg, request);
+        if let (false, Some(kind)) = (config.allows_message(room_id, &msg, request), kind) {
+            sender.notify(&kind.not_allowed_error());
+            return None;
+        }
+        if let Some(error) = config.oversized_audio(&msg) {
+            sender.notify(&error);
+            return None;
+        }
+        // Nobody else hears about messages the server cannot route
+        let parsed = match parsed {
+            Some(Err(e)) => {
+                sender.notify(&invalid_message(e.to_string()));
+                return None;
+            }
+            parsed => parsed.and_then(Result::ok),
+        };
+        // This server neither speaks nor translates, so there is nobody to follow
+        if let Some(
+            ClientMessage::Follow { .. }
+            | ClientMessage::Unfollow
+            | ClientMessage::SetTranslateTo { .. },
+        ) = parsed
+        {
+            sender.notify(&invalid_message("Speech and translation are not available".into()));
+            return None;
+        }
+        if let Some(ClientMessage::Control { action }) = parsed {
+            if !matches!(action.as_str(), "pause_room" | "resume_room") {
+                sender.notify(&invalid_message(format!("Unknown action '{}'", action)));
+                return None;
+            }
+            return handle_moderator_command(settings, room_id, sender, &action)
+                .map(Outgoing::Notice);
+        }
+        match parsed {
+            Some(ClientMessage::Pin { message_id }) => {
+                return handle_pin(settings, config, room_id, sender, &message_id, true)
+                    .map(Outgoing::Notice);
+            }
+            Some(ClientMessage::Unpin { message_id }) => {
+                return handle_pin(settings, config, room_id, sender, &message_id, false)
+                    .map(Outgoing::Notice);
+            }
+            _ => {}
+        }
+        // Only moderators may speak while the room is paused, presence and pings still work
+        let paused = settings.lock().unwrap().get(room_id).map_or(false, |s| s.paused);
+        if paused && !sender.role.moderates() && kind.is_some() {
+            sender.notify(&room_paused_notice());
+            return None;
+        }
+
+        // Ephemeral messages get their deadline, and never leave the server through the sink
+        let ephemeral = match stamp_expiry(&msg, config.max_expires_in) {
+            Ok(Some(stamped)) => {
+                msg = stamped;
+                true
+            }
+            Ok(None) => false,
+            Err(reason) => {
+                let error = json!({ "type": "error", "code": "invalid_expiry", "message": reason });
+                sender.notify(&error);
+                return None;
+            }
+        };
+        if let (Message::Text(text), false) = (&msg, ephemeral) {
+            let (room, name) = (room_id.to_string(), sender.name.clone());
+            events.send(RoomEvent::Chat { room, name, text: text.to_string() });
+        }
+        Some(Outgoing::Chat(stamp_message_id(settings, room_id, msg)))
+    });
+
+    match outgoing.flatten() {
+        Some(Outgoing::Chat(msg)) => rooms.forward(room_id, addr, &msg),
+        Some(Outgoing::Notice(notice)) => rooms.broadcast(room_id, &notice, None),
+        None => {}
     }
 }
 
-/// Forward messages from other participants to this client
-async fn read_received<S>(rx: S, outgoing: impl SinkExt<Message> + Unpin)
-where
-    S: futures_util::Stream<Item = Message> + Unpin,
-{
-    let _ = rx.map(Ok).forward(outgoing).await;
+/// Read the participant's first message and check that it is an `auth` message with one of
+/// the configured tokens, returning why it is not otherwise
+///
+/// Does nothing if the server admits everyone.
+async fn authenticate(
+    ws_stream: &mut WebSocketStream<ServerStream>,
+    config: &ServerConfig,
+) -> Result<(), &'static str> {
+    if config.auth_tokens.is_empty() {
+        return Ok(());
+    }
+    let msg = match tokio::time::timeout(config.auth_timeout, ws_stream.next()).await {
+        Ok(Some(Ok(msg))) => msg,
+        Ok(_) => return Err("Connection lost before authenticating"),
+        Err(_) => return Err("Authentication timed out"),
+    };
+    let auth: serde_json::Value = match &msg {
+        Message::Text(text) => serde_json::from_str(text).unwrap_or_default(),
+        _ => serde_json::Value::Null,
+    };
+    if auth["type"] != "auth" {
+        return Err("Authentication required");
+    }
+    match auth["token"].as_str() {
+        Some(token) if config.auth_tokens.contains(token) => Ok(()),
+        _ => Err("Authentication failed"),
+    }
 }
 
+/// Log a rejected handshake and build the response telling the client why
+fn reject(rejection: Rejection) -> ErrorResponse {
+    rejection.log();
+    let status = rejection.status.unwrap_or(StatusCode::BAD_REQUEST);
+    let mut response = Response::builder().status(status);
+    if let Some(retry_after) = rejection.retry_after_header() {
+        response = response.header(RETRY_AFTER, retry_after);
+    }
+    response.body(Some(rejection.message)).unwrap()
+}
+
+/// What the client asked for in a handshake that was accepted
+struct Handshake {
+    room_id: String,
+    /// The room as the client named it, which differs from `room_id` for an alias
+    room_name: String,
+    display_name: String,
+    encoding: Encoding,
+    batched: bool,
+    session_id: Option<String>,
+    spectator: bool,
+}
+
+// Handshake rejections are `ErrorResponse`s, as required by the tungstenite callback
+#[allow(clippy::result_large_err)]
 fn process_header_and_validate_participant_name(
     request: &Request,
-    rooms: &RoomMap,
-) -> Result<(String, String), ErrorResponse> {
+    peer: SocketAddr,
+    rooms: &RoomManager,
+    auth: &dyn AuthPolicy,
+    config: &ServerConfig,
+) -> Result<Handshake, ErrorResponse> {
+    if !config.headers_within_limits(request.headers()) {
+        let rejection =
+            Rejection::new("headers_too_large", "Request Header Fields Too Large", peer.ip())
+                .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        return Err(reject(rejection));
+    }
+
+    let client_ip = config.client_ip(peer.ip(), request.headers());
+    if client_ip != peer.ip() {
+        info!(%client_ip, "Handshake forwarded by a proxy");
+        if config.is_banned_ip(client_ip) {
+            let rejection = Rejection::new("ip_banned", "Address banned", client_ip)
+                .status(StatusCode::FORBIDDEN);
+            return Err(reject(rejection));
+        }
+    }
+
+    // Nothing about the rooms is looked at for clients the policy turns away
+    let query = request.uri().query().unwrap_or_default();
+    let query: HashMap<String, String> =
+        form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+    match auth.authorize(request.headers(), &query) {
+        Ok(AuthInfo { identity: Some(identity) }) => {
+            info!(%client_ip, identity, "Handshake authorized")
+        }
+        Ok(_) => {}
+        Err(e) => {
+            let rejection = Rejection::new("unauthorized", e.reason, client_ip).status(e.status);
+            return Err(reject(rejection));
+        }
+    }
+
     let mut room_id = String::from("default");
+    let mut room_name = room_id.clone();
     let mut display_name = String::from("Anonymous");
+    let mut encoding = Encoding::Json;
+    let mut batched = false;
+    let mut session_id = None;
+    let mut spectator = false;
 
     let uri = request.uri().to_string();
     if let Ok(url) = Url::parse(&format!("ws://localhost{}", uri)) {
-        room_id = url.path().trim_start_matches('/').to_string();
+        // The raw path, so traversal attempts are rejected rather than normalized away
+        let (path, query) = (request.uri().path(), request.uri().query());
+        room_id = match requested_room_id(path, query, config.max_room_id_length) {
+            Ok(Some(room_id)) => room_id,
+            Ok(None) if config.require_explicit_room => {
+                let rejection = Rejection::new("room_required", "Room required", client_ip)
+                    .status(StatusCode::BAD_REQUEST);
+                return Err(reject(rejection));
+            }
+            Ok(None) => "default".into(),
+            Err(e) => {
+                let message = format!("Invalid room: {}", e);
+                let rejection = Rejection::new("invalid_room", message, client_ip)
+                    .status(StatusCode::BAD_REQUEST);
+                return Err(reject(rejection));
+            }
+        };
 
-        if room_id.is_empty() {
-            room_id = "default".into();
+        if config.is_reserved_room(&room_id) {
+            let rejection = Rejection::new("room_reserved", "Reserved room name", client_ip)
+                .status(StatusCode::BAD_REQUEST)
+                .room(room_id);
+            return Err(reject(rejection));
         }
 
-        if let Some(name) = url.query_pairs().find(|(k, _)| k == "name") {
-            display_name = name.1.to_string();
+        room_name = room_id.clone();
+        let canonical = config.resolve_room(&room_id);
+        if canonical != room_id {
+            info!(room = %room_id, canonical, "Room is an alias");
+            room_id = canonical.to_string();
         }
-    }
 
-    // Check if name already exists in room
-    {
-        let rooms_lock = rooms.lock().unwrap();
-        if let Some(participants) = rooms_lock.get(&room_id) {
-            if participants.values().any(|p| p.name == display_name) {
-                // Fail handshake with HTTP 409 and reason
-                let resp = Response::builder()
-                    .status(409)
-                    .body(Some(format!("Name '{}' is already in use", display_name)))
-                    .unwrap();
-                return Err(resp);
+        // A name given must be more than whitespace, a missing one gets the default
+        if let Some((_, name)) = url.query_pairs().find(|(k, _)| k == "name") {
+            display_name = match normalize_name(&name) {
+                Some(name) => name,
+                None => {
+                    let rejection = Rejection::new("name_required", "Name required", client_ip)
+                        .status(StatusCode::BAD_REQUEST)
+                        .room(room_id);
+                    return Err(reject(rejection));
+                }
+            };
+        }
+
+        if let Some((_, value)) = url.query_pairs().find(|(k, _)| k == "encoding") {
+            encoding = match value.parse() {
+                Ok(encoding) => encoding,
+                Err(e) => {
+                    let message = format!("Invalid encoding: {}", e);
+                    let rejection = Rejection::new("invalid_encoding", message, client_ip)
+                        .status(StatusCode::BAD_REQUEST)
+                        .room(room_id)
+                        .name(display_name);
+                    return Err(reject(rejection));
+                }
+            };
+        }
+
+        if let Some((_, value)) = url.query_pairs().find(|(k, _)| k == "batch") {
+            batched = value == "true" || value == "1";
+        }
+
+        if let Some((_, value)) = url.query_pairs().find(|(k, _)| k == "spectator") {
+            spectator = value == "true" || value == "1";
+        }
+
+        // Lets a reconnecting client reclaim its name while it is reserved for it
+        if let Some((_, value)) = url.query_pairs().find(|(k, _)| k == "session_id") {
+            if !is_valid_session_id(&value) {
+                let message = format!(
+                    "Invalid session id, expected 1 to {} printable characters",
+                    MAX_SESSION_ID_LEN
+                );
+                let rejection = Rejection::new("invalid_session_id", message, client_ip)
+                    .status(StatusCode::BAD_REQUEST)
+                    .room(room_id)
+                    .name(display_name);
+                return Err(reject(rejection));
             }
+            session_id = Some(value.into_owned());
         }
     }
 
-    Ok((room_id, display_name))
+    if config.is_banned_name(&display_name) {
+        let rejection = Rejection::new("name_banned", "Name banned", client_ip)
+            .status(StatusCode::FORBIDDEN)
+            .room(room_id)
+            .name(display_name);
+        return Err(reject(rejection));
+    }
+
+    // Check if the room has space and the name is free, unless duplicates get renamed on insert
+    match rooms.can_join_as(&room_id, &display_name, session_id.as_deref(), spectator) {
+        Ok(()) => {}
+        Err(JoinError::RoomFull) => {
+            let rejection = Rejection::new("room_full", "Room full", client_ip)
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .retry_after(config.capacity_retry_after)
+                .room(room_id)
+                .name(display_name);
+            return Err(reject(rejection));
+        }
+        Err(e) => {
+            // Fail handshake with HTTP 409 and reason
+            let rejection = Rejection::new("name_in_use", e.to_string(), client_ip)
+                .status(StatusCode::CONFLICT)
+                .room(room_id)
+                .name(display_name);
+            return Err(reject(rejection));
+        }
+    }
+
+    Ok(Handshake { room_id, room_name, display_name, encoding, batched, session_id, spectator })
+}
+
+/// Keep the roster of other instances up to date and rebroadcast affected rooms
+async fn sync_remote_presence(
+    rooms: RoomManager,
+    presence: Arc<dyn PresenceBackend>,
+    remote: RemoteRoster,
+    coalescer: Coalescer,
+    max_listed: usize,
+) {
+    let mut events = presence.subscribe();
+    while let Some(event) = events.next().await {
+        let room_id = match &event {
+            PresenceEvent::Joined { room, .. } | PresenceEvent::Left { room, .. } => room.clone(),
+        };
+        remote.apply(event);
+
+        broadcast_roster(&rooms, &remote, &coalescer, &room_id, max_listed);
+    }
 }
 
-async fn handle_connection(rooms: RoomMap, stream: TcpStream, connection_addr: SocketAddr) {
+/// Everything the connections of the server share
+#[derive(Clone)]
+struct Shared {
+    rooms: RoomManager,
+    settings: SettingsMap,
+    presence: Arc<dyn PresenceBackend>,
+    remote: RemoteRoster,
+    coalescer: Coalescer,
+    events: Arc<dyn EventSink>,
+    auth: Arc<dyn AuthPolicy>,
+    shutdown: Shutdown,
+    acceptor: StreamAcceptor,
+    config: Arc<ServerConfig>,
+}
+
+/// Serve a participant until it disconnects, failing only if its TLS or WebSocket handshake
+/// errored
+async fn handle_connection(
+    shared: Shared,
+    stream: TcpStream,
+    connection_addr: SocketAddr,
+) -> Result<(), ServerError> {
+    let Shared {
+        rooms,
+        settings,
+        presence,
+        remote,
+        coalescer,
+        events,
+        auth,
+        shutdown,
+        acceptor,
+        config,
+    } = shared;
+    let stream = acceptor.accept(stream).await.map_err(ServerError::Tls)?;
     let mut room_id = String::new();
+    let mut room_name = String::new();
     let mut display_name = String::new();
+    let mut encoding = Encoding::Json;
+    let mut batched = false;
+    let mut session_id = None;
+    let mut spectator = false;
 
     // ---- WebSocket handshake & extract room/name ----
-    let ws_stream = accept_hdr_async(stream, |req: &Request, resp: Response| {
-        match process_header_and_validate_participant_name(req, &rooms) {
-            Ok((rid, dname)) => {
-                room_id = rid;
-                display_name = dname;
+    #[allow(clippy::result_large_err)]
+    let callback = |req: &Request, resp: Response| {
+        match process_header_and_validate_participant_name(
+            req,
+            connection_addr,
+            &rooms,
+            &*auth,
+            &config,
+        ) {
+            Ok(handshake) => {
+                let mut resp = resp;
+                // Tell the client which canonical room it joined, e.g. when it asked for an alias
+                if let Ok(room_header) = handshake.room_id.parse() {
+                    resp.headers_mut().insert("X-Room-Id", room_header);
+                }
+                room_id = handshake.room_id;
+                room_name = handshake.room_name;
+                display_name = handshake.display_name;
+                encoding = handshake.encoding;
+                batched = handshake.batched;
+                session_id = handshake.session_id;
+                spectator = handshake.spectator;
                 Ok(resp)
             }
             Err(reject_resp) => Err(reject_resp), // reject handshake here
         }
-    })
-    .await;
+    };
+    let ws_config = Some(config.websocket_config());
+    let ws_stream = accept_hdr_async_with_config(stream, callback, ws_config).await;
 
-    let ws_stream: WebSocketStream<TcpStream> = match ws_stream {
+    let ws_stream: WebSocketStream<ServerStream> = match ws_stream {
         Ok(stream) => {
-            println!("{} joined room '{}' as '{}'", connection_addr, room_id, display_name);
+            let span = Span::current();
+            span.record("room_id", room_id.as_str());
+            span.record("name", display_name.as_str());
+            info!("Handshake accepted");
             stream
         }
         Err(tungstenite::Error::Http(response)) => {
             // Extract and log reason from rejection
-            if let Some(reason) = response.body() {
-                println!(
-                    "Rejected connection from {}: {}",
-                    connection_addr,
-                    String::from_utf8_lossy(&reason)
-                );
-            } else {
-                println!(
-                    "Rejected connection from {} with status {}",
-                    connection_addr,
-                    response.status()
-                );
+            let status = response.status().as_u16();
+            match response.body() {
+                Some(reason) => {
+                    debug!(status, reason = %String::from_utf8_lossy(reason), "Handshake rejected")
+                }
+                None => debug!(status, "Handshake rejected"),
             }
-            return;
+            return Ok(());
+        }
+        Err(e) => return Err(ServerError::Handshake(Box::new(e))),
+    };
+
+    // ---- Wait for the participant to authenticate, if the server requires it ----
+    let mut ws_stream = ws_stream;
+    if let Err(reason) = authenticate(&mut ws_stream, &config).await {
+        warn!(reason, "Failed to authenticate");
+        let close = CloseFrame { code: CloseCode::Policy, reason: reason.into() };
+        let _ = ws_stream.close(Some(close)).await;
+        return Ok(());
+    }
+
+    // ---- Create the sender channels for this participant ----
+    let (tx, rx) = config.outbound_queue();
+    let (control_tx, control_rx) = config.outbound_queue();
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let info = connection_info(connection_id, connection_addr, &room_name);
+    let _ = control_tx.push(encoding.encode(&info));
+
+    // ---- Insert participant, unless someone took its name or place since the handshake ----
+    let mut participant =
+        Participant::new(connection_id, display_name.clone(), tx.clone(), control_tx.clone());
+    participant.room_name = Some(room_name.clone()).filter(|name| *name != room_id);
+    participant.encoding = encoding;
+    participant.session_id = session_id;
+    participant.spectator = spectator;
+    let (created, resumed) = match rooms.join(&room_id, connection_addr, participant) {
+        Ok(joined) => {
+            display_name = joined.name;
+            (joined.created, joined.resumed)
         }
         Err(e) => {
-            println!("Handshake error from {}: {:?}", connection_addr, e);
+            warn!(error = %e, "Could not join the room");
+            let code = match e {
+                JoinError::RoomFull => {
+                    let _ = ws_stream.send(encoding.encode(&config.room_full_error())).await;
+                    CloseCode::Again
+                }
+                JoinError::DuplicateName(_) => CloseCode::Policy,
+            };
+            let close = CloseFrame { code, reason: e.to_string().into() };
+            let _ = ws_stream.close(Some(close)).await;
+            return Ok(());
+        }
+    };
+    let (paused, pinned) = {
+        let mut settings = settings.lock().unwrap();
+        if created {
+            settings.remove(&room_id);
+        }
+        settings.get(&room_id).map_or((false, Vec::new()), |s| (s.paused, s.pinned.clone()))
+    };
+
+    info!(created, resumed, "Joined the room");
+    if tracing::enabled!(Level::DEBUG) {
+        for (room, names) in rooms.rosters() {
+            debug!(room, participants = ?names, "Room state");
+        }
+    }
+    presence.publish(PresenceEvent::Joined { room: room_id.clone(), name: display_name.clone() });
+    if created {
+        events.send(RoomEvent::RoomCreated { room: room_id.clone() });
+    }
+    events.send(RoomEvent::Joined { room: room_id.clone(), name: display_name.clone() });
+
+    // ---- Tell the joiner about the room it landed in, as it is now even if it reconnected ----
+    let snapshot = json!({
+        "type": "room_snapshot",
+        "room": sanitize_text(&room_name),
+        "name": sanitize_text(&display_name),
+        "created": created,
+        "moderator": created,
+        "role": if created { RoomRole::Owner } else { RoomRole::Participant }.as_str(),
+        "paused": paused,
+        "pinned": pinned,
+        "resumed": resumed
+    });
+    let _ = control_tx.push(encoding.encode(&snapshot));
+    let max_listed = config.max_listed_participants;
+    rooms.send_participants_to(&remote, &room_id, connection_addr, max_listed);
+
+    // ---- Broadcast updated room state ----
+    rooms.broadcast(&room_id, &join_notice(&display_name, connection_addr), Some(connection_addr));
+    broadcast_roster(&rooms, &remote, &coalescer, &room_id, config.max_listed_participants);
+
+    // ---- Relay messages until the participant disconnects ----
+    let mut rate_limit = config.message_rate_limit.map(TokenBucket::new);
+    let pongs = Heartbeat::new();
+    let activity = Activity::new();
+    let on_message = |msg: Message| {
+        if msg.is_pong() {
+            pongs.pong();
             return;
         }
+        activity.observe(&msg);
+        if let Some(bucket) = &mut rate_limit {
+            if !bucket.admit(&msg) {
+                info!("Dropped a message, rate limited");
+                let _ = control_tx.push(encoding.encode(&RateLimit::exceeded_notice()));
+                return;
+            }
+        }
+        if msg.is_text() || msg.is_binary() {
+            let (kind, payload) = config.describe_message(&room_id, &msg);
+            info!(kind, len = msg.len(), "Received a message");
+            if let Some(payload) = payload {
+                trace!(kind, payload = ?payload, "Payload of the message");
+            }
+        }
+        handle_incoming(&rooms, &settings, &*events, &config, &room_id, connection_addr, msg)
     };
+    let on_disconnect = || {
+        info!("Left the room");
 
-    // ---- Create a sender channel for this participant ----
-    let (tx, rx) = unbounded();
+        // ---- Remove participant, then flush what is still queued for it ----
+        remove_participant(&rooms, &*events, &room_id, connection_addr);
+        tx.close();
+        control_tx.close();
+    };
+    let outbound = prioritized(control_rx, rx);
+    let outbound = match config.batch_window {
+        Some(window) if batched => batch(outbound, window).boxed(),
+        _ => outbound.boxed(),
+    };
+    let outbound = match config.keepalive_interval {
+        Some(interval) => keepalive(outbound, interval).boxed(),
+        None => outbound,
+    };
+    let outbound = match config.ping_interval {
+        Some(interval) => heartbeat(outbound, pongs.clone(), interval, config.pong_timeout).boxed(),
+        None => outbound,
+    };
+    let outbound = match config.max_connection_lifetime {
+        Some(lifetime) => max_lifetime(outbound, lifetime).boxed(),
+        None => outbound,
+    };
+    let outbound = match config.idle_timeout {
+        Some(timeout) => {
+            let notice = encoding.encode(&idle_timeout_notice());
+            idle_timeout(outbound, activity.clone(), timeout, notice).boxed()
+        }
+        None => outbound,
+    };
+    let outbound = until_shutdown(outbound, shutdown, encoding.encode(&shutdown_notice()));
+    relay(
+        ws_stream,
+        outbound,
+        on_message,
+        on_disconnect,
+        config.drain_timeout,
+        config.write_timeout,
+    )
+    .await;
+    presence.publish(PresenceEvent::Left { room: room_id.clone(), name: display_name.clone() });
 
-    // ---- Insert participant (safe now because name already validated) ----
-    {
-        let mut map = rooms.lock().unwrap();
-        map.entry(room_id.clone())
-            .or_default()
-            .insert(connection_addr, Participant { name: display_name.clone(), sender: tx });
+    broadcast_roster(&rooms, &remote, &coalescer, &room_id, config.max_listed_participants);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), ServerError> {
+    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let addr = addr.parse::<SocketAddr>().map_err(|e| ServerError::config("address", e))?;
+    let mut config = ServerConfig::default();
+    if let (Some(cert), Some(key)) = (env::args().nth(2), env::args().nth(3)) {
+        config.tls = Some(TlsConfig::new(cert, key));
+    }
+    let config = Arc::new(config);
+    // RUST_LOG picks what is logged, e.g. `warn,[room_id=main]=debug` to debug a single room
+    Logger::new(config.log_format, LogFilter::from_env()).install().expect("Logger already set");
+    let listener = config.bind(addr)?;
+
+    // Init Room to Empty
+    let rooms = RoomManager::new()
+        .dedupe_names(config.auto_dedupe_names)
+        .max_participants(config.max_participants)
+        .max_members(config.max_members)
+        .max_spectators(config.max_spectators)
+        .reconnect_grace(config.reconnect_grace)
+        .max_reservations(config.max_reservations_per_ip, config.max_reservations_per_room)
+        .max_buffered_messages(config.max_buffered_messages);
+
+    // Single instance: swap in a shared backend to merge rosters across instances
+    let presence: Arc<dyn PresenceBackend> = Arc::new(NoopPresence);
+    let remote = RemoteRoster::new();
+    let coalescer = Coalescer::new(config.roster_coalesce_window);
+    let events = config.event_sink()?;
+    tokio::spawn(sync_remote_presence(
+        rooms.clone(),
+        presence.clone(),
+        remote.clone(),
+        coalescer.clone(),
+        config.max_listed_participants,
+    ));
+    let settings = SettingsMap::default();
+    let auth = config.auth_policy();
+    let shared = Shared {
+        rooms,
+        settings,
+        presence,
+        remote,
+        coalescer,
+        events,
+        auth,
+        shutdown: Shutdown::new(),
+        acceptor: config.stream_acceptor()?,
+        config,
+    };
 
-        println!("=== Current Room State ===");
-        for (room, participants) in map.iter() {
-            println!("Room: {}", room);
-            for (addr, participant) in participants.iter() {
-                println!("  Addr: {:?}, Name: {}", addr, participant.name);
+    info!(%addr, scheme = shared.acceptor.scheme(), "Listening, enter `shutdown` to stop");
+    run_until_shutdown(listener, shared, shutdown_requested()).await
+}
+
+/// Resolves once `shutdown` is entered on stdin, or never if stdin is closed.
+///
+/// Stands in for `tokio::signal::ctrl_c()`, as tokio's `signal` feature is not enabled here.
+async fn shutdown_requested() {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim() == "shutdown" {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Serve connections until `signal` resolves, then shut down gracefully: stop accepting, send
+/// every participant a `server_shutdown` notice and a close frame, and wait for the
+/// connections to finish, at most for the shutdown grace period
+// `tokio::select!` expands to `IntoFuture`, which the versions of tokio that build on the
+// minimum supported Rust version do without
+#[allow(clippy::incompatible_msrv)]
+async fn run_until_shutdown(
+    listener: TcpListener,
+    shared: Shared,
+    signal: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    let config = shared.config.clone();
+    let connections = InFlight::new();
+    pin_mut!(signal);
+    loop {
+        let (stream, addr) = tokio::select! {
+            _ = &mut signal => break,
+            accepted = config.accept(&listener) => accepted.map_err(ServerError::Accept)?,
+        };
+        if config.is_banned_ip(addr.ip()) {
+            let rejection = Rejection::new("ip_banned", "Address banned", addr.ip());
+            rejection.log();
+            continue;
+        }
+        if let Err(e) = config.configure_stream(&stream) {
+            warn!(%addr, error = %e, "Failed to configure the connection");
+        }
+        let connection = connections.begin();
+        let shared = shared.clone();
+        let span = info_span!("connection", %addr, room_id = Empty, name = Empty);
+        tokio::spawn(
+            async move {
+                if let Err(e) = handle_connection(shared, stream, addr).await {
+                    warn!(error = %e, "Connection failed");
+                }
+                drop(connection);
             }
+            .instrument(span),
+        );
+    }
+
+    drop(listener);
+    info!(connections = connections.count(), "Shutting down, closing every connection");
+    shared.shutdown.trigger();
+    if !connections.wait(config.shutdown_grace).await {
+        let (connections, grace) = (connections.count(), config.shutdown_grace);
+        warn!(connections, grace = ?grace, "Gave up on the connections left");
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "connect"))]
+mod tests {
+    use super::*;
+    use futures_util::SinkExt;
+    use tokio_tungstenite::{connect_async, NoopSink, RateLimit};
+
+    /// Start a server on an ephemeral port, wired up like `main`
+    fn spawn_server(config: ServerConfig) -> SocketAddr {
+        spawn_server_with_rooms(config).0
+    }
+
+    /// Start a server like `spawn_server`, returning its rooms too
+    fn spawn_server_with_rooms(config: ServerConfig) -> (SocketAddr, RoomManager) {
+        let listener = config.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = shared(config);
+        let rooms = shared.rooms.clone();
+
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                tokio::spawn(handle_connection(shared.clone(), stream, addr));
+            }
+        });
+        (addr, rooms)
+    }
+
+    /// The state of a server wired up like `main`
+    fn shared(config: ServerConfig) -> Shared {
+        let rooms = RoomManager::new()
+            .dedupe_names(config.auto_dedupe_names)
+            .max_participants(config.max_participants)
+            .max_members(config.max_members)
+            .max_spectators(config.max_spectators)
+            .reconnect_grace(config.reconnect_grace)
+            .max_reservations(config.max_reservations_per_ip, config.max_reservations_per_room)
+            .max_buffered_messages(config.max_buffered_messages);
+        Shared {
+            rooms,
+            settings: SettingsMap::default(),
+            presence: Arc::new(NoopPresence),
+            remote: RemoteRoster::new(),
+            coalescer: Coalescer::new(config.roster_coalesce_window),
+            events: Arc::new(NoopSink),
+            auth: config.auth_policy(),
+            shutdown: Shutdown::new(),
+            acceptor: config.stream_acceptor().unwrap(),
+            config: Arc::new(config),
         }
-        println!("==========================");
     }
 
-    // ---- Broadcast updated room state ----
-    broadcast_count(&rooms, &room_id);
-    broadcast_participants(&rooms, &room_id);
+    /// Join and return the first `count` messages the server sends
+    async fn first_messages(url: String, count: usize) -> Vec<serde_json::Value> {
+        let (mut ws_stream, _) = connect_async(url).await.unwrap();
+        let mut messages = Vec::new();
+        for _ in 0..count {
+            let msg = ws_stream.next().await.unwrap().unwrap();
+            messages.push(serde_json::from_str(msg.to_text().unwrap()).unwrap());
+        }
+        // Keep the connection open for the other participants of the test
+        tokio::spawn(async move { while ws_stream.next().await.is_some() {} });
+        messages
+    }
 
-    // ---- Split into outgoing/incoming streams ----
-    let (outgoing, incoming) = ws_stream.split();
+    /// Join and return the room snapshot, which follows the connection info
+    async fn snapshot(url: String) -> serde_json::Value {
+        let mut messages = first_messages(url, 2).await;
+        assert_eq!(messages[0]["type"], "connection_info");
+        messages.pop().unwrap()
+    }
 
-    let broadcast_incoming = incoming.try_for_each(|msg| {
-        handle_incoming(&rooms, &room_id, connection_addr, msg);
-        future::ok(())
-    });
-    let receive_from_others = read_received(rx, outgoing);
+    #[test]
+    fn broadcasts_reach_seeded_participants() {
+        let rooms = RoomManager::new();
+        let mut receivers = seed_room(&rooms, "main", &["Alice", "Bob"]);
+        seed_room(&rooms, "other", &["Carol"]);
+        assert_eq!(rooms.participants("main").len(), 2);
+
+        rooms.broadcast_participants(&RemoteRoster::new(), "main", 500);
+
+        for rx in &mut receivers {
+            let msg = rx.try_recv().unwrap();
+            let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            let mut entries = msg["participants"].as_array().unwrap().clone();
+            entries.sort_by_key(|entry| entry["joined_seq"].as_u64());
+            let names: Vec<_> = entries.iter().map(|entry| &entry["name"]).collect();
+            assert_eq!(names, vec!["Alice", "Bob"]);
+            assert!(entries.iter().all(|entry| entry["role"] == "participant"));
+            assert!(rx.try_recv().is_none(), "nothing else is queued");
+        }
+    }
+
+    #[test]
+    fn leavers_get_nothing_once_removed() {
+        let rooms = RoomManager::new();
+        let settings = SettingsMap::default();
+        let mut receivers = seed_room(&rooms, "main", &["Alice", "Bob", "Carol"]);
+        let carol = receivers.pop().unwrap();
+        let config = ServerConfig::default();
+        let alice = SocketAddr::from(([192, 0, 2, 1], 1));
+        let bob = SocketAddr::from(([192, 0, 2, 1], 2));
+
+        // Alice keeps talking while Bob leaves
+        let talker = {
+            let (rooms, settings) = (rooms.clone(), settings.clone());
+            std::thread::spawn(move || {
+                for i in 0..1000 {
+                    let msg = json!({ "type": "chat", "text": i.to_string() });
+                    let msg = Message::text(msg.to_string());
+                    handle_incoming(&rooms, &settings, &NoopSink, &config, "main", alice, msg);
+                }
+            })
+        };
+        remove_participant(&rooms, &NoopSink, "main", bob);
+        talker.join().unwrap();
+
+        assert_eq!(rooms.participants("main").len(), 2);
+        // Bob got a prefix of the chat, then his queue was closed
+        let drain = |mut rx: QueueReceiver| {
+            // Seeded participants get the notices, like Bob leaving, on the chat queue
+            let chat = std::iter::from_fn(move || rx.try_recv());
+            chat.filter(|msg| !msg.to_text().unwrap().contains(r#""leave""#)).collect::<Vec<_>>()
+        };
+        let bob_got = drain(receivers.pop().unwrap());
+        let carol_got = drain(carol);
+        assert_eq!(carol_got.len(), 1000);
+        assert_eq!(bob_got[..], carol_got[..bob_got.len()]);
+    }
+
+    #[test]
+    fn large_rooms_get_a_partial_list() {
+        let rooms = RoomManager::new();
+        let mut receivers = seed_room(&rooms, "main", &["Alice", "Bob", "Carol"]);
+
+        rooms.broadcast_participants(&RemoteRoster::new(), "main", 2);
+
+        let msg = receivers[0].try_recv().unwrap();
+        let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+        let names: Vec<_> =
+            msg["participants"].as_array().unwrap().iter().map(|e| &e["name"]).collect();
+        assert_eq!(names, vec!["Alice", "Bob"]);
+        assert_eq!(msg["total"], 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_first_joiners_create_the_room_once() {
+        let addr = spawn_server(ServerConfig::default());
+
+        let joins = (0..32)
+            .map(|i| tokio::spawn(snapshot(format!("ws://{}/race?name=joiner-{}", addr, i))));
+        let mut created = 0;
+        for join in joins.collect::<Vec<_>>() {
+            let snapshot = join.await.unwrap();
+            if snapshot["created"] == true {
+                created += 1;
+            }
+        }
+        assert_eq!(created, 1);
+    }
+
+    #[tokio::test]
+    async fn duplicate_names_can_be_suffixed() {
+        let config = ServerConfig { auto_dedupe_names: true, ..ServerConfig::default() };
+        let addr = spawn_server(config);
+
+        let first = snapshot(format!("ws://{}/main?name=Alice", addr)).await;
+        let second = snapshot(format!("ws://{}/main?name=Alice", addr)).await;
+        assert_eq!(first["name"], "Alice");
+        assert_eq!(second["name"], "Alice (2)");
+    }
+
+    #[tokio::test]
+    async fn joiners_get_the_participant_list_right_away() {
+        let addr = spawn_server(ServerConfig::default());
+
+        snapshot(format!("ws://{}/main?name=Alice", addr)).await;
+        let messages = first_messages(format!("ws://{}/main?name=Bob", addr), 3).await;
+        assert_eq!(messages[1]["type"], "room_snapshot");
+        assert_eq!(messages[2]["type"], "participants");
+        let mut names: Vec<_> =
+            messages[2]["participants"].as_array().unwrap().iter().map(|e| &e["name"]).collect();
+        names.sort_by_key(|name| name.as_str());
+        assert_eq!(names, vec!["Alice", "Bob"]);
+    }
+
+    #[tokio::test]
+    async fn close_frames_are_not_relayed() {
+        let (addr, rooms) = spawn_server_with_rooms(ServerConfig::default());
+        let (mut alice, _) = connect_async(format!("ws://{}/main?name=Alice", addr)).await.unwrap();
+        next_of_type(&mut alice, "room_snapshot").await;
+        let (mut bob, _) = connect_async(format!("ws://{}/main?name=Bob", addr)).await.unwrap();
+        next_of_type(&mut bob, "room_snapshot").await;
+
+        let close = CloseFrame { code: CloseCode::Normal, reason: "bye".into() };
+        alice.send(Message::Close(Some(close))).await.unwrap();
+
+        // Bob hears that Alice left, once, and keeps his connection
+        let mut leaves = 0;
+        let quiet = Duration::from_millis(200);
+        while let Ok(msg) = tokio::time::timeout(quiet, bob.next()).await {
+            let msg = msg.unwrap().unwrap();
+            assert!(!msg.is_close(), "close frames only end their own connection");
+            let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            if msg["type"] == "leave" {
+                assert_eq!(msg["name"], "Alice");
+                leaves += 1;
+            }
+        }
+        assert_eq!(leaves, 1);
+        let names: Vec<_> = rooms.participants("main").into_iter().map(|p| p.name).collect();
+        assert_eq!(names, ["Bob"]);
+    }
+
+    #[tokio::test]
+    async fn idle_participants_are_removed() {
+        let config = ServerConfig {
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..ServerConfig::default()
+        };
+        let (addr, rooms) = spawn_server_with_rooms(config);
+
+        let url = format!("ws://{}/main?name=Alice", addr);
+        let (mut alice, _) = connect_async(url).await.unwrap();
+        let url = format!("ws://{}/main?name=Bob", addr);
+        let (mut bob, _) = connect_async(url).await.unwrap();
+        // Bob keeps talking, which Alice reads but which does not keep her active
+        let chat = json!({ "type": "chat", "text": "hi" }).to_string();
+        for _ in 0..6 {
+            bob.send(Message::text(chat.clone())).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let mut types = Vec::new();
+        let close = loop {
+            match alice.next().await.unwrap().unwrap() {
+                Message::Close(frame) => break frame.unwrap(),
+                msg => {
+                    let msg: serde_json::Value =
+                        serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                    types.push(msg["type"].as_str().unwrap().to_string());
+                }
+            }
+        };
+        assert!(types.iter().any(|kind| kind == "chat"));
+        assert_eq!(types.last().map(String::as_str), Some("idle_timeout"));
+        assert_eq!(u16::from(close.code), 4003);
+
+        // Alice is removed once her connection is over, which the close frame may beat
+        while alice.next().await.is_some() {}
+        for _ in 0..50 {
+            if rooms.participants("main").len() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let names: Vec<_> = rooms.participants("main").into_iter().map(|p| p.name).collect();
+        assert_eq!(names, ["Bob"]);
+        tokio::spawn(async move { while bob.next().await.is_some() {} });
+    }
+
+    #[tokio::test]
+    async fn unresponsive_participants_are_removed() {
+        let config = ServerConfig {
+            ping_interval: Some(Duration::from_millis(50)),
+            pong_timeout: Duration::from_millis(50),
+            ..ServerConfig::default()
+        };
+        let (addr, rooms) = spawn_server_with_rooms(config);
+
+        // Reading answers the pings, Bob's client never reads
+        snapshot(format!("ws://{}/main?name=Alice", addr)).await;
+        let (_bob, _) = connect_async(format!("ws://{}/main?name=Bob", addr)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let names: Vec<_> = rooms.participants("main").into_iter().map(|p| p.name).collect();
+        assert_eq!(names, ["Alice"]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_every_connection() {
+        let config = ServerConfig::default();
+        let listener = config.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = futures_channel::oneshot::channel::<()>();
+        let server = tokio::spawn(run_until_shutdown(listener, shared(config), async {
+            let _ = stopped.await;
+        }));
+
+        let mut clients = Vec::new();
+        for name in ["Alice", "Bob"] {
+            let url = format!("ws://{}/main?name={}", addr, name);
+            let (mut ws_stream, _) = connect_async(url).await.unwrap();
+            // Read up to the participant list, so both have joined
+            while let Some(msg) = ws_stream.next().await {
+                let msg: serde_json::Value =
+                    serde_json::from_str(msg.unwrap().to_text().unwrap()).unwrap();
+                if msg["type"] == "participants" {
+                    break;
+                }
+            }
+            clients.push(ws_stream);
+        }
+        stop.send(()).unwrap();
+
+        for mut ws_stream in clients {
+            let mut types = Vec::new();
+            let close = loop {
+                match ws_stream.next().await.unwrap().unwrap() {
+                    Message::Close(frame) => break frame.unwrap(),
+                    msg => {
+                        let msg: serde_json::Value =
+                            serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                        types.push(msg["type"].as_str().unwrap().to_string());
+                    }
+                }
+            };
+            assert_eq!(types.last().map(String::as_str), Some("server_shutdown"));
+            assert_eq!(close.code, CloseCode::Away);
+            // Answering the close lets the server finish the connection
+            while ws_stream.next().await.is_some() {}
+        }
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+        assert!(connect_async(format!("ws://{}/main?name=Carol", addr)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown_reaches_participants_with_a_full_queue() {
+        let config = ServerConfig { outbound_queue_capacity: 4, ..ServerConfig::default() };
+        let listener = config.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = futures_channel::oneshot::channel::<()>();
+        let server = tokio::spawn(run_until_shutdown(listener, shared(config), async {
+            let _ = stopped.await;
+        }));
+        let url = |name| format!("ws://{}/main?name={}", addr, name);
+        let (mut alice, _) = connect_async(url("Alice")).await.unwrap();
+        next_of_type(&mut alice, "room_snapshot").await;
+        let (mut stalled, _) = connect_async(url("Stalled")).await.unwrap();
+        next_of_type(&mut stalled, "room_snapshot").await;
+        let (mut carol, _) = connect_async(url("Carol")).await.unwrap();
+        next_of_type(&mut carol, "room_snapshot").await;
+
+        // Nobody reads the stalled participant's socket, so its queue fills up behind it
+        let text = "x".repeat(64 * 1024);
+        let sent = 400;
+        for i in 0..sent {
+            let chat = json!({ "type": "chat", "text": text, "i": i });
+            alice.send(Message::text(chat.to_string())).await.unwrap();
+        }
+        // Carol getting the last message means the stalled queue got its copy too
+        while next_of_type(&mut carol, "chat").await["i"] != sent - 1 {}
+        stop.send(()).unwrap();
+
+        let (mut chats, mut last) = (0, String::new());
+        let close = loop {
+            match stalled.next().await.unwrap().unwrap() {
+                Message::Close(frame) => break frame.unwrap(),
+                msg => {
+                    let msg: serde_json::Value =
+                        serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                    chats += (msg["type"] == "chat") as i32;
+                    last = msg["type"].as_str().unwrap().to_owned();
+                }
+            }
+        };
+        assert_eq!(last, "server_shutdown");
+        assert_eq!(close.code, CloseCode::Away);
+        assert!(chats < sent, "the stalled participant got its whole backlog");
+        for mut ws_stream in [alice, stalled, carol] {
+            while ws_stream.next().await.is_some() {}
+        }
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_rooms_turn_joiners_away() {
+        let config = ServerConfig { max_participants: Some(2), ..ServerConfig::default() };
+        let addr = spawn_server(config);
+
+        snapshot(format!("ws://{}/main?name=Alice", addr)).await;
+        snapshot(format!("ws://{}/main?name=Bob", addr)).await;
+        match connect_async(format!("ws://{}/main?name=Carol", addr)).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 503);
+                assert_eq!(response.headers()["Retry-After"], "30");
+                assert_eq!(response.body().as_deref(), Some(&b"Room full"[..]));
+            }
+            other => panic!("unexpected handshake result: {:?}", other.map(|_| ())),
+        }
+
+        let snapshot = snapshot(format!("ws://{}/other?name=Carol", addr)).await;
+        assert_eq!(snapshot["room"], "other");
+    }
+
+    #[tokio::test]
+    async fn members_and_spectators_fill_rooms_separately() {
+        let config = ServerConfig { max_members: Some(1), ..ServerConfig::default() };
+        let addr = spawn_server(config);
+
+        snapshot(format!("ws://{}/main?name=Alice", addr)).await;
+        match connect_async(format!("ws://{}/main?name=Bob", addr)).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 503);
+                assert_eq!(response.body().as_deref(), Some(&b"Room full"[..]));
+            }
+            other => panic!("unexpected handshake result: {:?}", other.map(|_| ())),
+        }
+
+        let snapshot = snapshot(format!("ws://{}/main?name=Bob&spectator=true", addr)).await;
+        assert_eq!(snapshot["name"], "Bob");
+    }
+
+    #[tokio::test]
+    async fn blank_names_are_rejected() {
+        let addr = spawn_server(ServerConfig::default());
+
+        match connect_async(format!("ws://{}/main?name=%20%09", addr)).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 400);
+                assert_eq!(response.body().as_deref(), Some(&b"Name required"[..]));
+            }
+            other => panic!("unexpected handshake result: {:?}", other.map(|_| ())),
+        }
+        let snapshot = snapshot(format!("ws://{}/main?name=%20John%20%20Smith", addr)).await;
+        assert_eq!(snapshot["name"], "John Smith");
+        assert!(connect_async(format!("ws://{}/main?name=john+smith", addr)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn nested_room_paths_are_rejected() {
+        let addr = spawn_server(ServerConfig::default());
+
+        match connect_async(format!("ws://{}/a/b?name=Alice", addr)).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 400);
+                let body = response.body().as_deref().unwrap();
+                assert_eq!(body, &b"Invalid room: room ids cannot contain '/'"[..]);
+            }
+            other => panic!("unexpected handshake result: {:?}", other.map(|_| ())),
+        }
+        let snapshot = snapshot(format!("ws://{}/a/?name=Alice", addr)).await;
+        assert_eq!(snapshot["room"], "a");
+    }
+
+    #[tokio::test]
+    async fn handshakes_need_a_valid_token() {
+        let handshake_tokens = std::iter::once("s3cret".to_string()).collect();
+        let addr = spawn_server(ServerConfig { handshake_tokens, ..ServerConfig::default() });
+
+        for (query, status, reason) in
+            [("name=Alice", 401, "Token required"), ("token=guess", 403, "Invalid token")]
+        {
+            match connect_async(format!("ws://{}/main?{}", addr, query)).await {
+                Err(tungstenite::Error::Http(response)) => {
+                    assert_eq!(response.status(), status);
+                    assert_eq!(response.body().as_deref(), Some(reason.as_bytes()));
+                }
+                other => panic!("unexpected handshake result: {:?}", other.map(|_| ())),
+            }
+        }
+
+        let snapshot = snapshot(format!("ws://{}/main?name=Alice&token=s3cret", addr)).await;
+        assert_eq!(snapshot["name"], "Alice");
+    }
+
+    #[tokio::test]
+    async fn explicit_rooms_can_be_required() {
+        let config = ServerConfig { require_explicit_room: true, ..ServerConfig::default() };
+        let addr = spawn_server(config);
+
+        match connect_async(format!("ws://{}/?name=Alice", addr)).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 400);
+                assert_eq!(response.body().as_deref(), Some(&b"Room required"[..]));
+            }
+            other => panic!("unexpected handshake result: {:?}", other.map(|_| ())),
+        }
+
+        let snapshot = snapshot(format!("ws://{}/main?name=Alice", addr)).await;
+        assert_eq!(snapshot["room"], "main");
+    }
+
+    #[tokio::test]
+    async fn rooms_can_be_given_in_the_query() {
+        let config = ServerConfig { require_explicit_room: true, ..ServerConfig::default() };
+        let addr = spawn_server(config);
 
-    pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(broadcast_incoming, receive_from_others).await;
+        let snapshot_of = |path: &str| snapshot(format!("ws://{}{}", addr, path));
+        assert_eq!(snapshot_of("/main?name=Alice").await["room"], "main");
+        assert_eq!(snapshot_of("/?name=Bob&room=main").await["room"], "main");
+        assert_eq!(snapshot_of("/main?name=Carol&room=other").await["room"], "main");
 
-    println!("{} left room '{}'", connection_addr, room_id);
+        match connect_async(format!("ws://{}/?name=Dave&room=a%2Fb", addr)).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 400);
+                let body = String::from_utf8(response.into_body().unwrap()).unwrap();
+                assert!(body.starts_with("Invalid room"), "{}", body);
+            }
+            other => panic!("unexpected handshake result: {:?}", other.map(|_| ())),
+        }
+    }
 
-    // ---- Remove participant ----
+    #[tokio::test]
+    async fn only_the_first_joiner_creates_the_room() {
+        let addr = spawn_server(ServerConfig::default());
+
+        let first = snapshot(format!("ws://{}/main?name=Alice", addr)).await;
+        assert_eq!(first["type"], "room_snapshot");
+        assert_eq!(first["created"], true);
+
+        let second = snapshot(format!("ws://{}/main?name=Bob", addr)).await;
+        assert_eq!(second["type"], "room_snapshot");
+        assert_eq!(second["created"], false);
+    }
+
+    /// Read messages until one of type `kind` arrives
+    async fn next_of_type<S>(ws_stream: &mut S, kind: &str) -> serde_json::Value
+    where
+        S: futures_util::Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
     {
-        let mut room_map = rooms.lock().unwrap();
-        if let Some(peers) = room_map.get_mut(&room_id) {
-            peers.remove(&connection_addr);
+        loop {
+            let msg = ws_stream.next().await.unwrap().unwrap();
+            let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            if msg["type"] == kind {
+                return msg;
+            }
         }
     }
 
-    broadcast_count(&rooms, &room_id);
-    broadcast_participants(&rooms, &room_id);
-}
+    #[tokio::test]
+    async fn moderators_can_pause_the_room() {
+        let addr = spawn_server(ServerConfig::default());
+        let url = |name| format!("ws://{}/meeting?name={}", addr, name);
+        let (mut host, _) = connect_async(url("Host")).await.unwrap();
+        assert_eq!(next_of_type(&mut host, "room_snapshot").await["moderator"], true);
+        let (mut guest, _) = connect_async(url("Guest")).await.unwrap();
+        assert_eq!(next_of_type(&mut guest, "room_snapshot").await["paused"], false);
 
-#[tokio::main]
-async fn main() -> Result<(), IoError> {
-    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_string());
-    let listener = TcpListener::bind(&addr).await.expect("Can't bind");
+        guest.send(Message::text(r#"{"type":"pause_room"}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut guest, "error").await["code"], "not_moderator");
 
-    // Init Room to Empty
-    let rooms: RoomMap = Arc::new(Mutex::new(HashMap::new()));
+        host.send(Message::text(r#"{"type":"pause_room"}"#)).await.unwrap();
+        next_of_type(&mut guest, "room_paused").await;
+        guest.send(Message::text(r#"{"type":"chat","text":"hello?"}"#)).await.unwrap();
+        next_of_type(&mut guest, "room_paused").await;
+        let (mut late, _) = connect_async(url("Late")).await.unwrap();
+        assert_eq!(next_of_type(&mut late, "room_snapshot").await["paused"], true);
 
-    println!("Listening on {}", addr);
+        host.send(Message::text(r#"{"type":"resume_room"}"#)).await.unwrap();
+        next_of_type(&mut guest, "room_resumed").await;
+        guest.send(Message::text(r#"{"type":"chat","text":"back"}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut host, "chat").await["text"], "back");
+    }
 
-    while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(handle_connection(rooms.clone(), stream, addr));
+    #[tokio::test]
+    async fn reconnects_resync_the_room_settings() {
+        let config = ServerConfig {
+            reconnect_grace: Some(Duration::from_secs(30)),
+            ..ServerConfig::default()
+        };
+        let addr = spawn_server(config);
+        let url = |name| format!("ws://{}/meeting?name={}&session_id=s-{}", addr, name, name);
+        let (mut host, _) = connect_async(url("Host")).await.unwrap();
+        assert_eq!(next_of_type(&mut host, "room_snapshot").await["resumed"], false);
+        let (mut guest, _) = connect_async(url("Guest")).await.unwrap();
+        next_of_type(&mut guest, "room_snapshot").await;
+        guest.close(None).await.unwrap();
+        next_of_type(&mut host, "leave").await;
+
+        // The room changed while the guest was away
+        host.send(Message::text(r#"{"type":"pause_room"}"#)).await.unwrap();
+        next_of_type(&mut host, "room_paused").await;
+        let (mut guest, _) = connect_async(url("Guest")).await.unwrap();
+        let snapshot = next_of_type(&mut guest, "room_snapshot").await;
+        assert_eq!(snapshot["resumed"], true);
+        assert_eq!(snapshot["paused"], true);
+        let participants = next_of_type(&mut guest, "participants").await;
+        assert_eq!(participants["participants"].as_array().unwrap().len(), 2);
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn moderators_can_pin_messages() {
+        let addr = spawn_server(ServerConfig { max_pinned_messages: 1, ..ServerConfig::default() });
+        let url = |name| format!("ws://{}/meeting?name={}", addr, name);
+        let (mut host, _) = connect_async(url("Host")).await.unwrap();
+        next_of_type(&mut host, "room_snapshot").await;
+        let (mut guest, _) = connect_async(url("Guest")).await.unwrap();
+        assert_eq!(next_of_type(&mut guest, "room_snapshot").await["pinned"], json!([]));
+
+        guest.send(Message::text(r#"{"type":"chat","text":"agenda"}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut host, "chat").await["message_id"], "0");
+        guest.send(Message::text(r#"{"type":"pin","message_id":"0"}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut guest, "error").await["code"], "not_moderator");
+
+        host.send(Message::text(r#"{"type":"pin","message_id":"0"}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut guest, "message_pinned").await["message_id"], "0");
+        host.send(Message::text(r#"{"type":"pin","message_id":"9"}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut host, "error").await["code"], "unknown_message");
+        guest.send(Message::text(r#"{"type":"chat","text":"minutes"}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut host, "chat").await["message_id"], "1");
+        host.send(Message::text(r#"{"type":"pin","message_id":"1"}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut host, "error").await["code"], "too_many_pins");
+
+        let (mut late, _) = connect_async(url("Late")).await.unwrap();
+        assert_eq!(next_of_type(&mut late, "room_snapshot").await["pinned"], json!(["0"]));
+        host.send(Message::text(r#"{"type":"unpin","message_id":"0"}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut late, "message_unpinned").await["message_id"], "0");
+    }
+
+    #[tokio::test]
+    async fn joins_and_leaves_are_announced() {
+        let config = ServerConfig { auto_dedupe_names: true, ..ServerConfig::default() };
+        let addr = spawn_server(config);
+        let url = format!("ws://{}/main?name=Alice", addr);
+        let (mut first, _) = connect_async(url.clone()).await.unwrap();
+        next_of_type(&mut first, "room_snapshot").await;
+        let (mut second, _) = connect_async(url).await.unwrap();
+        next_of_type(&mut second, "room_snapshot").await;
+
+        let join = next_of_type(&mut first, "join").await;
+        assert_eq!(join["name"], "Alice (2)");
+        assert!(join["addr"].as_str().unwrap().starts_with("127.0.0.1:"));
+        assert!(join["timestamp"].is_string());
+
+        second.close(None).await.unwrap();
+        let leave = next_of_type(&mut first, "leave").await;
+        assert_eq!(leave["name"], "Alice (2)");
+        assert!(leave["timestamp"].is_string());
+    }
+
+    #[tokio::test]
+    async fn rooms_are_dropped_with_their_last_participant() {
+        let (addr, rooms) = spawn_server_with_rooms(ServerConfig::default());
+        let url = |name| format!("ws://{}/fleeting?name={}", addr, name);
+        let (mut alice, _) = connect_async(url("Alice")).await.unwrap();
+        next_of_type(&mut alice, "room_snapshot").await;
+        let (mut bob, _) = connect_async(url("Bob")).await.unwrap();
+        next_of_type(&mut bob, "room_snapshot").await;
+        assert_eq!(rooms.room_ids(), vec!["fleeting".to_string()]);
+
+        alice.close(None).await.unwrap();
+        bob.close(None).await.unwrap();
+        let emptied = async {
+            while !rooms.room_ids().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), emptied).await.expect("rooms left behind");
+    }
+
+    #[tokio::test]
+    async fn participants_can_update_their_metadata() {
+        let config = ServerConfig { max_participant_meta: 32, ..Default::default() };
+        let addr = spawn_server(config);
+        let url = |name| format!("ws://{}/meta?name={}", addr, name);
+        let (mut alice, _) = connect_async(url("Alice")).await.unwrap();
+        let alice_id = next_of_type(&mut alice, "connection_info").await["your_id"].clone();
+        let (mut bob, _) = connect_async(url("Bob")).await.unwrap();
+        next_of_type(&mut bob, "room_snapshot").await;
+
+        alice
+            .send(Message::text(r#"{"type":"update_meta","meta":{"color":"teal"}}"#))
+            .await
+            .unwrap();
+        let updated = next_of_type(&mut bob, "participant_updated").await;
+        assert_eq!(updated["id"], alice_id);
+        assert_eq!(updated["meta"], json!({ "color": "teal" }));
+
+        let update = r#"{"type":"update_meta","meta":{"color":null,"pronouns":"she/her"}}"#;
+        alice.send(Message::text(update)).await.unwrap();
+        let updated = next_of_type(&mut bob, "participant_updated").await;
+        assert_eq!(updated["meta"], json!({ "pronouns": "she/her" }));
+
+        alice.send(Message::text(r#"{"type":"update_meta","meta":{"hat":"top"}}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut alice, "error").await["code"], "meta_not_allowed");
+        let oversized = json!({ "type": "update_meta", "meta": { "avatar": "x".repeat(32) } });
+        alice.send(Message::text(oversized.to_string())).await.unwrap();
+        assert_eq!(next_of_type(&mut alice, "error").await["code"], "meta_too_large");
+    }
+
+    #[tokio::test]
+    async fn participants_join_once_authenticated() {
+        let config = ServerConfig {
+            auth_tokens: std::iter::once("secret".to_string()).collect(),
+            auth_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let addr = spawn_server(config);
+        let url = |name| format!("ws://{}/vault?name={}", addr, name);
+
+        let (mut alice, _) = connect_async(url("Alice")).await.unwrap();
+        alice.send(Message::text(r#"{"type":"auth","token":"secret"}"#)).await.unwrap();
+        next_of_type(&mut alice, "connection_info").await;
+
+        let closed_with = |msg: Option<Result<Message, tungstenite::Error>>| match msg {
+            Some(Ok(Message::Close(Some(frame)))) => frame.reason.to_string(),
+            other => panic!("expected a close frame, got {:?}", other),
+        };
+        let (mut mallory, _) = connect_async(url("Mallory")).await.unwrap();
+        mallory.send(Message::text(r#"{"type":"auth","token":"guess"}"#)).await.unwrap();
+        assert_eq!(closed_with(mallory.next().await), "Authentication failed");
+        let (mut chatty, _) = connect_async(url("Chatty")).await.unwrap();
+        chatty.send(Message::text(r#"{"type":"chat","text":"hi"}"#)).await.unwrap();
+        assert_eq!(closed_with(chatty.next().await), "Authentication required");
+        let (mut idle, _) = connect_async(url("Idle")).await.unwrap();
+        assert_eq!(closed_with(idle.next().await), "Authentication timed out");
+    }
+
+    #[test]
+    fn ephemeral_messages_get_a_deadline() {
+        let max = Duration::from_secs(60);
+        let ephemeral = Message::text(r#"{"type":"chat","text":"psst","expires_in":30}"#);
+        let stamped = stamp_expiry(&ephemeral, max).unwrap().unwrap();
+        let stamped: serde_json::Value = serde_json::from_str(stamped.to_text().unwrap()).unwrap();
+        let expires_at =
+            chrono::DateTime::parse_from_rfc3339(stamped["expires_at"].as_str().unwrap());
+        let left = expires_at.unwrap().signed_duration_since(chrono::Utc::now());
+        assert!(left > chrono::Duration::seconds(25) && left <= chrono::Duration::seconds(30));
+
+        let forever = Message::text(r#"{"type":"chat","text":"psst","expires_in":3600}"#);
+        assert!(stamp_expiry(&forever, max).is_err());
+        assert_eq!(stamp_expiry(&Message::text(r#"{"type":"chat"}"#), max), Ok(None));
+        assert_eq!(stamp_expiry(&Message::text("plain"), max), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn rooms_reject_disallowed_message_types() {
+        let mut config = ServerConfig::default();
+        let audio_only = std::iter::once(MessageType::Audio).collect();
+        config.allowed_client_message_types.insert("conference".into(), audio_only);
+        let addr = spawn_server(config);
+
+        let url = format!("ws://{}/conference?name=Alice", addr);
+        let (mut ws_stream, _) = connect_async(url).await.unwrap();
+        ws_stream.send(Message::text("hello")).await.unwrap();
+        loop {
+            let msg = ws_stream.next().await.unwrap().unwrap();
+            let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            if msg["type"] == "error" {
+                assert_eq!(msg["code"], "message_type_not_allowed");
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn audio_only_rooms_can_be_paused() {
+        let mut config = ServerConfig::default();
+        let audio_only = std::iter::once(MessageType::Audio).collect();
+        config.allowed_client_message_types.insert("conference".into(), audio_only);
+        let addr = spawn_server(config);
+        let url = |name| format!("ws://{}/conference?name={}", addr, name);
+        let (mut host, _) = connect_async(url("Host")).await.unwrap();
+        next_of_type(&mut host, "room_snapshot").await;
+        let (mut guest, _) = connect_async(url("Guest")).await.unwrap();
+        next_of_type(&mut guest, "room_snapshot").await;
+
+        host.send(Message::text(r#"{"type":"pause_room"}"#)).await.unwrap();
+        next_of_type(&mut guest, "room_paused").await;
+        guest.send(Message::binary(vec![0; 4])).await.unwrap();
+        next_of_type(&mut guest, "room_paused").await;
+
+        host.send(Message::text(r#"{"type":"control","action":"resume_room"}"#)).await.unwrap();
+        next_of_type(&mut guest, "room_resumed").await;
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_only_reach_the_server() {
+        let addr = spawn_server(ServerConfig::default());
+        let url = |name| format!("ws://{}/main?name={}", addr, name);
+        let (mut alice, _) = connect_async(url("Alice")).await.unwrap();
+        next_of_type(&mut alice, "room_snapshot").await;
+        let (mut bob, _) = connect_async(url("Bob")).await.unwrap();
+        next_of_type(&mut bob, "room_snapshot").await;
+
+        alice.send(Message::text("{not json")).await.unwrap();
+        assert_eq!(next_of_type(&mut alice, "error").await["reason"], "invalid_message");
+        alice.send(Message::text(r#"{"type":"control","action":"dance"}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut alice, "error").await["reason"], "invalid_message");
+
+        // Bob only ever hears the valid chat after them
+        alice.send(Message::text(r#"{"type":"chat","text":"hi"}"#)).await.unwrap();
+        loop {
+            let msg = bob.next().await.unwrap().unwrap();
+            let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            assert_ne!(msg["type"], "error");
+            if msg["type"] == "chat" {
+                assert_eq!(msg["text"], "hi");
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn floods_are_cut_to_the_rate_limit() {
+        let limit = RateLimit { capacity: 3, refill_rate: 0.0 };
+        let addr = spawn_server(ServerConfig {
+            message_rate_limit: Some(limit),
+            ..ServerConfig::default()
+        });
+        let url = |name| format!("ws://{}/main?name={}", addr, name);
+        let (mut alice, _) = connect_async(url("Alice")).await.unwrap();
+        next_of_type(&mut alice, "room_snapshot").await;
+        let (mut bob, _) = connect_async(url("Bob")).await.unwrap();
+        next_of_type(&mut bob, "room_snapshot").await;
+        let (mut carol, _) = connect_async(url("Carol")).await.unwrap();
+        next_of_type(&mut carol, "room_snapshot").await;
+
+        for i in 0..5 {
+            let chat = json!({ "type": "chat", "text": format!("spam {}", i) });
+            alice.send(Message::text(chat.to_string())).await.unwrap();
+        }
+        // Only the flooder hears about the dropped messages
+        next_of_type(&mut alice, "rate_limited").await;
+        next_of_type(&mut alice, "rate_limited").await;
+        carol.send(Message::text(r#"{"type":"chat","text":"done"}"#)).await.unwrap();
+
+        let mut heard = Vec::new();
+        loop {
+            let msg = bob.next().await.unwrap().unwrap();
+            let msg: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+            assert_ne!(msg["type"], "rate_limited");
+            if msg["type"] == "chat" {
+                if msg["text"] == "done" {
+                    break;
+                }
+                heard.push(msg["text"].clone());
+            }
+        }
+        assert_eq!(heard, ["spam 0", "spam 1", "spam 2"]);
+    }
+
+    #[tokio::test]
+    async fn oversized_audio_is_not_forwarded() {
+        let config = ServerConfig { max_audio_chunk_bytes: 16, ..ServerConfig::default() };
+        let addr = spawn_server(config);
+        let url = |name| format!("ws://{}/main?name={}", addr, name);
+        let (mut alice, _) = connect_async(url("Alice")).await.unwrap();
+        next_of_type(&mut alice, "room_snapshot").await;
+        let (mut bob, _) = connect_async(url("Bob")).await.unwrap();
+        next_of_type(&mut bob, "room_snapshot").await;
+
+        alice.send(Message::binary(vec![0; 17])).await.unwrap();
+        assert_eq!(next_of_type(&mut alice, "error").await["code"], "message_too_large");
+        alice.send(Message::binary(vec![1; 16])).await.unwrap();
+
+        // Bob only gets the chunk within the limit
+        loop {
+            match bob.next().await.unwrap().unwrap() {
+                Message::Binary(audio) => {
+                    assert_eq!(audio, vec![1; 16]);
+                    break;
+                }
+                msg => assert!(msg.is_text()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn room_messages_can_be_cbor_encoded() {
+        let addr = spawn_server(ServerConfig::default());
+        let url = format!("ws://{}/main?name=Alice&encoding=cbor", addr);
+        let (mut ws_stream, _) = connect_async(url).await.unwrap();
+
+        let mut types = Vec::new();
+        for _ in 0..5 {
+            let msg = ws_stream.next().await.unwrap().unwrap();
+            let msg = Encoding::Cbor.decode(&msg).expect("a CBOR frame");
+            types.push(msg["type"].as_str().unwrap().to_string());
+        }
+        // The joiner's own list, then the broadcast of the room's new roster
+        let expected =
+            ["connection_info", "room_snapshot", "participants", "count", "participants"];
+        assert_eq!(types, expected);
+    }
+
+    #[test]
+    fn concurrent_role_changes_keep_one_owner() {
+        let rooms = RoomManager::new();
+        let _receivers = seed_room(&rooms, "main", &["Owner", "Mod 1", "Mod 2", "Guest"]);
+        let people: Vec<(SocketAddr, String)> = rooms.with_room("main", |peers| {
+            let mut people: Vec<_> = peers.iter_mut().collect();
+            people.sort_by_key(|(_, p)| p.joined_seq);
+            for ((_, p), role) in
+                people.iter_mut().zip([RoomRole::Owner, RoomRole::Moderator, RoomRole::Moderator])
+            {
+                p.role = role;
+            }
+            people.into_iter().map(|(addr, p)| (*addr, p.id.to_string())).collect()
+        });
+        let (owner, mod_1, mod_2, guest) = (&people[0], &people[1], &people[2], &people[3]);
+
+        // Moderators disagree about the guest while the owner hands over to one of them, who
+        // at the same time tries to demote the owner
+        let changes = vec![
+            (mod_1.0, guest.1.clone(), "moderator"),
+            (mod_2.0, guest.1.clone(), "participant"),
+            (owner.0, mod_1.1.clone(), "owner"),
+            (mod_1.0, owner.1.clone(), "participant"),
+            (mod_2.0, mod_2.1.clone(), "owner"),
+        ];
+        let barrier = Arc::new(std::sync::Barrier::new(changes.len()));
+        let threads: Vec<_> = changes
+            .into_iter()
+            .map(|(addr, target, role)| {
+                let (rooms, barrier) = (rooms.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    change_role(&rooms, "main", addr, &target, role);
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+
+        let room = rooms.room("main").unwrap();
+        let roles: HashMap<_, _> = room.iter().map(|(addr, p)| (*addr, p.role)).collect();
+        assert_eq!(roles.values().filter(|role| **role == RoomRole::Owner).count(), 1);
+        assert_eq!(roles[&mod_1.0], RoomRole::Owner);
+        // Demoting the former owner only worked if it came after the handover
+        assert_ne!(roles[&owner.0], RoomRole::Owner);
+        assert_eq!(roles[&mod_2.0], RoomRole::Moderator);
+    }
+
+    #[test]
+    fn owners_cannot_step_down_without_a_successor() {
+        let rooms = RoomManager::new();
+        let mut receivers = seed_room(&rooms, "main", &["Owner", "Guest"]);
+        let (owner, owner_id) = rooms.with_room("main", |room| {
+            let (addr, owner) = room.iter_mut().min_by_key(|(_, p)| p.joined_seq).unwrap();
+            owner.role = RoomRole::Owner;
+            (*addr, owner.id.to_string())
+        });
+
+        change_role(&rooms, "main", owner, &owner_id, "moderator");
+        let error = Encoding::Json.decode(&receivers[0].try_recv().unwrap()).unwrap();
+        assert_eq!(error["code"], "owner_required");
+        assert_eq!(rooms.room("main").unwrap().get(&owner).unwrap().role, RoomRole::Owner);
+        assert!(receivers[1].try_recv().is_none(), "nothing changed");
+    }
+
+    #[test]
+    fn global_messages_reach_every_room_once() {
+        let rooms = RoomManager::new();
+        let mut receivers = seed_room(&rooms, "main", &["Alice", "Bob"]);
+        receivers.extend(seed_room(&rooms, "side", &["Carol"]));
+
+        let announcement = json!({ "type": "announcement", "message": "Maintenance at noon" });
+        rooms.broadcast_all(&announcement);
+
+        for mut rx in receivers {
+            let msg = rx.try_recv().unwrap();
+            assert_eq!(Encoding::Json.decode(&msg), Some(announcement.clone()));
+            assert!(rx.try_recv().is_none(), "nothing else was sent");
+        }
+    }
+
+    #[tokio::test]
+    async fn joiners_who_opt_in_get_batches() {
+        let config =
+            ServerConfig { batch_window: Some(Duration::from_millis(50)), ..Default::default() };
+        let addr = spawn_server(config);
+
+        let unbatched = first_messages(format!("ws://{}/main?name=Carol", addr), 1).await;
+        assert_eq!(unbatched[0]["type"], "connection_info");
+
+        let batched = first_messages(format!("ws://{}/main?name=Alice&batch=true", addr), 1).await;
+        assert_eq!(batched[0]["type"], "batch");
+        assert_eq!(batched[0]["messages"][0]["type"], "connection_info");
+        assert_eq!(batched[0]["messages"][1]["type"], "room_snapshot");
+    }
+
+    #[tokio::test]
+    async fn aliases_lead_into_their_canonical_room() {
+        let mut config = ServerConfig::default();
+        config.room_aliases.insert("lobby".into(), "main".into());
+        config.room_aliases.insert("hall".into(), "main".into());
+        let (addr, rooms) = spawn_server_with_rooms(config);
+
+        let (mut alice, _) =
+            connect_async(format!("ws://{}/lobby?name=Alice", addr)).await.unwrap();
+        assert_eq!(next_of_type(&mut alice, "room_snapshot").await["room"], "lobby");
+        let (mut bob, _) = connect_async(format!("ws://{}/hall?name=Bob", addr)).await.unwrap();
+        assert_eq!(next_of_type(&mut bob, "room_snapshot").await["room"], "hall");
+        assert_eq!(next_of_type(&mut alice, "join").await["name"], "Bob");
+
+        bob.send(Message::text(r#"{"type":"chat","text":"hi"}"#)).await.unwrap();
+        assert_eq!(next_of_type(&mut alice, "chat").await["text"], "hi");
+        assert_eq!(rooms.participants("main").len(), 2);
+        assert!(rooms.room_ids().iter().all(|room| room == "main"));
+    }
+
+    #[tokio::test]
+    async fn joiners_first_learn_their_connection_info() {
+        let mut config = ServerConfig::default();
+        config.room_aliases.insert("lobby".into(), "main".into());
+        let addr = spawn_server(config);
+
+        let first = first_messages(format!("ws://{}/lobby?name=Alice", addr), 2).await;
+        let second = first_messages(format!("ws://{}/main?name=Bob", addr), 1).await;
+        assert_eq!(first[0]["type"], "connection_info");
+        assert_eq!(first[0]["room"], "lobby");
+        assert_eq!(second[0]["room"], "main");
+        assert_eq!(first[0]["server_version"], env!("CARGO_PKG_VERSION"));
+        assert!(first[0]["your_addr"].as_str().unwrap().starts_with("127.0.0.1:"));
+        assert_eq!(first[1]["type"], "room_snapshot");
+        assert_ne!(first[0]["your_id"], second[0]["your_id"]);
+    }
+
+    #[tokio::test]
+    async fn handshakes_tell_the_canonical_room() {
+        let mut config = ServerConfig::default();
+        config.room_aliases.insert("lobby".into(), "main".into());
+        let addr = spawn_server(config);
+
+        let (_ws_stream, response) =
+            connect_async(format!("ws://{}/lobby?name=Carol", addr)).await.unwrap();
+        assert_eq!(response.headers()["X-Room-Id"], "main");
+        let (_ws_stream, response) =
+            connect_async(format!("ws://{}/?name=Dave", addr)).await.unwrap();
+        assert_eq!(response.headers()["X-Room-Id"], "default");
+    }
 }
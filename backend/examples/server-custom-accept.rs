@@ -112,10 +112,7 @@ async fn handle_request(
         || !headers
             .get(CONNECTION)
             .and_then(|h| h.to_str().ok())
-            .map(|h| {
-                h.split(|c| c == ' ' || c == ',')
-                    .any(|p| p.eq_ignore_ascii_case(upgrade.to_str().unwrap()))
-            })
+            .map(|h| h.split([' ', ',']).any(|p| p.eq_ignore_ascii_case(upgrade.to_str().unwrap())))
             .unwrap_or(false)
         || !headers
             .get(UPGRADE)
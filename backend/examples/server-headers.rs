@@ -41,6 +41,7 @@ async fn server() {
 }
 
 async fn accept_connection(stream: TcpStream) {
+    #[allow(clippy::result_large_err)]
     let callback = |req: &Request, mut response: Response| {
         debug!("Received a new ws handshake");
         debug!("The request's path is: {}", req.uri().path());
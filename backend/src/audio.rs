@@ -0,0 +1,179 @@
+//! Numbered audio chunks, and putting them back in order.
+use std::{collections::BTreeMap, convert::TryInto, error::Error, fmt};
+
+/// The length of the header before the audio of an encoded [`AudioChunk`].
+const HEADER_LEN: usize = 9;
+/// The bit of the flags byte telling that a chunk is final.
+const FINAL: u8 = 0x01;
+
+/// A piece of a participant's audio, numbered so that chunks reordered on their way can be
+/// put back in order with a [`ReorderBuffer`].
+///
+/// Chunks travel as the payload of binary messages: `seq` as a big-endian `u64`, a flags
+/// byte whose lowest bit is `is_final`, then the audio. The other bits of the flags are
+/// reserved, and ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioChunk {
+    /// The position of the chunk in the audio of its sender, counting from 0.
+    pub seq: u64,
+    /// The audio.
+    pub data: Vec<u8>,
+    /// Whether the chunk ends an utterance, i.e. the audio up to it makes sense on its own.
+    pub is_final: bool,
+}
+
+impl AudioChunk {
+    /// Creates a chunk which does not end its utterance.
+    pub fn new(seq: u64, data: impl Into<Vec<u8>>) -> Self {
+        AudioChunk { seq, data: data.into(), is_final: false }
+    }
+
+    /// Marks the chunk as the last of its utterance.
+    pub fn final_chunk(mut self) -> Self {
+        self.is_final = true;
+        self
+    }
+
+    /// Encodes the chunk into the payload of a binary message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(HEADER_LEN + self.data.len());
+        payload.extend_from_slice(&self.seq.to_be_bytes());
+        payload.push(if self.is_final { FINAL } else { 0 });
+        payload.extend_from_slice(&self.data);
+        payload
+    }
+
+    /// Decodes the payload of a binary message, failing if it is too short to be a chunk.
+    pub fn decode(payload: &[u8]) -> Result<Self, InvalidAudioChunk> {
+        if payload.len() < HEADER_LEN {
+            return Err(InvalidAudioChunk { len: payload.len() });
+        }
+        let seq = u64::from_be_bytes(payload[..8].try_into().unwrap());
+        let is_final = payload[8] & FINAL != 0;
+        Ok(AudioChunk { seq, data: payload[HEADER_LEN..].to_vec(), is_final })
+    }
+}
+
+/// Error returned when decoding an [`AudioChunk`] from a payload shorter than its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidAudioChunk {
+    len: usize,
+}
+
+impl fmt::Display for InvalidAudioChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "An audio chunk takes at least {} bytes, got {}", HEADER_LEN, self.len)
+    }
+}
+
+impl Error for InvalidAudioChunk {}
+
+/// Puts the [`AudioChunk`]s of a participant back in `seq` order, holding those arriving
+/// early until the ones before them arrived.
+///
+/// Only chunks within a window of the next one expected are held, so a lost chunk stalls the
+/// stream by that many chunks at most: the chunks beyond it are refused, telling the sender
+/// which chunk is missing.
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    next: u64,
+    window: u64,
+    held: BTreeMap<u64, AudioChunk>,
+}
+
+impl ReorderBuffer {
+    /// Creates a buffer expecting chunk 0 first, which holds chunks up to `window - 1` ahead
+    /// of the next one expected. A window of 0 is taken as 1, i.e. no reordering.
+    pub fn new(window: u64) -> Self {
+        ReorderBuffer { next: 0, window: window.max(1), held: BTreeMap::new() }
+    }
+
+    /// Returns the `seq` of the next chunk in order.
+    pub fn expected(&self) -> u64 {
+        self.next
+    }
+
+    /// Returns how many chunks are held, waiting for the ones before them.
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Takes `chunk`, returning the chunks now in order, if any, in `seq` order.
+    ///
+    /// Chunks already returned are ignored, as they are duplicates. Fails for a chunk too
+    /// far ahead of the next one expected, which is dropped.
+    pub fn push(&mut self, chunk: AudioChunk) -> Result<Vec<AudioChunk>, OutOfWindow> {
+        if chunk.seq < self.next {
+            return Ok(Vec::new());
+        }
+        if chunk.seq - self.next >= self.window {
+            return Err(OutOfWindow { seq: chunk.seq, expected: self.next });
+        }
+        self.held.insert(chunk.seq, chunk);
+        let mut ready = Vec::new();
+        while let Some(chunk) = self.held.remove(&self.next) {
+            self.next += 1;
+            ready.push(chunk);
+        }
+        Ok(ready)
+    }
+}
+
+/// Error returned by [`ReorderBuffer::push`] for a chunk too far ahead to be held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfWindow {
+    /// The `seq` of the chunk dropped.
+    pub seq: u64,
+    /// The `seq` of the next chunk expected, which the sender should send again.
+    pub expected: u64,
+}
+
+impl fmt::Display for OutOfWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Audio chunk {} is too far ahead, expected {}", self.seq, self.expected)
+    }
+}
+
+impl Error for OutOfWindow {}
+
+#[cfg(test)]
+mod tests {
+    use super::{AudioChunk, OutOfWindow, ReorderBuffer};
+
+    fn seqs(chunks: Vec<AudioChunk>) -> Vec<u64> {
+        chunks.into_iter().map(|chunk| chunk.seq).collect()
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let chunk = AudioChunk::new(258, vec![7, 8, 9]).final_chunk();
+        let payload = chunk.encode();
+        assert_eq!(payload[..9], [0, 0, 0, 0, 0, 0, 1, 2, 1]);
+        assert_eq!(AudioChunk::decode(&payload), Ok(chunk));
+        assert_eq!(AudioChunk::decode(&[0; 9]), Ok(AudioChunk::new(0, Vec::new())));
+        assert!(AudioChunk::decode(&[0; 8]).is_err());
+    }
+
+    #[test]
+    fn reordered_chunks_come_out_in_order() {
+        let mut buffer = ReorderBuffer::new(4);
+        assert_eq!(buffer.push(AudioChunk::new(2, [2])).map(seqs), Ok(vec![]));
+        assert_eq!(buffer.push(AudioChunk::new(1, [1])).map(seqs), Ok(vec![]));
+        assert_eq!(buffer.held(), 2);
+        assert_eq!(buffer.push(AudioChunk::new(0, [0])).map(seqs), Ok(vec![0, 1, 2]));
+        assert_eq!(buffer.push(AudioChunk::new(3, [3])).map(seqs), Ok(vec![3]));
+        // A duplicate of a chunk returned already
+        assert_eq!(buffer.push(AudioChunk::new(1, [1])).map(seqs), Ok(vec![]));
+        assert_eq!((buffer.expected(), buffer.held()), (4, 0));
+    }
+
+    #[test]
+    fn chunks_beyond_the_window_are_refused() {
+        let mut buffer = ReorderBuffer::new(4);
+        assert_eq!(buffer.push(AudioChunk::new(3, [3])).map(seqs), Ok(vec![]));
+        assert_eq!(buffer.push(AudioChunk::new(4, [4])), Err(OutOfWindow { seq: 4, expected: 0 }));
+        assert_eq!(buffer.push(AudioChunk::new(0, [0])).map(seqs), Ok(vec![0]));
+        assert_eq!(buffer.push(AudioChunk::new(4, [4])).map(seqs), Ok(vec![]));
+        assert_eq!(buffer.held(), 2);
+    }
+}
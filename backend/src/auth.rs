@@ -0,0 +1,128 @@
+//! Deciding which handshakes may open a connection at all.
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+};
+
+use tungstenite::http::{HeaderMap, StatusCode};
+
+/// Decides whether a handshake may become a connection, from its headers and query, e.g. by
+/// checking an API key or the `Origin` header.
+///
+/// The servers ask the policy before any other check looks at the room or the name, so a
+/// rejected client learns nothing about the rooms.
+pub trait AuthPolicy: Send + Sync {
+    /// Admits the handshake, telling who made it if the policy knows, or rejects it.
+    fn authorize(
+        &self,
+        headers: &HeaderMap,
+        query: &HashMap<String, String>,
+    ) -> Result<AuthInfo, AuthRejection>;
+}
+
+/// The client of an admitted handshake.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthInfo {
+    /// Who the client is, e.g. the account an API key belongs to, for logs and billing.
+    /// `None` if the policy admits clients without telling them apart.
+    pub identity: Option<String>,
+}
+
+/// Why a handshake was rejected by an [`AuthPolicy`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRejection {
+    /// The status of the response, `401 Unauthorized` or `403 Forbidden`.
+    pub status: StatusCode,
+    /// The body of the response.
+    pub reason: String,
+}
+
+impl AuthRejection {
+    /// Rejects a client which did not present any credentials, with `401 Unauthorized`.
+    pub fn unauthorized(reason: impl Into<String>) -> Self {
+        AuthRejection { status: StatusCode::UNAUTHORIZED, reason: reason.into() }
+    }
+
+    /// Rejects a client whose credentials do not admit it, with `403 Forbidden`.
+    pub fn forbidden(reason: impl Into<String>) -> Self {
+        AuthRejection { status: StatusCode::FORBIDDEN, reason: reason.into() }
+    }
+}
+
+impl fmt::Display for AuthRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl Error for AuthRejection {}
+
+/// The default policy, which admits every handshake.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopAuthPolicy;
+
+impl AuthPolicy for NoopAuthPolicy {
+    fn authorize(
+        &self,
+        _headers: &HeaderMap,
+        _query: &HashMap<String, String>,
+    ) -> Result<AuthInfo, AuthRejection> {
+        Ok(AuthInfo::default())
+    }
+}
+
+/// Admits handshakes whose `token` query parameter is one of a set of tokens.
+///
+/// Handshakes without a token are rejected with `401 Token required`, those with any other
+/// token with `403 Invalid token`.
+#[derive(Debug, Clone, Default)]
+pub struct TokenAuthPolicy {
+    tokens: HashSet<String>,
+}
+
+impl TokenAuthPolicy {
+    /// Creates a policy admitting the holders of `tokens`.
+    pub fn new<T: Into<String>>(tokens: impl IntoIterator<Item = T>) -> Self {
+        TokenAuthPolicy { tokens: tokens.into_iter().map(Into::into).collect() }
+    }
+}
+
+impl AuthPolicy for TokenAuthPolicy {
+    fn authorize(
+        &self,
+        _headers: &HeaderMap,
+        query: &HashMap<String, String>,
+    ) -> Result<AuthInfo, AuthRejection> {
+        match query.get("token") {
+            None => Err(AuthRejection::unauthorized("Token required")),
+            Some(token) if self.tokens.contains(token) => Ok(AuthInfo::default()),
+            Some(_) => Err(AuthRejection::forbidden("Invalid token")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tungstenite::http::{HeaderMap, StatusCode};
+
+    use super::{AuthPolicy, TokenAuthPolicy};
+
+    fn authorize(policy: &TokenAuthPolicy, query: &[(&str, &str)]) -> Result<(), StatusCode> {
+        let query: HashMap<_, _> =
+            query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        policy.authorize(&HeaderMap::new(), &query).map(|_| ()).map_err(|e| e.status)
+    }
+
+    #[test]
+    fn tokens_are_checked() {
+        let policy = TokenAuthPolicy::new(["s3cret", "other"]);
+
+        assert_eq!(authorize(&policy, &[("token", "s3cret"), ("name", "Alice")]), Ok(()));
+        assert_eq!(authorize(&policy, &[("token", "guess")]), Err(StatusCode::FORBIDDEN));
+        assert_eq!(authorize(&policy, &[("token", "")]), Err(StatusCode::FORBIDDEN));
+        assert_eq!(authorize(&policy, &[("name", "Alice")]), Err(StatusCode::UNAUTHORIZED));
+    }
+}
@@ -0,0 +1,90 @@
+//! Coalescing bursts of room updates.
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Limits how often an update is sent per key (e.g. per room), by merging the updates
+/// requested within a window into one.
+///
+/// The first request for a key starts a window; requests for that key arriving while the
+/// window is open are dropped, and the flush of the first request runs once it closes. The
+/// flush should send the state as of when it runs rather than as of when it was requested,
+/// so the changes of the whole burst are covered, the last one included.
+#[derive(Debug, Clone)]
+pub struct Coalescer {
+    window: Duration,
+    pending: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Coalescer {
+    /// Creates a coalescer with the given window. A zero window disables coalescing.
+    pub fn new(window: Duration) -> Self {
+        Coalescer { window, pending: Arc::default() }
+    }
+
+    /// Requests an update for `key`, to be sent by `flush`.
+    ///
+    /// Without a window, `flush` runs right away. Otherwise it runs on a spawned task at the
+    /// end of the window, unless an update for `key` is already pending, in which case it is
+    /// dropped.
+    pub fn schedule<F>(&self, key: &str, flush: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.window.is_zero() {
+            flush();
+            return;
+        }
+        if !self.pending.lock().unwrap().insert(key.to_string()) {
+            return;
+        }
+
+        let pending = self.pending.clone();
+        let key = key.to_string();
+        let window = self.window;
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            // Updates requested from now on need a flush of their own
+            pending.lock().unwrap().remove(&key);
+            flush();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::Coalescer;
+
+    #[tokio::test(start_paused = true)]
+    async fn bursts_are_flushed_once() {
+        let coalescer = Coalescer::new(Duration::from_millis(100));
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let schedule = |key| {
+            let flushes = flushes.clone();
+            coalescer.schedule(key, move || {
+                flushes.fetch_add(1, Ordering::SeqCst);
+            });
+        };
+
+        for _ in 0..10 {
+            schedule("main");
+        }
+        schedule("other");
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(flushes.load(Ordering::SeqCst), 2);
+
+        schedule("main");
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(flushes.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,892 @@
+//! Server configuration.
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use log::*;
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tungstenite::{
+    http::{header::FORWARDED, HeaderMap, StatusCode},
+    protocol::WebSocketConfig,
+    Message,
+};
+
+use crate::{
+    queue, AuthPolicy, ClientMessage, EventSink, HttpWebhookSink, JoinError, LogFormat,
+    NoopAuthPolicy, NoopSink, OutboundPipeline, OverflowPolicy, QueueReceiver, QueueSender,
+    RateLimit, ServerError, StreamAcceptor, TlsConfig, TokenAuthPolicy, WebhookConfig,
+};
+
+/// The response to requests which are not WebSocket handshakes, e.g. from a browser or a
+/// monitor hitting the root URL.
+#[derive(Debug, Clone)]
+pub struct LandingPage {
+    /// The status of the response. The default value is `200 OK`.
+    pub status: StatusCode,
+    /// The `Content-Type` of the response. The default value is `text/plain; charset=utf-8`.
+    pub content_type: String,
+    /// The body of the response. The default value is a short note pointing out that this is
+    /// not a web page.
+    pub body: String,
+}
+
+impl Default for LandingPage {
+    fn default() -> Self {
+        LandingPage {
+            status: StatusCode::OK,
+            content_type: "text/plain; charset=utf-8".into(),
+            body: "Hi, you are in the wrong place.".into(),
+        }
+    }
+}
+
+/// How the server treats the messages of a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoomMode {
+    /// Messages are relayed to the other participants and may be spoken, split into lines, etc.
+    #[default]
+    Standard,
+    /// The room is end-to-end encrypted, so payloads are opaque to the server.
+    ///
+    /// Messages are never split, spoken, transcribed, translated or logged. They are relayed
+    /// verbatim, wrapped in an envelope naming the sender: text frames become
+    /// `{"type":"encrypted","from":..,"payload":..}` and binary frames get a
+    /// [`binary_envelope`](crate::binary_envelope) with the same header minus the payload.
+    /// The only messages the server reads are `{"type":"key_exchange","to":..}`, which are
+    /// relayed to the named participant only (or to everyone without `to`), with `from` set.
+    ///
+    /// Since the server cannot read the messages, this mode is mutually exclusive with the
+    /// speech and language features: participants asking for them cannot join such a room.
+    Encrypted,
+}
+
+/// The kinds of messages participants send to each other, which rooms may restrict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageType {
+    /// Chat, i.e. `chat` messages and text frames which are no request to the server.
+    Chat,
+    /// Audio, i.e. binary frames and `audio_chunk` messages.
+    Audio,
+}
+
+impl MessageType {
+    /// Returns the type of `msg`, whose text parsed into `parsed` if it is a client message,
+    /// or `None` for requests to the server and control frames (pings, pongs and closes),
+    /// which are always allowed.
+    pub fn of(msg: &Message, parsed: Option<&ClientMessage>) -> Option<Self> {
+        match (msg, parsed) {
+            (_, Some(ClientMessage::Chat { .. })) => Some(MessageType::Chat),
+            (_, Some(ClientMessage::AudioChunk { .. })) => Some(MessageType::Audio),
+            (_, Some(_)) => None,
+            (Message::Text(_), None) => Some(MessageType::Chat),
+            (Message::Binary(_), None) => Some(MessageType::Audio),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the type, i.e. `chat` or `audio`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageType::Chat => "chat",
+            MessageType::Audio => "audio",
+        }
+    }
+
+    /// Returns the `message_type_not_allowed` error telling a participant that its message of
+    /// this type was dropped, as the room does not allow it.
+    pub fn not_allowed_error(self) -> Value {
+        json!({
+            "type": "error",
+            "code": "message_type_not_allowed",
+            "message": format!("Messages of type '{}' are not allowed in this room", self.as_str())
+        })
+    }
+}
+
+/// Configuration shared by the room servers.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Size of the listen backlog, i.e. how many accepted-but-not-yet-handled connections
+    /// the kernel queues for us. The default value is 1024.
+    pub backlog: u32,
+    /// Whether `SO_REUSEADDR` is set on the listening socket, so the server can be restarted
+    /// while connections of the previous process are still in `TIME_WAIT`. Enabled by default.
+    pub reuse_address: bool,
+    /// Whether `TCP_NODELAY` is set on accepted streams, i.e. whether Nagle's algorithm is
+    /// disabled. Chat messages and audio chunks are small frames which otherwise get delayed,
+    /// so this is enabled by default.
+    pub nodelay: bool,
+    /// The certificate to terminate TLS with, so clients connect with `wss://` URLs, see
+    /// [`stream_acceptor`](Self::stream_acceptor). Serving TLS takes the `rustls-tls-server`
+    /// feature. The default value is `None`, i.e. plaintext `ws://`.
+    pub tls: Option<TlsConfig>,
+    /// How long to wait before accepting again after a transient accept error, e.g. running
+    /// out of file descriptors. The wait doubles with each consecutive error, up to a second.
+    /// The default value is 100 milliseconds.
+    pub accept_backoff: Duration,
+    /// Alternative names for rooms, mapping an alias (e.g. `lobby`) to the id of the room it
+    /// stands for (e.g. `main`). Participants joining through an alias end up in the same room
+    /// as those joining with the canonical id. Empty by default.
+    pub room_aliases: HashMap<String, String>,
+    /// How long to keep flushing the messages still queued for a participant who stopped
+    /// sending, before closing its connection. The default value is 5 seconds.
+    pub drain_timeout: Duration,
+    /// How long a write to a participant's socket may take before the participant is
+    /// disconnected as a slow consumer, see [`relay`](crate::relay). The default value is
+    /// `None`, i.e. writes may block for as long as the client stalls.
+    pub write_timeout: Option<Duration>,
+    /// How many messages may wait in each queue (the chat and the room's own messages) of a
+    /// participant for its socket, see [`queue`](crate::queue). The default value is 1024.
+    pub outbound_queue_capacity: usize,
+    /// What happens to messages for a participant whose queue is full. The default value is
+    /// [`OverflowPolicy::DropOldest`].
+    pub overflow_policy: OverflowPolicy,
+    /// How long a connection may go without the server sending anything before it is sent a
+    /// keepalive message, see [`keepalive`](crate::keepalive). This keeps reverse proxies from
+    /// closing connections which are merely quiet. The default value is `None`, i.e. no
+    /// keepalives are sent.
+    pub keepalive_interval: Option<Duration>,
+    /// How often each connection is sent a WebSocket ping, see
+    /// [`heartbeat`](crate::heartbeat). Unlike keepalives, pings must be answered, so
+    /// participants whose network went away without closing the connection are found out and
+    /// removed from their room. The default value is 30 seconds.
+    pub ping_interval: Option<Duration>,
+    /// How long a ping may go unanswered before the connection is closed, see
+    /// [`ping_interval`](Self::ping_interval). The default value is 10 seconds.
+    pub pong_timeout: Duration,
+    /// How long a connection may stay open before the participant is asked to reconnect, see
+    /// [`max_lifetime`](crate::max_lifetime), e.g. to make it present fresh credentials. The
+    /// default value is `None`, i.e. connections may stay open for good.
+    pub max_connection_lifetime: Option<Duration>,
+    /// How long a participant may go without sending anything before it is disconnected,
+    /// see [`idle_timeout`](crate::idle_timeout). Only what the participant sends counts, not
+    /// the messages of the room it receives, nor the pongs its client answers pings with. The
+    /// default value is `None`, i.e. idle participants stay.
+    pub idle_timeout: Option<Duration>,
+    /// How long the messages queued for a participant are collected into a single batch frame,
+    /// see [`batch`](crate::batch). Only participants who opt in with `batch=true` get batches.
+    /// The default value is `None`, i.e. every message is sent as a frame of its own.
+    pub batch_window: Option<Duration>,
+    /// Rooms in line mode, keyed by canonical room id. Text frames sent to these rooms are
+    /// split with [`split_lines`](crate::split_lines) and every line is relayed as a message
+    /// of its own, for clients batching newline-delimited messages into one frame. Empty by
+    /// default, i.e. every frame is relayed as is.
+    pub line_split_rooms: HashSet<String>,
+    /// Modes of the rooms not in [`RoomMode::Standard`], keyed by canonical room id. Empty by
+    /// default.
+    pub room_modes: HashMap<String, RoomMode>,
+    /// The types of messages participants may send to a room, keyed by canonical room id,
+    /// e.g. only [`Audio`](MessageType::Audio) in a conference room. Messages of other types
+    /// are rejected, while requests to the server, e.g. controls or `update_meta`, are always
+    /// allowed. Rooms not listed allow every type, the map is empty by default.
+    pub allowed_client_message_types: HashMap<String, HashSet<MessageType>>,
+    /// Whether a handshake must name its room. If enabled, handshakes with an empty path are
+    /// rejected with `400 Room required`, instead of joining everyone who forgot the room to
+    /// the shared `default` room. Disabled by default.
+    pub require_explicit_room: bool,
+    /// How long to gather the roster changes of a room (joins and leaves) before broadcasting
+    /// the resulting participant list and count, see [`Coalescer`](crate::Coalescer). This
+    /// avoids flooding clients during e.g. a reconnect storm. The default value is zero, i.e.
+    /// every change is broadcast right away.
+    pub roster_coalesce_window: Duration,
+    /// The response to requests which are not WebSocket handshakes.
+    pub landing_page: LandingPage,
+    /// How fast each participant may send messages, enforced with a
+    /// [`TokenBucket`](crate::TokenBucket). Messages over the limit are dropped and the sender
+    /// is told so. The default value is `None`, i.e. no limit.
+    pub message_rate_limit: Option<RateLimit>,
+    /// Display names nobody may join with, compared case-insensitively. Handshakes using them
+    /// are rejected with `403 Name banned`. Empty by default.
+    pub banned_names: HashSet<String>,
+    /// Networks whose connections are closed right after being accepted, e.g. `10.1.2.3/32`
+    /// for a single address. Handshakes a trusted proxy forwards for them are rejected with
+    /// `403 Address banned`. Empty by default.
+    pub banned_networks: Vec<IpNet>,
+    /// Whether the client address reported by a proxy in `Forwarded` or `X-Forwarded-For` is
+    /// used for bans and logs, see [`client_ip`](Self::client_ip). Only [trusted
+    /// proxies](Self::trusted_proxies) are believed. Disabled by default.
+    pub trust_forwarded_for: bool,
+    /// The networks of the reverse proxies or load balancers in front of the server. Empty by
+    /// default.
+    pub trusted_proxies: Vec<IpNet>,
+    /// The tokens admitting participants. If any are set, a participant must send
+    /// `{"type":"auth","token":"<token>"}` as its first message, within the
+    /// [`auth_timeout`](Self::auth_timeout), before it joins its room. Until then nobody else
+    /// learns about it. Any other first message, or an unknown token, closes the connection.
+    /// Empty by default, i.e. participants join right after the handshake.
+    pub auth_tokens: HashSet<String>,
+    /// The tokens admitting handshakes, passed as their `token` query parameter, see
+    /// [`TokenAuthPolicy`](crate::TokenAuthPolicy). Unlike [`auth_tokens`](Self::auth_tokens),
+    /// these are checked before the connection is upgraded. Empty by default, i.e. every
+    /// handshake is admitted.
+    pub handshake_tokens: HashSet<String>,
+    /// How long a participant may take to authenticate with one of the
+    /// [`auth_tokens`](Self::auth_tokens). The default value is 5 seconds.
+    pub auth_timeout: Duration,
+    /// The token authorizing requests to the diagnostics endpoints, which are only served if
+    /// it is set. Requests must carry it as `Authorization: Bearer <token>`. The default value
+    /// is `None`, i.e. diagnostics are disabled.
+    pub debug_token: Option<String>,
+    /// Whether the diagnostics include the list of active connections, with their address,
+    /// id, room, name, join time and traffic. Requires [`debug_token`](Self::debug_token).
+    /// Disabled by default.
+    pub debug_connections: bool,
+    /// How the servers write their logs, the format a [`Logger`](crate::Logger) is created
+    /// with. The default value is [`LogFormat::Human`].
+    pub log_format: LogFormat,
+    /// The transforms rendering each message fanned out to a participant for that recipient.
+    /// The default pipeline holds the built-in transforms; append to it to add custom ones.
+    pub outbound: OutboundPipeline,
+    /// Room names nobody may join, because they are (or may become) HTTP routes of the
+    /// server. Handshakes to these rooms are rejected with `400 Reserved room name`. The
+    /// default list is `debug`, `health`, `metrics` and `rooms`.
+    pub reserved_rooms: HashSet<String>,
+    /// The longest room id a handshake may ask for, see
+    /// [`parse_room_id`](crate::parse_room_id). The default value is 64.
+    pub max_room_id_length: usize,
+    /// The maximum number of headers of a handshake request. The default value is 64.
+    pub max_header_count: usize,
+    /// The maximum size of the headers of a handshake request in bytes, i.e. of their names
+    /// and values. The default value is 16 KiB.
+    ///
+    /// Handshakes exceeding either limit are rejected with `431 Request Header Fields Too
+    /// Large`, before their headers are looked at.
+    pub max_header_size: usize,
+    /// The largest message a participant may send, in bytes. The WebSocket refuses larger
+    /// messages and frames before buffering them, see [`websocket_config`](Self::websocket_config),
+    /// which ends the connection. The default value is 1 MiB.
+    pub max_message_bytes: usize,
+    /// The largest audio chunk, i.e. binary message, relayed to a room, in bytes. Larger
+    /// chunks are not relayed (nor transcribed), as each peer would get a copy, and the sender
+    /// gets a `message_too_large` error instead. The default value is 256 KiB.
+    pub max_audio_chunk_bytes: usize,
+    /// The directory the final transcripts of the rooms are saved to, one file per room, see
+    /// [`FileTranscriptSink`](crate::FileTranscriptSink). The default value is `None`, i.e.
+    /// transcripts are not kept.
+    pub transcript_dir: Option<PathBuf>,
+    /// The webhook the room events are posted to, see [`HttpWebhookSink`](crate::HttpWebhookSink).
+    /// The default value is `None`, i.e. events are not reported anywhere.
+    pub webhook: Option<WebhookConfig>,
+    /// Whether joining with a name already in use in the room succeeds under a suffixed name
+    /// (e.g. `Alice (2)`, see [`unique_name`](crate::unique_name)) instead of being rejected
+    /// with `409`. The joiner learns its name from the `room_snapshot`. Disabled by default.
+    pub auto_dedupe_names: bool,
+    /// Whether the payloads of the text messages participants send are logged, to debug
+    /// protocol issues, in the `payload` field of a `TRACE` event next to the `INFO` one of
+    /// every message. Otherwise only their kind and size are. Messages of
+    /// [encrypted](RoomMode::Encrypted) rooms are never logged. Disabled by default, as chat
+    /// is private.
+    pub log_message_payloads: bool,
+    /// How many bytes of a payload are logged at most when
+    /// [`log_message_payloads`](Self::log_message_payloads) is enabled. The default value is
+    /// 1024.
+    pub max_logged_payload: usize,
+    /// How many names the participant list broadcast to a room holds at most. Larger rooms
+    /// get the first names only, along with the `total` number of participants, so the
+    /// message stays small. The default value is 500.
+    pub max_listed_participants: usize,
+    /// The longest lifetime participants may give an ephemeral message with `expires_in`
+    /// (in seconds). Messages asking for longer are rejected. The default value is 24
+    /// hours.
+    pub max_expires_in: Duration,
+    /// The metadata fields participants may set on themselves with `update_meta`. Updates
+    /// holding any other field are rejected. The default fields are `avatar`, `color` and
+    /// `pronouns`.
+    pub participant_meta_fields: HashSet<String>,
+    /// How large the metadata of a participant may grow, in bytes of JSON. Updates making it
+    /// larger are rejected. The default value is 1024.
+    pub max_participant_meta: usize,
+    /// How many messages a room may have pinned at once. Pinning more is rejected until one
+    /// is unpinned. The default value is 10.
+    pub max_pinned_messages: usize,
+    /// How many audio frames of a participant may wait for their transcription. Frames
+    /// arriving while the queue is full are not transcribed, and the sender gets
+    /// `audio_dropped`. The default value is 8.
+    pub transcription_queue: usize,
+    /// How far ahead of the next one in order the [`AudioChunk`](crate::AudioChunk)s of
+    /// participants sending numbered audio may arrive, see
+    /// [`ReorderBuffer`](crate::ReorderBuffer). Chunks further ahead are dropped, and the
+    /// sender gets `audio_nack` naming the chunk missing. The default value is 32.
+    pub audio_reorder_window: u64,
+    /// How many transcriptions may run at once, across all rooms. Frames arriving while as
+    /// many are running are not transcribed, and the sender gets `transcription_skipped`.
+    /// The default value is 16.
+    pub max_concurrent_transcriptions: usize,
+    /// How many participants a room may hold. Handshakes to a full room are rejected with
+    /// `503 Room full`. The default value is `None`, i.e. rooms may grow without limit.
+    pub max_participants: Option<usize>,
+    /// How many participants of a room may be members, i.e. joined without the `spectator`
+    /// query parameter, see [`RoomManager::max_members`](crate::RoomManager::max_members).
+    /// Handshakes of members to a room holding as many are rejected with `503 Room full`.
+    /// The default value is `None`, i.e. only `max_participants` applies.
+    pub max_members: Option<usize>,
+    /// How many participants of a room may be spectators, i.e. joined with
+    /// `spectator=true`, see [`RoomManager::max_spectators`](crate::RoomManager::max_spectators).
+    /// Handshakes of spectators to a room holding as many are rejected with `503 Room full`.
+    /// The default value is `None`, i.e. only `max_participants` applies.
+    pub max_spectators: Option<usize>,
+    /// How long clients turned away for capacity, e.g. by
+    /// [`max_participants`](Self::max_participants), are told to wait before trying again,
+    /// in the `Retry-After` header of the rejection. The default value is 30 seconds.
+    pub capacity_retry_after: Duration,
+    /// How long the name of a participant that joined with a `session_id` query parameter
+    /// stays reserved once its connection is gone, so the client can reconnect with the same
+    /// `session_id` and get the name back, see
+    /// [`RoomManager::reconnect_grace`](crate::RoomManager::reconnect_grace). The default
+    /// value is `None`, i.e. names are freed right away.
+    pub reconnect_grace: Option<Duration>,
+    /// How many names may be reserved at once for the participants of one IP address,
+    /// across rooms. Past it, the oldest reservation is released early. The default value
+    /// is 4.
+    pub max_reservations_per_ip: usize,
+    /// How many names may be reserved at once in one room. Past it, the oldest reservation
+    /// is released early. The default value is 64.
+    pub max_reservations_per_room: usize,
+    /// How many of the latest messages relayed in a room are kept in memory, see
+    /// [`RoomManager::max_buffered_messages`](crate::RoomManager::max_buffered_messages).
+    /// Past it, the oldest message is dropped, so the memory a room takes stays bounded
+    /// however busy it is. The default value is 100.
+    pub max_buffered_messages: usize,
+    /// How long a shutdown waits, first for the work in flight (e.g. transcriptions) to
+    /// deliver its results, then for the connections to close, before giving up on either.
+    /// The default value is 10 seconds.
+    pub shutdown_grace: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            backlog: 1024,
+            reuse_address: true,
+            nodelay: true,
+            room_aliases: HashMap::new(),
+            accept_backoff: Duration::from_millis(100),
+            drain_timeout: Duration::from_secs(5),
+            write_timeout: None,
+            outbound_queue_capacity: 1024,
+            overflow_policy: OverflowPolicy::DropOldest,
+            keepalive_interval: None,
+            ping_interval: Some(Duration::from_secs(30)),
+            pong_timeout: Duration::from_secs(10),
+            max_connection_lifetime: None,
+            idle_timeout: None,
+            batch_window: None,
+            line_split_rooms: HashSet::new(),
+            room_modes: HashMap::new(),
+            allowed_client_message_types: HashMap::new(),
+            require_explicit_room: false,
+            roster_coalesce_window: Duration::ZERO,
+            landing_page: LandingPage::default(),
+            message_rate_limit: None,
+            banned_names: HashSet::new(),
+            banned_networks: Vec::new(),
+            trust_forwarded_for: false,
+            trusted_proxies: Vec::new(),
+            auth_tokens: HashSet::new(),
+            handshake_tokens: HashSet::new(),
+            auth_timeout: Duration::from_secs(5),
+            debug_token: None,
+            debug_connections: false,
+            log_format: LogFormat::Human,
+            outbound: OutboundPipeline::default(),
+            reserved_rooms: ["debug", "health", "metrics", "rooms"]
+                .iter()
+                .map(|room| room.to_string())
+                .collect(),
+            max_room_id_length: 64,
+            max_header_count: 64,
+            max_header_size: 16 * 1024,
+            max_message_bytes: 1024 * 1024,
+            max_audio_chunk_bytes: 256 * 1024,
+            transcript_dir: None,
+            webhook: None,
+            tls: None,
+            auto_dedupe_names: false,
+            log_message_payloads: false,
+            max_logged_payload: 1024,
+            max_listed_participants: 500,
+            max_expires_in: Duration::from_secs(24 * 60 * 60),
+            participant_meta_fields: ["avatar", "color", "pronouns"]
+                .iter()
+                .map(|field| field.to_string())
+                .collect(),
+            max_participant_meta: 1024,
+            max_pinned_messages: 10,
+            transcription_queue: 8,
+            audio_reorder_window: 32,
+            max_concurrent_transcriptions: 16,
+            max_participants: None,
+            max_members: None,
+            max_spectators: None,
+            capacity_retry_after: Duration::from_secs(30),
+            reconnect_grace: None,
+            max_reservations_per_ip: 4,
+            max_reservations_per_room: 64,
+            max_buffered_messages: 100,
+            shutdown_grace: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Creates a listener bound to `addr` with the configured socket options.
+    pub fn bind(&self, addr: SocketAddr) -> Result<TcpListener, ServerError> {
+        let listen = || {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            socket.set_reuseaddr(self.reuse_address)?;
+            socket.bind(addr)?;
+            socket.listen(self.backlog)
+        };
+        listen().map_err(|source| ServerError::Bind { addr, source })
+    }
+
+    /// Creates the acceptor turning accepted connections into streams, loading the
+    /// [`tls`](Self::tls) certificate if one is configured.
+    pub fn stream_acceptor(&self) -> Result<StreamAcceptor, ServerError> {
+        StreamAcceptor::new(self.tls.as_ref())
+    }
+
+    /// Creates the sink the events of the rooms go to, posting them to the
+    /// [`webhook`](Self::webhook) if one is configured. Must be called from a Tokio runtime.
+    pub fn event_sink(&self) -> Result<Arc<dyn EventSink>, ServerError> {
+        match self.webhook.clone() {
+            Some(webhook) => {
+                let sink =
+                    HttpWebhookSink::new(webhook).map_err(|e| ServerError::config("webhook", e))?;
+                Ok(Arc::new(sink))
+            }
+            None => Ok(Arc::new(NoopSink)),
+        }
+    }
+
+    /// Resolves a requested room to the canonical room id used to key the room map.
+    ///
+    /// Rooms without an alias are their own canonical id, so aliased rooms share the
+    /// participants of their canonical room.
+    pub fn resolve_room<'a>(&'a self, room: &'a str) -> &'a str {
+        self.room_aliases.get(room).map(String::as_str).unwrap_or(room)
+    }
+
+    /// Creates a queue of the messages sent to a participant, holding at most
+    /// [`outbound_queue_capacity`](Self::outbound_queue_capacity) of them.
+    pub fn outbound_queue(&self) -> (QueueSender, QueueReceiver) {
+        queue(self.outbound_queue_capacity, self.overflow_policy)
+    }
+
+    /// Returns the configuration of the WebSocket of a participant, which enforces
+    /// [`max_message_bytes`](Self::max_message_bytes).
+    pub fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig::default()
+            .max_message_size(Some(self.max_message_bytes))
+            .max_frame_size(Some(self.max_message_bytes))
+    }
+
+    /// Returns whether the headers of a handshake request stay within the configured limits.
+    pub fn headers_within_limits(&self, headers: &HeaderMap) -> bool {
+        let size: usize =
+            headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+        headers.len() <= self.max_header_count && size <= self.max_header_size
+    }
+
+    /// Returns whether the requested room `room` collides with a reserved route.
+    pub fn is_reserved_room(&self, room: &str) -> bool {
+        let route = room.split('/').next().unwrap_or(room);
+        self.reserved_rooms.contains(route)
+    }
+
+    /// Returns the mode of the canonical room `room`.
+    pub fn room_mode(&self, room: &str) -> RoomMode {
+        self.room_modes.get(room).copied().unwrap_or_default()
+    }
+
+    /// Returns whether participants may send `msg`, which parsed into `parsed`, to the
+    /// canonical room `room`, see [`MessageType::of`].
+    pub fn allows_message(
+        &self,
+        room: &str,
+        msg: &Message,
+        parsed: Option<&ClientMessage>,
+    ) -> bool {
+        match (MessageType::of(msg, parsed), self.allowed_client_message_types.get(room)) {
+            (Some(kind), Some(allowed)) => allowed.contains(&kind),
+            _ => true,
+        }
+    }
+
+    /// Returns the `message_too_large` error to tell the sender of `msg` if it is an audio
+    /// chunk larger than [`max_audio_chunk_bytes`](Self::max_audio_chunk_bytes).
+    ///
+    /// Every peer would get a copy of an oversized chunk, so it is not relayed and only its
+    /// sender hears of it.
+    pub fn oversized_audio(&self, msg: &Message) -> Option<Value> {
+        match msg {
+            Message::Binary(audio) if audio.len() > self.max_audio_chunk_bytes => Some(json!({
+                "type": "error",
+                "code": "message_too_large",
+                "message": format!(
+                    "Audio chunks are at most {} bytes, this one of {} was dropped",
+                    self.max_audio_chunk_bytes,
+                    audio.len()
+                )
+            })),
+            _ => None,
+        }
+    }
+
+    /// Returns the `room_full` error to tell a client which could not join a full room.
+    ///
+    /// Past the handshake, the [`capacity_retry_after`](Self::capacity_retry_after) wait can
+    /// only be told in a message, as `retry_after_ms`.
+    pub fn room_full_error(&self) -> Value {
+        json!({
+            "type": "error",
+            "reason": "room_full",
+            "message": JoinError::RoomFull.to_string(),
+            "retry_after_ms": self.capacity_retry_after.as_millis() as u64,
+        })
+    }
+
+    /// Returns the policy handshakes are checked against, which admits everyone unless
+    /// [`handshake_tokens`](Self::handshake_tokens) are set.
+    pub fn auth_policy(&self) -> Arc<dyn AuthPolicy> {
+        if self.handshake_tokens.is_empty() {
+            Arc::new(NoopAuthPolicy)
+        } else {
+            Arc::new(TokenAuthPolicy::new(self.handshake_tokens.iter().cloned()))
+        }
+    }
+
+    /// Returns whether text frames sent to the canonical room `room` are split into lines.
+    ///
+    /// Frames of [encrypted](RoomMode::Encrypted) rooms are never split.
+    pub fn splits_lines(&self, room: &str) -> bool {
+        self.line_split_rooms.contains(room) && self.room_mode(room) != RoomMode::Encrypted
+    }
+
+    /// Returns whether the display name `name` is banned.
+    pub fn is_banned_name(&self, name: &str) -> bool {
+        self.banned_names.iter().any(|banned| banned.to_lowercase() == name.to_lowercase())
+    }
+
+    /// Returns whether connections from `ip` are banned.
+    pub fn is_banned_ip(&self, ip: IpAddr) -> bool {
+        self.banned_networks.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Returns the address of the client behind a handshake received from `peer`.
+    ///
+    /// If [`trust_forwarded_for`](Self::trust_forwarded_for) is set, the chain of addresses
+    /// reported in the `Forwarded` header (or else `X-Forwarded-For`) is walked back from
+    /// `peer` as long as the hops are trusted proxies, so clients cannot spoof their address
+    /// by sending the header themselves. Otherwise, or without the header, this is `peer`.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trust_forwarded_for {
+            return peer;
+        }
+        let elements = |name: &str| {
+            let values = headers.get_all(name).into_iter();
+            values.flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        };
+        let chain: Vec<Option<IpAddr>> = if headers.contains_key(FORWARDED) {
+            elements(FORWARDED.as_str()).map(parse_forwarded_for).collect()
+        } else {
+            elements(X_FORWARDED_FOR).map(parse_node).collect()
+        };
+
+        let mut client = peer;
+        for hop in chain.into_iter().rev() {
+            let trusted = self.trusted_proxies.iter().any(|network| network.contains(&client));
+            match hop {
+                Some(hop) if trusted => client = hop,
+                _ => break,
+            }
+        }
+        client
+    }
+
+    /// Describes a message sent by a participant of the canonical room `room` for the logs,
+    /// returning its kind, e.g. `text`, and its payload if that is logged.
+    ///
+    /// The payload is cut to [`max_logged_payload`](Self::max_logged_payload) bytes, ending in
+    /// `...` if it was.
+    pub fn describe_message(&self, room: &str, msg: &Message) -> (&'static str, Option<String>) {
+        let kind = match msg {
+            Message::Text(_) => "text",
+            Message::Binary(_) => "binary",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Close(_) => "close",
+            Message::Frame(_) => "frame",
+        };
+
+        let redacted = self.room_mode(room) == RoomMode::Encrypted;
+        let payload = match (msg, self.log_message_payloads && !redacted) {
+            (Message::Text(text), true) => {
+                let mut end = text.len().min(self.max_logged_payload);
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                let ellipsis = if end < text.len() { "..." } else { "" };
+                Some(format!("{}{}", &text[..end], ellipsis))
+            }
+            _ => None,
+        };
+        (kind, payload)
+    }
+
+    /// Accepts the next connection on `listener`.
+    ///
+    /// Transient errors, which mostly mean the process ran out of some resource for a while,
+    /// are logged and the accept is retried after the [`accept_backoff`](Self::accept_backoff).
+    /// Only errors which mean the listener itself is unusable are returned.
+    pub async fn accept(&self, listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+        let mut backoff = self.accept_backoff;
+        loop {
+            match listener.accept().await {
+                Ok(accepted) => return Ok(accepted),
+                Err(e) if is_fatal_accept_error(&e) => return Err(e),
+                Err(e) => {
+                    warn!("Failed to accept a connection, retrying in {:?}: {}", backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF.max(self.accept_backoff));
+                }
+            }
+        }
+    }
+
+    /// Applies the configured options to a freshly accepted stream.
+    pub fn configure_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)
+    }
+}
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Returns whether an accept error means the listener is unusable, rather than e.g. that the
+/// peer gave up early or the process is out of file descriptors for now.
+fn is_fatal_accept_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::InvalidInput | io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+    )
+}
+
+/// Parses the `for` node of an element of a `Forwarded` header, e.g. `for=192.0.2.60;proto=https`.
+fn parse_forwarded_for(element: &str) -> Option<IpAddr> {
+    let mut pairs = element.split(';').filter_map(|pair| pair.split_once('='));
+    let (_, node) = pairs.find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))?;
+    parse_node(node)
+}
+
+/// Parses a node of a forwarded chain, i.e. an address with an optional port, IPv6 ones in
+/// brackets if quoted. Obfuscated and `unknown` nodes give `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+    use tungstenite::{
+        http::{HeaderMap, HeaderValue},
+        Message,
+    };
+
+    use std::{io, net::IpAddr};
+
+    use super::{is_fatal_accept_error, ClientMessage, MessageType, RoomMode, ServerConfig};
+    use crate::ServerError;
+
+    #[tokio::test]
+    async fn accepted_streams_are_configured() {
+        let config = ServerConfig::default();
+        let listener = config.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        config.configure_stream(&stream).unwrap();
+
+        assert!(stream.nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn binding_a_port_in_use_fails() {
+        let config = ServerConfig::default();
+        let listener = config.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        match config.bind(addr) {
+            Err(ServerError::Bind { addr: failed, source }) => {
+                assert_eq!(failed, addr);
+                assert_eq!(source.kind(), io::ErrorKind::AddrInUse);
+            }
+            other => panic!("expected a bind error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn only_unusable_listeners_stop_accepting() {
+        assert!(is_fatal_accept_error(&io::ErrorKind::InvalidInput.into()));
+        assert!(!is_fatal_accept_error(&io::ErrorKind::ConnectionAborted.into()));
+        assert!(!is_fatal_accept_error(&io::ErrorKind::Interrupted.into()));
+        #[cfg(unix)]
+        {
+            // EMFILE, i.e. too many open files
+            assert!(!is_fatal_accept_error(&io::Error::from_raw_os_error(24)));
+        }
+    }
+
+    #[test]
+    fn aliases_resolve_to_the_same_room() {
+        let mut config = ServerConfig::default();
+        config.room_aliases.insert("lobby".into(), "main".into());
+        config.room_aliases.insert("hall".into(), "main".into());
+
+        assert_eq!(config.resolve_room("lobby"), "main");
+        assert_eq!(config.resolve_room("hall"), "main");
+        assert_eq!(config.resolve_room("main"), "main");
+        assert_eq!(config.resolve_room("other"), "other");
+    }
+
+    #[test]
+    fn oversized_headers_are_caught() {
+        let config =
+            ServerConfig { max_header_count: 2, max_header_size: 32, ..Default::default() };
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_static("localhost"));
+        assert!(config.headers_within_limits(&headers));
+
+        headers.insert("x-padding", HeaderValue::from_str(&"x".repeat(32)).unwrap());
+        assert!(!config.headers_within_limits(&headers));
+
+        headers.insert("x-padding", HeaderValue::from_static("x"));
+        headers.insert("x-more", HeaderValue::from_static("x"));
+        assert!(!config.headers_within_limits(&headers));
+    }
+
+    #[test]
+    fn rooms_may_restrict_message_types() {
+        let mut config = ServerConfig::default();
+        let audio_only = std::iter::once(MessageType::Audio).collect();
+        config.allowed_client_message_types.insert("conference".into(), audio_only);
+
+        assert!(!config.allows_message("conference", &Message::text("hi"), None));
+        assert!(config.allows_message("conference", &Message::binary(vec![0; 4]), None));
+        assert!(config.allows_message("conference", &Message::Ping(Default::default()), None));
+        assert!(config.allows_message("main", &Message::text("hi"), None));
+
+        let chat = r#"{"type":"chat","text":"hi"}"#;
+        let parsed = ClientMessage::parse(chat).unwrap();
+        assert!(!config.allows_message("conference", &Message::text(chat), Some(&parsed)));
+        let audio = r#"{"type":"audio_chunk","data":"AAE=","seq":0}"#;
+        let parsed = ClientMessage::parse(audio).unwrap();
+        assert!(config.allows_message("conference", &Message::text(audio), Some(&parsed)));
+        let pause = r#"{"type":"pause_room"}"#;
+        let parsed = ClientMessage::parse(pause).unwrap();
+        assert!(config.allows_message("conference", &Message::text(pause), Some(&parsed)));
+    }
+
+    #[test]
+    fn only_oversized_audio_is_turned_away() {
+        let config = ServerConfig { max_audio_chunk_bytes: 4, ..ServerConfig::default() };
+
+        assert_eq!(config.oversized_audio(&Message::binary(vec![0; 4])), None);
+        assert_eq!(config.oversized_audio(&Message::text("hello")), None);
+        let error = config.oversized_audio(&Message::binary(vec![0; 5])).unwrap();
+        assert_eq!(error["code"], "message_too_large");
+        assert_eq!(error["message"], "Audio chunks are at most 4 bytes, this one of 5 was dropped");
+    }
+
+    #[test]
+    fn payloads_are_only_logged_on_request() {
+        let msg = Message::text("héllo");
+        let mut config = ServerConfig::default();
+        assert_eq!(config.describe_message("main", &msg), ("text", None));
+
+        config.log_message_payloads = true;
+        config.max_logged_payload = 2;
+        config.room_modes.insert("secret".into(), RoomMode::Encrypted);
+        assert_eq!(config.describe_message("main", &msg), ("text", Some("h...".into())));
+        assert_eq!(config.describe_message("secret", &msg), ("text", None));
+        config.max_logged_payload = 6;
+        assert_eq!(config.describe_message("main", &msg), ("text", Some("héllo".into())));
+        let binary = Message::binary(vec![0; 3]);
+        assert_eq!(config.describe_message("main", &binary), ("binary", None));
+    }
+
+    #[test]
+    fn routes_are_reserved() {
+        let config = ServerConfig::default();
+        assert!(config.is_reserved_room("metrics"));
+        assert!(config.is_reserved_room("debug/self-test"));
+        assert!(!config.is_reserved_room("healthy"));
+        assert!(!config.is_reserved_room("main"));
+    }
+
+    #[test]
+    fn bans_match_names_and_networks() {
+        let mut config = ServerConfig::default();
+        config.banned_names.insert("Spammer".into());
+        config.banned_networks.push("10.1.0.0/16".parse().unwrap());
+        config.banned_networks.push("2001:db8::1/128".parse().unwrap());
+
+        assert!(config.is_banned_name("spammer"));
+        assert!(!config.is_banned_name("Alice"));
+        assert!(config.is_banned_ip("10.1.2.3".parse().unwrap()));
+        assert!(!config.is_banned_ip("10.2.0.1".parse().unwrap()));
+        assert!(config.is_banned_ip("2001:db8::1".parse().unwrap()));
+        assert!(!config.is_banned_ip("2001:db8::2".parse().unwrap()));
+    }
+
+    #[test]
+    fn forwarded_addresses_are_only_believed_from_trusted_proxies() {
+        let mut config = ServerConfig::default();
+        let proxy = "10.0.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("6.6.6.6, 203.0.113.7"));
+        assert_eq!(config.client_ip(proxy, &headers), proxy);
+
+        config.trust_forwarded_for = true;
+        config.trusted_proxies.push("10.0.0.0/8".parse().unwrap());
+        // The leftmost address was made up by the client, the proxy only vouches for its peer
+        assert_eq!(config.client_ip(proxy, &headers), "203.0.113.7".parse::<IpAddr>().unwrap());
+        let outsider = "198.51.100.1".parse().unwrap();
+        assert_eq!(config.client_ip(outsider, &headers), outsider);
+
+        headers.insert(
+            "forwarded",
+            HeaderValue::from_static(r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.2"#),
+        );
+        assert_eq!(config.client_ip(proxy, &headers), "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn encrypted_rooms_are_never_split() {
+        let mut config = ServerConfig::default();
+        config.line_split_rooms.insert("secret".into());
+        config.line_split_rooms.insert("main".into());
+        config.room_modes.insert("secret".into(), RoomMode::Encrypted);
+
+        assert_eq!(config.room_mode("secret"), RoomMode::Encrypted);
+        assert_eq!(config.room_mode("main"), RoomMode::Standard);
+        assert!(!config.splits_lines("secret"));
+        assert!(config.splits_lines("main"));
+    }
+}
@@ -0,0 +1,235 @@
+//! Serializations of the server's own messages, as negotiated by each participant.
+use std::{
+    convert::{TryFrom, TryInto},
+    error::Error,
+    fmt,
+    str::FromStr,
+};
+
+use serde_json::{Map, Number, Value};
+use tungstenite::Message;
+
+/// How the messages the server generates itself (e.g. the room snapshot and roster updates)
+/// are serialized for a participant.
+///
+/// Participants pick one with the `encoding` handshake parameter (`json` or `cbor`). Both
+/// carry the same structure, CBOR ([RFC 8949]) is just more compact for clients on constrained
+/// links. Chat relayed between participants is never re-encoded.
+///
+/// [RFC 8949]: https://www.rfc-editor.org/rfc/rfc8949
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// JSON in text frames.
+    #[default]
+    Json,
+    /// CBOR in binary frames.
+    Cbor,
+}
+
+impl Encoding {
+    /// Serializes `value` into a message.
+    pub fn encode(self, value: &Value) -> Message {
+        match self {
+            Encoding::Json => Message::text(value.to_string()),
+            Encoding::Cbor => {
+                let mut buf = Vec::new();
+                write_cbor(&mut buf, value);
+                Message::binary(buf)
+            }
+        }
+    }
+
+    /// Deserializes a message produced by [`encode`](Self::encode), returning `None` if it is
+    /// not a well-formed message of this encoding.
+    pub fn decode(self, msg: &Message) -> Option<Value> {
+        match (self, msg) {
+            (Encoding::Json, Message::Text(text)) => serde_json::from_str(text).ok(),
+            (Encoding::Cbor, Message::Binary(data)) => {
+                let mut input = &data[..];
+                let value = read_cbor(&mut input, 0)?;
+                Some(value).filter(|_| input.is_empty())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = InvalidEncoding;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(Encoding::Json),
+            "cbor" => Ok(Encoding::Cbor),
+            _ => Err(InvalidEncoding(s.to_string())),
+        }
+    }
+}
+
+/// Error returned when a string names no supported [`Encoding`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidEncoding(String);
+
+impl fmt::Display for InvalidEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is not a supported encoding, expected 'json' or 'cbor'", self.0)
+    }
+}
+
+impl Error for InvalidEncoding {}
+
+/// How deeply nested the arrays and maps of a decoded message may be.
+const MAX_DEPTH: usize = 64;
+
+fn write_head(buf: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    if n < 24 {
+        buf.push(major | n as u8);
+    } else if n <= u8::MAX.into() {
+        buf.extend_from_slice(&[major | 24, n as u8]);
+    } else if n <= u16::MAX.into() {
+        buf.push(major | 25);
+        buf.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u32::MAX.into() {
+        buf.push(major | 26);
+        buf.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        buf.push(major | 27);
+        buf.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn write_cbor(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => buf.push(0xf6),
+        Value::Bool(false) => buf.push(0xf4),
+        Value::Bool(true) => buf.push(0xf5),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => write_head(buf, 0, n),
+            // Negative integers are encoded as -1 - n
+            (None, Some(n)) => write_head(buf, 1, !n as u64),
+            (None, None) => {
+                buf.push(0xfb);
+                buf.extend_from_slice(&n.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        },
+        Value::String(s) => {
+            write_head(buf, 3, s.len() as u64);
+            buf.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(items) => {
+            write_head(buf, 4, items.len() as u64);
+            items.iter().for_each(|item| write_cbor(buf, item));
+        }
+        Value::Object(entries) => {
+            write_head(buf, 5, entries.len() as u64);
+            for (key, value) in entries {
+                write_head(buf, 3, key.len() as u64);
+                buf.extend_from_slice(key.as_bytes());
+                write_cbor(buf, value);
+            }
+        }
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Some(taken)
+}
+
+/// Reads the major type and argument of the next item, only definite lengths are supported.
+fn read_head(input: &mut &[u8]) -> Option<(u8, u64)> {
+    let initial = *take(input, 1)?.first()?;
+    let n = match initial & 0x1f {
+        n @ 0..=23 => n.into(),
+        24 => take(input, 1)?[0].into(),
+        25 => u16::from_be_bytes(take(input, 2)?.try_into().ok()?).into(),
+        26 => u32::from_be_bytes(take(input, 4)?.try_into().ok()?).into(),
+        27 => u64::from_be_bytes(take(input, 8)?.try_into().ok()?),
+        _ => return None,
+    };
+    Some((initial >> 5, n))
+}
+
+fn read_string(input: &mut &[u8], len: u64) -> Option<String> {
+    let bytes = take(input, len.try_into().ok()?)?;
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+fn read_cbor(input: &mut &[u8], depth: usize) -> Option<Value> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let simple = input.first().map(|initial| initial & 0x1f);
+    let value = match read_head(input)? {
+        (0, n) => n.into(),
+        (1, n) => i64::try_from(n).ok().map(|n| -1 - n)?.into(),
+        (3, len) => read_string(input, len)?.into(),
+        (4, len) => {
+            let items = (0..len).map(|_| read_cbor(input, depth + 1)).collect::<Option<_>>()?;
+            Value::Array(items)
+        }
+        (5, len) => {
+            let mut entries = Map::new();
+            for _ in 0..len {
+                let key = match read_head(input)? {
+                    (3, len) => read_string(input, len)?,
+                    _ => return None,
+                };
+                entries.insert(key, read_cbor(input, depth + 1)?);
+            }
+            Value::Object(entries)
+        }
+        (7, n) => match (simple?, n) {
+            (20, _) => false.into(),
+            (21, _) => true.into(),
+            (22, _) => Value::Null,
+            (26, bits) => Number::from_f64(f32::from_bits(bits as u32).into())?.into(),
+            (27, bits) => Number::from_f64(f64::from_bits(bits))?.into(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Encoding;
+
+    #[test]
+    fn cbor_snapshots_decode_like_json_ones() {
+        let snapshot = json!({
+            "type": "room_snapshot",
+            "room": "main",
+            "name": "Ålice",
+            "created": true,
+            "participants": ["Ålice", "Bob"],
+            "count": 300,
+            "offset": -25,
+            "load": 0.5,
+            "topic": null,
+        });
+
+        let cbor = Encoding::Cbor.encode(&snapshot);
+        let json = Encoding::Json.encode(&snapshot);
+        assert!(cbor.is_binary() && json.is_text());
+        assert!(cbor.len() < json.len());
+        assert_eq!(Encoding::Cbor.decode(&cbor), Some(snapshot.clone()));
+        assert_eq!(Encoding::Json.decode(&json), Some(snapshot));
+    }
+
+    #[test]
+    fn malformed_cbor_is_rejected() {
+        let truncated = tungstenite::Message::binary(vec![0x82, 0x01]);
+        assert_eq!(Encoding::Cbor.decode(&truncated), None);
+        assert_eq!("CBOR".parse(), Ok(Encoding::Cbor));
+        assert!("xml".parse::<Encoding>().is_err());
+    }
+}
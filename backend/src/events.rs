@@ -0,0 +1,622 @@
+//! Reporting what happens in the rooms to external integrations.
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, io,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+    time::Instant,
+};
+use tracing::{debug, warn};
+use tungstenite::stream::Mode;
+use url::Url;
+
+use crate::{Connector, MaybeTlsStream};
+
+/// Something that happened in a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomEvent {
+    /// The first participant joined `room`, creating it.
+    RoomCreated {
+        /// The room id.
+        room: String,
+    },
+    /// The last participant left `room`, closing it.
+    RoomClosed {
+        /// The room id.
+        room: String,
+    },
+    /// `name` joined `room`.
+    Joined {
+        /// The room id.
+        room: String,
+        /// The participant's display name.
+        name: String,
+    },
+    /// `name` left `room`.
+    Left {
+        /// The room id.
+        room: String,
+        /// The participant's display name.
+        name: String,
+    },
+    /// `name` sent the chat message `text` to `room`.
+    Chat {
+        /// The room id.
+        room: String,
+        /// The participant's display name.
+        name: String,
+        /// The message.
+        text: String,
+    },
+}
+
+impl RoomEvent {
+    /// Returns the name of the kind of event, e.g. `join` or `room_created`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RoomEvent::RoomCreated { .. } => "room_created",
+            RoomEvent::RoomClosed { .. } => "room_closed",
+            RoomEvent::Joined { .. } => "join",
+            RoomEvent::Left { .. } => "leave",
+            RoomEvent::Chat { .. } => "chat",
+        }
+    }
+
+    /// Builds the JSON description of the event, e.g.
+    /// `{"event":"join","room":"main","name":"Alice","timestamp":..}`.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "event": self.kind(),
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+        });
+        match self {
+            RoomEvent::RoomCreated { room } | RoomEvent::RoomClosed { room } => {
+                json["room"] = room.as_str().into();
+            }
+            RoomEvent::Joined { room, name } | RoomEvent::Left { room, name } => {
+                json["room"] = room.as_str().into();
+                json["name"] = name.as_str().into();
+            }
+            RoomEvent::Chat { room, name, text } => {
+                json["room"] = room.as_str().into();
+                json["name"] = name.as_str().into();
+                json["text"] = text.as_str().into();
+            }
+        }
+        json
+    }
+}
+
+/// Receives the events of the rooms, e.g. to forward them to another service.
+pub trait EventSink: Send + Sync {
+    /// Handles an event.
+    ///
+    /// This is called from the join, leave and broadcast paths, so it must not block;
+    /// implementations should queue the event and deliver it in the background.
+    fn send(&self, event: RoomEvent);
+}
+
+/// The default sink, which drops every event.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopSink;
+
+impl EventSink for NoopSink {
+    fn send(&self, _event: RoomEvent) {}
+}
+
+/// Configuration of an [`HttpWebhookSink`].
+#[derive(Clone)]
+pub struct WebhookConfig {
+    /// The `http://` or `https://` URL the events are posted to. `https://` takes one of the
+    /// TLS features.
+    pub url: Url,
+    /// The connector `https://` webhooks are reached through, e.g. to trust a private
+    /// certificate authority. The default value is `None`, i.e. the default connector of the
+    /// enabled TLS feature.
+    pub connector: Option<Connector>,
+    /// The [kinds](RoomEvent::kind) of events to post. The default value is `None`, i.e. all
+    /// of them.
+    pub events: Option<HashSet<String>>,
+    /// How many events may wait for delivery. Events arriving while the queue is full are
+    /// dropped. The default value is 256.
+    pub queue_size: usize,
+    /// How many times a failed delivery is retried before the event is dropped. The default
+    /// value is 3.
+    pub retries: u32,
+    /// How long a delivery may take. The default value is 5 seconds.
+    pub timeout: Duration,
+    /// How long to gather the joins and leaves of the rooms before posting them, as one
+    /// `{"event":"presence","room":..,"joined":[..],"left":[..]}` summary per room. This keeps
+    /// rooms with a lot of churn from flooding the webhook. Chat and the creation and closing
+    /// of rooms are always posted right away. The default value is `None`, i.e. joins and
+    /// leaves are posted one by one.
+    pub presence_batch_interval: Option<Duration>,
+}
+
+impl WebhookConfig {
+    /// Creates the configuration of a webhook posting all events to `url`.
+    pub fn new(url: Url) -> Self {
+        WebhookConfig {
+            url,
+            connector: None,
+            events: None,
+            queue_size: 256,
+            retries: 3,
+            timeout: Duration::from_secs(5),
+            presence_batch_interval: None,
+        }
+    }
+}
+
+impl fmt::Debug for WebhookConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebhookConfig")
+            .field("url", &self.url.as_str())
+            .field("connector", &self.connector.as_ref().map(|_| ".."))
+            .field("events", &self.events)
+            .field("queue_size", &self.queue_size)
+            .field("retries", &self.retries)
+            .field("timeout", &self.timeout)
+            .field("presence_batch_interval", &self.presence_batch_interval)
+            .finish()
+    }
+}
+
+/// An [`EventSink`] posting each event as JSON to a webhook.
+///
+/// Events are queued and posted one at a time by a background task, retrying with a growing
+/// delay, so a slow or failing webhook never holds up the rooms. The connection to the webhook
+/// is kept open between events, unless the webhook closes it.
+#[derive(Debug, Clone)]
+pub struct HttpWebhookSink {
+    events: Option<HashSet<String>>,
+    queue: mpsc::Sender<RoomEvent>,
+}
+
+impl HttpWebhookSink {
+    /// Spawns the task delivering events to the webhook described by `config`.
+    ///
+    /// Fails if the URL is neither an `http://` nor an `https://` URL, or if it is an
+    /// `https://` one without a TLS feature. Must be called from a Tokio runtime.
+    pub fn new(config: WebhookConfig) -> io::Result<Self> {
+        let tls = cfg!(any(feature = "native-tls", feature = "__rustls-tls"));
+        let supported = match config.url.scheme() {
+            "http" => true,
+            "https" => tls,
+            _ => false,
+        };
+        if !supported || config.url.host_str().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported webhook URL: {}", config.url),
+            ));
+        }
+
+        let (queue, mut events) = mpsc::channel::<RoomEvent>(config.queue_size);
+        let WebhookConfig { url, connector, retries, timeout, presence_batch_interval, .. } =
+            config;
+        let mut client = WebhookClient { url, connector, retries, timeout, connection: None };
+        tokio::spawn(async move {
+            let mut batches = BTreeMap::<String, PresenceBatch>::new();
+            let mut flush_at = None;
+            loop {
+                let next = match flush_at {
+                    Some(deadline) => tokio::time::timeout_at(deadline, events.recv()).await.ok(),
+                    None => Some(events.recv().await),
+                };
+                match (next, presence_batch_interval) {
+                    (Some(Some(RoomEvent::Joined { room, name })), Some(interval)) => {
+                        batches.entry(room).or_default().joined.push(name);
+                        flush_at.get_or_insert_with(|| Instant::now() + interval);
+                    }
+                    (Some(Some(RoomEvent::Left { room, name })), Some(interval)) => {
+                        batches.entry(room).or_default().left.push(name);
+                        flush_at.get_or_insert_with(|| Instant::now() + interval);
+                    }
+                    (Some(Some(event)), _) => client.deliver(event.kind(), event.to_json()).await,
+                    // The batch is due, or the sink is gone and this is the last batch
+                    (due_or_closed, _) => {
+                        for (room, batch) in std::mem::take(&mut batches) {
+                            client.deliver("presence", batch.to_json(&room)).await;
+                        }
+                        flush_at = None;
+                        if due_or_closed.is_some() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(HttpWebhookSink { events: config.events, queue })
+    }
+}
+
+impl EventSink for HttpWebhookSink {
+    fn send(&self, event: RoomEvent) {
+        if self.events.as_ref().map_or(true, |events| events.contains(event.kind())) {
+            if let Err(e) = self.queue.try_send(event) {
+                warn!(error = %e, "Dropped a webhook event");
+            }
+        }
+    }
+}
+
+/// The joins and leaves of a room gathered for a presence summary.
+#[derive(Debug, Default)]
+struct PresenceBatch {
+    joined: Vec<String>,
+    left: Vec<String>,
+}
+
+impl PresenceBatch {
+    fn to_json(&self, room: &str) -> serde_json::Value {
+        serde_json::json!({
+            "event": "presence",
+            "room": room,
+            "joined": self.joined,
+            "left": self.left,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+        })
+    }
+}
+
+/// How long the head of a webhook's response may be at most.
+const MAX_RESPONSE_HEAD: u64 = 16 * 1024;
+
+type Connection = BufReader<MaybeTlsStream<TcpStream>>;
+
+/// Posts to a webhook over HTTP/1.1, keeping the connection open while the webhook does.
+struct WebhookClient {
+    url: Url,
+    connector: Option<Connector>,
+    retries: u32,
+    timeout: Duration,
+    connection: Option<Connection>,
+}
+
+impl WebhookClient {
+    /// Posts `event`, retrying failed attempts with a growing delay.
+    async fn deliver(&mut self, kind: &'static str, event: serde_json::Value) {
+        let body = event.to_string();
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                tokio::time::sleep(Duration::from_millis(100 << attempt.min(6))).await;
+            }
+            match tokio::time::timeout(self.timeout, self.post(&body)).await {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => debug!(kind, error = %e, "Failed to post an event"),
+                Err(_) => debug!(kind, "Timed out posting an event"),
+            }
+        }
+        warn!(kind, attempts = self.retries + 1, "Dropped an event, the webhook kept failing");
+    }
+
+    /// Posts `body`, succeeding if the webhook answers with a `2xx` status.
+    async fn post(&mut self, body: &str) -> io::Result<()> {
+        // The webhook may have closed the connection kept from last time meanwhile, which
+        // only shows once it is used, so that gets another go on a new connection
+        let status = match self.connection.take() {
+            Some(connection) => match self.exchange(connection, body).await {
+                Ok(status) => status,
+                Err(_) => self.connect_and_exchange(body).await?,
+            },
+            None => self.connect_and_exchange(body).await?,
+        };
+        if (200..300).contains(&status) {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, format!("webhook answered {}", status)))
+        }
+    }
+
+    async fn connect_and_exchange(&mut self, body: &str) -> io::Result<u16> {
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port_or_known_default().unwrap_or(80);
+        let mode = if self.url.scheme() == "https" { Mode::Tls } else { Mode::Plain };
+        let stream = TcpStream::connect((host, port)).await?;
+        let stream =
+            crate::tls::wrap_stream(stream, host.to_string(), mode, self.connector.clone())
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        self.exchange(BufReader::new(stream), body).await
+    }
+
+    /// Sends the request posting `body` on `connection` and reads the whole response,
+    /// returning its status. The connection is kept if the webhook allows it.
+    async fn exchange(&mut self, mut connection: Connection, body: &str) -> io::Result<u16> {
+        let host = self.url.host_str().unwrap_or_default();
+        let authority = match self.url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let target = &self.url[url::Position::BeforePath..];
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            target,
+            authority,
+            body.len(),
+            body
+        );
+        connection.get_mut().write_all(request.as_bytes()).await?;
+        connection.get_mut().flush().await?;
+
+        // Interim responses, e.g. `100 Continue`, are followed by the actual one
+        let head = loop {
+            let head = read_head(&mut connection).await?;
+            if !(100..200).contains(&head.status) {
+                break head;
+            }
+        };
+        let framed = match (head.status, head.chunked, head.content_length) {
+            (204 | 304, ..) => true,
+            (_, true, _) => {
+                read_chunked(&mut connection).await?;
+                true
+            }
+            (_, false, Some(len)) => {
+                discard(&mut connection, len).await?;
+                true
+            }
+            // The body lasts until the webhook closes the connection
+            (_, false, None) => {
+                tokio::io::copy(&mut connection, &mut tokio::io::sink()).await?;
+                false
+            }
+        };
+        if framed && head.keep_alive {
+            self.connection = Some(connection);
+        }
+        Ok(head.status)
+    }
+}
+
+/// What a webhook's response says about itself.
+#[derive(Debug, PartialEq, Eq)]
+struct ResponseHead {
+    status: u16,
+    content_length: Option<u64>,
+    chunked: bool,
+    keep_alive: bool,
+}
+
+/// Reads the status line and headers of a response.
+async fn read_head<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<ResponseHead> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let mut reader = reader.take(MAX_RESPONSE_HEAD);
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "webhook closed the connection"));
+    }
+    let mut parts = line.trim_end().splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    let status = parts.next().and_then(|status| status.parse().ok());
+    let status = status
+        .filter(|_| version.starts_with("HTTP/1."))
+        .ok_or_else(|| invalid("invalid status line"))?;
+    let mut head = ResponseHead {
+        status,
+        content_length: None,
+        chunked: false,
+        keep_alive: version == "HTTP/1.1",
+    };
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(invalid("response head too large or cut short"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(head);
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("invalid header"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            head.content_length =
+                Some(value.parse().map_err(|_| invalid("invalid Content-Length"))?);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            head.chunked = value
+                .rsplit(',')
+                .next()
+                .map_or(false, |coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        } else if name.eq_ignore_ascii_case("connection") {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    head.keep_alive = false;
+                } else if option.eq_ignore_ascii_case("keep-alive") {
+                    head.keep_alive = true;
+                }
+            }
+        }
+    }
+}
+
+/// Reads and drops a chunked body, along with its trailers.
+async fn read_chunked<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<()> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid chunked body");
+    let mut line = String::new();
+    loop {
+        line.clear();
+        (&mut *reader).take(MAX_RESPONSE_HEAD).read_line(&mut line).await?;
+        let size = line.trim_end().split(';').next().unwrap_or_default();
+        let size = u64::from_str_radix(size.trim(), 16).map_err(|_| invalid())?;
+        if size == 0 {
+            break;
+        }
+        discard(reader, size + 2).await?;
+    }
+    // Trailers, up to the empty line ending the body
+    loop {
+        line.clear();
+        if (&mut *reader).take(MAX_RESPONSE_HEAD).read_line(&mut line).await? == 0 {
+            return Err(invalid());
+        }
+        if line.trim_end().is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads and drops `len` bytes.
+async fn discard<R: AsyncReadExt + Unpin>(reader: &mut R, len: u64) -> io::Result<()> {
+    let read = tokio::io::copy(&mut reader.take(len), &mut tokio::io::sink()).await?;
+    if read < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "response body cut short"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{read_head, EventSink, HttpWebhookSink, ResponseHead, RoomEvent, WebhookConfig};
+
+    /// Accepts a request and answers it with `status`, returning the request.
+    async fn respond(listener: &TcpListener, status: &str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![0; 4096];
+        let len = stream.read(&mut request).await.unwrap();
+        let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(request[..len].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn events_are_posted_and_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks/rooms", listener.local_addr().unwrap());
+        let mut config = WebhookConfig::new(url.parse().unwrap());
+        config.events = Some(std::iter::once("join".to_string()).collect());
+        let sink = HttpWebhookSink::new(config).unwrap();
+
+        sink.send(RoomEvent::Chat { room: "main".into(), name: "Bob".into(), text: "hi".into() });
+        sink.send(RoomEvent::Joined { room: "main".into(), name: "Alice".into() });
+
+        let failed = respond(&listener, "500 Internal Server Error").await;
+        let delivered = respond(&listener, "204 No Content").await;
+        assert!(failed.starts_with("POST /hooks/rooms HTTP/1.1\r\n"));
+        assert_eq!(failed, delivered);
+
+        let body = delivered.split("\r\n\r\n").nth(1).unwrap();
+        let event: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(event["event"], "join");
+        assert_eq!(event["name"], "Alice");
+    }
+
+    /// Reads a whole request off `stream`, returning its body.
+    async fn read_body(stream: &mut BufReader<TcpStream>) -> serde_json::Value {
+        let mut len = 0;
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            stream.read_line(&mut line).await.unwrap();
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                len = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connections_are_kept_open() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks/rooms", listener.local_addr().unwrap());
+        let sink = HttpWebhookSink::new(WebhookConfig::new(url.parse().unwrap())).unwrap();
+        sink.send(RoomEvent::RoomCreated { room: "main".into() });
+        sink.send(RoomEvent::Joined { room: "main".into(), name: "Alice".into() });
+
+        // Both events come over the same connection, whichever way the bodies are framed
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        assert_eq!(read_body(&mut stream).await["event"], "room_created");
+        let chunked = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       3\r\nabc\r\n2;note=1\r\nde\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        stream.get_mut().write_all(chunked.as_bytes()).await.unwrap();
+        assert_eq!(read_body(&mut stream).await["event"], "join");
+        let sized = "HTTP/1.1 202 Accepted\r\nContent-Length: 2\r\n\r\nok";
+        stream.get_mut().write_all(sized.as_bytes()).await.unwrap();
+
+        // Until the webhook closes it, which only costs a new connection
+        sink.send(RoomEvent::Left { room: "main".into(), name: "Alice".into() });
+        assert_eq!(read_body(&mut stream).await["event"], "leave");
+        let closing = "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n";
+        stream.get_mut().write_all(closing.as_bytes()).await.unwrap();
+        sink.send(RoomEvent::RoomClosed { room: "main".into() });
+        let (stream, _) = listener.accept().await.unwrap();
+        assert_eq!(read_body(&mut BufReader::new(stream)).await["event"], "room_closed");
+    }
+
+    #[tokio::test]
+    async fn response_heads_are_parsed() {
+        let head = |text: &'static str| async move { read_head(&mut text.as_bytes()).await };
+        let response = "HTTP/1.1 200 OK\r\ncontent-length: 12\r\nX-Other: a:b\r\n\r\n";
+        let expected = ResponseHead {
+            status: 200,
+            content_length: Some(12),
+            chunked: false,
+            keep_alive: true,
+        };
+        assert_eq!(head(response).await.unwrap(), expected);
+
+        let response = "HTTP/1.0 503 Service Unavailable\r\nConnection: keep-alive\r\n\
+                        Transfer-Encoding: gzip, chunked\r\n\r\n";
+        let expected =
+            ResponseHead { status: 503, content_length: None, chunked: true, keep_alive: true };
+        assert_eq!(head(response).await.unwrap(), expected);
+
+        assert!(head("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n").await.is_err());
+        assert!(head("SSH-2.0-OpenSSH\r\n\r\n").await.is_err());
+        assert!(head("").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn presence_is_posted_in_batches() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks/rooms", listener.local_addr().unwrap());
+        let mut config = WebhookConfig::new(url.parse().unwrap());
+        config.presence_batch_interval = Some(Duration::from_millis(50));
+        let sink = HttpWebhookSink::new(config).unwrap();
+
+        sink.send(RoomEvent::Joined { room: "main".into(), name: "Alice".into() });
+        sink.send(RoomEvent::Joined { room: "main".into(), name: "Bob".into() });
+        sink.send(RoomEvent::Chat { room: "main".into(), name: "Bob".into(), text: "hi".into() });
+        sink.send(RoomEvent::Left { room: "main".into(), name: "Alice".into() });
+
+        let body = |request: String| -> serde_json::Value {
+            serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap()
+        };
+        let chat = body(respond(&listener, "204 No Content").await);
+        let presence = body(respond(&listener, "204 No Content").await);
+        assert_eq!(chat["event"], "chat");
+        assert_eq!(presence["event"], "presence");
+        assert_eq!(presence["room"], "main");
+        assert_eq!(presence["joined"], serde_json::json!(["Alice", "Bob"]));
+        assert_eq!(presence["left"], serde_json::json!(["Alice"]));
+    }
+
+    #[test]
+    fn only_http_webhooks_are_supported() {
+        let config = WebhookConfig::new("ftp://example.com/hook".parse().unwrap());
+        assert!(HttpWebhookSink::new(config).is_err());
+        #[cfg(not(any(feature = "native-tls", feature = "__rustls-tls")))]
+        {
+            let config = WebhookConfig::new("https://example.com/hook".parse().unwrap());
+            assert!(HttpWebhookSink::new(config).is_err());
+        }
+    }
+}
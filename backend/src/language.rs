@@ -0,0 +1,250 @@
+//! Language tags negotiated by room participants.
+use std::{error::Error, fmt, str::FromStr};
+
+/// A validated and normalized BCP-47 language tag, e.g. `en`, `ja` or `zh-Hant-TW`.
+///
+/// Participants negotiate the languages they speak and want to read during the
+/// handshake. Parsing them into a `LanguageCode` there means the transcription and
+/// translation code never has to deal with arbitrary strings.
+///
+/// The primary language must be a known ISO 639 code. Common mistakes and the
+/// three-letter codes of languages with a two-letter one are normalized to the latter,
+/// e.g. `jp` and `jpn` to `ja`, as most backends only take those.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LanguageCode(String);
+
+impl LanguageCode {
+    /// Returns the normalized tag.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Parses the values of a list-valued parameter such as `translate_to` into a list of
+    /// distinct languages, in order of first appearance.
+    ///
+    /// Each value may hold several comma-separated tags, so the repeated-key form
+    /// (`translate_to=en&translate_to=ja`) and the comma-separated form (`translate_to=en,ja`)
+    /// give the same list. Empty entries are skipped.
+    pub fn parse_list<'a, I>(values: I) -> Result<Vec<LanguageCode>, InvalidLanguageCode>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut list = Vec::new();
+        for tag in values.into_iter().flat_map(|value| value.split(',')) {
+            if tag.trim().is_empty() {
+                continue;
+            }
+            let code = tag.parse()?;
+            if !list.contains(&code) {
+                list.push(code);
+            }
+        }
+        Ok(list)
+    }
+}
+
+impl FromStr for LanguageCode {
+    type Err = InvalidLanguageCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidLanguageCode(s.to_string());
+
+        let mut tag = String::with_capacity(s.len());
+        for (i, subtag) in s.trim().split(['-', '_']).enumerate() {
+            if subtag.is_empty()
+                || subtag.len() > 8
+                || !subtag.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return Err(invalid());
+            }
+
+            if i == 0 {
+                // The primary language subtag is the ISO 639 code.
+                let primary = subtag.to_ascii_lowercase();
+                let primary = match ALIASES.binary_search_by_key(&primary.as_str(), |(a, _)| a) {
+                    Ok(alias) => ALIASES[alias].1,
+                    Err(_) => primary.as_str(),
+                };
+                if ISO_639_1.binary_search(&primary).is_err()
+                    && THREE_LETTER_ONLY.binary_search(&primary).is_err()
+                {
+                    return Err(invalid());
+                }
+                tag.push_str(primary);
+                continue;
+            }
+
+            tag.push('-');
+            match subtag.len() {
+                // Script, e.g. `Hant`.
+                4 if subtag.chars().all(|c| c.is_ascii_alphabetic()) => {
+                    tag.push_str(&subtag[..1].to_ascii_uppercase());
+                    tag.push_str(&subtag[1..].to_ascii_lowercase());
+                }
+                // Region, e.g. `US` or `419`.
+                2 | 3 if subtag.len() == 2 || subtag.chars().all(|c| c.is_ascii_digit()) => {
+                    tag.push_str(&subtag.to_ascii_uppercase())
+                }
+                _ => tag.push_str(&subtag.to_ascii_lowercase()),
+            }
+        }
+
+        Ok(LanguageCode(tag))
+    }
+}
+
+/// The ISO 639-1 codes, sorted.
+const ISO_639_1: &[&str] = &[
+    "aa", "ab", "ae", "af", "ak", "am", "an", "ar", "as", "av", "ay", "az", "ba", "be", "bg", "bh",
+    "bi", "bm", "bn", "bo", "br", "bs", "ca", "ce", "ch", "co", "cr", "cs", "cu", "cv", "cy", "da",
+    "de", "dv", "dz", "ee", "el", "en", "eo", "es", "et", "eu", "fa", "ff", "fi", "fj", "fo", "fr",
+    "fy", "ga", "gd", "gl", "gn", "gu", "gv", "ha", "he", "hi", "ho", "hr", "ht", "hu", "hy", "hz",
+    "ia", "id", "ie", "ig", "ii", "ik", "io", "is", "it", "iu", "ja", "jv", "ka", "kg", "ki", "kj",
+    "kk", "kl", "km", "kn", "ko", "kr", "ks", "ku", "kv", "kw", "ky", "la", "lb", "lg", "li", "ln",
+    "lo", "lt", "lu", "lv", "mg", "mh", "mi", "mk", "ml", "mn", "mr", "ms", "mt", "my", "na", "nb",
+    "nd", "ne", "ng", "nl", "nn", "no", "nr", "nv", "ny", "oc", "oj", "om", "or", "os", "pa", "pi",
+    "pl", "ps", "pt", "qu", "rm", "rn", "ro", "ru", "rw", "sa", "sc", "sd", "se", "sg", "si", "sk",
+    "sl", "sm", "sn", "so", "sq", "sr", "ss", "st", "su", "sv", "sw", "ta", "te", "tg", "th", "ti",
+    "tk", "tl", "tn", "to", "tr", "ts", "tt", "tw", "ty", "ug", "uk", "ur", "uz", "ve", "vi", "vo",
+    "wa", "wo", "xh", "yi", "yo", "za", "zh", "zu",
+];
+
+/// The ISO 639-2/3 codes of languages speech and translation services commonly support
+/// which have no ISO 639-1 code, sorted.
+const THREE_LETTER_ONLY: &[&str] =
+    &["ast", "ceb", "ckb", "fil", "gsw", "haw", "hmn", "mni", "nso", "yue"];
+
+/// Codes participants use instead of the ISO 639-1 one, sorted: country codes, deprecated
+/// codes and the three-letter codes of common languages.
+const ALIASES: &[(&str, &str)] = &[
+    ("ara", "ar"),
+    ("ben", "bn"),
+    ("ces", "cs"),
+    ("chi", "zh"),
+    ("cmn", "zh"),
+    ("cn", "zh"),
+    ("cz", "cs"),
+    ("cze", "cs"),
+    ("dan", "da"),
+    ("deu", "de"),
+    ("dk", "da"),
+    ("dut", "nl"),
+    ("ell", "el"),
+    ("eng", "en"),
+    ("fas", "fa"),
+    ("fin", "fi"),
+    ("fra", "fr"),
+    ("fre", "fr"),
+    ("ger", "de"),
+    ("gr", "el"),
+    ("gre", "el"),
+    ("heb", "he"),
+    ("hin", "hi"),
+    ("hun", "hu"),
+    ("in", "id"),
+    ("ind", "id"),
+    ("ita", "it"),
+    ("iw", "he"),
+    ("ji", "yi"),
+    ("jp", "ja"),
+    ("jpn", "ja"),
+    ("jw", "jv"),
+    ("kor", "ko"),
+    ("mo", "ro"),
+    ("nld", "nl"),
+    ("nor", "no"),
+    ("per", "fa"),
+    ("pol", "pl"),
+    ("por", "pt"),
+    ("ron", "ro"),
+    ("rum", "ro"),
+    ("rus", "ru"),
+    ("spa", "es"),
+    ("swe", "sv"),
+    ("tha", "th"),
+    ("tur", "tr"),
+    ("ua", "uk"),
+    ("ukr", "uk"),
+    ("vie", "vi"),
+    ("vn", "vi"),
+    ("zho", "zh"),
+];
+
+impl fmt::Display for LanguageCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Error returned when a string is not a valid language tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidLanguageCode(String);
+
+impl fmt::Display for InvalidLanguageCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is not a valid language code", self.0)
+    }
+}
+
+impl Error for InvalidLanguageCode {}
+
+#[cfg(test)]
+mod tests {
+    use super::LanguageCode;
+
+    fn parse(s: &str) -> Option<String> {
+        s.parse::<LanguageCode>().ok().map(|code| code.to_string())
+    }
+
+    #[test]
+    fn normalizes_case_and_separators() {
+        assert_eq!(parse("EN").as_deref(), Some("en"));
+        assert_eq!(parse("en_us").as_deref(), Some("en-US"));
+        assert_eq!(parse("zh-hant-tw").as_deref(), Some("zh-Hant-TW"));
+        assert_eq!(parse("es-419").as_deref(), Some("es-419"));
+    }
+
+    #[test]
+    fn repeated_and_comma_separated_lists_match() {
+        let repeated = LanguageCode::parse_list(vec!["en", "ja", "EN"]).unwrap();
+        let separated = LanguageCode::parse_list(vec!["en, ja,", ""]).unwrap();
+        assert_eq!(repeated, separated);
+        assert_eq!(repeated, vec!["en".parse().unwrap(), "ja".parse().unwrap()]);
+        assert!(LanguageCode::parse_list(vec!["en,english"]).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_tags() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("e"), None);
+        assert_eq!(parse("english"), None);
+        assert_eq!(parse("en-"), None);
+        assert_eq!(parse("en--US"), None);
+        assert_eq!(parse("e1"), None);
+        assert_eq!(parse("en-US!"), None);
+    }
+
+    #[test]
+    fn normalizes_aliases() {
+        assert_eq!(parse("jp").as_deref(), Some("ja"));
+        assert_eq!(parse("JPN").as_deref(), Some("ja"));
+        assert_eq!(parse("iw").as_deref(), Some("he"));
+        assert_eq!(parse("cn-TW").as_deref(), Some("zh-TW"));
+        assert_eq!(parse("yue").as_deref(), Some("yue"));
+    }
+
+    #[test]
+    fn rejects_unknown_languages() {
+        assert_eq!(parse("xx"), None);
+        assert_eq!(parse("qq-US"), None);
+        assert_eq!(parse("abc"), None);
+        assert_eq!(parse("zz_ZZ"), None);
+    }
+
+    #[test]
+    fn tables_are_sorted() {
+        assert!(super::ISO_639_1.windows(2).all(|w| w[0] < w[1]));
+        assert!(super::THREE_LETTER_ONLY.windows(2).all(|w| w[0] < w[1]));
+        assert!(super::ALIASES.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+}
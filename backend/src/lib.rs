@@ -12,14 +12,55 @@
 
 pub use tungstenite;
 
+mod audio;
+#[cfg(feature = "server")]
+mod auth;
+#[cfg(feature = "server")]
+mod coalesce;
 mod compat;
+#[cfg(feature = "server")]
+mod config;
 #[cfg(feature = "connect")]
 mod connect;
+mod encoding;
+#[cfg(feature = "server")]
+mod events;
 mod handshake;
+mod language;
+#[cfg(feature = "server")]
+mod logging;
+#[cfg(feature = "server")]
+mod metrics;
+mod outbound;
+mod pipeline;
+mod presence;
+#[cfg(feature = "server")]
+mod protocol;
+#[cfg(feature = "server")]
+mod queue;
+#[cfg(feature = "server")]
+mod rate_limit;
+#[cfg(feature = "server")]
+mod rejection;
+#[cfg(feature = "server")]
+mod relay;
+#[cfg(feature = "server")]
+mod room;
+#[cfg(feature = "server")]
+mod room_id;
+#[cfg(feature = "server")]
+mod server_error;
+#[cfg(feature = "server")]
+mod server_tls;
+#[cfg(feature = "server")]
+mod shutdown;
 #[cfg(feature = "stream")]
 mod stream;
+mod text;
 #[cfg(any(feature = "native-tls", feature = "__rustls-tls", feature = "connect"))]
 mod tls;
+#[cfg(feature = "server")]
+mod transcript_sink;
 
 use std::io::{Read, Write};
 
@@ -63,6 +104,62 @@ pub use connect::connect_async_tls_with_config;
 #[cfg(feature = "stream")]
 pub use stream::MaybeTlsStream;
 
+pub use audio::{AudioChunk, InvalidAudioChunk, OutOfWindow, ReorderBuffer};
+#[cfg(feature = "server")]
+pub use auth::{AuthInfo, AuthPolicy, AuthRejection, NoopAuthPolicy, TokenAuthPolicy};
+#[cfg(feature = "server")]
+pub use coalesce::Coalescer;
+#[cfg(feature = "server")]
+pub use config::{LandingPage, MessageType, RoomMode, ServerConfig};
+pub use encoding::{Encoding, InvalidEncoding};
+#[cfg(feature = "server")]
+pub use events::{EventSink, HttpWebhookSink, NoopSink, RoomEvent, WebhookConfig};
+pub use language::{InvalidLanguageCode, LanguageCode};
+#[cfg(feature = "server")]
+pub use logging::{InvalidLogFilter, LogFilter, Logger, LoggerAlreadySet};
+#[cfg(feature = "server")]
+pub use metrics::Metrics;
+pub use outbound::{
+    EmojiShortcodes, LocalTimestamp, OutboundPipeline, OutboundTransform, Recipient,
+};
+pub use pipeline::{
+    binary_envelope, NoopTranscriber, NoopTranslator, SynthError, Synthesizer, TranscribeError,
+    Transcriber, Transcript, TranscriptEvent, TranslateError, Translations, Translator, WordTiming,
+};
+pub use presence::{NoopPresence, PresenceBackend, PresenceEvent, RemoteRoster};
+#[cfg(feature = "server")]
+pub use protocol::{ClientMessage, InvalidMessage};
+#[cfg(feature = "server")]
+pub use queue::{queue, OverflowPolicy, QueueClosed, QueueReceiver, QueueSender};
+#[cfg(feature = "server")]
+pub use rate_limit::{RateLimit, TokenBucket};
+#[cfg(feature = "server")]
+pub use rejection::{InvalidLogFormat, LogFormat, Rejection};
+#[cfg(feature = "server")]
+pub use relay::{
+    batch, heartbeat, idle_timeout, keepalive, max_lifetime, prioritized, relay, until_shutdown,
+    Activity, Heartbeat,
+};
+#[cfg(feature = "server")]
+pub use room::{
+    is_valid_session_id, join_notice, leave_notice, ConnectionStats, Delivery, JoinError, Joined,
+    Left, Participant, Room, RoomManager, RoomRole, MAX_SESSION_ID_LEN,
+};
+#[cfg(feature = "server")]
+pub use room_id::{parse_room_id, requested_room_id, InvalidRoomId};
+#[cfg(feature = "server")]
+pub use server_error::ServerError;
+#[cfg(feature = "server")]
+pub use server_tls::{ServerStream, StreamAcceptor, TlsConfig};
+#[cfg(feature = "server")]
+pub use shutdown::{InFlight, InFlightGuard, Shutdown};
+pub use text::{normalize_name, same_name, sanitize_text, split_lines, unique_name};
+#[cfg(feature = "server")]
+pub use transcript_sink::{
+    FileTranscriptSink, NoopTranscriptSink, TranscriptEntry, TranscriptMatch, TranscriptQuery,
+    TranscriptSink,
+};
+
 use tungstenite::protocol::CloseFrame;
 
 /// Creates a WebSocket handshake from a request and a stream.
@@ -78,6 +175,7 @@ use tungstenite::protocol::CloseFrame;
 /// This is typically used for clients who have already established, for
 /// example, a TCP connection to the remote server.
 #[cfg(feature = "handshake")]
+#[allow(clippy::extra_unused_lifetimes)]
 pub async fn client_async<'a, R, S>(
     request: R,
     stream: S,
@@ -92,6 +190,7 @@ where
 /// The same as `client_async()` but the one can specify a websocket configuration.
 /// Please refer to `client_async()` for more details.
 #[cfg(feature = "handshake")]
+#[allow(clippy::extra_unused_lifetimes, clippy::result_large_err)]
 pub async fn client_async_with_config<'a, R, S>(
     request: R,
     stream: S,
@@ -161,6 +260,7 @@ where
 /// The same as `accept_hdr_async()` but the one can specify a websocket configuration.
 /// Please refer to `accept_hdr_async()` for more details.
 #[cfg(feature = "handshake")]
+#[allow(clippy::result_large_err)]
 pub async fn accept_hdr_async_with_config<S, C>(
     stream: S,
     callback: C,
@@ -319,6 +419,7 @@ where
     }
 }
 
+#[allow(clippy::result_large_err)]
 impl<T> Sink<Message> for WebSocketStream<T>
 where
     T: AsyncRead + AsyncWrite + Unpin,
@@ -396,6 +497,7 @@ where
 /// Get a domain from an URL.
 #[cfg(any(feature = "connect", feature = "native-tls", feature = "__rustls-tls"))]
 #[inline]
+#[allow(clippy::result_large_err)]
 fn domain(request: &tungstenite::handshake::client::Request) -> Result<String, WsError> {
     match request.uri().host() {
         // rustls expects IPv6 addresses without the surrounding [] brackets
@@ -0,0 +1,598 @@
+//! Structured logs of the servers, with the fields of the spans an event happened in.
+//!
+//! [`Logger`] is a small `tracing` subscriber: every event is written with the fields of its
+//! spans, e.g. the `addr`, `room_id` and `name` of the connection it belongs to, as one line
+//! in the [`LogFormat`] of the server. The records of the `log` crate, which this crate logs
+//! with, are written the same way.
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    fmt, io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span,
+    subscriber::Interest,
+    Event, Level, Metadata, Subscriber,
+};
+use tracing_core::span::Current;
+
+use crate::LogFormat;
+
+/// Which events are logged, parsed from directives like those of `RUST_LOG`.
+///
+/// Directives are separated by commas and each is one of:
+///
+/// - a level, e.g. `info`, for every event no other directive covers,
+/// - a target and a level, e.g. `tokio_tungstenite=debug`, for the events of the target and
+///   the modules below it, the longest matching target winning,
+/// - a span field and a level, e.g. `[room_id=main]=debug`, for the events of spans with
+///   the field set to the value, so a room can be debugged without the noise of the others.
+///
+/// A target without a level, e.g. `room_server`, logs everything of the target. The default
+/// filter logs events at `info` and above.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+    fields: Vec<(String, String, LevelFilter)>,
+}
+
+impl LogFilter {
+    /// Reads the filter from the `RUST_LOG` environment variable, falling back to the
+    /// default filter if it is unset or invalid.
+    pub fn from_env() -> Self {
+        match std::env::var("RUST_LOG") {
+            Ok(directives) => directives.parse().unwrap_or_else(|e| {
+                eprintln!("Ignoring RUST_LOG: {}", e);
+                LogFilter::default()
+            }),
+            Err(_) => LogFilter::default(),
+        }
+    }
+
+    /// The most verbose level any directive enables.
+    fn max_level(&self) -> LevelFilter {
+        let targets = self.targets.iter().map(|(_, level)| *level);
+        let fields = self.fields.iter().map(|(_, _, level)| *level);
+        targets.chain(fields).fold(self.default, LevelFilter::max)
+    }
+
+    /// Returns whether an event of `target` at `level`, in spans with `fields`, is logged.
+    fn enabled<'a>(
+        &self,
+        target: &str,
+        level: &Level,
+        mut fields: impl Iterator<Item = (&'a str, &'a Value)>,
+    ) -> bool {
+        let by_target = self
+            .targets
+            .iter()
+            .filter(|(prefix, _)| is_within(target, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level);
+        if by_target >= *level {
+            return true;
+        }
+        fields.any(|(name, value)| {
+            self.fields.iter().any(|(field, expected, enabled)| {
+                *enabled >= *level && field == name && value_text(value) == *expected
+            })
+        })
+    }
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter { default: LevelFilter::INFO, targets: Vec::new(), fields: Vec::new() }
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = InvalidLogFilter;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = LogFilter::default();
+        let invalid = |directive: &str| InvalidLogFilter(directive.to_string());
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            if let Some(rest) = directive.strip_prefix('[') {
+                let (field, level) = rest.split_once("]=").ok_or_else(|| invalid(directive))?;
+                let (name, value) = field.split_once('=').ok_or_else(|| invalid(directive))?;
+                let level = level.parse().map_err(|_| invalid(directive))?;
+                filter.fields.push((name.to_string(), value.to_string(), level));
+            } else if let Some((target, level)) = directive.split_once('=') {
+                let level = level.parse().map_err(|_| invalid(directive))?;
+                filter.targets.push((target.to_string(), level));
+            } else if let Ok(level) = directive.parse() {
+                filter.default = level;
+            } else {
+                filter.targets.push((directive.to_string(), LevelFilter::TRACE));
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// Error returned when a directive of a [`LogFilter`] cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidLogFilter(String);
+
+impl fmt::Display for InvalidLogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is not a log directive, expected e.g. 'info' or 'target=debug'", self.0)
+    }
+}
+
+impl Error for InvalidLogFilter {}
+
+/// Error returned when installing a [`Logger`] in a process which has one already.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoggerAlreadySet;
+
+impl fmt::Display for LoggerAlreadySet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "A logger is already installed")
+    }
+}
+
+impl Error for LoggerAlreadySet {}
+
+/// A `tracing` subscriber writing every event with the fields of its spans, one line each.
+///
+/// Cloning the logger gives another handle on the same spans and output.
+#[derive(Clone)]
+pub struct Logger {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    format: LogFormat,
+    filter: LogFilter,
+    writer: Mutex<Box<dyn io::Write + Send>>,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    next_id: AtomicU64,
+}
+
+struct SpanData {
+    metadata: &'static Metadata<'static>,
+    parent: Option<u64>,
+    fields: Fields,
+    /// Handles on the span, including the children it outlives through.
+    refs: usize,
+}
+
+thread_local! {
+    /// The spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Logger {
+    /// Creates a logger writing to stderr in `format`, the events `filter` lets through.
+    pub fn new(format: LogFormat, filter: LogFilter) -> Self {
+        Logger {
+            inner: Arc::new(Inner {
+                format,
+                filter,
+                writer: Mutex::new(Box::new(io::stderr())),
+                spans: Mutex::default(),
+                next_id: AtomicU64::new(1),
+            }),
+        }
+    }
+
+    /// Sets where the lines are written, e.g. a file. Only takes effect on a logger which
+    /// was not cloned yet.
+    pub fn writer(mut self, writer: impl io::Write + Send + 'static) -> Self {
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.writer = Mutex::new(Box::new(writer));
+        }
+        self
+    }
+
+    /// Installs the logger for the whole process, for `tracing` events and `log` records.
+    pub fn install(self) -> Result<(), LoggerAlreadySet> {
+        let max_level = self.inner.filter.max_level();
+        let bridge = Box::leak(Box::new(LogBridge(self.inner.clone())));
+        tracing::subscriber::set_global_default(self).map_err(|_| LoggerAlreadySet)?;
+        log::set_logger(bridge).map_err(|_| LoggerAlreadySet)?;
+        log::set_max_level(match max_level.into_level() {
+            Some(Level::ERROR) => log::LevelFilter::Error,
+            Some(Level::WARN) => log::LevelFilter::Warn,
+            Some(Level::INFO) => log::LevelFilter::Info,
+            Some(Level::DEBUG) => log::LevelFilter::Debug,
+            Some(Level::TRACE) => log::LevelFilter::Trace,
+            None => log::LevelFilter::Off,
+        });
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Logger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Logger")
+            .field("format", &self.inner.format)
+            .field("filter", &self.inner.filter)
+            .finish()
+    }
+}
+
+impl Inner {
+    /// Returns the innermost span entered on this thread, if any.
+    fn current(&self) -> Option<u64> {
+        ENTERED.with(|entered| entered.borrow().last().copied())
+    }
+
+    /// Writes a line for an event of `target` at `level` in the span `parent` and the ones
+    /// around it, unless the filter drops it.
+    fn write(&self, level: &Level, target: &str, parent: Option<u64>, fields: Fields) {
+        let line = {
+            let spans = self.spans.lock().unwrap();
+            // Innermost first, as the parents are found
+            let mut chain = Vec::new();
+            let mut next = parent;
+            while let Some(span) = next.and_then(|id| spans.get(&id)) {
+                chain.push(span);
+                next = span.parent;
+            }
+            let span_fields = chain.iter().flat_map(|span| span.fields.iter());
+            if !self.filter.enabled(target, level, span_fields) {
+                return;
+            }
+            chain.reverse();
+            let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+            match self.format {
+                LogFormat::Human => {
+                    let mut line = format!("{} {:>5} ", timestamp, level);
+                    for span in &chain {
+                        line.push_str(span.metadata.name());
+                        let fields: Vec<_> = span.fields.iter().map(human_field).collect();
+                        line.push_str(&format!("{{{}}}: ", fields.join(" ")));
+                    }
+                    line.push_str(target);
+                    line.push(':');
+                    if let Some(message) = fields.get("message") {
+                        line.push(' ');
+                        line.push_str(&value_text(message));
+                    }
+                    for field in fields.iter().filter(|(name, _)| *name != "message") {
+                        line.push(' ');
+                        line.push_str(&human_field(field));
+                    }
+                    line
+                }
+                LogFormat::Json => {
+                    // Inner spans override the fields of outer ones
+                    let span: Map<_, _> =
+                        chain.iter().flat_map(|span| span.fields.to_map()).collect();
+                    let spans: Vec<_> = chain.iter().map(|span| span.metadata.name()).collect();
+                    json!({
+                        "timestamp": timestamp,
+                        "level": level.as_str(),
+                        "target": target,
+                        "span": span,
+                        "spans": spans,
+                        "fields": fields.to_map(),
+                    })
+                    .to_string()
+                }
+            }
+        };
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writeln!(writer, "{}", line);
+    }
+
+    /// Drops a handle on the span `id`, and the span with its last handle.
+    fn release(&self, spans: &mut HashMap<u64, SpanData>, id: u64) -> bool {
+        let span = match spans.get_mut(&id) {
+            Some(span) => span,
+            None => return false,
+        };
+        span.refs -= 1;
+        if span.refs > 0 {
+            return false;
+        }
+        let parent = spans.remove(&id).and_then(|span| span.parent);
+        if let Some(parent) = parent {
+            self.release(spans, parent);
+        }
+        true
+    }
+}
+
+impl Subscriber for Logger {
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        // Asked for every event, as other loggers of the process may filter differently
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // Spans are always kept for their fields, events are filtered once their spans are known
+        metadata.is_span() || self.inner.filter.max_level() >= *metadata.level()
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+        let parent = if attrs.is_root() {
+            None
+        } else {
+            attrs.parent().map(span::Id::into_u64).or_else(|| self.inner.current())
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut spans = self.inner.spans.lock().unwrap();
+        if let Some(parent) = parent.and_then(|parent| spans.get_mut(&parent)) {
+            parent.refs += 1;
+        }
+        spans.insert(id, SpanData { metadata: attrs.metadata(), parent, fields, refs: 1 });
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+        if let Some(span) = self.inner.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(&mut span.fields);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let parent = if event.is_root() {
+            None
+        } else {
+            event.parent().map(span::Id::into_u64).or_else(|| self.inner.current())
+        };
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        self.inner.write(metadata.level(), metadata.target(), parent, fields);
+    }
+
+    fn enter(&self, span: &span::Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &span::Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(i) = entered.iter().rposition(|id| *id == span.into_u64()) {
+                entered.remove(i);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &span::Id) -> span::Id {
+        if let Some(span) = self.inner.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            span.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: span::Id) -> bool {
+        let mut spans = self.inner.spans.lock().unwrap();
+        self.inner.release(&mut spans, span.into_u64())
+    }
+
+    fn current_span(&self) -> Current {
+        let spans = self.inner.spans.lock().unwrap();
+        match self.inner.current().and_then(|id| Some((id, spans.get(&id)?.metadata))) {
+            Some((id, metadata)) => Current::new(span::Id::from_u64(id), metadata),
+            None => Current::none(),
+        }
+    }
+}
+
+/// Writes the records of the `log` crate like events of the current span.
+struct LogBridge(Arc<Inner>);
+
+impl log::Log for LogBridge {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.0.filter.max_level() >= log_level(metadata.level())
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        let mut fields = Fields::default();
+        fields.insert("message", record.args().to_string().into());
+        self.0.write(&log_level(record.level()), record.target(), self.0.current(), fields);
+    }
+
+    fn flush(&self) {
+        let _ = self.0.writer.lock().unwrap().flush();
+    }
+}
+
+fn log_level(level: log::Level) -> Level {
+    match level {
+        log::Level::Error => Level::ERROR,
+        log::Level::Warn => Level::WARN,
+        log::Level::Info => Level::INFO,
+        log::Level::Debug => Level::DEBUG,
+        log::Level::Trace => Level::TRACE,
+    }
+}
+
+/// The fields of a span or event in the order they were recorded.
+#[derive(Debug, Default, Clone)]
+struct Fields(Vec<(&'static str, Value)>);
+
+impl Fields {
+    /// Sets the field `name`, replacing its value if it was recorded before.
+    fn insert(&mut self, name: &'static str, value: Value) {
+        match self.0.iter_mut().find(|(field, _)| *field == name) {
+            Some((_, old)) => *old = value,
+            None => self.0.push((name, value)),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.0.iter().find(|(field, _)| *field == name).map(|(_, value)| value)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.0.iter().map(|(name, value)| (*name, value))
+    }
+
+    fn to_map(&self) -> Map<String, Value> {
+        self.0.iter().map(|(name, value)| (name.to_string(), value.clone())).collect()
+    }
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field.name(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field.name(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field.name(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field.name(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field.name(), value.into());
+    }
+}
+
+/// Returns the text of a field value, strings without their quotes.
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Renders a field as `name=value`, quoting values that would not read as one word.
+fn human_field((name, value): (&str, &Value)) -> String {
+    match value {
+        Value::String(s) if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '"') => {
+            format!("{}={:?}", name, s)
+        }
+        value => format!("{}={}", name, value_text(value)),
+    }
+}
+
+/// Returns whether `target` is `prefix` or a module below it.
+fn is_within(target: &str, prefix: &str) -> bool {
+    target.strip_prefix(prefix).map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{debug, info, info_span, level_filters::LevelFilter};
+
+    use super::{InvalidLogFilter, LogFilter, Logger};
+    use crate::LogFormat;
+
+    /// Collects what a logger writes
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn lines(&self) -> Vec<String> {
+            let text = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            text.lines().map(String::from).collect()
+        }
+    }
+
+    /// Logs a join in two rooms and a debug event in each
+    fn log_rooms(format: LogFormat, filter: &str) -> Vec<String> {
+        let buffer = Buffer::default();
+        let logger = Logger::new(format, filter.parse().unwrap()).writer(buffer.clone());
+        tracing::subscriber::with_default(logger, || {
+            for room_id in ["main", "quiet"] {
+                let span = info_span!(
+                    "connection",
+                    addr = "192.0.2.1:1",
+                    room_id,
+                    name = tracing::field::Empty
+                );
+                let _entered = span.enter();
+                // Recorded once known, like the name a handshake settles on
+                tracing::Span::current().record("name", "Alice B");
+                info!(created = true, "Joined the room");
+                debug!("Received a message");
+            }
+        });
+        buffer.lines()
+    }
+
+    #[test]
+    fn events_carry_the_fields_of_their_connection() {
+        let lines = log_rooms(LogFormat::Human, "info");
+        assert_eq!(lines.len(), 2);
+        let (_timestamp, line) = lines[0].split_once(' ').unwrap();
+        assert_eq!(
+            line,
+            " INFO connection{addr=192.0.2.1:1 room_id=main name=\"Alice B\"}: \
+             tokio_tungstenite::logging::tests: Joined the room created=true"
+        );
+
+        let lines = log_rooms(LogFormat::Json, "info");
+        let json: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["fields"]["message"], "Joined the room");
+        assert_eq!(json["fields"]["created"], true);
+        assert_eq!(json["spans"], serde_json::json!(["connection"]));
+        assert_eq!(json["span"]["room_id"], "quiet");
+        assert_eq!(json["span"]["name"], "Alice B");
+    }
+
+    #[test]
+    fn one_room_can_be_debugged() {
+        let lines = log_rooms(LogFormat::Human, "warn,[room_id=main]=debug");
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.contains("room_id=main")));
+        assert!(lines[1].ends_with(": Received a message"));
+    }
+
+    #[test]
+    fn filters_parse_like_rust_log() {
+        let filter: LogFilter = "warn, tokio_tungstenite=debug,room_server".parse().unwrap();
+        assert_eq!(filter.default, LevelFilter::WARN);
+        assert_eq!(
+            filter.targets,
+            [
+                ("tokio_tungstenite".to_string(), LevelFilter::DEBUG),
+                ("room_server".to_string(), LevelFilter::TRACE)
+            ]
+        );
+        assert_eq!(filter.max_level(), LevelFilter::TRACE);
+        assert_eq!(
+            "[room_id]=debug".parse::<LogFilter>(),
+            Err(InvalidLogFilter("[room_id]=debug".into()))
+        );
+        assert!("main=loud".parse::<LogFilter>().is_err());
+    }
+}
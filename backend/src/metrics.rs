@@ -0,0 +1,173 @@
+//! Counters of what a server does, exposed in the Prometheus text format for operators to
+//! scrape.
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::RoomManager;
+
+/// The counters of a server, shared by all its connections.
+///
+/// The gauges, i.e. the rooms, participants and buffered messages, are not tracked here but read off the
+/// [`RoomManager`] when the metrics are [rendered](Metrics::render), so they cannot drift
+/// from the rooms. Cloning the metrics gives another handle on the same counters.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    messages_broadcast: AtomicU64,
+    bytes_forwarded: AtomicU64,
+    /// By the reason sent to clients, sorted so the output is stable.
+    handshakes_rejected: Mutex<BTreeMap<String, u64>>,
+    transcriptions: AtomicU64,
+    translations: AtomicU64,
+}
+
+impl Metrics {
+    /// Creates metrics with every counter at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a message a participant sent to its room.
+    pub fn message_broadcast(&self) {
+        self.counters.messages_broadcast.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` queued for a participant, once for every recipient of a message.
+    pub fn bytes_forwarded(&self, bytes: usize) {
+        self.counters.bytes_forwarded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a handshake turned away for `reason`, e.g. `room_full`.
+    pub fn handshake_rejected(&self, reason: &str) {
+        let mut rejected = self.counters.handshakes_rejected.lock().unwrap();
+        *rejected.entry(reason.to_string()).or_default() += 1;
+    }
+
+    /// Counts a call to the transcriber.
+    pub fn transcription(&self) {
+        self.counters.transcriptions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts `count` calls to the translator, see
+    /// [`Translations::attempted`](crate::Translations::attempted).
+    pub fn translations(&self, count: usize) {
+        self.counters.translations.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics, with the gauges of `rooms`, in the Prometheus text exposition
+    /// format, to be served with the content type `text/plain; version=0.0.4`.
+    pub fn render(&self, rooms: &RoomManager) -> String {
+        let counters = &self.counters;
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(
+                out,
+                "# HELP {} {}\n# TYPE {} {}\n{} {}",
+                name, help, name, kind, name, value
+            );
+        };
+        metric("rooms_active", "gauge", "Rooms anyone is in.", rooms.room_ids().len() as u64);
+        metric(
+            "participants",
+            "gauge",
+            "Participants of all rooms.",
+            rooms.participant_count() as u64,
+        );
+        metric(
+            "messages_broadcast_total",
+            "counter",
+            "Messages participants sent to their room.",
+            counters.messages_broadcast.load(Ordering::Relaxed),
+        );
+        metric(
+            "forwarded_bytes_total",
+            "counter",
+            "Bytes of the messages queued for participants, per recipient.",
+            counters.bytes_forwarded.load(Ordering::Relaxed),
+        );
+        metric(
+            "transcriptions_total",
+            "counter",
+            "Calls to the transcriber.",
+            counters.transcriptions.load(Ordering::Relaxed),
+        );
+        metric(
+            "translations_total",
+            "counter",
+            "Calls to the translator.",
+            counters.translations.load(Ordering::Relaxed),
+        );
+
+        out.push_str("# HELP buffered_messages Messages a room keeps in memory, by room.\n");
+        out.push_str("# TYPE buffered_messages gauge\n");
+        for (room_id, size) in rooms.buffer_sizes() {
+            let _ =
+                writeln!(out, "buffered_messages{{room=\"{}\"}} {}", escape_label(&room_id), size);
+        }
+
+        out.push_str("# HELP handshakes_rejected_total Handshakes turned away, by reason.\n");
+        out.push_str("# TYPE handshakes_rejected_total counter\n");
+        for (reason, count) in counters.handshakes_rejected.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "handshakes_rejected_total{{reason=\"{}\"}} {}",
+                escape_label(reason),
+                count
+            );
+        }
+        out
+    }
+}
+
+/// Escapes a label value as the text format requires.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use crate::RoomManager;
+
+    #[test]
+    fn renders_the_text_format() {
+        let metrics = Metrics::new();
+        metrics.message_broadcast();
+        metrics.bytes_forwarded(5);
+        metrics.bytes_forwarded(5);
+        metrics.handshake_rejected("room_full");
+        metrics.handshake_rejected("name_in_use");
+        metrics.handshake_rejected("room_full");
+        metrics.handshake_rejected("odd \"reason\"");
+        metrics.translations(2);
+
+        let text = metrics.render(&RoomManager::new());
+        let lines: Vec<_> = text.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(
+            lines,
+            [
+                "rooms_active 0",
+                "participants 0",
+                "messages_broadcast_total 1",
+                "forwarded_bytes_total 10",
+                "transcriptions_total 0",
+                "translations_total 2",
+                "handshakes_rejected_total{reason=\"name_in_use\"} 1",
+                "handshakes_rejected_total{reason=\"odd \\\"reason\\\"\"} 1",
+                "handshakes_rejected_total{reason=\"room_full\"} 2",
+            ]
+        );
+        assert!(text.contains("# TYPE rooms_active gauge\n"));
+        assert!(text.contains("# TYPE forwarded_bytes_total counter\n"));
+    }
+}
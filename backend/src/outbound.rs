@@ -0,0 +1,315 @@
+//! Per-recipient rendering of the messages sent to participants.
+use std::{fmt, sync::Arc};
+
+use chrono::{DateTime, FixedOffset};
+use tungstenite::Message;
+
+use crate::LanguageCode;
+
+/// What an [`OutboundTransform`] knows about the participant a message is sent to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recipient {
+    /// The participant's display name.
+    pub name: String,
+    /// The canonical id of the participant's room.
+    pub room: String,
+    /// The language the participant reads, if it told.
+    pub translate_to: Option<LanguageCode>,
+    /// The UTC offset the participant wants local times in, if it told.
+    pub timezone: Option<FixedOffset>,
+}
+
+/// A step of an [`OutboundPipeline`], rendering a message for one recipient.
+///
+/// Closures taking the message and the recipient implement this trait too.
+pub trait OutboundTransform: Send + Sync {
+    /// Returns the message to send to `recipient` instead of `msg`, or `None` to not send it
+    /// to that recipient at all.
+    fn transform(&self, msg: Message, recipient: &Recipient) -> Option<Message>;
+
+    /// Returns whether the transform renders the messages relayed from other participants
+    /// too, rather than only the server's own. Defaults to `true`.
+    fn renders_relayed(&self) -> bool {
+        true
+    }
+}
+
+impl<F> OutboundTransform for F
+where
+    F: Fn(Message, &Recipient) -> Option<Message> + Send + Sync,
+{
+    fn transform(&self, msg: Message, recipient: &Recipient) -> Option<Message> {
+        self(msg, recipient)
+    }
+}
+
+/// The transforms applied, in order, to every message fanned out to a participant.
+///
+/// The default pipeline holds the built-in transforms, i.e. [`LocalTimestamp`]. Use
+/// [`OutboundPipeline::new`] to start from an empty one. Opt-in transforms such as
+/// [`EmojiShortcodes`] are added with [`OutboundPipeline::with`].
+#[derive(Clone)]
+pub struct OutboundPipeline {
+    transforms: Vec<Arc<dyn OutboundTransform>>,
+}
+
+impl OutboundPipeline {
+    /// Creates a pipeline without any transform, which sends every message as is.
+    pub fn new() -> Self {
+        OutboundPipeline { transforms: Vec::new() }
+    }
+
+    /// Appends `transform` to the pipeline.
+    pub fn with<T>(mut self, transform: T) -> Self
+    where
+        T: OutboundTransform + 'static,
+    {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
+
+    /// Renders `msg`, one of the server's own messages, for `recipient`, returning `None` if
+    /// a transform dropped it.
+    pub fn apply(&self, msg: Message, recipient: &Recipient) -> Option<Message> {
+        self.transforms.iter().try_fold(msg, |msg, transform| transform.transform(msg, recipient))
+    }
+
+    /// Renders `msg`, relayed from another participant, for `recipient` like
+    /// [`apply`](Self::apply), skipping the transforms that only
+    /// [render the server's messages](OutboundTransform::renders_relayed).
+    pub fn apply_relayed(&self, msg: Message, recipient: &Recipient) -> Option<Message> {
+        let mut transforms = self.transforms.iter().filter(|t| t.renders_relayed());
+        transforms.try_fold(msg, |msg, transform| transform.transform(msg, recipient))
+    }
+}
+
+impl Default for OutboundPipeline {
+    fn default() -> Self {
+        OutboundPipeline::new().with(LocalTimestamp)
+    }
+}
+
+impl fmt::Debug for OutboundPipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutboundPipeline").field("transforms", &self.transforms.len()).finish()
+    }
+}
+
+/// Adds the time preformatted in the recipient's timezone to JSON messages.
+///
+/// Messages with an RFC3339 `timestamp` get a `local_timestamp` (e.g. `2025-01-31 18:30:00`)
+/// in the offset of recipients who set one. Anything else is left alone, and so are the
+/// messages relayed from participants, which reach the others as they were sent.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalTimestamp;
+
+impl OutboundTransform for LocalTimestamp {
+    fn transform(&self, msg: Message, recipient: &Recipient) -> Option<Message> {
+        let local = match (&msg, recipient.timezone) {
+            (Message::Text(text), Some(timezone)) => {
+                serde_json::from_str::<serde_json::Value>(text).ok().and_then(|mut value| {
+                    let timestamp = value.get("timestamp")?.as_str()?;
+                    let local = DateTime::parse_from_rfc3339(timestamp).ok()?;
+                    value["local_timestamp"] = local
+                        .with_timezone(&timezone)
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string()
+                        .into();
+                    Some(Message::text(value.to_string()))
+                })
+            }
+            _ => None,
+        };
+        Some(local.unwrap_or(msg))
+    }
+
+    fn renders_relayed(&self) -> bool {
+        false
+    }
+}
+
+/// Expands `:smile:` style shortcodes in chat to Unicode emoji, from a built-in table.
+///
+/// Only plain text chat is touched: JSON messages generated by the server are left alone,
+/// and so is anything inside `` `code` `` spans or ```` ``` ```` blocks. Encrypted rooms
+/// never reach the pipeline. At most [`EmojiShortcodes::MAX_EXPANSIONS`] shortcodes are
+/// expanded per message, the rest are sent as written.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EmojiShortcodes;
+
+impl EmojiShortcodes {
+    /// How many shortcodes are expanded in a single message at most.
+    pub const MAX_EXPANSIONS: usize = 64;
+
+    /// Returns `text` with its shortcodes expanded, or `None` if there were none to expand.
+    pub fn expand(text: &str) -> Option<String> {
+        let mut expanded = String::with_capacity(text.len());
+        let mut budget = Self::MAX_EXPANSIONS;
+        // Every backtick toggles in and out of code, which covers fences as well as spans.
+        for (i, segment) in text.split('`').enumerate() {
+            if i > 0 {
+                expanded.push('`');
+            }
+            if i % 2 == 1 {
+                expanded.push_str(segment);
+                continue;
+            }
+            let mut rest = segment;
+            while let Some(start) = rest.find(':') {
+                expanded.push_str(&rest[..start]);
+                let after = &rest[start + 1..];
+                let emoji = after
+                    .find(':')
+                    .filter(|_| budget > 0)
+                    .and_then(|end| Some((end, shortcode(&after[..end])?)));
+                match emoji {
+                    Some((end, emoji)) => {
+                        expanded.push_str(emoji);
+                        rest = &after[end + 1..];
+                        budget -= 1;
+                    }
+                    None => {
+                        expanded.push(':');
+                        rest = after;
+                    }
+                }
+            }
+            expanded.push_str(rest);
+        }
+        Some(expanded).filter(|_| budget < Self::MAX_EXPANSIONS)
+    }
+}
+
+impl OutboundTransform for EmojiShortcodes {
+    fn transform(&self, msg: Message, _: &Recipient) -> Option<Message> {
+        let expanded = match &msg {
+            Message::Text(text) if !is_json_object(text) => EmojiShortcodes::expand(text),
+            _ => None,
+        };
+        Some(expanded.map(Message::text).unwrap_or(msg))
+    }
+}
+
+fn is_json_object(text: &str) -> bool {
+    text.trim_start().starts_with('{')
+        && serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(text).is_ok()
+}
+
+/// The built-in shortcodes, sorted by name.
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("angry", "😠"),
+    ("clap", "👏"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("ok_hand", "👌"),
+    ("party", "🥳"),
+    ("pray", "🙏"),
+    ("rocket", "🚀"),
+    ("sad", "😞"),
+    ("smile", "😄"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("sunglasses", "😎"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("wave", "👋"),
+    ("wink", "😉"),
+];
+
+fn shortcode(name: &str) -> Option<&'static str> {
+    SHORTCODES.binary_search_by_key(&name, |&(name, _)| name).ok().map(|i| SHORTCODES[i].1)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tungstenite::Message;
+
+    use super::{EmojiShortcodes, OutboundPipeline, Recipient, SHORTCODES};
+
+    fn recipient(timezone: Option<&str>) -> Recipient {
+        Recipient {
+            name: "Alice".into(),
+            room: "main".into(),
+            translate_to: None,
+            timezone: timezone.map(|tz| tz.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn default_pipeline_adds_local_timestamps() {
+        let pipeline = OutboundPipeline::default();
+        let msg = json!({ "type": "chat", "timestamp": "2025-01-31T09:30:00+00:00" }).to_string();
+
+        let local = pipeline.apply(Message::text(msg.clone()), &recipient(Some("+09:00"))).unwrap();
+        let local: serde_json::Value = serde_json::from_str(local.to_text().unwrap()).unwrap();
+        assert_eq!(local["local_timestamp"], "2025-01-31 18:30:00");
+
+        let utc = pipeline.apply(Message::text(msg.clone()), &recipient(None)).unwrap();
+        assert_eq!(utc, Message::text(msg));
+    }
+
+    #[test]
+    fn relayed_messages_keep_their_bytes() {
+        let pipeline = OutboundPipeline::default().with(EmojiShortcodes);
+        let relayed = r#"{ "type":"chat",  "timestamp":"2025-01-31T09:30:00+00:00" }"#;
+
+        let msg = pipeline.apply_relayed(Message::text(relayed), &recipient(Some("+09:00")));
+        assert_eq!(msg, Some(Message::text(relayed)));
+        let msg = pipeline.apply_relayed(Message::text("hi :wave:"), &recipient(Some("+09:00")));
+        assert_eq!(msg, Some(Message::text("hi 👋")));
+    }
+
+    #[test]
+    fn transforms_run_in_order_and_may_drop() {
+        let pipeline = OutboundPipeline::new()
+            .with(|msg: Message, _: &Recipient| Some(Message::text(format!("{}!", msg))))
+            .with(|msg: Message, recipient: &Recipient| {
+                Some(msg).filter(|_| recipient.timezone.is_some())
+            });
+
+        assert_eq!(
+            pipeline.apply(Message::text("hi"), &recipient(Some("+01:00"))),
+            Some(Message::text("hi!"))
+        );
+        assert_eq!(pipeline.apply(Message::text("hi"), &recipient(None)), None);
+    }
+
+    #[test]
+    fn shortcodes_expand_outside_code() {
+        assert!(SHORTCODES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        let expand = |text: &str| EmojiShortcodes::expand(text);
+        assert_eq!(expand("hi :wave: :tada::+1:").as_deref(), Some("hi 👋 🎉👍"));
+        assert_eq!(expand("at 18:30:00 :nope:"), None);
+        assert_eq!(expand("`:smile:` :smile:").as_deref(), Some("`:smile:` 😄"));
+        assert_eq!(expand("```\n:smile:\n``` ok"), None);
+
+        let many = ":fire:".repeat(EmojiShortcodes::MAX_EXPANSIONS + 1);
+        let expanded = expand(&many).unwrap();
+        assert_eq!(expanded.matches('🔥').count(), EmojiShortcodes::MAX_EXPANSIONS);
+        assert!(expanded.ends_with(":fire:"));
+
+        let pipeline = OutboundPipeline::new().with(EmojiShortcodes);
+        let notice = json!({ "type": "notice", "message": ":smile:" }).to_string();
+        assert_eq!(
+            pipeline.apply(Message::text(notice.clone()), &recipient(None)),
+            Some(Message::text(notice))
+        );
+        assert_eq!(
+            pipeline.apply(Message::text(":smile:"), &recipient(None)),
+            Some(Message::text("😄"))
+        );
+    }
+}
@@ -0,0 +1,423 @@
+//! Hooks for the transcribe → translate → speak pipeline.
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+};
+
+use futures_util::{
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt,
+};
+use log::*;
+use serde_json::json;
+
+use crate::{sanitize_text, LanguageCode};
+
+/// Turns text into speech for participants who asked for audio (`tts=true`).
+///
+/// Implementations usually call out to an external text-to-speech service. The returned
+/// bytes are opaque to the server and are forwarded as-is in a [`binary_envelope`].
+pub trait Synthesizer: Send + Sync {
+    /// Synthesizes `text`, which is written in `lang`, into audio.
+    fn synthesize<'a>(
+        &'a self,
+        text: &'a str,
+        lang: &'a LanguageCode,
+    ) -> BoxFuture<'a, Result<Vec<u8>, SynthError>>;
+}
+
+/// Error returned by a [`Synthesizer`].
+#[derive(Debug)]
+pub struct SynthError(Box<dyn Error + Send + Sync>);
+
+impl SynthError {
+    /// Wraps the underlying error of the text-to-speech backend.
+    pub fn new<E>(error: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        SynthError(error.into())
+    }
+}
+
+impl fmt::Display for SynthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "speech synthesis failed: {}", self.0)
+    }
+}
+
+impl Error for SynthError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.0)
+    }
+}
+
+/// Turns the audio participants send into text.
+///
+/// Implementations usually call out to an external speech-to-text service, which may be slow:
+/// servers should run it apart from the connection it serves the audio of.
+pub trait Transcriber: Send + Sync {
+    /// Transcribes `audio`, which is spoken in `lang`, with the backend's `profile` (one of
+    /// [`profiles`](Self::profiles), or empty for the default one).
+    fn transcribe<'a>(
+        &'a self,
+        audio: &'a [u8],
+        lang: &'a LanguageCode,
+        profile: &'a str,
+    ) -> BoxFuture<'a, Result<Transcript, TranscribeError>>;
+
+    /// Transcribes `audio` like [`transcribe`](Self::transcribe), as it is heard: the stream
+    /// yields the interim hypotheses of the backend, each replacing the one before, then the
+    /// final transcript.
+    ///
+    /// Backends reporting interim results override this. By default the stream holds the
+    /// result of [`transcribe`](Self::transcribe) alone.
+    fn transcribe_stream<'a>(
+        &'a self,
+        audio: &'a [u8],
+        lang: &'a LanguageCode,
+        profile: &'a str,
+    ) -> BoxStream<'a, Result<TranscriptEvent, TranscribeError>> {
+        let transcript = self.transcribe(audio, lang, profile);
+        Box::pin(stream::once(transcript.map(|result| result.map(TranscriptEvent::Final))))
+    }
+
+    /// Returns the languages the backend transcribes, or `None` if it takes any.
+    fn supported_languages(&self) -> Option<HashSet<LanguageCode>> {
+        None
+    }
+
+    /// Returns the profiles rooms may pick besides the default one, e.g. models tuned for
+    /// medical vocabulary or phone audio. None by default.
+    fn profiles(&self) -> HashSet<String> {
+        HashSet::new()
+    }
+
+    /// Returns whether the backend transcribes `lang`.
+    fn supports(&self, lang: &LanguageCode) -> bool {
+        self.supported_languages().map_or(true, |languages| languages.contains(lang))
+    }
+}
+
+/// Error returned by a [`Transcriber`].
+#[derive(Debug)]
+pub struct TranscribeError(Box<dyn Error + Send + Sync>);
+
+impl TranscribeError {
+    /// Wraps the underlying error of the speech-to-text backend.
+    pub fn new<E>(error: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        TranscribeError(error.into())
+    }
+}
+
+impl fmt::Display for TranscribeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transcription failed: {}", self.0)
+    }
+}
+
+impl Error for TranscribeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.0)
+    }
+}
+
+/// A stand-in for a speech-to-text service, which "transcribes" any audio into a placeholder
+/// naming its size, so servers run without one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopTranscriber;
+
+impl Transcriber for NoopTranscriber {
+    fn transcribe<'a>(
+        &'a self,
+        audio: &'a [u8],
+        _lang: &'a LanguageCode,
+        _profile: &'a str,
+    ) -> BoxFuture<'a, Result<Transcript, TranscribeError>> {
+        let placeholder = Transcript::new(format!("[{} bytes of audio]", audio.len()));
+        Box::pin(future::ready(Ok(placeholder)))
+    }
+}
+
+/// Translates text for participants who read another language than the one it is written in.
+pub trait Translator: Send + Sync {
+    /// Translates `text` from `from` to `to`.
+    fn translate<'a>(
+        &'a self,
+        text: &'a str,
+        from: &'a LanguageCode,
+        to: &'a LanguageCode,
+    ) -> BoxFuture<'a, Result<String, TranslateError>>;
+
+    /// Returns whether the backend translates from `from` to `to`. Every pair by default.
+    fn supports(&self, _from: &LanguageCode, _to: &LanguageCode) -> bool {
+        true
+    }
+}
+
+/// Error returned by a [`Translator`].
+#[derive(Debug)]
+pub struct TranslateError(Box<dyn Error + Send + Sync>);
+
+impl TranslateError {
+    /// Wraps the underlying error of the translation backend.
+    pub fn new<E>(error: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        TranslateError(error.into())
+    }
+}
+
+impl fmt::Display for TranslateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "translation failed: {}", self.0)
+    }
+}
+
+impl Error for TranslateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.0)
+    }
+}
+
+/// A stand-in for a translation service, which "translates" any text into itself, so
+/// servers run without one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopTranslator;
+
+impl Translator for NoopTranslator {
+    fn translate<'a>(
+        &'a self,
+        text: &'a str,
+        _from: &'a LanguageCode,
+        _to: &'a LanguageCode,
+    ) -> BoxFuture<'a, Result<String, TranslateError>> {
+        Box::pin(future::ready(Ok(text.to_string())))
+    }
+}
+
+/// The translations of one text, each language translated at most once however often it is
+/// asked for, e.g. while fanning the text out to a room.
+pub struct Translations<'a> {
+    translator: &'a dyn Translator,
+    text: &'a str,
+    from: &'a LanguageCode,
+    done: HashMap<LanguageCode, Option<String>>,
+}
+
+impl<'a> Translations<'a> {
+    /// Creates the translations of `text`, which is written in `from`.
+    pub fn new(translator: &'a dyn Translator, text: &'a str, from: &'a LanguageCode) -> Self {
+        Translations { translator, text, from, done: HashMap::new() }
+    }
+
+    /// Returns the language the text is written in.
+    pub fn from(&self) -> &LanguageCode {
+        self.from
+    }
+
+    /// Returns the translation of the text to `to`, or `None` if it failed, which is only
+    /// attempted once too. The text is its own translation to the language it is written in.
+    pub async fn get(&mut self, to: &LanguageCode) -> Option<&str> {
+        if to == self.from {
+            return Some(self.text);
+        }
+        if !self.done.contains_key(to) {
+            let translation = match self.translator.translate(self.text, self.from, to).await {
+                Ok(translation) => Some(translation),
+                Err(e) => {
+                    warn!("Failed to translate from {} to {}: {}", self.from, to, e);
+                    None
+                }
+            };
+            self.done.insert(to.clone(), translation);
+        }
+        self.done[to].as_deref()
+    }
+
+    /// Returns how many translations were asked of the translator so far, failed ones
+    /// included.
+    pub fn attempted(&self) -> usize {
+        self.done.len()
+    }
+}
+
+/// The transcription of what a participant said.
+///
+/// Besides the plain text, transcription backends often report when each word was spoken,
+/// which clients can use for karaoke-style highlighting.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Transcript {
+    /// The transcribed text.
+    pub text: String,
+    /// The timing of the words of `text`, empty if the backend does not report it.
+    pub words: Vec<WordTiming>,
+}
+
+/// What [`Transcriber::transcribe_stream`] reports while transcribing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TranscriptEvent {
+    /// An interim hypothesis of the text so far, which the next event replaces.
+    Partial(String),
+    /// The transcript of the whole audio, which ends the stream.
+    Final(Transcript),
+}
+
+impl TranscriptEvent {
+    /// Builds the `transcript` message telling participants what `speaker` said in `lang`,
+    /// with `final` telling a [final](Transcript::to_message) transcript from a partial one.
+    pub fn to_message(&self, speaker: &str, lang: &LanguageCode) -> serde_json::Value {
+        match self {
+            TranscriptEvent::Partial(text) => json!({
+                "type": "transcript",
+                "final": false,
+                "name": sanitize_text(speaker),
+                "lang": lang.as_str(),
+                "text": sanitize_text(text),
+            }),
+            TranscriptEvent::Final(transcript) => transcript.to_message(speaker, lang),
+        }
+    }
+}
+
+/// When a word of a [`Transcript`] was spoken.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WordTiming {
+    /// The word as it appears in the transcript.
+    pub word: String,
+    /// Seconds from the start of the audio to the start of the word.
+    pub start: f64,
+    /// Seconds from the start of the audio to the end of the word.
+    pub end: f64,
+    /// How confident the backend is about the word, from 0 to 1.
+    pub confidence: f32,
+}
+
+impl Transcript {
+    /// Creates a transcript without word timings.
+    pub fn new(text: impl Into<String>) -> Self {
+        Transcript { text: text.into(), words: Vec::new() }
+    }
+
+    /// Builds the `transcript` message telling participants what `speaker` said in `lang`.
+    ///
+    /// The message always has the plain `text`, and `final: true` as opposed to the partial
+    /// ones of [`TranscriptEvent`], so simple clients can ignore the rest. The word timings
+    /// are added as `words: [{"w":..,"start":..,"end":..,"conf":..}]` if any.
+    pub fn to_message(&self, speaker: &str, lang: &LanguageCode) -> serde_json::Value {
+        let mut msg = json!({
+            "type": "transcript",
+            "final": true,
+            "name": sanitize_text(speaker),
+            "lang": lang.as_str(),
+            "text": sanitize_text(&self.text),
+        });
+        if !self.words.is_empty() {
+            let words: Vec<_> = self
+                .words
+                .iter()
+                .map(|word| {
+                    json!({
+                        "w": sanitize_text(&word.word),
+                        "start": word.start,
+                        "end": word.end,
+                        "conf": word.confidence,
+                    })
+                })
+                .collect();
+            msg["words"] = words.into();
+        }
+        msg
+    }
+}
+
+/// Builds the payload of a binary frame carrying a JSON `header` describing `payload`.
+///
+/// The layout is the length of the header as a big-endian `u32`, followed by the UTF-8
+/// encoded header and the raw payload, so clients can tell what the bytes are without
+/// a separate text message.
+pub fn binary_envelope(header: &serde_json::Value, payload: &[u8]) -> Vec<u8> {
+    let header = header.to_string();
+    let mut frame = Vec::with_capacity(4 + header.len() + payload.len());
+    frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    frame.extend_from_slice(header.as_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::convert::TryInto;
+
+    use futures_util::StreamExt;
+
+    use super::{
+        binary_envelope, NoopTranscriber, Transcriber, Transcript, TranscriptEvent, WordTiming,
+    };
+    use crate::LanguageCode;
+
+    #[test]
+    fn envelope_prefixes_header_length() {
+        let header = json!({ "type": "tts" });
+        let frame = binary_envelope(&header, &[1, 2, 3]);
+
+        let header_len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+        let parsed: serde_json::Value = serde_json::from_slice(&frame[4..4 + header_len]).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(&frame[4 + header_len..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn transcript_message_carries_word_timings() {
+        let lang = "ja".parse().unwrap();
+        let plain = Transcript::new("hello world").to_message("Alice", &lang);
+        assert_eq!(
+            plain,
+            json!({
+                "type": "transcript",
+                "final": true,
+                "name": "Alice",
+                "lang": "ja",
+                "text": "hello world"
+            })
+        );
+
+        let mut timed = Transcript::new("hello");
+        timed.words.push(WordTiming {
+            word: "hello".into(),
+            start: 0.1,
+            end: 0.4,
+            confidence: 0.5,
+        });
+        let msg = timed.to_message("Alice", &lang);
+        assert_eq!(msg["text"], "hello");
+        assert_eq!(msg["words"], json!([{ "w": "hello", "start": 0.1, "end": 0.4, "conf": 0.5 }]));
+    }
+
+    #[tokio::test]
+    async fn noop_transcriber_takes_every_language() {
+        let lang: LanguageCode = "ja".parse().unwrap();
+        assert!(NoopTranscriber.supports(&lang));
+        let transcript = NoopTranscriber.transcribe(&[0; 3], &lang, "").await.unwrap();
+        assert_eq!(transcript.text, "[3 bytes of audio]");
+    }
+
+    #[tokio::test]
+    async fn transcripts_stream_as_a_final_event_by_default() {
+        let lang: LanguageCode = "ja".parse().unwrap();
+        let events: Vec<_> = NoopTranscriber.transcribe_stream(&[0; 3], &lang, "").collect().await;
+        let event = TranscriptEvent::Final(Transcript::new("[3 bytes of audio]"));
+        assert_eq!(events.into_iter().map(Result::unwrap).collect::<Vec<_>>(), [event]);
+
+        let partial = TranscriptEvent::Partial("hel".into()).to_message("Alice", &lang);
+        assert_eq!(partial["final"], false);
+        assert_eq!(partial["text"], "hel");
+    }
+}
@@ -0,0 +1,121 @@
+//! Sharing room rosters between several server instances.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::stream::{self, BoxStream};
+
+/// A participant joining or leaving a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PresenceEvent {
+    /// `name` joined `room`.
+    Joined {
+        /// The room id.
+        room: String,
+        /// The participant's display name.
+        name: String,
+    },
+    /// `name` left `room`.
+    Left {
+        /// The room id.
+        room: String,
+        /// The participant's display name.
+        name: String,
+    },
+}
+
+/// Propagates presence between the instances of a multi-instance deployment.
+///
+/// Each instance only has sockets of its own participants, but the roster it sends should
+/// reflect the whole room. The interface is modelled after a pub/sub channel (e.g. Redis):
+/// local joins and leaves are published, and the events published by the other instances
+/// are received through [`subscribe`](PresenceBackend::subscribe).
+pub trait PresenceBackend: Send + Sync {
+    /// Announces a local join or leave to the other instances.
+    ///
+    /// This is called from the join and leave paths, so it must not block; implementations
+    /// should queue the event and publish it in the background.
+    fn publish(&self, event: PresenceEvent);
+
+    /// Returns the stream of events published by the other instances. Events published by
+    /// this instance must not be echoed back.
+    fn subscribe(&self) -> BoxStream<'static, PresenceEvent>;
+}
+
+/// The default backend of a single-instance deployment, which shares nothing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopPresence;
+
+impl PresenceBackend for NoopPresence {
+    fn publish(&self, _event: PresenceEvent) {}
+
+    fn subscribe(&self) -> BoxStream<'static, PresenceEvent> {
+        Box::pin(stream::pending())
+    }
+}
+
+/// The participants of other instances, built from the events of a [`PresenceBackend`].
+#[derive(Debug, Clone, Default)]
+pub struct RemoteRoster {
+    rooms: Arc<Mutex<HashMap<String, Vec<String>>>>,
+}
+
+impl RemoteRoster {
+    /// Creates an empty roster.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies an event received from another instance.
+    pub fn apply(&self, event: PresenceEvent) {
+        let mut rooms = self.rooms.lock().unwrap();
+        match event {
+            PresenceEvent::Joined { room, name } => rooms.entry(room).or_default().push(name),
+            PresenceEvent::Left { room, name } => {
+                if let Some(names) = rooms.get_mut(&room) {
+                    if let Some(i) = names.iter().position(|n| *n == name) {
+                        names.swap_remove(i);
+                    }
+                    if names.is_empty() {
+                        rooms.remove(&room);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the names of the remote participants of `room`.
+    pub fn participants(&self, room: &str) -> Vec<String> {
+        self.rooms.lock().unwrap().get(room).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PresenceEvent, RemoteRoster};
+
+    fn joined(room: &str, name: &str) -> PresenceEvent {
+        PresenceEvent::Joined { room: room.into(), name: name.into() }
+    }
+
+    fn left(room: &str, name: &str) -> PresenceEvent {
+        PresenceEvent::Left { room: room.into(), name: name.into() }
+    }
+
+    #[test]
+    fn roster_follows_remote_events() {
+        let roster = RemoteRoster::new();
+        roster.apply(joined("main", "Alice"));
+        roster.apply(joined("main", "Bob"));
+        roster.apply(joined("other", "Carol"));
+        roster.apply(left("main", "Alice"));
+        roster.apply(left("main", "Nobody"));
+
+        assert_eq!(roster.participants("main"), vec!["Bob".to_string()]);
+        assert_eq!(roster.participants("other"), vec!["Carol".to_string()]);
+
+        roster.apply(left("other", "Carol"));
+        assert!(roster.participants("other").is_empty());
+    }
+}
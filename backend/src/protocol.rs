@@ -0,0 +1,217 @@
+//! The messages clients send to a room.
+use std::{error::Error, fmt};
+
+use serde_json::{Map, Value};
+
+/// A text frame sent by a client, by its `type`.
+///
+/// Fields the server does not read, e.g. the `expires_in` of a chat message, are allowed and
+/// relayed along with the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientMessage {
+    /// Chat for the rest of the room, `{"type":"chat","text":".."}`.
+    Chat {
+        /// The chat text.
+        text: String,
+    },
+    /// A piece of audio sent as text, `{"type":"audio_chunk","data":"..","seq":0}`.
+    AudioChunk {
+        /// The base64-encoded audio.
+        data: String,
+        /// The position of the chunk in the sender's stream, so receivers can reorder them.
+        seq: u64,
+    },
+    /// A request to the room itself, `{"type":"control","action":"pause_room"}`.
+    ///
+    /// The shorthands `{"type":"pause_room"}` and `{"type":"resume_room"}` are controls too.
+    Control {
+        /// What the client asks the room to do.
+        action: String,
+    },
+    /// The participant describing itself, `{"type":"update_meta","meta":{"color":"teal"}}`.
+    UpdateMeta {
+        /// The fields to change, of which `null` ones are cleared. Empty without a `meta`
+        /// object.
+        meta: Map<String, Value>,
+    },
+    /// A moderator giving someone a role, `{"type":"set_role","id":"..","role":".."}`.
+    SetRole {
+        /// The connection id of the participant to give the role to.
+        id: String,
+        /// The role to give.
+        role: String,
+    },
+    /// A moderator pinning a message for the room, `{"type":"pin","message_id":".."}`.
+    Pin {
+        /// The id the server gave the message when relaying it.
+        message_id: String,
+    },
+    /// A moderator unpinning a message, `{"type":"unpin","message_id":".."}`.
+    Unpin {
+        /// The id of the pinned message.
+        message_id: String,
+    },
+    /// A request to hear someone's speech interpreted, `{"type":"follow","name":"..","lang":".."}`.
+    Follow {
+        /// The display name of the participant to follow.
+        name: String,
+        /// The language to hear the speech in.
+        lang: String,
+    },
+    /// A request to stop following anyone, `{"type":"unfollow"}`.
+    Unfollow,
+    /// A request to read the room in other languages from now on,
+    /// `{"type":"control","action":"set_translate_to","lang":"de,fr"}`.
+    SetTranslateTo {
+        /// The comma-separated languages to translate to.
+        lang: String,
+    },
+}
+
+impl ClientMessage {
+    /// Parses the text of a frame.
+    pub fn parse(text: &str) -> Result<Self, InvalidMessage> {
+        let mut msg = match serde_json::from_str(text) {
+            Ok(Value::Object(msg)) => msg,
+            _ => return Err(InvalidMessage::NotAnObject),
+        };
+        let kind = match msg.get("type") {
+            Some(Value::String(kind)) => kind.clone(),
+            _ => return Err(InvalidMessage::UnknownType(String::new())),
+        };
+
+        match kind.as_str() {
+            "chat" => Ok(ClientMessage::Chat { text: take_string(&mut msg, "text")? }),
+            "audio_chunk" => {
+                let seq = msg.get("seq").and_then(Value::as_u64);
+                let seq = seq.ok_or(InvalidMessage::InvalidField("seq"))?;
+                Ok(ClientMessage::AudioChunk { data: take_string(&mut msg, "data")?, seq })
+            }
+            "control" => match take_string(&mut msg, "action")? {
+                action if action == "set_translate_to" => {
+                    Ok(ClientMessage::SetTranslateTo { lang: lenient(&mut msg, "lang") })
+                }
+                action => Ok(ClientMessage::Control { action }),
+            },
+            "pause_room" | "resume_room" => Ok(ClientMessage::Control { action: kind }),
+            "update_meta" => match msg.remove("meta") {
+                Some(Value::Object(meta)) => Ok(ClientMessage::UpdateMeta { meta }),
+                _ => Ok(ClientMessage::UpdateMeta { meta: Map::new() }),
+            },
+            // Unknown participants, roles and languages are rejected along with the request
+            "set_role" => Ok(ClientMessage::SetRole {
+                id: lenient(&mut msg, "id"),
+                role: lenient(&mut msg, "role"),
+            }),
+            "pin" => Ok(ClientMessage::Pin { message_id: lenient(&mut msg, "message_id") }),
+            "unpin" => Ok(ClientMessage::Unpin { message_id: lenient(&mut msg, "message_id") }),
+            "follow" => Ok(ClientMessage::Follow {
+                name: lenient(&mut msg, "name"),
+                lang: lenient(&mut msg, "lang"),
+            }),
+            "unfollow" => Ok(ClientMessage::Unfollow),
+            _ => Err(InvalidMessage::UnknownType(kind)),
+        }
+    }
+}
+
+fn take_string(
+    msg: &mut Map<String, Value>,
+    field: &'static str,
+) -> Result<String, InvalidMessage> {
+    match msg.remove(field) {
+        Some(Value::String(value)) => Ok(value),
+        _ => Err(InvalidMessage::InvalidField(field)),
+    }
+}
+
+/// Takes a field which the request it is part of validates, empty if it is missing
+fn lenient(msg: &mut Map<String, Value>, field: &'static str) -> String {
+    take_string(msg, field).unwrap_or_default()
+}
+
+/// Why a text frame is not a [`ClientMessage`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidMessage {
+    /// The text is not a JSON object.
+    NotAnObject,
+    /// The object has a `type` the server does not know, which is empty if it has none.
+    UnknownType(String),
+    /// A field the type requires is missing or not of the right kind.
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for InvalidMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidMessage::NotAnObject => write!(f, "Messages must be JSON objects"),
+            InvalidMessage::UnknownType(kind) if kind.is_empty() => {
+                write!(f, "Messages must have a type")
+            }
+            InvalidMessage::UnknownType(kind) => write!(f, "Unknown message type '{}'", kind),
+            InvalidMessage::InvalidField(field) => write!(f, "Missing or invalid '{}'", field),
+        }
+    }
+}
+
+impl Error for InvalidMessage {}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientMessage, InvalidMessage};
+
+    #[test]
+    fn chat_keeps_its_text() {
+        let msg = ClientMessage::parse(r#"{"type":"chat","text":"hi","expires_in":30}"#);
+        assert_eq!(msg, Ok(ClientMessage::Chat { text: "hi".into() }));
+
+        let msg = ClientMessage::parse(r#"{"type":"chat","text":3}"#);
+        assert_eq!(msg, Err(InvalidMessage::InvalidField("text")));
+    }
+
+    #[test]
+    fn controls_have_an_action() {
+        let pause = ClientMessage::Control { action: "pause_room".into() };
+        assert_eq!(ClientMessage::parse(r#"{"type":"control","action":"pause_room"}"#), Ok(pause));
+        let resume = ClientMessage::Control { action: "resume_room".into() };
+        assert_eq!(ClientMessage::parse(r#"{"type":"resume_room"}"#), Ok(resume));
+
+        let translate = ClientMessage::SetTranslateTo { lang: "de".into() };
+        let msg =
+            ClientMessage::parse(r#"{"type":"control","action":"set_translate_to","lang":"de"}"#);
+        assert_eq!(msg, Ok(translate));
+
+        let audio = ClientMessage::parse(r#"{"type":"audio_chunk","data":"AAE=","seq":7}"#);
+        assert_eq!(audio, Ok(ClientMessage::AudioChunk { data: "AAE=".into(), seq: 7 }));
+    }
+
+    #[test]
+    fn follows_name_a_participant() {
+        let msg = ClientMessage::parse(r#"{"type":"follow","name":"Alice","lang":"ja"}"#);
+        assert_eq!(msg, Ok(ClientMessage::Follow { name: "Alice".into(), lang: "ja".into() }));
+        let msg = ClientMessage::parse(r#"{"type":"follow","name":"Alice"}"#);
+        assert_eq!(msg, Ok(ClientMessage::Follow { name: "Alice".into(), lang: "".into() }));
+        assert_eq!(ClientMessage::parse(r#"{"type":"unfollow"}"#), Ok(ClientMessage::Unfollow));
+    }
+
+    #[test]
+    fn pins_name_a_message() {
+        let msg = ClientMessage::parse(r#"{"type":"pin","message_id":"7"}"#);
+        assert_eq!(msg, Ok(ClientMessage::Pin { message_id: "7".into() }));
+        let msg = ClientMessage::parse(r#"{"type":"unpin"}"#);
+        assert_eq!(msg, Ok(ClientMessage::Unpin { message_id: "".into() }));
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        assert_eq!(ClientMessage::parse("hello"), Err(InvalidMessage::NotAnObject));
+        assert_eq!(ClientMessage::parse(r#"{"type":"chat""#), Err(InvalidMessage::NotAnObject));
+        assert_eq!(ClientMessage::parse("[1, 2]"), Err(InvalidMessage::NotAnObject));
+        assert_eq!(
+            ClientMessage::parse(r#"{"text":"hi"}"#),
+            Err(InvalidMessage::UnknownType("".into()))
+        );
+        let dance = ClientMessage::parse(r#"{"type":"dance"}"#);
+        assert_eq!(dance, Err(InvalidMessage::UnknownType("dance".into())));
+    }
+}
@@ -0,0 +1,323 @@
+//! The bounded queues of the messages sent to a participant.
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures_util::{task::AtomicWaker, Stream};
+use log::*;
+use tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+/// What a [`queue`] does with a message pushed while it is full, i.e. while the participant
+/// reads slower than the room talks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Makes room by dropping the oldest update, such as a participant list or count that
+    /// a later one supersedes anyway, or the oldest message if no update is queued.
+    #[default]
+    DropOldest,
+    /// Disconnects the participant: the queued messages are dropped, and the queue ends with
+    /// a close frame with code `4001`, like the one [`relay`](crate::relay) sends to a slow
+    /// consumer.
+    DisconnectSlow,
+}
+
+/// Creates a queue holding at most `capacity` messages, what happens to more is up to
+/// `policy`.
+///
+/// Cloning the sending half gives another sender into the same queue. The receiving half
+/// ends once the queue is empty and either closed or without senders.
+pub fn queue(capacity: usize, policy: OverflowPolicy) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            messages: VecDeque::new(),
+            senders: 1,
+            closed: false,
+            farewell: None,
+        }),
+        waker: AtomicWaker::new(),
+        capacity: capacity.max(1),
+        policy,
+    });
+    (QueueSender { shared: shared.clone() }, QueueReceiver { shared })
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    waker: AtomicWaker,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+#[derive(Debug)]
+struct State {
+    messages: VecDeque<Queued>,
+    senders: usize,
+    closed: bool,
+    /// The close frame a disconnected participant is sent before the queue ends.
+    farewell: Option<Message>,
+}
+
+#[derive(Debug)]
+struct Queued {
+    msg: Message,
+    update: bool,
+    /// What the message is the latest of, for a later one to replace while it is queued.
+    key: Option<String>,
+}
+
+/// The sending half of a [`queue`].
+#[derive(Debug)]
+pub struct QueueSender {
+    shared: Arc<Shared>,
+}
+
+impl QueueSender {
+    /// Returns how many messages are queued, waiting for the receiver.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().messages.len()
+    }
+
+    /// Returns whether no message is queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Queues `msg`, failing if the queue was closed.
+    pub fn push(&self, msg: Message) -> Result<(), QueueClosed> {
+        self.enqueue(Queued { msg, update: false, key: None })
+    }
+
+    /// Queues `msg`, an update a later one of its kind supersedes, e.g. a participant list,
+    /// so it is the first to be dropped if the queue overflows. Fails if it was closed.
+    pub fn push_update(&self, msg: Message) -> Result<(), QueueClosed> {
+        self.enqueue(Queued { msg, update: true, key: None })
+    }
+
+    /// Queues `msg` as an update like [`push_update`](Self::push_update), replacing the
+    /// message pushed with the same `key` if it is still queued, e.g. the previous partial
+    /// transcript of a speaker. Fails if the queue was closed.
+    pub fn push_superseding(&self, msg: Message, key: &str) -> Result<(), QueueClosed> {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed {
+                return Err(QueueClosed);
+            }
+            let queued = state.messages.iter_mut().find(|q| q.key.as_deref() == Some(key));
+            if let Some(queued) = queued {
+                queued.msg = msg;
+                return Ok(());
+            }
+        }
+        self.enqueue(Queued { msg, update: true, key: Some(key.to_string()) })
+    }
+
+    fn enqueue(&self, queued: Queued) -> Result<(), QueueClosed> {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed {
+                return Err(QueueClosed);
+            }
+            if state.messages.len() >= self.shared.capacity {
+                match self.shared.policy {
+                    OverflowPolicy::DropOldest => {
+                        let oldest = state.messages.iter().position(|queued| queued.update);
+                        state.messages.remove(oldest.unwrap_or(0));
+                        debug!("Dropped a message for a participant reading too slowly");
+                    }
+                    OverflowPolicy::DisconnectSlow => {
+                        debug!("Disconnecting a participant reading too slowly");
+                        let close = CloseFrame {
+                            code: CloseCode::from(4001),
+                            reason: "Slow consumer".into(),
+                        };
+                        state.messages.clear();
+                        state.closed = true;
+                        state.farewell = Some(Message::Close(Some(close)));
+                        drop(state);
+                        self.shared.waker.wake();
+                        return Err(QueueClosed);
+                    }
+                }
+            }
+            state.messages.push_back(queued);
+        }
+        self.shared.waker.wake();
+        Ok(())
+    }
+
+    /// Closes the queue: nothing can be pushed anymore, and the receiver ends once it took
+    /// what is queued already.
+    pub fn close(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.waker.wake();
+    }
+
+    /// Returns whether the queue was closed, by a sender, for overflowing or by dropping the
+    /// receiver.
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+}
+
+impl Clone for QueueSender {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        QueueSender { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.senders -= 1;
+            state.senders == 0
+        };
+        if last {
+            self.shared.waker.wake();
+        }
+    }
+}
+
+/// The receiving half of a [`queue`], a stream of the queued messages.
+#[derive(Debug)]
+pub struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+
+impl QueueReceiver {
+    /// Takes the next message if one is queued, without waiting.
+    pub fn try_recv(&mut self) -> Option<Message> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.messages.pop_front().map(|queued| queued.msg).or_else(|| state.farewell.take())
+    }
+
+    /// Returns how many messages are queued.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().messages.len()
+    }
+
+    /// Returns whether no message is queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Stream for QueueReceiver {
+    type Item = Message;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        // Registered before looking, so a push in between is not missed
+        self.shared.waker.register(cx.waker());
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(msg) = state.messages.pop_front().map(|queued| queued.msg) {
+            return Poll::Ready(Some(msg));
+        }
+        if let Some(farewell) = state.farewell.take() {
+            return Poll::Ready(Some(farewell));
+        }
+        if state.closed || state.senders == 0 {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        state.messages.clear();
+    }
+}
+
+/// Error returned when pushing to a [`queue`] that was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueClosed;
+
+impl fmt::Display for QueueClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The queue is closed")
+    }
+}
+
+impl Error for QueueClosed {}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use tungstenite::Message;
+
+    use super::{queue, OverflowPolicy, QueueClosed};
+
+    #[test]
+    fn overflowing_queues_drop_updates_first() {
+        let (tx, mut rx) = queue(3, OverflowPolicy::DropOldest);
+        tx.push_update(Message::text("count 1")).unwrap();
+        tx.push(Message::text("chat 1")).unwrap();
+        tx.push_update(Message::text("count 2")).unwrap();
+        tx.push(Message::text("chat 2")).unwrap();
+        assert_eq!(rx.len(), 3);
+        // Without updates left, the oldest message goes
+        tx.push(Message::text("chat 3")).unwrap();
+        tx.push(Message::text("chat 4")).unwrap();
+
+        assert_eq!(rx.try_recv(), Some(Message::text("chat 2")));
+        assert_eq!(rx.try_recv(), Some(Message::text("chat 3")));
+        assert_eq!(rx.try_recv(), Some(Message::text("chat 4")));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn superseding_messages_replace_the_queued_one() {
+        let (tx, mut rx) = queue(4, OverflowPolicy::DropOldest);
+        tx.push_superseding(Message::text("partial 1"), "alice").unwrap();
+        tx.push(Message::text("chat")).unwrap();
+        tx.push_superseding(Message::text("partial 2"), "alice").unwrap();
+        tx.push_superseding(Message::text("other"), "bob").unwrap();
+
+        assert_eq!(rx.try_recv(), Some(Message::text("partial 2")));
+        // Once sent, the next one is queued anew
+        tx.push_superseding(Message::text("partial 3"), "alice").unwrap();
+        assert_eq!(rx.try_recv(), Some(Message::text("chat")));
+        assert_eq!(rx.try_recv(), Some(Message::text("other")));
+        assert_eq!(rx.try_recv(), Some(Message::text("partial 3")));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[tokio::test]
+    async fn slow_participants_can_be_disconnected() {
+        let (tx, mut rx) = queue(2, OverflowPolicy::DisconnectSlow);
+        let other = tx.clone();
+        tx.push(Message::text("chat 1")).unwrap();
+        tx.push(Message::text("chat 2")).unwrap();
+        assert_eq!(other.push(Message::text("chat 3")), Err(QueueClosed));
+        assert!(tx.is_closed());
+
+        match rx.next().await {
+            Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 4001),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        assert_eq!(rx.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn queues_end_with_their_senders() {
+        let (tx, mut rx) = queue(4, OverflowPolicy::DropOldest);
+        let other = tx.clone();
+        tx.push(Message::text("last")).unwrap();
+        drop(tx);
+        drop(other);
+        assert_eq!(rx.next().await, Some(Message::text("last")));
+        assert_eq!(rx.next().await, None);
+    }
+}
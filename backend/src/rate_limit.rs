@@ -0,0 +1,112 @@
+//! Limiting how fast a participant may send messages.
+use std::time::Instant;
+
+use serde_json::{json, Value};
+use tungstenite::Message;
+
+/// The rate a participant may send messages at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// How many messages may be sent at once, after being quiet for a while.
+    pub capacity: u32,
+    /// How many messages per second may be sent in the long run.
+    pub refill_rate: f64,
+}
+
+impl RateLimit {
+    /// Returns the `rate_limited` notice telling a participant that its message was dropped
+    /// for exceeding the limit.
+    pub fn exceeded_notice() -> Value {
+        json!({
+            "type": "rate_limited",
+            "message": "You are sending messages too fast, this one was dropped"
+        })
+    }
+}
+
+/// A token bucket enforcing a [`RateLimit`].
+///
+/// The bucket starts out full with `capacity` tokens and every message takes one. Tokens are
+/// given back at `refill_rate` per second, up to `capacity`, so a participant can send a burst
+/// of messages at once, but not flood the room for long.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(limit: RateLimit) -> Self {
+        TokenBucket { limit, tokens: limit.capacity.into(), last_refill: Instant::now() }
+    }
+
+    /// Takes a token for a message, returning whether the message is within the limit.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    /// Takes a token for `msg` like [`try_acquire`](Self::try_acquire) if it is chat, i.e. a
+    /// text or binary frame. Control frames are not limited, so they pass without one.
+    pub fn admit(&mut self, msg: &Message) -> bool {
+        !(msg.is_text() || msg.is_binary()) || self.try_acquire()
+    }
+
+    /// Returns the number of tokens left as of the last message, i.e. how many more messages
+    /// could have been sent at once.
+    pub fn tokens(&self) -> f64 {
+        self.tokens
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.limit.refill_rate).min(self.limit.capacity.into());
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tungstenite::Message;
+
+    use super::{RateLimit, TokenBucket};
+
+    #[test]
+    fn bursts_are_allowed_but_not_sustained() {
+        let mut bucket = TokenBucket::new(RateLimit { capacity: 10, refill_rate: 2.0 });
+        let start = Instant::now();
+
+        assert!((0..10).all(|_| bucket.try_acquire_at(start)));
+        assert!(!bucket.try_acquire_at(start));
+
+        // Half a second gives one token back
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_acquire_at(later));
+        assert!(!bucket.try_acquire_at(later));
+
+        // Refills never exceed the burst
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!((0..20).filter(|_| bucket.try_acquire_at(much_later)).count(), 10);
+    }
+
+    #[test]
+    fn control_frames_are_not_limited() {
+        let mut bucket = TokenBucket::new(RateLimit { capacity: 1, refill_rate: 0.0 });
+
+        assert!(bucket.admit(&Message::text("hi")));
+        assert!(!bucket.admit(&Message::binary(vec![0; 4])));
+        assert!(bucket.admit(&Message::Ping(Default::default())));
+        assert!(bucket.admit(&Message::Pong(Default::default())));
+    }
+}
@@ -0,0 +1,185 @@
+//! Log records of the connections and handshakes a server turns away.
+use std::{error::Error, fmt, net::IpAddr, str::FromStr, time::Duration};
+
+use serde_json::json;
+use tungstenite::http::StatusCode;
+
+/// How log records meant for operators are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// A sentence for people reading the log, e.g. during development.
+    #[default]
+    Human,
+    /// A JSON object per line for log pipelines.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = InvalidLogFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(InvalidLogFormat(s.to_string())),
+        }
+    }
+}
+
+/// Error returned when a string names no [`LogFormat`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidLogFormat(String);
+
+impl fmt::Display for InvalidLogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is not a log format, expected 'human' or 'json'", self.0)
+    }
+}
+
+impl Error for InvalidLogFormat {}
+
+/// A connection or handshake turned away by the server, with the same fields whichever check
+/// rejected it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    /// A stable identifier of the check, e.g. `name_banned`.
+    pub reason_code: &'static str,
+    /// The message sent to the client, or logged if the connection was dropped without one.
+    pub message: String,
+    /// The status of the response, `None` if the connection was dropped before the handshake.
+    pub status: Option<StatusCode>,
+    /// The address of the client.
+    pub ip: IpAddr,
+    /// The name the client asked for, if it got as far as telling.
+    pub name: Option<String>,
+    /// The room the client asked for, if it got as far as telling.
+    pub room: Option<String>,
+    /// How long the client should wait before trying again, sent as `Retry-After`, for
+    /// rejections over capacity.
+    pub retry_after: Option<Duration>,
+}
+
+impl Rejection {
+    /// Creates a rejection of a client which has not told its name or room yet.
+    pub fn new(reason_code: &'static str, message: impl Into<String>, ip: IpAddr) -> Self {
+        Rejection {
+            reason_code,
+            message: message.into(),
+            status: None,
+            ip,
+            name: None,
+            room: None,
+            retry_after: None,
+        }
+    }
+
+    /// Sets the status of the response.
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Sets the room the client asked for.
+    pub fn room(mut self, room: impl Into<String>) -> Self {
+        self.room = Some(room.into());
+        self
+    }
+
+    /// Sets the name the client asked for.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets how long the client should wait before trying again.
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    /// Returns the value of the `Retry-After` header, in whole seconds rounded up.
+    pub fn retry_after_header(&self) -> Option<String> {
+        let retry_after = self.retry_after?;
+        Some((retry_after.as_secs_f64().ceil() as u64).to_string())
+    }
+
+    /// Logs the rejection as a warning event with its fields, for the [`Logger`](crate::Logger)
+    /// to write in the format of the server.
+    pub fn log(&self) {
+        tracing::warn!(
+            reason_code = self.reason_code,
+            status = self.status.map(|status| status.as_u16()),
+            ip = %self.ip,
+            name = self.name.as_deref(),
+            room = self.room.as_deref(),
+            "Rejected: {}",
+            self.message
+        );
+    }
+
+    /// Renders the log record of the rejection in `format`.
+    pub fn render(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Human => {
+                let mut line = format!("Rejected {}", self.ip);
+                if let Some(name) = &self.name {
+                    line.push_str(&format!(" as '{}'", name));
+                }
+                if let Some(room) = &self.room {
+                    line.push_str(&format!(" in room '{}'", room));
+                }
+                line.push_str(&format!(": {} ({}", self.message, self.reason_code));
+                if let Some(status) = self.status {
+                    line.push_str(&format!(", {}", status.as_u16()));
+                }
+                line.push(')');
+                line
+            }
+            LogFormat::Json => json!({
+                "event": "rejection",
+                "reason_code": self.reason_code,
+                "message": self.message,
+                "status": self.status.map(|status| status.as_u16()),
+                "ip": self.ip.to_string(),
+                "name": self.name,
+                "room": self.room,
+            })
+            .to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tungstenite::http::StatusCode;
+
+    use super::{LogFormat, Rejection};
+
+    #[test]
+    fn rejections_render_the_same_fields_in_either_format() {
+        let rejection =
+            Rejection::new("name_in_use", "Name 'Alice' is already in use", [127, 0, 0, 1].into())
+                .status(StatusCode::CONFLICT)
+                .room("main")
+                .name("Alice");
+
+        assert_eq!(
+            rejection.render(LogFormat::Human),
+            "Rejected 127.0.0.1 as 'Alice' in room 'main': Name 'Alice' is already in use (name_in_use, 409)"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&rejection.render(LogFormat::Json)).unwrap();
+        assert_eq!(json["reason_code"], "name_in_use");
+        assert_eq!(json["status"], 409);
+        assert_eq!(json["ip"], "127.0.0.1");
+        assert_eq!(json["name"], "Alice");
+        assert_eq!(json["room"], "main");
+
+        let dropped = Rejection::new("ip_banned", "Address banned", [10, 0, 0, 1].into());
+        assert_eq!(
+            dropped.render(LogFormat::Human),
+            "Rejected 10.0.0.1: Address banned (ip_banned)"
+        );
+        assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+    }
+}
@@ -0,0 +1,670 @@
+//! Relaying messages between a participant's socket and its outbound queue.
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::{
+    future::{self, Either},
+    pin_mut,
+    stream::{self, PollNext},
+    SinkExt, Stream, StreamExt, TryStreamExt,
+};
+use log::*;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Instant,
+};
+use tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Error as WsError, Message,
+};
+
+use crate::{Shutdown, WebSocketStream};
+
+/// Merges a participant's queue of control messages with its queue of ordinary ones, for use
+/// as the `outbound` stream of [`relay`].
+///
+/// Whenever both queues have messages waiting, control messages (e.g. notices about the room
+/// or the connection) are sent first, so they are not held up by a backlog of chat. The merged
+/// stream ends once both queues have ended.
+pub fn prioritized<H, N>(control: H, normal: N) -> impl Stream<Item = Message> + Unpin
+where
+    H: Stream<Item = Message> + Unpin,
+    N: Stream<Item = Message> + Unpin,
+{
+    stream::select_with_strategy(control, normal, |_: &mut ()| PollNext::Left)
+}
+
+/// Interleaves `outbound` with `{"type":"keepalive"}` messages, for use as the `outbound`
+/// stream of [`relay`].
+///
+/// A keepalive is sent whenever nothing was sent for `interval`, so reverse proxies do not
+/// close connections that are merely quiet. Keepalives only flow to the participant, so they
+/// tell nothing about whether the participant itself is still there. The stream ends once
+/// `outbound` ends.
+pub fn keepalive<R>(outbound: R, interval: Duration) -> impl Stream<Item = Message> + Unpin
+where
+    R: Stream<Item = Message> + Unpin,
+{
+    Box::pin(stream::unfold(outbound, move |mut outbound| async move {
+        match tokio::time::timeout(interval, outbound.next()).await {
+            Ok(Some(msg)) => Some((msg, outbound)),
+            Ok(None) => None,
+            Err(_) => Some((Message::text(r#"{"type":"keepalive"}"#), outbound)),
+        }
+    }))
+}
+
+/// When a participant last answered a ping, shared between [`heartbeat`] and whoever reads the
+/// participant's socket. Cloning it gives another handle on the same participant.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    last_pong: Arc<Mutex<Instant>>,
+}
+
+impl Heartbeat {
+    /// Creates a heartbeat for a participant which just connected, as if it had just answered.
+    pub fn new() -> Self {
+        Heartbeat { last_pong: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    /// Notes that a pong arrived from the participant.
+    pub fn pong(&self) {
+        *self.last_pong.lock().unwrap() = Instant::now();
+    }
+
+    fn answered_since(&self, ping: Instant) -> bool {
+        *self.last_pong.lock().unwrap() >= ping
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat::new()
+    }
+}
+
+/// Interleaves `outbound` with a ping every `interval`, and ends it once a ping goes
+/// unanswered for `timeout`, for use as the `outbound` stream of [`relay`].
+///
+/// Pongs are reported with [`Heartbeat::pong`] by the reader of the socket. A participant that
+/// misses one, e.g. because its network went away without a close frame, is sent a close
+/// frame with code `4002` and [`relay`] disconnects it like any other. Nothing is spawned, so
+/// the pings stop with the connection however it ends. The stream ends once `outbound` ends.
+pub fn heartbeat<R>(
+    outbound: R,
+    heartbeat: Heartbeat,
+    interval: Duration,
+    timeout: Duration,
+) -> impl Stream<Item = Message> + Unpin
+where
+    R: Stream<Item = Message> + Unpin,
+{
+    let state = (Some(outbound), Instant::now() + interval, None);
+    let messages = stream::unfold(state, move |(outbound, mut next_ping, mut pending)| {
+        let heartbeat = heartbeat.clone();
+        async move {
+            let mut outbound = outbound?;
+            loop {
+                // The oldest unanswered ping is the one that times out
+                let wake = match pending {
+                    Some(ping) => next_ping.min(ping + timeout),
+                    None => next_ping,
+                };
+                match tokio::time::timeout_at(wake, outbound.next()).await {
+                    Ok(Some(msg)) => return Some((msg, (Some(outbound), next_ping, pending))),
+                    Ok(None) => return None,
+                    Err(_) => {}
+                }
+
+                let now = Instant::now();
+                if let Some(ping) = pending.filter(|ping| !heartbeat.answered_since(*ping)) {
+                    if now >= ping + timeout {
+                        let close = CloseFrame {
+                            code: CloseCode::from(4002),
+                            reason: "Heartbeat timeout".into(),
+                        };
+                        return Some((Message::Close(Some(close)), (None, next_ping, pending)));
+                    }
+                } else {
+                    pending = None;
+                }
+                if now >= next_ping {
+                    next_ping = now + interval;
+                    let pending = pending.or(Some(now));
+                    return Some((
+                        Message::Ping(Default::default()),
+                        (Some(outbound), next_ping, pending),
+                    ));
+                }
+            }
+        }
+    });
+    Box::pin(messages)
+}
+
+/// Ends `outbound` once `lifetime` has passed, for use as the `outbound` stream of [`relay`].
+///
+/// When the time is up, the participant is sent `{"type":"reconnect_required"}` followed by a
+/// close frame with code `4000`, so it reconnects (and authenticates again) rather than
+/// treating the disconnect as an error. This caps how long a connection can outlive the
+/// credentials it was opened with.
+pub fn max_lifetime<R>(outbound: R, lifetime: Duration) -> impl Stream<Item = Message> + Unpin
+where
+    R: Stream<Item = Message> + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + lifetime;
+    let messages = stream::unfold(Some(outbound), move |outbound| async move {
+        let mut outbound = outbound?;
+        match tokio::time::timeout_at(deadline, outbound.next()).await {
+            Ok(Some(msg)) => Some((vec![msg], Some(outbound))),
+            Ok(None) => None,
+            Err(_) => {
+                let close = CloseFrame {
+                    code: CloseCode::from(4000),
+                    reason: "Connection lifetime exceeded".into(),
+                };
+                let notice = Message::text(r#"{"type":"reconnect_required"}"#);
+                Some((vec![notice, Message::Close(Some(close))], None))
+            }
+        }
+    });
+    Box::pin(messages.flat_map(stream::iter))
+}
+
+/// When a participant last sent a message of its own, shared between [`idle_timeout`] and
+/// whoever reads the participant's socket. Cloning it gives another handle on the same
+/// participant.
+#[derive(Debug, Clone)]
+pub struct Activity {
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl Activity {
+    /// Creates the activity of a participant which just connected, as if it had just sent
+    /// something.
+    pub fn new() -> Self {
+        Activity { last_active: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    /// Notes that the participant sent a message.
+    pub fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    /// Notes that the participant sent `msg` like [`touch`](Self::touch), unless it is a
+    /// control frame: those are mostly answered by the client library, so they do not count.
+    pub fn observe(&self, msg: &Message) {
+        if msg.is_text() || msg.is_binary() {
+            self.touch();
+        }
+    }
+
+    fn last_active(&self) -> Instant {
+        *self.last_active.lock().unwrap()
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity::new()
+    }
+}
+
+/// Ends `outbound` once the participant was idle for `timeout`, for use as the `outbound`
+/// stream of [`relay`].
+///
+/// Activity is reported with [`Activity::touch`] by the reader of the socket, for the
+/// messages the participant sends itself. What `outbound` yields does not count: a
+/// participant only listening to a busy room is as idle as one in an empty room, and holds
+/// its name and place just the same. When the time is up, the participant is sent `notice`,
+/// e.g. `{"type":"idle_timeout"}` in its encoding, followed by a close frame with code `4003`.
+pub fn idle_timeout<R>(
+    outbound: R,
+    activity: Activity,
+    timeout: Duration,
+    notice: Message,
+) -> impl Stream<Item = Message> + Unpin
+where
+    R: Stream<Item = Message> + Unpin,
+{
+    let messages = stream::unfold(Some((outbound, notice)), move |state| {
+        let activity = activity.clone();
+        async move {
+            let (mut outbound, notice) = state?;
+            loop {
+                let deadline = activity.last_active() + timeout;
+                match tokio::time::timeout_at(deadline, outbound.next()).await {
+                    Ok(Some(msg)) => return Some((vec![msg], Some((outbound, notice)))),
+                    Ok(None) => return None,
+                    // The participant was active while waiting, so the deadline moved
+                    Err(_) if activity.last_active() + timeout > Instant::now() => {}
+                    Err(_) => break,
+                }
+            }
+            let close = CloseFrame { code: CloseCode::from(4003), reason: "Idle timeout".into() };
+            Some((vec![notice, Message::Close(Some(close))], None))
+        }
+    });
+    Box::pin(messages.flat_map(stream::iter))
+}
+
+/// Ends `outbound` once `shutdown` is triggered, for use as the `outbound` stream of
+/// [`relay`].
+///
+/// The participant is then sent `notice`, e.g. `{"type":"server_shutdown"}` in its encoding,
+/// and a close frame with code `1001`, right away: whatever is still queued in `outbound` is
+/// dropped rather than flushed, so a participant with a backlog learns about the shutdown and
+/// is closed as promptly as the others.
+pub fn until_shutdown<R>(
+    outbound: R,
+    shutdown: Shutdown,
+    notice: Message,
+) -> impl Stream<Item = Message> + Unpin
+where
+    R: Stream<Item = Message> + Unpin,
+{
+    let messages = stream::unfold(Some((outbound, notice)), move |state| {
+        let shutdown = shutdown.clone();
+        async move {
+            let (mut outbound, notice) = state?;
+            let closing = {
+                let triggered = shutdown.triggered();
+                pin_mut!(triggered);
+                // A triggered shutdown wins over anything queued
+                match future::select(triggered, outbound.next()).await {
+                    Either::Left(_) => true,
+                    Either::Right((Some(msg), _)) => {
+                        return Some((vec![msg], Some((outbound, notice))))
+                    }
+                    Either::Right((None, _)) => false,
+                }
+            };
+            if !closing {
+                return None;
+            }
+            let close = CloseFrame { code: CloseCode::Away, reason: "Server shutting down".into() };
+            Some((vec![notice, Message::Close(Some(close))], None))
+        }
+    });
+    Box::pin(messages.flat_map(stream::iter))
+}
+
+/// Coalesces the text messages `outbound` yields within `window` of each other into a single
+/// `{"type":"batch","messages":[...]}` frame, for use as the `outbound` stream of [`relay`].
+///
+/// Busy rooms otherwise write every small message as a frame of its own. Messages which are
+/// JSON are batched as their value, other text as a string. Binary and control frames are
+/// never batched: they end the current batch and are sent as is, so the order of the messages
+/// is kept. A lone message is sent as is too. The stream ends once `outbound` ends.
+pub fn batch<R>(outbound: R, window: Duration) -> impl Stream<Item = Message> + Unpin
+where
+    R: Stream<Item = Message> + Unpin,
+{
+    let batches = stream::unfold((Some(outbound), None), move |(outbound, held)| async move {
+        let mut outbound = outbound?;
+        let first = match held {
+            Some(msg) => msg,
+            None => outbound.next().await?,
+        };
+        if !first.is_text() {
+            return Some((vec![first], (Some(outbound), None)));
+        }
+
+        let deadline = tokio::time::Instant::now() + window;
+        let mut texts = vec![first];
+        let (mut outbound, mut held) = (Some(outbound), None);
+        while let Some(stream) = outbound.as_mut() {
+            match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(Some(msg)) if msg.is_text() => texts.push(msg),
+                Ok(Some(msg)) => {
+                    held = Some(msg);
+                    break;
+                }
+                Ok(None) => outbound = None,
+                Err(_) => break,
+            }
+        }
+
+        let msgs = if texts.len() == 1 { texts } else { vec![batch_frame(texts)] };
+        Some((msgs, (outbound, held)))
+    });
+    Box::pin(batches.flat_map(stream::iter))
+}
+
+fn batch_frame(texts: Vec<Message>) -> Message {
+    let messages: Vec<serde_json::Value> = texts
+        .into_iter()
+        .filter_map(|msg| msg.into_text().ok())
+        .map(|text| serde_json::from_str(&text).unwrap_or_else(|_| text.as_str().into()))
+        .collect();
+    Message::text(serde_json::json!({ "type": "batch", "messages": messages }).to_string())
+}
+
+/// Drives a participant's connection until it is over.
+///
+/// Every message read from `ws_stream` is handed to `on_message`, while messages produced by
+/// `outbound` are written to the socket. A close frame from the participant is not handed on,
+/// it ends the reading instead. Once either side stops, `on_disconnect` is called exactly once
+/// so the caller can stop queueing messages for the participant, typically by removing it
+/// from its room and closing the sending half of `outbound`.
+///
+/// If the participant stopped sending while the socket is still writable (e.g. after sending
+/// an invalid frame), whatever is still queued in `outbound` is flushed to the socket, waiting
+/// at most `drain_timeout`. The socket is closed with a proper close frame in any case, so a
+/// disconnect does not truncate the messages sent to the participant just before. Nothing is
+/// written after a close frame from `outbound`, e.g. the one [`until_shutdown`] ends with.
+///
+/// A participant that does not take a message within `write_timeout` is a slow consumer: it
+/// is disconnected with close code `4001`, which is itself given at most `write_timeout` to
+/// get through, so a stalled client holds up nothing for longer than that.
+pub async fn relay<S, R, F, D>(
+    ws_stream: WebSocketStream<S>,
+    mut outbound: R,
+    mut on_message: F,
+    on_disconnect: D,
+    drain_timeout: Duration,
+    write_timeout: Option<Duration>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: Stream<Item = Message> + Unpin,
+    F: FnMut(Message),
+    D: FnOnce(),
+{
+    let (mut outgoing, mut incoming) = ws_stream.split();
+    let mut stalled = false;
+
+    {
+        let receive_incoming = async {
+            while let Some(msg) = incoming.try_next().await? {
+                if let Message::Close(frame) = msg {
+                    match frame {
+                        Some(frame) => debug!(
+                            "Participant closed the connection: {} {}",
+                            u16::from(frame.code),
+                            frame.reason
+                        ),
+                        None => debug!("Participant closed the connection"),
+                    }
+                    break;
+                }
+                on_message(msg);
+            }
+            Ok::<_, WsError>(())
+        };
+        let forward_outbound = async {
+            while let Some(msg) = outbound.next().await {
+                // Nothing may follow a close frame
+                let closing = msg.is_close();
+                match write_timeout {
+                    Some(write_timeout) => tokio::time::timeout(write_timeout, outgoing.send(msg))
+                        .await
+                        .map_err(|_| WsError::Io(io::ErrorKind::TimedOut.into()))??,
+                    None => outgoing.send(msg).await?,
+                }
+                if closing {
+                    break;
+                }
+            }
+            Ok::<_, WsError>(())
+        };
+
+        pin_mut!(receive_incoming, forward_outbound);
+        match future::select(receive_incoming, forward_outbound).await {
+            Either::Left((result, forward_outbound)) => {
+                if let Err(e) = result {
+                    debug!("Participant stopped sending: {}", e);
+                }
+                on_disconnect();
+
+                match tokio::time::timeout(drain_timeout, forward_outbound).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        stalled = is_stalled(&e);
+                        debug!("Failed to flush the pending messages: {}", e);
+                    }
+                    Err(_) => debug!("Gave up flushing the pending messages"),
+                }
+            }
+            Either::Right((result, _)) => {
+                if let Err(e) = result {
+                    stalled = is_stalled(&e);
+                    debug!("Failed to write to participant: {}", e);
+                }
+                on_disconnect();
+            }
+        }
+    }
+
+    if let (true, Some(write_timeout)) = (stalled, write_timeout) {
+        debug!("Disconnecting a slow consumer");
+        let close = CloseFrame { code: CloseCode::from(4001), reason: "Slow consumer".into() };
+        let _ =
+            tokio::time::timeout(write_timeout, outgoing.send(Message::Close(Some(close)))).await;
+        return;
+    }
+
+    if let Err(e) = outgoing.close().await {
+        debug!("Failed to close the connection: {}", e);
+    }
+}
+
+fn is_stalled(e: &WsError) -> bool {
+    matches!(e, WsError::Io(e) if e.kind() == io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures_util::{SinkExt, StreamExt};
+    use tungstenite::{
+        protocol::{Role, WebSocketConfig},
+        Message,
+    };
+
+    use super::{
+        batch, heartbeat, idle_timeout, keepalive, max_lifetime, prioritized, relay,
+        until_shutdown, Activity, Heartbeat,
+    };
+    use crate::{Shutdown, WebSocketStream};
+
+    #[tokio::test]
+    async fn pending_messages_are_flushed_on_disconnect() {
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let config = WebSocketConfig::default().max_message_size(Some(16));
+        let server = WebSocketStream::from_raw_socket(server_io, Role::Server, Some(config)).await;
+        let mut client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+
+        let (tx, rx) = futures_channel::mpsc::unbounded();
+        tx.unbounded_send(Message::text("before")).unwrap();
+
+        let relay = tokio::spawn(relay(
+            server,
+            rx,
+            |_| {},
+            move || {
+                tx.unbounded_send(Message::text("last")).unwrap();
+                tx.close_channel();
+            },
+            Duration::from_secs(5),
+            None,
+        ));
+
+        // An oversized message makes the server stop reading from the participant.
+        client.send(Message::text("x".repeat(64))).await.unwrap();
+
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("before"));
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text("last"));
+        assert!(matches!(client.next().await, Some(Ok(Message::Close(_)))));
+        relay.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_readers_are_disconnected() {
+        let (_client_io, server_io) = tokio::io::duplex(64);
+        let server = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+
+        let (tx, rx) = futures_channel::mpsc::unbounded();
+        for _ in 0..16 {
+            tx.unbounded_send(Message::text("x".repeat(32))).unwrap();
+        }
+        let disconnected = Arc::new(AtomicBool::new(false));
+        let on_disconnect = {
+            let disconnected = disconnected.clone();
+            move || disconnected.store(true, Ordering::SeqCst)
+        };
+
+        // The client never reads, so the socket stops taking writes once its buffer is full.
+        let relay = relay(
+            server,
+            rx,
+            |_| {},
+            on_disconnect,
+            Duration::from_secs(5),
+            Some(Duration::from_secs(1)),
+        );
+        tokio::time::timeout(Duration::from_secs(10), relay).await.expect("relay gave up");
+        assert!(disconnected.load(Ordering::SeqCst));
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn control_messages_jump_the_queue() {
+        let (control_tx, control_rx) = futures_channel::mpsc::unbounded();
+        let (normal_tx, normal_rx) = futures_channel::mpsc::unbounded();
+        normal_tx.unbounded_send(Message::text("chat 1")).unwrap();
+        normal_tx.unbounded_send(Message::text("chat 2")).unwrap();
+        control_tx.unbounded_send(Message::text("closing")).unwrap();
+        drop((control_tx, normal_tx));
+
+        let sent: Vec<_> = prioritized(control_rx, normal_rx).collect().await;
+        assert_eq!(sent, ["closing", "chat 1", "chat 2"].map(Message::text));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_connections_get_keepalives() {
+        let (tx, rx) = futures_channel::mpsc::unbounded();
+        let mut outbound = keepalive(rx, Duration::from_secs(30));
+        let keepalive = Message::text(r#"{"type":"keepalive"}"#);
+
+        tx.unbounded_send(Message::text("chat")).unwrap();
+        assert_eq!(outbound.next().await, Some(Message::text("chat")));
+        assert_eq!(outbound.next().await, Some(keepalive.clone()));
+        assert_eq!(outbound.next().await, Some(keepalive));
+
+        drop(tx);
+        assert_eq!(outbound.next().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_pings_close_the_connection() {
+        let (tx, rx) = futures_channel::mpsc::unbounded();
+        let pongs = Heartbeat::new();
+        let interval = Duration::from_secs(30);
+        let mut outbound = heartbeat(rx, pongs.clone(), interval, Duration::from_secs(10));
+        let start = tokio::time::Instant::now();
+
+        tx.unbounded_send(Message::text("chat")).unwrap();
+        assert_eq!(outbound.next().await, Some(Message::text("chat")));
+        assert!(matches!(outbound.next().await, Some(Message::Ping(_))));
+        pongs.pong();
+        assert!(matches!(outbound.next().await, Some(Message::Ping(_))));
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+
+        // Nobody answers the second ping
+        match outbound.next().await {
+            Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 4002),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(70));
+        assert_eq!(outbound.next().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_are_closed_at_the_end_of_their_lifetime() {
+        let (tx, rx) = futures_channel::mpsc::unbounded();
+        let mut outbound = max_lifetime(rx, Duration::from_secs(3600));
+
+        tx.unbounded_send(Message::text("chat")).unwrap();
+        assert_eq!(outbound.next().await, Some(Message::text("chat")));
+        assert_eq!(outbound.next().await, Some(Message::text(r#"{"type":"reconnect_required"}"#)));
+        match outbound.next().await {
+            Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 4000),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        assert_eq!(outbound.next().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_participants_are_disconnected() {
+        let (tx, rx) = futures_channel::mpsc::unbounded();
+        let activity = Activity::new();
+        let notice = Message::text(r#"{"type":"idle_timeout"}"#);
+        let mut outbound = idle_timeout(rx, activity.clone(), Duration::from_secs(60), notice);
+        let start = tokio::time::Instant::now();
+
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        activity.touch();
+        // Messages to the participant do not keep it active
+        tx.unbounded_send(Message::text("chat")).unwrap();
+        assert_eq!(outbound.next().await, Some(Message::text("chat")));
+
+        assert_eq!(outbound.next().await, Some(Message::text(r#"{"type":"idle_timeout"}"#)));
+        assert_eq!(start.elapsed(), Duration::from_secs(100));
+        match outbound.next().await {
+            Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 4003),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        assert_eq!(outbound.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn shutdowns_skip_the_backlog() {
+        let (tx, rx) = futures_channel::mpsc::unbounded();
+        let shutdown = Shutdown::new();
+        let notice = Message::text(r#"{"type":"server_shutdown"}"#);
+        let mut outbound = until_shutdown(rx, shutdown.clone(), notice.clone());
+
+        tx.unbounded_send(Message::text("chat")).unwrap();
+        assert_eq!(outbound.next().await, Some(Message::text("chat")));
+        for _ in 0..1000 {
+            tx.unbounded_send(Message::text("backlog")).unwrap();
+        }
+        shutdown.trigger();
+        assert_eq!(outbound.next().await, Some(notice));
+        match outbound.next().await {
+            Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 1001),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        assert_eq!(outbound.next().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_within_the_window_are_batched() {
+        let (tx, rx) = futures_channel::mpsc::unbounded();
+        let mut outbound = batch(rx, Duration::from_millis(5));
+
+        tx.unbounded_send(Message::text(r#"{"type":"chat","text":"hi"}"#)).unwrap();
+        tx.unbounded_send(Message::text("plain")).unwrap();
+        tx.unbounded_send(Message::binary(vec![1])).unwrap();
+        tx.unbounded_send(Message::text("alone")).unwrap();
+        let batched = r#"{"messages":[{"text":"hi","type":"chat"},"plain"],"type":"batch"}"#;
+        assert_eq!(outbound.next().await, Some(Message::text(batched)));
+        assert_eq!(outbound.next().await, Some(Message::binary(vec![1])));
+        assert_eq!(outbound.next().await, Some(Message::text("alone")));
+
+        tx.unbounded_send(Message::text("last")).unwrap();
+        drop(tx);
+        assert_eq!(outbound.next().await, Some(Message::text("last")));
+        assert_eq!(outbound.next().await, None);
+    }
+}
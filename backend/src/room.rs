@@ -0,0 +1,1414 @@
+//! Rooms of participants and the messages a server sends to them.
+use std::{
+    collections::{hash_map, HashMap, VecDeque},
+    error::Error,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::AtomicU64, Arc, Mutex, MutexGuard, RwLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use serde_json::{json, Map, Value};
+use tokio::time::Instant;
+use tungstenite::Message;
+
+use crate::{
+    same_name, sanitize_text, unique_name, Encoding, LanguageCode, OutboundPipeline, QueueClosed,
+    QueueSender, Recipient, RemoteRoster, Translations,
+};
+
+/// What a participant may do in its room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RoomRole {
+    /// Moderates, and is the only one who may hand the ownership over. Every room has one.
+    Owner,
+    /// May pause and resume the room and change the roles of others.
+    Moderator,
+    /// May speak and nothing else.
+    #[default]
+    Participant,
+}
+
+impl RoomRole {
+    /// Parses the name of a role as sent by clients, e.g. `moderator`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "owner" => Some(RoomRole::Owner),
+            "moderator" => Some(RoomRole::Moderator),
+            "participant" => Some(RoomRole::Participant),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the role as sent to clients.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RoomRole::Owner => "owner",
+            RoomRole::Moderator => "moderator",
+            RoomRole::Participant => "participant",
+        }
+    }
+
+    /// Returns whether the role may moderate the room.
+    pub fn moderates(self) -> bool {
+        self != RoomRole::Participant
+    }
+}
+
+/// How a participant wants the messages of the others delivered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Delivery {
+    /// As they were sent.
+    #[default]
+    Text,
+    /// Spoken, if the server has a synthesizer.
+    Audio,
+    /// As they were sent, and spoken.
+    Both,
+}
+
+impl Delivery {
+    /// Parses the name of a delivery as sent by clients, e.g. `audio`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "text" => Some(Delivery::Text),
+            "audio" => Some(Delivery::Audio),
+            "both" => Some(Delivery::Both),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the participant wants the messages as they were sent.
+    pub fn wants_text(self) -> bool {
+        self != Delivery::Audio
+    }
+
+    /// Returns whether the participant wants the messages spoken.
+    pub fn wants_audio(self) -> bool {
+        self != Delivery::Text
+    }
+}
+
+/// Traffic of a connection, for diagnostics.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    /// Bytes of the messages the participant sent.
+    pub bytes_in: AtomicU64,
+    /// Bytes of the messages sent to the participant.
+    pub bytes_out: AtomicU64,
+    /// The [tokens](crate::TokenBucket::tokens) the participant had left as of its last
+    /// message, if its messages are rate limited.
+    pub rate_limit_tokens: Mutex<Option<f64>>,
+}
+
+/// A participant of a room, with the queues of its connection.
+#[derive(Debug, Clone)]
+pub struct Participant {
+    /// The id the server assigned to the connection.
+    pub id: u64,
+    /// The display name, unique in the room.
+    pub name: String,
+    /// Queue of the chat relayed to the participant.
+    pub sender: QueueSender,
+    /// Queue of the room's own messages, sent ahead of the chat queued in `sender`.
+    pub control: QueueSender,
+    /// How the room's own messages are serialized for the participant. Defaults to JSON.
+    pub encoding: Encoding,
+    /// What the participant may do in the room. Defaults to [`RoomRole::Participant`].
+    pub role: RoomRole,
+    /// Increases with every join, so clients can sort the roster by join time. Defaults to
+    /// the connection id.
+    pub joined_seq: u64,
+    /// What the participant told about itself, e.g. its color.
+    pub meta: Map<String, Value>,
+    /// The room as requested by the participant, if that was an alias of the room it is in.
+    pub room_name: Option<String>,
+    /// The language the participant speaks, if it is transcribed.
+    pub transcribe_to: Option<LanguageCode>,
+    /// Languages the participant reads, most preferred first.
+    pub translate_to: Vec<LanguageCode>,
+    /// How the participant wants the messages of the others delivered. Defaults to text.
+    pub delivery: Delivery,
+    /// UTC offset the participant wants local timestamps in, RFC3339 only by default.
+    pub timezone: Option<FixedOffset>,
+    /// When the participant joined.
+    pub joined_at: DateTime<Utc>,
+    /// Traffic of the participant's connection.
+    pub stats: Arc<ConnectionStats>,
+    /// The session the client told to recognize it by when it reconnects, see
+    /// [`RoomManager::reconnect_grace`].
+    pub session_id: Option<String>,
+    /// Whether the participant only follows the room, e.g. as a guest, so it takes a place
+    /// of [`RoomManager::max_spectators`] instead of [`RoomManager::max_members`].
+    pub spectator: bool,
+}
+
+impl Participant {
+    /// Creates a participant joining now, with the defaults of everything it may choose.
+    pub fn new(
+        id: u64,
+        name: impl Into<String>,
+        sender: QueueSender,
+        control: QueueSender,
+    ) -> Self {
+        Participant {
+            id,
+            name: name.into(),
+            sender,
+            control,
+            encoding: Encoding::Json,
+            role: RoomRole::Participant,
+            joined_seq: id,
+            meta: Map::new(),
+            room_name: None,
+            transcribe_to: None,
+            translate_to: Vec::new(),
+            delivery: Delivery::Text,
+            timezone: None,
+            joined_at: Utc::now(),
+            stats: Arc::default(),
+            session_id: None,
+            spectator: false,
+        }
+    }
+
+    /// Describes the participant in the roster, with the keys clients sort it by.
+    pub fn roster_entry(&self) -> Value {
+        json!({
+            "id": self.id.to_string(),
+            "name": sanitize_text(&self.name),
+            "joined_seq": self.joined_seq,
+            "role": self.role.as_str(),
+            "meta": self.meta
+        })
+    }
+
+    /// Returns what the outbound transforms know about the participant of `room_id`.
+    pub fn recipient(&self, room_id: &str) -> Recipient {
+        Recipient {
+            name: self.name.clone(),
+            room: room_id.to_string(),
+            translate_to: self.translate_to.first().cloned(),
+            timezone: self.timezone,
+        }
+    }
+
+    /// Queues one of the room's own messages, ahead of the chat.
+    pub fn notify(&self, msg: &Value) {
+        let _ = self.control.push(self.encoding.encode(msg));
+    }
+}
+
+/// The participants of a room by the address of their connection.
+#[derive(Debug, Clone, Default)]
+pub struct Room {
+    participants: HashMap<SocketAddr, Participant>,
+}
+
+impl Room {
+    /// Creates an empty room.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the participant connected from `addr`, returning the one it replaced, if any.
+    pub fn add_participant(
+        &mut self,
+        addr: SocketAddr,
+        participant: Participant,
+    ) -> Option<Participant> {
+        self.participants.insert(addr, participant)
+    }
+
+    /// Removes the participant connected from `addr`, if it is in the room.
+    ///
+    /// Its queues are left open, so whatever was queued before can still be flushed.
+    pub fn remove_participant(&mut self, addr: &SocketAddr) -> Option<Participant> {
+        self.participants.remove(addr)
+    }
+
+    /// Returns the participant connected from `addr`.
+    pub fn get(&self, addr: &SocketAddr) -> Option<&Participant> {
+        self.participants.get(addr)
+    }
+
+    /// Returns the participant connected from `addr`, to change it.
+    pub fn get_mut(&mut self, addr: &SocketAddr) -> Option<&mut Participant> {
+        self.participants.get_mut(addr)
+    }
+
+    /// Returns the participants and the addresses they are connected from.
+    pub fn iter(&self) -> hash_map::Iter<'_, SocketAddr, Participant> {
+        self.participants.iter()
+    }
+
+    /// Returns the participants and the addresses they are connected from, to change them.
+    pub fn iter_mut(&mut self) -> hash_map::IterMut<'_, SocketAddr, Participant> {
+        self.participants.iter_mut()
+    }
+
+    /// Returns the participants.
+    pub fn participants(&self) -> hash_map::Values<'_, SocketAddr, Participant> {
+        self.participants.values()
+    }
+
+    /// Returns everyone but the participant connected from `addr`.
+    pub fn others(&self, addr: SocketAddr) -> impl Iterator<Item = &Participant> {
+        self.participants.iter().filter(move |(peer, _)| **peer != addr).map(|(_, p)| p)
+    }
+
+    /// Returns whether a participant goes by `name`, or by the same name in another case or
+    /// with other whitespace, see [`same_name`].
+    pub fn has_name(&self, name: &str) -> bool {
+        self.participants.values().any(|p| same_name(&p.name, name))
+    }
+
+    /// Returns the number of participants.
+    pub fn len(&self) -> usize {
+        self.participants.len()
+    }
+
+    /// Returns whether the room has no participants, as if it did not exist.
+    pub fn is_empty(&self) -> bool {
+        self.participants.is_empty()
+    }
+}
+
+/// Why a participant could not join a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// Someone in the room already goes by the name.
+    DuplicateName(String),
+    /// The room holds as many participants as it may.
+    RoomFull,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::DuplicateName(name) => write!(f, "Name '{}' is already in use", name),
+            JoinError::RoomFull => write!(f, "Room full"),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+/// The longest session id a client may send, see [`is_valid_session_id`].
+pub const MAX_SESSION_ID_LEN: usize = 128;
+
+/// Returns whether `session_id` can identify the session of a client across reconnects: 1 to
+/// [`MAX_SESSION_ID_LEN`] printable ASCII characters, without spaces.
+pub fn is_valid_session_id(session_id: &str) -> bool {
+    !session_id.is_empty()
+        && session_id.len() <= MAX_SESSION_ID_LEN
+        && session_id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// A participant that joined a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Joined {
+    /// The name the participant joined as, which differs from the one it asked for if
+    /// duplicate names get renamed.
+    pub name: String,
+    /// Whether the participant created the room, and so owns it.
+    pub created: bool,
+    /// Whether the participant reconnected, taking back the name reserved for its session.
+    pub resumed: bool,
+}
+
+/// A participant that left a room.
+#[derive(Debug, Clone)]
+pub struct Left {
+    /// The participant, whose queues are closed.
+    pub participant: Participant,
+    /// Whether the participant was the last in the room.
+    pub closed: bool,
+}
+
+/// The `join` message telling a room that `name` joined from `addr`.
+///
+/// Both example servers send it to everyone but the joiner, who gets a snapshot instead.
+pub fn join_notice(name: &str, addr: SocketAddr) -> Value {
+    json!({
+        "type": "join",
+        "name": sanitize_text(name),
+        "addr": addr.to_string(),
+        "timestamp": Utc::now().to_rfc3339()
+    })
+}
+
+/// The `leave` message telling a room that `name` left it.
+///
+/// `name` should be the one the participant joined as, see [`Left::participant`].
+pub fn leave_notice(name: &str) -> Value {
+    json!({ "type": "leave", "name": sanitize_text(name), "timestamp": Utc::now().to_rfc3339() })
+}
+
+/// The rooms of a server, shared by all its connections.
+///
+/// Every method takes the lock for as long as it needs the rooms and sends nothing while
+/// holding it: the senders are collected first and the messages sent once it is released.
+/// Only joining, leaving and [`with_room`](RoomManager::with_room) take it for writing, so
+/// broadcasts to different rooms never wait on each other.
+/// Cloning the manager gives another handle on the same rooms.
+#[derive(Debug, Clone)]
+pub struct RoomManager {
+    rooms: Arc<RwLock<HashMap<String, Room>>>,
+    /// Taken after `rooms` whenever both are needed, oldest first.
+    reservations: Arc<Mutex<Vec<Reservation>>>,
+    /// The latest messages relayed in each room, oldest first, taken after `rooms` too.
+    buffers: Arc<Mutex<HashMap<String, VecDeque<Message>>>>,
+    max_buffered_messages: usize,
+    dedupe_names: bool,
+    max_participants: Option<usize>,
+    max_members: Option<usize>,
+    max_spectators: Option<usize>,
+    reconnect_grace: Option<Duration>,
+    max_reservations_per_ip: usize,
+    max_reservations_per_room: usize,
+    outbound: OutboundPipeline,
+}
+
+/// The name of a participant that left, kept for its session until `expires`.
+#[derive(Debug, Clone)]
+struct Reservation {
+    room_id: String,
+    name: String,
+    session_id: String,
+    ip: IpAddr,
+    joined_seq: u64,
+    spectator: bool,
+    expires: Instant,
+}
+
+impl Reservation {
+    /// Returns whether the reservation keeps `name` in `room_id` from anyone but `session_id`.
+    fn holds(&self, room_id: &str, name: &str, session_id: Option<&str>) -> bool {
+        self.room_id == room_id
+            && same_name(&self.name, name)
+            && Some(self.session_id.as_str()) != session_id
+    }
+}
+
+impl RoomManager {
+    /// Creates a manager without any room, which rejects duplicate names, lets rooms grow
+    /// without limit, frees the names of leavers right away, keeps no messages and sends every
+    /// message as is.
+    pub fn new() -> Self {
+        RoomManager {
+            rooms: Arc::default(),
+            reservations: Arc::default(),
+            buffers: Arc::default(),
+            max_buffered_messages: 0,
+            dedupe_names: false,
+            max_participants: None,
+            max_members: None,
+            max_spectators: None,
+            reconnect_grace: None,
+            max_reservations_per_ip: 4,
+            max_reservations_per_room: 64,
+            outbound: OutboundPipeline::new(),
+        }
+    }
+
+    /// Sets whether a joiner taking a name in use gets a unique one, e.g. `Alice (2)`,
+    /// instead of being rejected.
+    pub fn dedupe_names(mut self, dedupe_names: bool) -> Self {
+        self.dedupe_names = dedupe_names;
+        self
+    }
+
+    /// Sets how many participants a room may hold, turning further joiners away with
+    /// [`JoinError::RoomFull`].
+    pub fn max_participants(mut self, max_participants: Option<usize>) -> Self {
+        self.max_participants = max_participants;
+        self
+    }
+
+    /// Sets how many participants of a room may be members, i.e. not
+    /// [spectators](Participant::spectator), turning further members away with
+    /// [`JoinError::RoomFull`] while spectators may still join.
+    pub fn max_members(mut self, max_members: Option<usize>) -> Self {
+        self.max_members = max_members;
+        self
+    }
+
+    /// Sets how many participants of a room may be [spectators](Participant::spectator),
+    /// turning further spectators away with [`JoinError::RoomFull`] while members may still
+    /// join. Both count against [`max_participants`](Self::max_participants) too.
+    pub fn max_spectators(mut self, max_spectators: Option<usize>) -> Self {
+        self.max_spectators = max_spectators;
+        self
+    }
+
+    /// Sets how long the name of a leaver that joined with a [session
+    /// id](Participant::session_id) stays reserved, so a client losing its connection can
+    /// rejoin with the same session id and get its name and place in the roster back.
+    ///
+    /// Until then the name is taken for anyone else, as if the leaver were still in the room,
+    /// and the reservation counts against [`max_participants`](Self::max_participants), as
+    /// well as [`max_members`](Self::max_members) or [`max_spectators`](Self::max_spectators).
+    /// `None`, the default, frees names as soon as their participant leaves.
+    pub fn reconnect_grace(mut self, reconnect_grace: Option<Duration>) -> Self {
+        self.reconnect_grace = reconnect_grace;
+        self
+    }
+
+    /// Sets how many names may be reserved at once for the leavers of one IP address, across
+    /// rooms, and for the leavers of one room. Leaving past either limit releases the oldest
+    /// reservation early, so a client reconnecting under ever new session ids cannot hoard
+    /// names. The default values are 4 and 64.
+    pub fn max_reservations(mut self, per_ip: usize, per_room: usize) -> Self {
+        self.max_reservations_per_ip = per_ip;
+        self.max_reservations_per_room = per_room;
+        self
+    }
+
+    /// Sets how many of the latest messages relayed in a room it keeps in memory, see
+    /// [`buffered_messages`](Self::buffered_messages). Past it, the oldest message is dropped
+    /// for the new one, so a busy room holds as little as a quiet one. The default value is
+    /// 0, i.e. none are kept.
+    pub fn max_buffered_messages(mut self, max_buffered_messages: usize) -> Self {
+        self.max_buffered_messages = max_buffered_messages;
+        self
+    }
+
+    /// Sets the pipeline every message is rendered with for its recipient.
+    pub fn outbound(mut self, outbound: OutboundPipeline) -> Self {
+        self.outbound = outbound;
+        self
+    }
+
+    /// Checks whether a member named `name` could join `room_id` right now, e.g. to reject
+    /// it during the handshake. `session_id` is the one it joins with, which may reclaim a
+    /// name reserved for it.
+    pub fn can_join(
+        &self,
+        room_id: &str,
+        name: &str,
+        session_id: Option<&str>,
+    ) -> Result<(), JoinError> {
+        self.can_join_as(room_id, name, session_id, false)
+    }
+
+    /// Checks whether a participant named `name` could join `room_id` right now, as a
+    /// [spectator](Participant::spectator) or a member, see [`can_join`](Self::can_join).
+    pub fn can_join_as(
+        &self,
+        room_id: &str,
+        name: &str,
+        session_id: Option<&str>,
+        spectator: bool,
+    ) -> Result<(), JoinError> {
+        let rooms = self.rooms.read().unwrap();
+        let reservations = self.live_reservations();
+        let room = rooms.get(room_id);
+        if self.is_full(room, &reservations, room_id, session_id, spectator) {
+            return Err(JoinError::RoomFull);
+        }
+        if !self.dedupe_names && name_taken(room, &reservations, room_id, name, session_id) {
+            return Err(JoinError::DuplicateName(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn is_full(
+        &self,
+        room: Option<&Room>,
+        reservations: &[Reservation],
+        room_id: &str,
+        session_id: Option<&str>,
+        spectator: bool,
+    ) -> bool {
+        let reserved: Vec<_> = reservations
+            .iter()
+            .filter(|r| r.room_id == room_id && Some(r.session_id.as_str()) != session_id)
+            .collect();
+        let full = self
+            .max_participants
+            .map_or(false, |max| room.map_or(0, Room::len) + reserved.len() >= max);
+        let max_of_role = if spectator { self.max_spectators } else { self.max_members };
+        full || max_of_role.map_or(false, |max| {
+            let present = room
+                .map_or(0, |room| room.participants().filter(|p| p.spectator == spectator).count());
+            present + reserved.iter().filter(|r| r.spectator == spectator).count() >= max
+        })
+    }
+
+    /// Locks the reservations, dropping the expired ones.
+    fn live_reservations(&self) -> MutexGuard<'_, Vec<Reservation>> {
+        let mut reservations = self.reservations.lock().unwrap();
+        let now = Instant::now();
+        reservations.retain(|r| r.expires > now);
+        reservations
+    }
+
+    /// Returns how many names are reserved for leavers that may reconnect, see
+    /// [`reconnect_grace`](Self::reconnect_grace).
+    pub fn reservation_count(&self) -> usize {
+        self.live_reservations().len()
+    }
+
+    /// Adds the participant connected from `addr` to `room_id`.
+    ///
+    /// Whoever joins a room nobody is in creates it and becomes its [`RoomRole::Owner`]. The
+    /// limits are checked under the same lock as the insert, so of two joiners racing for the
+    /// last place in a room only one gets it.
+    ///
+    /// A participant rejoining with the session id of a leaver whose name is still reserved
+    /// takes the reservation back, and keeps the place in the roster if it rejoins under the
+    /// same name.
+    pub fn join(
+        &self,
+        room_id: &str,
+        addr: SocketAddr,
+        mut participant: Participant,
+    ) -> Result<Joined, JoinError> {
+        let mut rooms = self.rooms.write().unwrap();
+        let mut reservations = self.live_reservations();
+        let session_id = participant.session_id.as_deref();
+        let spectator = participant.spectator;
+        if self.is_full(rooms.get(room_id), &reservations, room_id, session_id, spectator) {
+            return Err(JoinError::RoomFull);
+        }
+        let taken =
+            |name: &str| name_taken(rooms.get(room_id), &reservations, room_id, name, session_id);
+        if taken(&participant.name) {
+            if !self.dedupe_names {
+                return Err(JoinError::DuplicateName(participant.name));
+            }
+            participant.name = unique_name(&participant.name, taken);
+        }
+        let mut resumed = false;
+        if let Some(session_id) = session_id {
+            let own = |r: &Reservation| r.room_id == room_id && r.session_id == session_id;
+            if let Some(r) = reservations.iter().find(|r| own(r)) {
+                if same_name(&r.name, &participant.name) {
+                    participant.joined_seq = r.joined_seq;
+                }
+                resumed = true;
+            }
+            reservations.retain(|r| !own(r));
+        }
+        let room = rooms.entry(room_id.to_string()).or_default();
+        let created = room.is_empty();
+        if created {
+            participant.role = RoomRole::Owner;
+        }
+        let name = participant.name.clone();
+        room.add_participant(addr, participant);
+        Ok(Joined { name, created, resumed })
+    }
+
+    /// Takes the participant connected from `addr` out of `room_id`, then closes its queues.
+    ///
+    /// No broadcast started after the removal reaches the participant. The messages of
+    /// those that collected its senders before are either flushed with the rest of its
+    /// queues or, once they are closed, refused by them. The room is dropped with its last
+    /// participant, in the same lock acquisition, so a joiner either joins before and keeps
+    /// it alive or creates it anew after.
+    ///
+    /// With a [`reconnect_grace`](Self::reconnect_grace), the name of a participant that
+    /// joined with a session id is reserved for it, although the room may close.
+    pub fn leave(&self, room_id: &str, addr: SocketAddr) -> Option<Left> {
+        let left = {
+            let mut rooms = self.rooms.write().unwrap();
+            let room = rooms.get_mut(room_id)?;
+            let participant = room.remove_participant(&addr)?;
+            let closed = room.is_empty();
+            if closed {
+                rooms.remove(room_id);
+                self.buffers.lock().unwrap().remove(room_id);
+            }
+            if let (Some(grace), Some(session_id)) = (self.reconnect_grace, &participant.session_id)
+            {
+                self.reserve(Reservation {
+                    room_id: room_id.to_string(),
+                    name: participant.name.clone(),
+                    session_id: session_id.clone(),
+                    ip: addr.ip(),
+                    joined_seq: participant.joined_seq,
+                    spectator: participant.spectator,
+                    expires: Instant::now() + grace,
+                });
+            }
+            Left { participant, closed }
+        };
+        left.participant.sender.close();
+        left.participant.control.close();
+        Some(left)
+    }
+
+    /// Adds `reservation`, releasing the oldest ones of its IP address and room past the
+    /// limits.
+    fn reserve(&self, reservation: Reservation) {
+        let mut reservations = self.live_reservations();
+        let (ip, room_id) = (reservation.ip, reservation.room_id.clone());
+        reservations.push(reservation);
+        release_oldest(&mut reservations, self.max_reservations_per_ip, |r| r.ip == ip);
+        release_oldest(&mut reservations, self.max_reservations_per_room, |r| r.room_id == room_id);
+    }
+
+    /// Returns the participants of `room_id`, empty if nobody is in it.
+    pub fn participants(&self, room_id: &str) -> Vec<Participant> {
+        let rooms = self.rooms.read().unwrap();
+        rooms.get(room_id).map(|room| room.participants().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Returns a copy of `room_id`, if anyone is in it.
+    pub fn room(&self, room_id: &str) -> Option<Room> {
+        self.rooms.read().unwrap().get(room_id).cloned()
+    }
+
+    /// Returns how many participants all rooms hold together.
+    pub fn participant_count(&self) -> usize {
+        self.rooms.read().unwrap().values().map(Room::len).sum()
+    }
+
+    /// Returns the ids of the rooms anyone is in.
+    pub fn room_ids(&self) -> Vec<String> {
+        self.rooms.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns the latest messages relayed in `room_id`, oldest first, see
+    /// [`max_buffered_messages`](Self::max_buffered_messages). Empty once the room closed.
+    pub fn buffered_messages(&self, room_id: &str) -> Vec<Message> {
+        let buffers = self.buffers.lock().unwrap();
+        buffers.get(room_id).map(|buffer| buffer.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Returns how many messages every room anyone is in keeps, by room id, sorted by id.
+    pub fn buffer_sizes(&self) -> Vec<(String, usize)> {
+        let rooms = self.rooms.read().unwrap();
+        let buffers = self.buffers.lock().unwrap();
+        let mut sizes: Vec<_> = rooms
+            .keys()
+            .map(|room_id| (room_id.clone(), buffers.get(room_id).map_or(0, VecDeque::len)))
+            .collect();
+        sizes.sort_unstable();
+        sizes
+    }
+
+    /// Keeps `msg` as the latest message relayed in `room_id`, if anyone is in the room, for
+    /// servers relaying messages themselves rather than with [`forward`](Self::forward).
+    pub fn buffer_message(&self, room_id: &str, msg: &Message) {
+        let rooms = self.rooms.read().unwrap();
+        if rooms.contains_key(room_id) {
+            self.buffer(room_id, msg);
+        }
+    }
+
+    /// Appends `msg` to the buffer of `room_id`, dropping the oldest messages past the limit.
+    ///
+    /// The caller holds the rooms lock and checked the room exists, so no buffer outlives its
+    /// room.
+    fn buffer(&self, room_id: &str, msg: &Message) {
+        if self.max_buffered_messages == 0 {
+            return;
+        }
+        let mut buffers = self.buffers.lock().unwrap();
+        let buffer = buffers.entry(room_id.to_string()).or_default();
+        if buffer.len() == self.max_buffered_messages {
+            buffer.pop_front();
+        }
+        buffer.push_back(msg.clone());
+    }
+
+    /// Returns the names of the participants of every room anyone is in, by room id, e.g. to
+    /// list the rooms. Rooms are sorted by id and participants by when they joined.
+    ///
+    /// Only the names are copied under the read lock, however many rooms there are.
+    pub fn rosters(&self) -> Vec<(String, Vec<String>)> {
+        let mut rosters: Vec<_> = {
+            let rooms = self.rooms.read().unwrap();
+            rooms
+                .iter()
+                .map(|(room_id, room)| {
+                    let names = room.participants().map(|p| (p.joined_seq, p.name.clone()));
+                    (room_id.clone(), names.collect::<Vec<_>>())
+                })
+                .collect()
+        };
+        rosters.sort_by(|a, b| a.0.cmp(&b.0));
+        rosters
+            .into_iter()
+            .map(|(room_id, mut names)| {
+                names.sort_unstable();
+                (room_id, names.into_iter().map(|(_, name)| name).collect())
+            })
+            .collect()
+    }
+
+    /// Runs `f` on `room_id` under the read lock, for lookups the other methods do not cover,
+    /// returning `None` if nobody is in the room.
+    ///
+    /// `f` should only decide what to send, like for [`with_room`](RoomManager::with_room).
+    pub fn read_room<R>(&self, room_id: &str, f: impl FnOnce(&Room) -> R) -> Option<R> {
+        self.rooms.read().unwrap().get(room_id).map(f)
+    }
+
+    /// Runs `f` on `room_id` under the write lock, for changes the other methods do not cover.
+    ///
+    /// The room is empty if nobody is in it, and is dropped again if `f` leaves it empty.
+    /// `f` should only decide what to send and leave sending to the other methods once it
+    /// returned; a reply to a single participant with [`Participant::notify`] is fine.
+    pub fn with_room<R>(&self, room_id: &str, f: impl FnOnce(&mut Room) -> R) -> R {
+        let mut rooms = self.rooms.write().unwrap();
+        let room = rooms.entry(room_id.to_string()).or_default();
+        let result = f(room);
+        if room.is_empty() {
+            rooms.remove(room_id);
+        }
+        result
+    }
+
+    /// Sends one of the room's own messages to everyone in `room_id` but `exclude`, ahead of
+    /// the chat.
+    pub fn broadcast(&self, room_id: &str, msg: &Value, exclude: Option<SocketAddr>) {
+        let recipients = {
+            let rooms = self.rooms.read().unwrap();
+            match rooms.get(room_id) {
+                Some(room) => control_recipients(room_id, room, exclude),
+                None => return,
+            }
+        };
+        self.send_encoded(recipients, msg);
+    }
+
+    /// Sends one of the server's own messages to every participant of every room.
+    pub fn broadcast_all(&self, msg: &Value) {
+        let recipients = {
+            let rooms = self.rooms.read().unwrap();
+            rooms.iter().flat_map(|(id, room)| control_recipients(id, room, None)).collect()
+        };
+        self.send_encoded(recipients, msg);
+    }
+
+    /// Relays chat from the participant connected from `from` to everyone else in `room_id`,
+    /// keeping it among the room's [buffered messages](Self::buffered_messages).
+    pub fn forward(&self, room_id: &str, from: SocketAddr, msg: &Message) {
+        let recipients: Vec<_> = {
+            let rooms = self.rooms.read().unwrap();
+            match rooms.get(room_id) {
+                Some(room) => {
+                    self.buffer(room_id, msg);
+                    room.others(from).map(|p| (p.sender.clone(), p.recipient(room_id))).collect()
+                }
+                None => return,
+            }
+        };
+        for (tx, recipient) in recipients {
+            if let Some(msg) = self.outbound.apply_relayed(msg.clone(), &recipient) {
+                let _ = tx.push(msg);
+            }
+        }
+    }
+
+    /// Sends the participant count of `room_id`, including the participants of other
+    /// instances.
+    pub fn broadcast_count(&self, remote: &RemoteRoster, room_id: &str) {
+        let recipients = {
+            let rooms = self.rooms.read().unwrap();
+            rooms.get(room_id).map(|room| control_recipients(room_id, room, None))
+        };
+        let recipients = recipients.unwrap_or_default();
+        let count = recipients.len() + remote.participants(room_id).len();
+
+        self.send_update(recipients, &json!({ "type": "count", "count": count }));
+    }
+
+    /// Sends the participant list of `room_id`, including the participants of other
+    /// instances.
+    ///
+    /// Only the first `max_listed` participants are listed, rooms with more participants
+    /// also get their `total` so clients can tell the list is partial.
+    pub fn broadcast_participants(&self, remote: &RemoteRoster, room_id: &str, max_listed: usize) {
+        self.send_participants(remote, room_id, max_listed, None);
+    }
+
+    /// Sends the participant list of `room_id` to the participant connected from `addr`
+    /// only, like [`broadcast_participants`](RoomManager::broadcast_participants), e.g. so a
+    /// joiner can show the room before the next change is broadcast.
+    pub fn send_participants_to(
+        &self,
+        remote: &RemoteRoster,
+        room_id: &str,
+        addr: SocketAddr,
+        max_listed: usize,
+    ) {
+        self.send_participants(remote, room_id, max_listed, Some(addr));
+    }
+
+    fn send_participants(
+        &self,
+        remote: &RemoteRoster,
+        room_id: &str,
+        max_listed: usize,
+        only: Option<SocketAddr>,
+    ) {
+        let (mut list, recipients): (Vec<Value>, _) = {
+            let rooms = self.rooms.read().unwrap();
+            match rooms.get(room_id) {
+                Some(room) => {
+                    let mut local: Vec<&Participant> = room.participants().collect();
+                    // The earliest joiners are listed if not all of them fit
+                    if local.len() > max_listed {
+                        local.sort_by_key(|p| p.joined_seq);
+                    }
+                    let list = local.into_iter().map(Participant::roster_entry).collect();
+                    let recipients = match only {
+                        Some(addr) => room
+                            .get(&addr)
+                            .map(|p| vec![(p.control.clone(), p.encoding, p.recipient(room_id))])
+                            .unwrap_or_default(),
+                        None => control_recipients(room_id, room, None),
+                    };
+                    (list, recipients)
+                }
+                None => return,
+            }
+        };
+        // Other instances only share names
+        list.extend(
+            remote
+                .participants(room_id)
+                .iter()
+                .map(|name| json!({ "name": sanitize_text(name), "role": "participant" })),
+        );
+
+        let total = list.len();
+        let mut msg = json!({ "type": "participants" });
+        if total > max_listed {
+            list.truncate(max_listed);
+            msg["total"] = total.into();
+        }
+        msg["participants"] = list.into();
+        self.send_update(recipients, &msg);
+    }
+
+    /// Sends `msg`, the partial transcript of what the participant at `from` is saying, to
+    /// everyone else in `room_id`. It replaces the previous partial transcript of `from` in
+    /// the queues it is still waiting in, so slow readers only get the latest one.
+    pub fn broadcast_partial(&self, room_id: &str, from: SocketAddr, msg: &Value) {
+        let recipients = {
+            let rooms = self.rooms.read().unwrap();
+            match rooms.get(room_id) {
+                Some(room) => control_recipients(room_id, room, Some(from)),
+                None => return,
+            }
+        };
+        let key = format!("partial {}", from);
+        self.queue_encoded(recipients, msg, |tx, msg| tx.push_superseding(msg, &key));
+    }
+
+    /// Sends `msg`, which holds the text of `translations`, to everyone in `room_id` with the
+    /// text translated into the languages each participant asked for.
+    ///
+    /// The translations are added as `translations: {"<lang>": ".."}`, so every participant
+    /// gets a message of its own. Each language is translated once however many participants
+    /// read it, and languages the translation failed for are left out.
+    pub async fn broadcast_translated(
+        &self,
+        room_id: &str,
+        msg: &Value,
+        translations: &mut Translations<'_>,
+    ) {
+        let recipients: Vec<_> = {
+            let rooms = self.rooms.read().unwrap();
+            let room = rooms.get(room_id).into_iter().flat_map(Room::participants);
+            room.map(|p| {
+                ((p.control.clone(), p.encoding, p.recipient(room_id)), p.translate_to.clone())
+            })
+            .collect()
+        };
+
+        let from = translations.from().clone();
+        for (recipient, languages) in recipients {
+            let mut translated = Map::new();
+            for lang in languages.iter().filter(|lang| **lang != from) {
+                if let Some(translation) = translations.get(lang).await {
+                    translated.insert(lang.to_string(), sanitize_text(translation).into());
+                }
+            }
+            let mut msg = msg.clone();
+            msg["translations"] = translated.into();
+            self.send_encoded(vec![recipient], &msg);
+        }
+    }
+
+    /// Sends `msg` to each recipient, serialized once per encoding in use.
+    fn send_encoded(&self, recipients: Vec<ControlRecipient>, msg: &Value) {
+        self.queue_encoded(recipients, msg, QueueSender::push);
+    }
+
+    /// Sends `msg` like [`send_encoded`](Self::send_encoded), as an update a later one
+    /// supersedes, which full queues drop first.
+    fn send_update(&self, recipients: Vec<ControlRecipient>, msg: &Value) {
+        self.queue_encoded(recipients, msg, QueueSender::push_update);
+    }
+
+    fn queue_encoded(
+        &self,
+        recipients: Vec<ControlRecipient>,
+        msg: &Value,
+        push: impl Fn(&QueueSender, Message) -> Result<(), QueueClosed>,
+    ) {
+        let (mut json, mut cbor) = (None, None);
+        for (tx, encoding, recipient) in recipients {
+            let encoded = match encoding {
+                Encoding::Json => &mut json,
+                Encoding::Cbor => &mut cbor,
+            };
+            let encoded = encoded.get_or_insert_with(|| encoding.encode(msg)).clone();
+            if let Some(msg) = self.outbound.apply(encoded, &recipient) {
+                let _ = push(&tx, msg);
+            }
+        }
+    }
+}
+
+impl Default for RoomManager {
+    fn default() -> Self {
+        RoomManager::new()
+    }
+}
+
+/// Returns whether someone in `room`, or a leaver other than `session_id` with a live
+/// reservation, goes by `name` in `room_id`.
+fn name_taken(
+    room: Option<&Room>,
+    reservations: &[Reservation],
+    room_id: &str,
+    name: &str,
+    session_id: Option<&str>,
+) -> bool {
+    room.map_or(false, |room| room.has_name(name))
+        || reservations.iter().any(|r| r.holds(room_id, name, session_id))
+}
+
+/// Drops the oldest of the `reservations` matching `matches` until at most `limit` are left.
+fn release_oldest(
+    reservations: &mut Vec<Reservation>,
+    limit: usize,
+    matches: impl Fn(&Reservation) -> bool,
+) {
+    let mut excess = reservations.iter().filter(|r| matches(r)).count().saturating_sub(limit);
+    reservations.retain(|r| {
+        let release = excess > 0 && matches(r);
+        excess -= release as usize;
+        !release
+    });
+}
+
+/// The control queue of a participant, with what is needed to render a message for it.
+type ControlRecipient = (QueueSender, Encoding, Recipient);
+
+/// Collects the control queues of everyone in `room` but `exclude`.
+fn control_recipients(
+    room_id: &str,
+    room: &Room,
+    exclude: Option<SocketAddr>,
+) -> Vec<ControlRecipient> {
+    room.iter()
+        .filter(|(addr, _)| Some(**addr) != exclude)
+        .map(|(_, p)| (p.control.clone(), p.encoding, p.recipient(room_id)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use futures_util::future::{self, BoxFuture};
+    use serde_json::json;
+    use tungstenite::Message;
+
+    use super::{JoinError, Joined, Participant, RoomManager, RoomRole};
+    use crate::{
+        queue, Encoding, LanguageCode, OverflowPolicy, QueueReceiver, QueueSender, RemoteRoster,
+        TranslateError, Translations, Translator,
+    };
+
+    /// A queue none of the tests fill up
+    fn channel() -> (QueueSender, QueueReceiver) {
+        queue(64, OverflowPolicy::DropOldest)
+    }
+
+    #[test]
+    fn broadcasts_reach_whoever_is_in_the_room() {
+        let rooms = RoomManager::new();
+        let mut receivers = Vec::new();
+        for port in 1..=3 {
+            let (sender, rx) = channel();
+            let (control, control_rx) = channel();
+            let participant = Participant::new(port, "Alice", sender, control);
+            let addr = SocketAddr::from(([192, 0, 2, 1], port as u16));
+            let joined = rooms.join("main", addr, participant);
+            receivers.push((joined, rx, control_rx));
+        }
+        let alice = SocketAddr::from(([192, 0, 2, 1], 1));
+        assert!(receivers[0].0.as_ref().is_ok_and(|joined| joined.created));
+        assert_eq!(receivers[1].0, Err(JoinError::DuplicateName("Alice".into())));
+        assert_eq!(rooms.participants("main")[0].role, RoomRole::Owner);
+
+        let rooms = rooms.dedupe_names(true);
+        let (sender, rx) = channel();
+        let (control, control_rx) = channel();
+        let bob = SocketAddr::from(([192, 0, 2, 1], 4));
+        let joined = rooms.join("main", bob, Participant::new(4, "Alice", sender, control));
+        assert_eq!(joined.as_ref().map(|joined| joined.name.as_str()), Ok("Alice (2)"));
+        receivers.push((joined, rx, control_rx));
+
+        let notice = serde_json::json!({ "type": "room_resumed" });
+        rooms.broadcast("main", &notice, Some(bob));
+        rooms.forward("main", alice, &Message::text("hi"));
+        let left = rooms.leave("main", bob).unwrap();
+        assert!(!left.closed);
+        rooms.forward("main", alice, &Message::text("still there?"));
+
+        let (_, alice_chat, alice_control) = &mut receivers[0];
+        assert_eq!(Encoding::Json.decode(&alice_control.try_recv().unwrap()), Some(notice));
+        assert!(alice_chat.try_recv().is_none(), "chat is not echoed");
+        let (_, bob_chat, bob_control) = &mut receivers[3];
+        assert_eq!(bob_chat.try_recv().unwrap(), Message::text("hi"));
+        assert!(bob_chat.try_recv().is_none() && bob_control.try_recv().is_none());
+    }
+
+    #[test]
+    fn flooding_a_stalled_participant_triggers_the_overflow_policy() {
+        let alice = SocketAddr::from(([192, 0, 2, 1], 1));
+        for policy in [OverflowPolicy::DropOldest, OverflowPolicy::DisconnectSlow] {
+            let rooms = RoomManager::new();
+            let mut queues = Vec::new();
+            for (port, name) in [(1, "Alice"), (2, "Bob")] {
+                let (sender, rx) = queue(8, policy);
+                let (control, control_rx) = queue(8, policy);
+                let addr = SocketAddr::from(([192, 0, 2, 1], port));
+                let participant = Participant::new(port.into(), name, sender, control);
+                rooms.join("main", addr, participant).unwrap();
+                queues.push((rx, control_rx));
+            }
+            // Nobody reads Bob's queues while Alice floods the room
+            for i in 0..100 {
+                rooms.forward("main", alice, &Message::text(format!("chat {}", i)));
+                rooms.broadcast_count(&RemoteRoster::new(), "main");
+            }
+
+            let (mut chat, mut control) = queues.pop().unwrap();
+            let chat: Vec<_> = std::iter::from_fn(|| chat.try_recv()).collect();
+            let control: Vec<_> = std::iter::from_fn(|| control.try_recv()).collect();
+            match policy {
+                OverflowPolicy::DropOldest => {
+                    let expected: Vec<_> =
+                        (92..100).map(|i| Message::text(format!("chat {}", i))).collect();
+                    assert_eq!(chat, expected);
+                    assert_eq!(control.len(), 8);
+                }
+                OverflowPolicy::DisconnectSlow => {
+                    for queue in [chat, control] {
+                        match &queue[..] {
+                            [Message::Close(Some(frame))] => {
+                                assert_eq!(u16::from(frame.code), 4001)
+                            }
+                            other => panic!("expected a close frame, got {:?}", other),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rooms_keep_only_their_latest_messages() {
+        let rooms = RoomManager::new().max_buffered_messages(3);
+        let alice = SocketAddr::from(([192, 0, 2, 1], 1));
+        let (sender, _rx) = channel();
+        let (control, _control_rx) = channel();
+        rooms.join("main", alice, Participant::new(1, "Alice", sender, control)).unwrap();
+        for i in 0..5 {
+            rooms.forward("main", alice, &Message::text(format!("chat {}", i)));
+        }
+        rooms.buffer_message("elsewhere", &Message::text("nobody hears this"));
+
+        let expected: Vec<_> = (2..5).map(|i| Message::text(format!("chat {}", i))).collect();
+        assert_eq!(rooms.buffered_messages("main"), expected);
+        assert_eq!(rooms.buffer_sizes(), [("main".to_string(), 3)]);
+        rooms.leave("main", alice).unwrap();
+        assert!(rooms.buffered_messages("main").is_empty());
+    }
+
+    #[test]
+    fn names_differing_in_case_collide() {
+        let rooms = RoomManager::new();
+        let join = |rooms: &RoomManager, port: u16, name: &str| {
+            let (sender, _) = channel();
+            let (control, _) = channel();
+            let addr = SocketAddr::from(([192, 0, 2, 1], port));
+            rooms.join("main", addr, Participant::new(port.into(), name, sender, control))
+        };
+        join(&rooms, 1, "John").unwrap();
+
+        assert_eq!(
+            rooms.can_join("main", "john", None),
+            Err(JoinError::DuplicateName("john".into()))
+        );
+        assert_eq!(join(&rooms, 2, "JOHN"), Err(JoinError::DuplicateName("JOHN".into())));
+        let rooms = rooms.dedupe_names(true);
+        assert_eq!(join(&rooms, 3, "john").map(|joined| joined.name), Ok("john (2)".into()));
+    }
+
+    #[test]
+    fn racing_joiners_cannot_overfill_a_room() {
+        let rooms = RoomManager::new().max_participants(Some(3));
+        let joiners = (1..=16u16).map(|port| {
+            let rooms = rooms.clone();
+            std::thread::spawn(move || {
+                let (sender, _) = channel();
+                let (control, _) = channel();
+                let participant =
+                    Participant::new(port.into(), format!("p{}", port), sender, control);
+                rooms.join("main", SocketAddr::from(([192, 0, 2, 1], port)), participant)
+            })
+        });
+        let results: Vec<_> =
+            joiners.collect::<Vec<_>>().into_iter().map(|j| j.join().unwrap()).collect();
+
+        assert_eq!(results.iter().filter(|joined| joined.is_ok()).count(), 3);
+        assert!(results.iter().all(|joined| joined.is_ok() || *joined == Err(JoinError::RoomFull)));
+        assert_eq!(rooms.can_join("main", "p17", None), Err(JoinError::RoomFull));
+        assert_eq!(rooms.can_join("other", "p17", None), Ok(()));
+    }
+
+    #[test]
+    fn members_and_spectators_have_caps_of_their_own() {
+        let rooms = RoomManager::new().max_members(Some(2)).max_spectators(Some(1));
+        let join_as = |rooms: &RoomManager, port: u16, name: &str, spectator: bool| {
+            let (sender, _) = channel();
+            let (control, _) = channel();
+            let mut participant = Participant::new(port.into(), name, sender, control);
+            participant.spectator = spectator;
+            rooms.join("main", SocketAddr::from(([192, 0, 2, 1], port)), participant)
+        };
+        join_as(&rooms, 1, "Alice", false).unwrap();
+        join_as(&rooms, 2, "Bob", true).unwrap();
+        join_as(&rooms, 3, "Carol", false).unwrap();
+
+        assert_eq!(join_as(&rooms, 4, "Dave", false), Err(JoinError::RoomFull));
+        assert_eq!(join_as(&rooms, 5, "Erin", true), Err(JoinError::RoomFull));
+        assert_eq!(rooms.can_join_as("main", "Dave", None, false), Err(JoinError::RoomFull));
+
+        // Unlimited spectators still fill the room only up to its overall cap
+        let rooms = rooms.max_spectators(None).max_participants(Some(4));
+        assert_eq!(rooms.can_join_as("main", "Erin", None, true), Ok(()));
+        join_as(&rooms, 5, "Erin", true).unwrap();
+        assert_eq!(rooms.can_join_as("main", "Frank", None, true), Err(JoinError::RoomFull));
+    }
+
+    /// Joins `name` from port `port` of a test address, with `session_id` if given
+    fn join_session(
+        rooms: &RoomManager,
+        port: u16,
+        name: &str,
+        session_id: Option<&str>,
+    ) -> Result<Joined, JoinError> {
+        let (sender, _) = channel();
+        let (control, _) = channel();
+        let mut participant = Participant::new(port.into(), name, sender, control);
+        participant.session_id = session_id.map(String::from);
+        rooms.join("main", SocketAddr::from(([192, 0, 2, 1], port)), participant)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnects_reclaim_their_name_within_the_grace() {
+        let rooms = RoomManager::new().reconnect_grace(Some(Duration::from_secs(30)));
+        join_session(&rooms, 1, "Alice", Some("s-alice")).unwrap();
+        join_session(&rooms, 2, "Bob", None).unwrap();
+        let alice = SocketAddr::from(([192, 0, 2, 1], 1));
+        assert!(rooms.leave("main", alice).is_some());
+        assert_eq!(rooms.reservation_count(), 1);
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        let taken = JoinError::DuplicateName("alice".into());
+        assert_eq!(rooms.can_join("main", "alice", None), Err(taken.clone()));
+        assert_eq!(rooms.can_join("main", "alice", Some("s-other")), Err(taken.clone()));
+        assert_eq!(join_session(&rooms, 3, "alice", Some("s-other")), Err(taken));
+        assert_eq!(rooms.can_join("main", "Alice", Some("s-alice")), Ok(()));
+
+        let joined = join_session(&rooms, 4, "Alice", Some("s-alice")).unwrap();
+        assert_eq!(joined.name, "Alice");
+        assert!(joined.resumed);
+        assert_eq!(rooms.reservation_count(), 0);
+        let alice = rooms.participants("main").into_iter().find(|p| p.name == "Alice").unwrap();
+        assert_eq!(alice.joined_seq, 1, "the reconnect keeps its place in the roster");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn names_are_free_for_anyone_once_the_grace_expires() {
+        let rooms = RoomManager::new().reconnect_grace(Some(Duration::from_secs(30)));
+        join_session(&rooms, 1, "Alice", Some("s-alice")).unwrap();
+        let left = rooms.leave("main", SocketAddr::from(([192, 0, 2, 1], 1))).unwrap();
+        assert!(left.closed, "the reservation outlives the room");
+
+        // Renamed joiners steer clear of reserved names too
+        let dedupe = rooms.clone().dedupe_names(true);
+        assert_eq!(join_session(&dedupe, 2, "Alice", None).unwrap().name, "Alice (2)");
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert_eq!(rooms.reservation_count(), 0);
+        assert_eq!(join_session(&rooms, 3, "Alice", Some("s-mallory")).unwrap().name, "Alice");
+        // The name went to someone else, whoever had it cannot reclaim it
+        assert_eq!(
+            join_session(&rooms, 4, "Alice", Some("s-alice")),
+            Err(JoinError::DuplicateName("Alice".into()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hoarded_reservations_release_the_oldest_early() {
+        let rooms = RoomManager::new()
+            .reconnect_grace(Some(Duration::from_secs(30)))
+            .max_reservations(2, 64)
+            .max_participants(Some(3));
+        for port in 1..=3 {
+            let name = format!("p{}", port);
+            join_session(&rooms, port, &name, Some(&format!("s{}", port))).unwrap();
+        }
+        for port in 1..=3 {
+            rooms.leave("main", SocketAddr::from(([192, 0, 2, 1], port)));
+        }
+
+        // Only the two latest reservations of the address hold
+        assert_eq!(rooms.reservation_count(), 2);
+        assert_eq!(rooms.can_join("main", "p1", None), Ok(()));
+        assert_eq!(rooms.can_join("main", "p3", None), Err(JoinError::DuplicateName("p3".into())));
+        // Reserved places count against the capacity, but not for their own session
+        join_session(&rooms, 4, "p4", None).unwrap();
+        assert_eq!(rooms.can_join("main", "p5", None), Err(JoinError::RoomFull));
+        assert_eq!(rooms.can_join("main", "p2", Some("s2")), Ok(()));
+    }
+
+    /// Tags its translations with the target language, counting how often it is called
+    #[derive(Default)]
+    struct Tagging(AtomicUsize);
+
+    impl Translator for Tagging {
+        fn translate<'a>(
+            &'a self,
+            text: &'a str,
+            _from: &'a LanguageCode,
+            to: &'a LanguageCode,
+        ) -> BoxFuture<'a, Result<String, TranslateError>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Box::pin(future::ready(Ok(format!("[{}] {}", to, text))))
+        }
+    }
+
+    #[tokio::test]
+    async fn recipients_get_their_own_translations() {
+        let rooms = RoomManager::new();
+        let mut controls = Vec::new();
+        for &(port, languages) in &[(1, "en"), (2, "fr,en"), (3, "en"), (4, "ja")] {
+            let (sender, _) = channel();
+            let (control, control_rx) = channel();
+            let mut participant = Participant::new(port, format!("p{}", port), sender, control);
+            participant.translate_to = LanguageCode::parse_list(vec![languages]).unwrap();
+            let addr = SocketAddr::from(([192, 0, 2, 1], port as u16));
+            rooms.join("main", addr, participant).unwrap();
+            controls.push(control_rx);
+        }
+
+        let translator = Tagging::default();
+        let msg = json!({ "type": "transcript", "lang": "ja", "text": "konnichiwa" });
+        let ja = "ja".parse().unwrap();
+        let mut translations = Translations::new(&translator, "konnichiwa", &ja);
+        rooms.broadcast_translated("main", &msg, &mut translations).await;
+
+        let mut translations = controls.iter_mut().map(|control| {
+            let msg = Encoding::Json.decode(&control.try_recv().unwrap()).unwrap();
+            assert_eq!(msg["text"], "konnichiwa");
+            msg["translations"].clone()
+        });
+        assert_eq!(translations.next().unwrap(), json!({ "en": "[en] konnichiwa" }));
+        let both = json!({ "fr": "[fr] konnichiwa", "en": "[en] konnichiwa" });
+        assert_eq!(translations.next().unwrap(), both);
+        assert_eq!(translations.next().unwrap(), json!({ "en": "[en] konnichiwa" }));
+        assert_eq!(translations.next().unwrap(), json!({}), "the text is in Japanese already");
+        assert_eq!(translator.0.load(Ordering::Relaxed), 2, "each language is translated once");
+    }
+
+    /// Times broadcasts in 1000 rooms at once while participants come and go, to compare how
+    /// the locking of the rooms holds up under contention. Run it on its own, in release mode:
+    /// `cargo test --release --features server --lib -- --ignored --nocapture 1000_rooms`.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    #[ignore = "a benchmark, see its doc comment"]
+    async fn broadcasts_in_1000_rooms_at_once() {
+        const ROOMS: u16 = 1000;
+        const PEERS: u16 = 4;
+        const BROADCASTS: usize = 200;
+        let rooms = RoomManager::new();
+        let mut receivers = Vec::new();
+        for room in 0..ROOMS {
+            for peer in 0..PEERS {
+                let (sender, rx) = channel();
+                let (control, control_rx) = channel();
+                let port = room * PEERS + peer;
+                let participant =
+                    Participant::new(port.into(), format!("p{}", peer), sender, control);
+                let addr = SocketAddr::from(([192, 0, 2, 1], port));
+                rooms.join(&format!("room-{}", room), addr, participant).unwrap();
+                receivers.push((rx, control_rx));
+            }
+        }
+
+        let tasks = (0..ROOMS).map(|room| {
+            let rooms = rooms.clone();
+            tokio::spawn(async move {
+                let room_id = format!("room-{}", room);
+                let notice = json!({ "type": "notice", "message": "hello" });
+                let chat = Message::text("hello");
+                let from = SocketAddr::from(([192, 0, 2, 1], room * PEERS));
+                let guest = SocketAddr::from(([192, 0, 2, 2], room));
+                let mut latencies = Vec::with_capacity(BROADCASTS);
+                for i in 0..BROADCASTS {
+                    let start = Instant::now();
+                    match i % 10 {
+                        // Every so often someone joins or leaves, which takes the write lock
+                        0 => {
+                            let (sender, _) = channel();
+                            let (control, _) = channel();
+                            let guest_participant = Participant::new(0, "guest", sender, control);
+                            rooms.join(&room_id, guest, guest_participant).unwrap();
+                        }
+                        5 => drop(rooms.leave(&room_id, guest)),
+                        n if n % 2 == 0 => rooms.broadcast(&room_id, &notice, None),
+                        _ => rooms.forward(&room_id, from, &chat),
+                    }
+                    latencies.push(start.elapsed());
+                    tokio::task::yield_now().await;
+                }
+                latencies
+            })
+        });
+        let latencies = future::join_all(tasks).await.into_iter().map(Result::unwrap);
+        let mut latencies: Vec<_> = latencies.flatten().collect();
+        latencies.sort();
+
+        let at = |quantile: f64| latencies[((latencies.len() - 1) as f64 * quantile) as usize];
+        println!(
+            "{} operations in {} rooms: p50 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+            latencies.len(),
+            ROOMS,
+            at(0.5),
+            at(0.99),
+            at(0.999),
+            latencies[latencies.len() - 1]
+        );
+        drop(receivers);
+    }
+}
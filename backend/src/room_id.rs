@@ -0,0 +1,160 @@
+//! Room ids requested in the path or query of a handshake.
+use std::{error::Error, fmt};
+
+use url::form_urlencoded;
+
+/// Parses the path of a handshake request, e.g. `/main`, into the room id it asks for.
+///
+/// Room ids are a single path segment of ASCII letters, digits, `-` and `_`, at most
+/// `max_len` characters long. A trailing slash is ignored, so `/main/` asks for `main` too.
+/// An empty path, i.e. `/` or a URL with only a query such as `/?name=Alice`, asks for no
+/// room in particular and gives `None`, leaving the server to pick a default room or to
+/// require one.
+///
+/// Pass the raw path of the request: the check runs before any percent-decoding or dot
+/// segment normalization, so `/a/../b` or `/%2e%2e` are rejected rather than resolved.
+pub fn parse_room_id(path: &str, max_len: usize) -> Result<Option<String>, InvalidRoomId> {
+    let room = path.strip_prefix('/').unwrap_or(path);
+    let room = match room.strip_suffix('/') {
+        Some(trimmed) if !trimmed.is_empty() => trimmed,
+        _ => room,
+    };
+    validate(room, max_len)
+}
+
+/// Picks the room id a handshake asks for from its raw `path` and `query`, e.g.
+/// `/main?name=Alice`, or `/?room=main&name=Alice` for clients that cannot pick the path.
+///
+/// The path wins: the `room` query parameter is only looked at if the path asks for no room,
+/// see [`parse_room_id`]. The parameter is percent-decoded and then held to the same rules
+/// as the path, and the last one counts if it is given more than once. `None` means neither
+/// asks for a room.
+pub fn requested_room_id(
+    path: &str,
+    query: Option<&str>,
+    max_len: usize,
+) -> Result<Option<String>, InvalidRoomId> {
+    if let Some(room) = parse_room_id(path, max_len)? {
+        return Ok(Some(room));
+    }
+    let params = form_urlencoded::parse(query.unwrap_or_default().as_bytes());
+    match params.filter(|(key, _)| key == "room").last() {
+        Some((_, room)) => validate(&room, max_len),
+        None => Ok(None),
+    }
+}
+
+/// Checks that `room` is a valid room id, `None` if it is empty.
+fn validate(room: &str, max_len: usize) -> Result<Option<String>, InvalidRoomId> {
+    if room.is_empty() {
+        return Ok(None);
+    }
+
+    if room.split('/').any(|segment| segment == "." || segment == "..") {
+        return Err(InvalidRoomId::PathTraversal);
+    }
+    if room.contains('/') {
+        return Err(InvalidRoomId::MultipleSegments);
+    }
+    if let Some(c) = room.chars().find(|&c| !(c.is_ascii_alphanumeric() || c == '-' || c == '_')) {
+        return Err(InvalidRoomId::InvalidCharacter(c));
+    }
+    if room.len() > max_len {
+        return Err(InvalidRoomId::TooLong { max_len });
+    }
+    Ok(Some(room.to_string()))
+}
+
+/// Error returned by [`parse_room_id`] for paths that are not a valid room id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidRoomId {
+    /// The path has a `.` or `..` segment.
+    PathTraversal,
+    /// The path has more than one segment, e.g. `/a/b`.
+    MultipleSegments,
+    /// The room id holds a character other than ASCII letters, digits, `-` and `_`.
+    InvalidCharacter(char),
+    /// The room id is longer than the limit.
+    TooLong {
+        /// The longest room id allowed.
+        max_len: usize,
+    },
+}
+
+impl fmt::Display for InvalidRoomId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidRoomId::PathTraversal => write!(f, "room ids cannot be '.' or '..'"),
+            InvalidRoomId::MultipleSegments => write!(f, "room ids cannot contain '/'"),
+            InvalidRoomId::InvalidCharacter(c) => {
+                write!(f, "'{}' is not allowed, use letters, digits, '-' and '_'", c)
+            }
+            InvalidRoomId::TooLong { max_len } => {
+                write!(f, "room ids are at most {} characters long", max_len)
+            }
+        }
+    }
+}
+
+impl Error for InvalidRoomId {}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_room_id, requested_room_id, InvalidRoomId};
+
+    fn parse(path: &str) -> Result<Option<String>, InvalidRoomId> {
+        parse_room_id(path, 16)
+    }
+
+    #[test]
+    fn accepts_single_segments() {
+        assert_eq!(parse("/main"), Ok(Some("main".to_string())));
+        assert_eq!(parse("/Team-42_b"), Ok(Some("Team-42_b".to_string())));
+        assert_eq!(parse("/main/"), Ok(Some("main".to_string())));
+        assert_eq!(parse(&format!("/{}", "a".repeat(16))), Ok(Some("a".repeat(16))));
+    }
+
+    #[test]
+    fn empty_paths_ask_for_no_room() {
+        assert_eq!(parse("/"), Ok(None));
+        assert_eq!(parse(""), Ok(None));
+    }
+
+    #[test]
+    fn rooms_can_be_given_in_the_query() {
+        let request = |path, query| requested_room_id(path, query, 16);
+        let main = Ok(Some("main".to_string()));
+        // Path only, query only, and both
+        assert_eq!(request("/main", Some("name=Alice")), main);
+        assert_eq!(request("/", Some("name=Alice&room=main")), main);
+        assert_eq!(request("/main", Some("room=other")), main);
+        assert_eq!(request("/main", Some("room=..")), main);
+
+        assert_eq!(request("/", None), Ok(None));
+        assert_eq!(request("/", Some("room=")), Ok(None));
+        assert_eq!(request("/", Some("room=a&room=b")), Ok(Some("b".to_string())));
+        // The decoded parameter is held to the rules of the path
+        assert_eq!(request("/", Some("room=a%2Fb")), Err(InvalidRoomId::MultipleSegments));
+        assert_eq!(request("/", Some("room=%2e%2e")), Err(InvalidRoomId::PathTraversal));
+        assert_eq!(request("/", Some("room=a+b")), Err(InvalidRoomId::InvalidCharacter(' ')));
+        assert_eq!(request("/a/b", Some("room=main")), Err(InvalidRoomId::MultipleSegments));
+    }
+
+    #[test]
+    fn rejects_oversized_names() {
+        let path = format!("/{}", "a".repeat(17));
+        assert_eq!(parse(&path), Err(InvalidRoomId::TooLong { max_len: 16 }));
+    }
+
+    #[test]
+    fn rejects_nested_and_traversing_paths() {
+        assert_eq!(parse("/a/b"), Err(InvalidRoomId::MultipleSegments));
+        assert_eq!(parse("//"), Err(InvalidRoomId::MultipleSegments));
+        assert_eq!(parse("/a//"), Err(InvalidRoomId::MultipleSegments));
+        assert_eq!(parse("/a/../b"), Err(InvalidRoomId::PathTraversal));
+        assert_eq!(parse("/.."), Err(InvalidRoomId::PathTraversal));
+        assert_eq!(parse("/%2e%2e"), Err(InvalidRoomId::InvalidCharacter('%')));
+        assert_eq!(parse("/caf\u{e9}"), Err(InvalidRoomId::InvalidCharacter('\u{e9}')));
+        assert_eq!(parse("/a.b"), Err(InvalidRoomId::InvalidCharacter('.')));
+    }
+}
@@ -0,0 +1,74 @@
+//! The errors of running a server, for applications embedding one to react to.
+use std::{error::Error, fmt, io, net::SocketAddr};
+
+/// Error returned while starting or running a server.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The listener could not be bound to its address, e.g. as the port is in use.
+    Bind {
+        /// The address the listener was to be bound to.
+        addr: SocketAddr,
+        /// The error of the socket.
+        source: io::Error,
+    },
+    /// The listener became unusable, so no connection is accepted anymore. Transient accept
+    /// errors are retried instead.
+    Accept(io::Error),
+    /// The TLS handshake of a connection failed.
+    Tls(io::Error),
+    /// The WebSocket handshake of a connection failed, for another reason than the server
+    /// rejecting it.
+    Handshake(Box<tungstenite::Error>),
+    /// A connection could not be upgraded to a WebSocket.
+    Upgrade(Box<dyn Error + Send + Sync>),
+    /// A setting the server was started with is invalid.
+    Config {
+        /// The setting, e.g. `webhook`.
+        setting: &'static str,
+        /// Why it is invalid.
+        source: Box<dyn Error + Send + Sync>,
+    },
+}
+
+impl ServerError {
+    /// Creates the error of an invalid `setting`.
+    pub fn config<E>(setting: &'static str, error: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        ServerError::Config { setting, source: error.into() }
+    }
+
+    /// Creates the error of a failed upgrade, wrapping the one of the HTTP server.
+    pub fn upgrade<E>(error: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        ServerError::Upgrade(error.into())
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Bind { addr, source } => write!(f, "failed to bind {}: {}", addr, source),
+            ServerError::Accept(e) => write!(f, "failed to accept connections: {}", e),
+            ServerError::Tls(e) => write!(f, "TLS handshake failed: {}", e),
+            ServerError::Handshake(e) => write!(f, "WebSocket handshake failed: {}", e),
+            ServerError::Upgrade(e) => write!(f, "failed to upgrade the connection: {}", e),
+            ServerError::Config { setting, source } => write!(f, "invalid {}: {}", setting, source),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Bind { source, .. }
+            | ServerError::Accept(source)
+            | ServerError::Tls(source) => Some(source),
+            ServerError::Handshake(e) => Some(&**e),
+            ServerError::Upgrade(source) | ServerError::Config { source, .. } => Some(&**source),
+        }
+    }
+}
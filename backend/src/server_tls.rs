@@ -0,0 +1,185 @@
+//! Terminating TLS in the server itself, for `wss://` without a reverse proxy in front.
+use std::{
+    fmt, io,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::ServerError;
+
+/// Where to find the certificate a server terminates TLS with, see
+/// [`ServerConfig::tls`](crate::ServerConfig::tls).
+///
+/// Serving TLS takes the `rustls-tls-server` feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// The PEM file of the certificate chain, the certificate of the server first.
+    pub cert_path: PathBuf,
+    /// The PEM file of the private key of the certificate, in PKCS#8, PKCS#1 or SEC1.
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    /// Creates the configuration of the certificate in `cert_path`, whose key is in `key_path`.
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        TlsConfig { cert_path: cert_path.into(), key_path: key_path.into() }
+    }
+}
+
+/// Turns the connections a server accepts into [`ServerStream`]s, going through the TLS
+/// handshake first if the server terminates TLS.
+///
+/// Cloning the acceptor gives another handle on the same certificate.
+#[derive(Clone)]
+pub struct StreamAcceptor {
+    #[cfg(feature = "rustls-tls-server")]
+    tls: Option<tokio_rustls::TlsAcceptor>,
+}
+
+impl StreamAcceptor {
+    /// Creates an acceptor of plaintext connections.
+    pub fn plain() -> Self {
+        StreamAcceptor {
+            #[cfg(feature = "rustls-tls-server")]
+            tls: None,
+        }
+    }
+
+    /// Creates an acceptor terminating TLS with the certificate `tls` names, loading it, or of
+    /// plaintext connections for `None`.
+    ///
+    /// Fails if the certificate or key cannot be loaded, or if TLS is asked for without the
+    /// `rustls-tls-server` feature.
+    pub fn new(tls: Option<&TlsConfig>) -> Result<Self, ServerError> {
+        match tls {
+            None => Ok(Self::plain()),
+            #[cfg(feature = "rustls-tls-server")]
+            Some(tls) => Ok(StreamAcceptor { tls: Some(load(tls)?) }),
+            #[cfg(not(feature = "rustls-tls-server"))]
+            Some(_) => {
+                Err(ServerError::config("tls", "serving TLS takes the rustls-tls-server feature"))
+            }
+        }
+    }
+
+    /// Returns the scheme of the URLs clients connect with, `wss` or `ws`.
+    pub fn scheme(&self) -> &'static str {
+        #[cfg(feature = "rustls-tls-server")]
+        if self.tls.is_some() {
+            return "wss";
+        }
+        "ws"
+    }
+
+    /// Takes a connection the server accepted, failing if its TLS handshake does.
+    ///
+    /// Only the TLS handshake is awaited, the WebSocket one is up to the server.
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<ServerStream> {
+        #[cfg(feature = "rustls-tls-server")]
+        if let Some(tls) = &self.tls {
+            return Ok(ServerStream::Rustls(Box::new(tls.accept(stream).await?)));
+        }
+        Ok(ServerStream::Plain(stream))
+    }
+}
+
+impl fmt::Debug for StreamAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamAcceptor").field("scheme", &self.scheme()).finish()
+    }
+}
+
+/// Loads the certificate and key `tls` names.
+#[cfg(feature = "rustls-tls-server")]
+fn load(tls: &TlsConfig) -> Result<tokio_rustls::TlsAcceptor, ServerError> {
+    use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+    let certs = CertificateDer::pem_file_iter(&tls.cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| ServerError::config("TLS certificate", e))?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key_path)
+        .map_err(|e| ServerError::config("TLS key", e))?;
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| ServerError::config("TLS certificate", e))?;
+    Ok(tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config)))
+}
+
+/// A connection accepted by a server, encrypted if the server terminates TLS.
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum ServerStream {
+    /// Unencrypted socket stream.
+    Plain(TcpStream),
+    /// Encrypted socket stream using `rustls`.
+    #[cfg(feature = "rustls-tls-server")]
+    Rustls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+impl ServerStream {
+    /// Returns a shared reference to the socket.
+    pub fn get_ref(&self) -> &TcpStream {
+        match self {
+            ServerStream::Plain(s) => s,
+            #[cfg(feature = "rustls-tls-server")]
+            ServerStream::Rustls(s) => s.get_ref().0,
+        }
+    }
+}
+
+impl From<TcpStream> for ServerStream {
+    fn from(stream: TcpStream) -> Self {
+        ServerStream::Plain(stream)
+    }
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "rustls-tls-server")]
+            ServerStream::Rustls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "rustls-tls-server")]
+            ServerStream::Rustls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "rustls-tls-server")]
+            ServerStream::Rustls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "rustls-tls-server")]
+            ServerStream::Rustls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Shutting a server down without cutting its connections or their pending work short.
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{watch, Notify};
+
+/// The signal telling the connections of a server to close, shared by all of them.
+///
+/// Every connection watches it with [`until_shutdown`](crate::until_shutdown). Cloning it
+/// gives another handle on the same signal.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    signal: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    /// Creates a signal which has not been triggered.
+    pub fn new() -> Self {
+        Shutdown { signal: Arc::new(watch::channel(false).0) }
+    }
+
+    /// Triggers the signal, for every handle and for good.
+    pub fn trigger(&self) {
+        self.signal.send_replace(true);
+    }
+
+    /// Returns whether the signal was triggered.
+    pub fn is_triggered(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    /// Waits until the signal is triggered, returning right away if it already was.
+    pub async fn triggered(&self) {
+        let mut triggered = self.signal.subscribe();
+        while !*triggered.borrow_and_update() {
+            // The sender lives as long as `self`, so this never fails
+            let _ = triggered.changed().await;
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+/// Counts the work in flight, e.g. connections or transcriptions, so a shutdown can wait for
+/// it to finish. Clones share the count.
+#[derive(Debug, Clone, Default)]
+pub struct InFlight {
+    inner: Arc<InFlightInner>,
+}
+
+#[derive(Debug, Default)]
+struct InFlightInner {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl InFlight {
+    /// Creates a counter with nothing in flight.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a piece of work as in flight until the returned guard is dropped.
+    pub fn begin(&self) -> InFlightGuard {
+        self.inner.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard { inner: self.inner.clone() }
+    }
+
+    /// Returns how many pieces of work are in flight.
+    pub fn count(&self) -> usize {
+        self.inner.count.load(Ordering::SeqCst)
+    }
+
+    /// Waits until nothing is in flight, at most for `timeout`, returning whether everything
+    /// finished in time.
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Registered before checking, so the last guard cannot be dropped unnoticed
+            let idle = self.inner.idle.notified();
+            if self.count() == 0 {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, idle).await.is_err() {
+                return self.count() == 0;
+            }
+        }
+    }
+}
+
+/// A piece of work counted by an [`InFlight`] counter for as long as the guard lives.
+#[derive(Debug)]
+pub struct InFlightGuard {
+    inner: Arc<InFlightInner>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.inner.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{InFlight, Shutdown};
+
+    #[tokio::test]
+    async fn every_handle_sees_the_trigger() {
+        let shutdown = Shutdown::new();
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.triggered().await }
+        });
+        assert!(!shutdown.is_triggered());
+
+        shutdown.clone().trigger();
+        waiter.await.unwrap();
+        assert!(shutdown.is_triggered());
+        // Waiting after the fact returns right away
+        shutdown.triggered().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_ends_with_the_last_piece_of_work() {
+        let in_flight = InFlight::new();
+        assert!(in_flight.wait(Duration::ZERO).await);
+
+        let (first, second) = (in_flight.begin(), in_flight.begin());
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            drop(first);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            drop(second);
+        });
+        let start = tokio::time::Instant::now();
+        assert!(in_flight.wait(Duration::from_secs(5)).await);
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        let _stuck = in_flight.begin();
+        assert!(!in_flight.wait(Duration::from_secs(5)).await);
+        assert_eq!(in_flight.count(), 1);
+    }
+}
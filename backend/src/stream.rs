@@ -13,6 +13,8 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 /// A stream that might be protected with TLS.
 #[non_exhaustive]
 #[derive(Debug)]
+// Boxing the rustls stream would change the public variants
+#[allow(clippy::large_enum_variant)]
 pub enum MaybeTlsStream<S> {
     /// Unencrypted socket stream.
     Plain(S),
@@ -0,0 +1,101 @@
+//! Text the server relays or puts into messages of its own.
+
+/// Turns `raw` into text that is safe to place in a [`Message::Text`](tungstenite::Message::Text).
+///
+/// Invalid UTF-8 sequences (e.g. the output of a misbehaving transcription or translation
+/// backend) are replaced with `U+FFFD`, and control characters other than line breaks and
+/// tabs are stripped, so that a constructed message never makes clients choke.
+pub fn sanitize_text(raw: impl AsRef<[u8]>) -> String {
+    String::from_utf8_lossy(raw.as_ref())
+        .chars()
+        .filter(|&c| !c.is_control() || matches!(c, '\n' | '\r' | '\t'))
+        .collect()
+}
+
+/// Splits a frame holding newline-delimited messages into the messages.
+///
+/// Both `\n` and `\r\n` end a line. A frame is always complete, so a last line without a
+/// line ending is a message too, while blank lines (including the one after a trailing line
+/// ending) are dropped rather than relayed as empty messages.
+pub fn split_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.is_empty())
+}
+
+/// Tidies a display name: surrounding whitespace is trimmed and each run of whitespace inside
+/// becomes a single space. Returns `None` if nothing but whitespace is left.
+pub fn normalize_name(name: &str) -> Option<String> {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    Some(name).filter(|name| !name.is_empty())
+}
+
+/// Returns whether `a` and `b` are the same name when telling participants apart, i.e.
+/// ignoring case and whitespace, so `John` and ` john ` are.
+pub fn same_name(a: &str, b: &str) -> bool {
+    let key = |name: &str| normalize_name(name).unwrap_or_default().to_lowercase();
+    key(a) == key(b)
+}
+
+/// Returns `name`, or if it is taken, `name` with the smallest free suffix, e.g. `Alice (2)`.
+pub fn unique_name(name: &str, mut is_taken: impl FnMut(&str) -> bool) -> String {
+    if !is_taken(name) {
+        return name.to_string();
+    }
+    (2..).map(|n| format!("{} ({})", name, n)).find(|candidate| !is_taken(candidate)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tungstenite::{protocol::Role, Message};
+
+    use super::{normalize_name, same_name, sanitize_text, split_lines, unique_name};
+    use crate::WebSocketStream;
+
+    #[test]
+    fn strips_control_characters() {
+        assert_eq!(sanitize_text("a\u{0}b\u{1b}[2Jc\u{7f}"), "ab[2Jc");
+        assert_eq!(sanitize_text("line\nnext\tcell"), "line\nnext\tcell");
+    }
+
+    #[test]
+    fn replaces_invalid_sequences() {
+        // A CESU-8 encoded lone surrogate and a truncated multi-byte sequence.
+        assert_eq!(sanitize_text(b"a\xed\xa0\x80b\xe3\x81"), "a\u{fffd}\u{fffd}\u{fffd}b\u{fffd}");
+    }
+
+    #[test]
+    fn splits_batched_lines() {
+        let lines: Vec<_> = split_lines("hello\r\nworld\n\nbye\n").collect();
+        assert_eq!(lines, ["hello", "world", "bye"]);
+        assert_eq!(split_lines("no line ending").collect::<Vec<_>>(), ["no line ending"]);
+        assert_eq!(split_lines("\n\r\n").count(), 0);
+    }
+
+    #[test]
+    fn names_are_told_apart_regardless_of_case_and_whitespace() {
+        assert_eq!(normalize_name("  John \t Smith\n").as_deref(), Some("John Smith"));
+        assert_eq!(normalize_name(" \t\n"), None);
+        assert!(same_name("John", "john"));
+        assert!(same_name("John Smith", " JOHN  smith "));
+        assert!(!same_name("John", "Joan"));
+    }
+
+    #[test]
+    fn taken_names_get_the_smallest_free_suffix() {
+        let taken = ["Alice", "Alice (2)", "Alice (4)"];
+        assert_eq!(unique_name("Bob", |name| taken.contains(&name)), "Bob");
+        assert_eq!(unique_name("Alice", |name| taken.contains(&name)), "Alice (3)");
+    }
+
+    #[tokio::test]
+    async fn sanitized_translator_output_is_sent() {
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let mut server = WebSocketStream::from_raw_socket(server_io, Role::Server, None).await;
+        let mut client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+
+        let text = sanitize_text(b"caf\xc3 \xff\xfe\x00ok");
+        server.send(Message::text(text.clone())).await.unwrap();
+
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::text(text));
+    }
+}
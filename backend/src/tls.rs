@@ -3,6 +3,7 @@ use tokio::io::{AsyncRead, AsyncWrite};
 
 use tungstenite::{
     client::uri_mode, error::Error, handshake::client::Response, protocol::WebSocketConfig,
+    stream::Mode,
 };
 
 use crate::{client_async_with_config, IntoClientRequest, WebSocketStream};
@@ -201,11 +202,28 @@ where
 
     #[cfg(any(feature = "native-tls", feature = "__rustls-tls"))]
     let domain = crate::domain(&request)?;
+    #[cfg(not(any(feature = "native-tls", feature = "__rustls-tls")))]
+    let domain = String::new();
 
     // Make sure we check domain and mode first. URL must be valid.
     let mode = uri_mode(request.uri())?;
 
-    let stream = match connector {
+    let stream = wrap_stream(stream, domain, mode, connector).await?;
+    client_async_with_config(request, stream, config).await
+}
+
+/// Upgrades `stream` to TLS with `connector`, or with the default connector of the enabled TLS
+/// feature, if `mode` asks for it.
+pub(crate) async fn wrap_stream<S>(
+    stream: S,
+    #[allow(unused_variables)] domain: String,
+    mode: Mode,
+    connector: Option<Connector>,
+) -> Result<MaybeTlsStream<S>, Error>
+where
+    S: 'static + AsyncRead + AsyncWrite + Send + Unpin,
+{
+    match connector {
         Some(conn) => match conn {
             #[cfg(feature = "native-tls")]
             Connector::NativeTls(conn) => {
@@ -231,7 +249,5 @@ where
                 self::encryption::plain::wrap_stream(stream, mode).await
             }
         }
-    }?;
-
-    client_async_with_config(request, stream, config).await
+    }
 }
@@ -0,0 +1,358 @@
+//! Keeping what is said in the rooms, e.g. as the minutes of a meeting.
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use futures_util::future::{self, BoxFuture};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+};
+
+use crate::LanguageCode;
+
+/// A final transcript of something said in a room, as a [`TranscriptSink`] records it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptEntry {
+    /// The display name of the speaker.
+    pub speaker: String,
+    /// The text as it was said, i.e. untranslated.
+    pub text: String,
+    /// The language the text is in.
+    pub lang: LanguageCode,
+    /// When the transcript was broadcast.
+    pub timestamp: DateTime<Utc>,
+}
+
+impl TranscriptEntry {
+    /// Creates the entry of `text`, which `speaker` said in `lang`, timestamped now.
+    pub fn new(speaker: impl Into<String>, text: impl Into<String>, lang: LanguageCode) -> Self {
+        TranscriptEntry { speaker: speaker.into(), text: text.into(), lang, timestamp: Utc::now() }
+    }
+
+    /// Builds the JSON of the entry, e.g.
+    /// `{"speaker":"Alice","text":"hello","lang":"en","timestamp":"2024-05-01T12:00:00Z"}`.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "speaker": self.speaker,
+            "text": self.text,
+            "lang": self.lang.as_str(),
+            "timestamp": self.timestamp.to_rfc3339(),
+        })
+    }
+
+    /// Reads an entry back from its [JSON](Self::to_json), if `json` is one.
+    pub fn from_json(json: &serde_json::Value) -> Option<Self> {
+        let timestamp = DateTime::parse_from_rfc3339(json["timestamp"].as_str()?).ok()?;
+        Some(TranscriptEntry {
+            speaker: json["speaker"].as_str()?.to_string(),
+            text: json["text"].as_str()?.to_string(),
+            lang: json["lang"].as_str()?.parse().ok()?,
+            timestamp: timestamp.with_timezone(&Utc),
+        })
+    }
+}
+
+/// What to look for in the transcripts of a room, see [`FileTranscriptSink::search`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptQuery {
+    /// The text the entries contain, in any case.
+    pub text: String,
+    /// How early the entries may be, if at all.
+    pub from: Option<DateTime<Utc>>,
+    /// How late the entries may be, if at all.
+    pub to: Option<DateTime<Utc>>,
+    /// How many of the entries said before and after a match come with it. The default value
+    /// is 1.
+    pub context: usize,
+    /// How many matches are found at most, the earliest ones. The default value is 100.
+    pub limit: usize,
+}
+
+impl TranscriptQuery {
+    /// Creates the query of the entries containing `text`, whenever they were said.
+    pub fn new(text: impl Into<String>) -> Self {
+        TranscriptQuery { text: text.into(), from: None, to: None, context: 1, limit: 100 }
+    }
+
+    /// Returns whether `entry` is one the query looks for.
+    pub fn matches(&self, entry: &TranscriptEntry) -> bool {
+        self.from.map_or(true, |from| entry.timestamp >= from)
+            && self.to.map_or(true, |to| entry.timestamp <= to)
+            && entry.text.to_lowercase().contains(&self.text.to_lowercase())
+    }
+}
+
+/// An entry a [`TranscriptQuery`] matches, with the ones said around it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptMatch {
+    /// The entry matched.
+    pub entry: TranscriptEntry,
+    /// The entries said right before, the earliest first.
+    pub before: Vec<TranscriptEntry>,
+    /// The entries said right after, the earliest first.
+    pub after: Vec<TranscriptEntry>,
+}
+
+impl TranscriptMatch {
+    /// Builds the JSON of the match, e.g. `{"entry":{..},"before":[..],"after":[..]}` with
+    /// the [JSON of the entries](TranscriptEntry::to_json).
+    pub fn to_json(&self) -> serde_json::Value {
+        let entries = |entries: &[TranscriptEntry]| {
+            entries.iter().map(TranscriptEntry::to_json).collect::<Vec<_>>()
+        };
+        serde_json::json!({
+            "entry": self.entry.to_json(),
+            "before": entries(&self.before),
+            "after": entries(&self.after),
+        })
+    }
+}
+
+/// Keeps the final transcripts of the rooms, e.g. on disk or in a database.
+pub trait TranscriptSink: Send + Sync {
+    /// Records `entry`, the latest transcript of `room`.
+    ///
+    /// Several transcripts may be recorded at once, of the same room too.
+    fn record<'a>(&'a self, room: &'a str, entry: TranscriptEntry)
+        -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// The default sink, which keeps nothing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopTranscriptSink;
+
+impl TranscriptSink for NoopTranscriptSink {
+    fn record<'a>(
+        &'a self,
+        _room: &'a str,
+        _entry: TranscriptEntry,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Appends the transcripts of each room to `<room>.jsonl` in a directory, one
+/// [JSON entry](TranscriptEntry::to_json) per line.
+///
+/// The entries of a room are written one at a time, so their lines never interleave, while
+/// different rooms are written to at once.
+#[derive(Debug)]
+pub struct FileTranscriptSink {
+    dir: PathBuf,
+    /// The locks of the files being written to, by room
+    writing: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl FileTranscriptSink {
+    /// Creates a sink writing to `dir`, creating the directory if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(FileTranscriptSink { dir, writing: Mutex::default() })
+    }
+
+    /// Returns the path of the file of `room`, failing for rooms which make no file name,
+    /// e.g. `..`.
+    pub fn path(&self, room: &str) -> io::Result<PathBuf> {
+        let unsafe_name = matches!(room, "" | "." | "..") || room.contains(['/', '\\', '\0']);
+        if unsafe_name {
+            let message = format!("room '{}' makes no file name", room.escape_debug());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        Ok(self.dir.join(format!("{}.jsonl", room)))
+    }
+
+    /// Searches the transcripts of `room` for the entries `query` matches.
+    ///
+    /// The file is read a line at a time, so only the matches and their context are held in
+    /// memory, however long the room went on. A room without a file has no matches, and
+    /// lines that hold no entry, e.g. one cut short by a crash, are skipped.
+    pub async fn search(
+        &self,
+        room: &str,
+        query: &TranscriptQuery,
+    ) -> io::Result<Vec<TranscriptMatch>> {
+        let file = match File::open(self.path(room)?).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut lines = BufReader::new(file).lines();
+        let mut recent = VecDeque::new();
+        let mut matches: Vec<TranscriptMatch> = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            let json = serde_json::from_str(&line).ok();
+            let entry = match json.as_ref().and_then(TranscriptEntry::from_json) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            // The later a match, the fewer entries it has after it
+            for found in matches.iter_mut().rev().take_while(|m| m.after.len() < query.context) {
+                found.after.push(entry.clone());
+            }
+            if matches.len() < query.limit {
+                if query.matches(&entry) {
+                    let before = recent.iter().cloned().collect();
+                    matches.push(TranscriptMatch {
+                        entry: entry.clone(),
+                        before,
+                        after: Vec::new(),
+                    });
+                }
+            } else if matches.last().map_or(true, |m| m.after.len() >= query.context) {
+                break;
+            }
+            recent.push_back(entry);
+            if recent.len() > query.context {
+                recent.pop_front();
+            }
+        }
+        Ok(matches)
+    }
+
+    async fn append(&self, room: &str, line: String) -> io::Result<()> {
+        let path = self.path(room)?;
+        let lock = self.writing.lock().unwrap().entry(room.to_string()).or_default().clone();
+        let written = async {
+            let _writing = lock.lock().await;
+            let mut file = OpenOptions::new().create(true).append(true).open(&path).await?;
+            file.write_all(line.as_bytes()).await?;
+            file.flush().await
+        }
+        .await;
+
+        // Forget the lock once nobody else waits for it, only the map and this write hold it
+        let mut writing = self.writing.lock().unwrap();
+        if Arc::strong_count(&lock) == 2 {
+            writing.remove(room);
+        }
+        written
+    }
+}
+
+impl TranscriptSink for FileTranscriptSink {
+    fn record<'a>(
+        &'a self,
+        room: &'a str,
+        entry: TranscriptEntry,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let line = format!("{}\n", entry.to_json());
+        Box::pin(self.append(room, line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path::PathBuf};
+
+    use futures_util::future;
+
+    use super::{FileTranscriptSink, TranscriptEntry, TranscriptQuery, TranscriptSink};
+
+    /// A directory of its own for the test called `name`, emptied
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("transcripts-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn entries_are_appended_as_json_lines() {
+        let dir = scratch_dir("append");
+        let sink = FileTranscriptSink::new(&dir).unwrap();
+        let hello = TranscriptEntry::new("Alice", "hello", "en".parse().unwrap());
+        let hallo = TranscriptEntry::new("Bob", "hallo \"du\"", "de".parse().unwrap());
+        sink.record("main", hello.clone()).await.unwrap();
+        sink.record("main", hallo.clone()).await.unwrap();
+
+        let text = std::fs::read_to_string(dir.join("main.jsonl")).unwrap();
+        let entries: Vec<_> = text
+            .lines()
+            .map(|line| TranscriptEntry::from_json(&serde_json::from_str(line).unwrap()).unwrap())
+            .collect();
+        assert_eq!(entries, [hello, hallo]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_entries_do_not_interleave() {
+        let dir = scratch_dir("concurrent");
+        let sink = FileTranscriptSink::new(&dir).unwrap();
+        let text = "word ".repeat(2000);
+        let records = (0..20).map(|i| {
+            let entry =
+                TranscriptEntry::new(format!("Speaker {}", i), &text, "en".parse().unwrap());
+            sink.record("main", entry)
+        });
+        for recorded in future::join_all(records).await {
+            recorded.unwrap();
+        }
+
+        let written = std::fs::read_to_string(dir.join("main.jsonl")).unwrap();
+        assert_eq!(written.lines().count(), 20);
+        for line in written.lines() {
+            let entry = TranscriptEntry::from_json(&serde_json::from_str(line).unwrap()).unwrap();
+            assert_eq!(entry.text, text);
+        }
+        assert!(sink.writing.lock().unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn searches_find_entries_with_their_context() {
+        let dir = scratch_dir("search");
+        let sink = FileTranscriptSink::new(&dir).unwrap();
+        let texts = ["hello", "the budget", "next week", "Budget approved", "bye"];
+        let mut entries = Vec::new();
+        for (minutes, text) in texts.iter().enumerate() {
+            let mut entry = TranscriptEntry::new("Alice", *text, "en".parse().unwrap());
+            entry.timestamp = "2024-05-01T12:00:00Z".parse().unwrap();
+            entry.timestamp += chrono::Duration::minutes(minutes as i64);
+            sink.record("main", entry.clone()).await.unwrap();
+            entries.push(entry);
+        }
+
+        let found = sink.search("main", &TranscriptQuery::new("BUDGET")).await.unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].entry, entries[1]);
+        assert_eq!(
+            (found[0].before.as_slice(), found[0].after.as_slice()),
+            (&entries[..1], &entries[2..3])
+        );
+        assert_eq!(
+            (found[1].before.as_slice(), found[1].after.as_slice()),
+            (&entries[2..3], &entries[4..])
+        );
+
+        let mut query = TranscriptQuery::new("budget");
+        query.from = Some(entries[2].timestamp);
+        let found = sink.search("main", &query).await.unwrap();
+        assert_eq!(found.iter().map(|m| &m.entry).collect::<Vec<_>>(), [&entries[3]]);
+        query.from = None;
+        query.to = Some(entries[2].timestamp);
+        query.context = 0;
+        let found = sink.search("main", &query).await.unwrap();
+        assert_eq!(found.iter().map(|m| &m.entry).collect::<Vec<_>>(), [&entries[1]]);
+        assert!(found[0].before.is_empty() && found[0].after.is_empty());
+
+        assert!(sink.search("silent", &TranscriptQuery::new("budget")).await.unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rooms_must_make_file_names() {
+        let dir = scratch_dir("names");
+        let sink = FileTranscriptSink::new(&dir).unwrap();
+        for room in ["..", "a/b", ""] {
+            let entry = TranscriptEntry::new("Alice", "hello", "en".parse().unwrap());
+            let e = sink.record(room, entry).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+#![cfg(all(feature = "rustls-tls-server", feature = "connect"))]
+
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use rustls_pki_types::{pem::PemObject, CertificateDer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::{
+    accept_async, connect_async_tls_with_config, tungstenite::Message, Connector, EventSink,
+    HttpWebhookSink, RoomEvent, ServerConfig, ServerError, TlsConfig, WebhookConfig,
+};
+
+/// The self-signed certificate of `localhost` in `tests/tls`
+fn localhost() -> TlsConfig {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls");
+    TlsConfig::new(format!("{}/cert.pem", dir), format!("{}/key.pem", dir))
+}
+
+/// A connector trusting the certificate of `tls`, as nobody signed it
+fn trusting(tls: &TlsConfig) -> Connector {
+    let mut roots = rustls::RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(&tls.cert_path).unwrap();
+    roots.add_parsable_certificates(certs.map(Result::unwrap));
+    let client =
+        rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    Connector::Rustls(Arc::new(client))
+}
+
+#[tokio::test]
+async fn servers_terminate_tls() {
+    let tls = localhost();
+    let config = ServerConfig { tls: Some(tls.clone()), ..ServerConfig::default() };
+    let acceptor = config.stream_acceptor().unwrap();
+    assert_eq!(acceptor.scheme(), "wss");
+    let listener = config.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream = acceptor.accept(stream).await.expect("Failed the TLS handshake");
+        let mut ws_stream = accept_async(stream).await.expect("Failed the WebSocket handshake");
+        while let Some(Ok(msg)) = ws_stream.next().await {
+            if msg.is_text() {
+                ws_stream.send(msg).await.unwrap();
+            }
+        }
+    });
+
+    let url = format!("wss://localhost:{}/", port);
+    let (mut ws_stream, _) =
+        connect_async_tls_with_config(url, None, false, Some(trusting(&tls))).await.unwrap();
+
+    ws_stream.send(Message::text("hello")).await.unwrap();
+    assert_eq!(ws_stream.next().await.unwrap().unwrap(), Message::text("hello"));
+}
+
+#[tokio::test]
+async fn webhooks_are_posted_over_tls() {
+    let tls = localhost();
+    let config = ServerConfig { tls: Some(tls.clone()), ..ServerConfig::default() };
+    let acceptor = config.stream_acceptor().unwrap();
+    let listener = config.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let url = format!("https://localhost:{}/hook", port);
+    let mut webhook = WebhookConfig::new(url.parse().unwrap());
+    webhook.connector = Some(trusting(&tls));
+    let sink = HttpWebhookSink::new(webhook).unwrap();
+    sink.send(RoomEvent::RoomCreated { room: "main".into() });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = acceptor.accept(stream).await.expect("Failed the TLS handshake");
+    let mut request = vec![0; 4096];
+    let len = stream.read(&mut request).await.unwrap();
+    let request = String::from_utf8(request[..len].to_vec()).unwrap();
+    assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+    assert!(request.contains(r#""event":"room_created""#));
+    stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+}
+
+#[test]
+fn missing_certificates_fail_to_load() {
+    let tls = TlsConfig::new("missing/cert.pem", localhost().key_path);
+    let config = ServerConfig { tls: Some(tls), ..ServerConfig::default() };
+    match config.stream_acceptor() {
+        Err(ServerError::Config { setting, .. }) => assert_eq!(setting, "TLS certificate"),
+        other => panic!("expected a config error, got {:?}", other),
+    }
+}